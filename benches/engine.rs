@@ -0,0 +1,271 @@
+//! Benchmarks of the engine over representative workloads, run them with `cargo bench`.
+//!
+//! A benchmark whose name contains the first argument is the only one run, e.g.
+//! `cargo bench -- get`. The number of keys of the read benchmarks is taken from
+//! `AILURUS_BENCH_KEYS`, one million by default.
+//!
+//! Every fixture is generated from a fixed seed, the same benchmark thus runs on the same
+//! store from one run to the next. Each benchmark runs for every supported index type; the
+//! file IO backend is the only one the engine exposes.
+
+use ailurus_kv::engine::Engine;
+use ailurus_kv::options::{
+    IndexType, IteratorOptions, Options, OptionsBuilder, SyncPolicy, WriteBatchOptions,
+};
+use bytes::Bytes;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Index types the benchmarks run for
+const INDEXES: &[IndexType] = &[IndexType::BTree, IndexType::SkipList, IndexType::HashMap];
+
+/// Size of the values written by the benchmarks
+const VALUE_LEN: usize = 128;
+
+/// Times a benchmark is sampled, the median sample is reported
+const SAMPLES: usize = 10;
+
+fn key(i: u64) -> Bytes {
+    format!("key-{:010}", i).into()
+}
+
+fn value(rng: &mut fastrand::Rng) -> Bytes {
+    std::iter::repeat_with(|| rng.alphanumeric() as u8)
+        .take(VALUE_LEN)
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn options(dir: &Path, index: &IndexType) -> OptionsBuilder {
+    let mut builder = OptionsBuilder::default();
+    builder
+        .dir_path(dir.to_path_buf())
+        .index_type(index.clone())
+        .sync_policy(SyncPolicy::Never);
+    builder
+}
+
+/// A store of `keys` keys, put in a random order drawn from a fixed seed
+struct Fixture {
+    opts: Options,
+    keys: u64,
+    _dir: TempDir,
+}
+
+impl Fixture {
+    fn new(builder: &mut OptionsBuilder, keys: u64) -> Fixture {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = builder.dir_path(dir.path().to_path_buf()).build().unwrap();
+        let mut rng = fastrand::Rng::with_seed(keys);
+        let mut order: Vec<u64> = (0..keys).collect();
+        rng.shuffle(&mut order);
+
+        let engine = Engine::new(opts.clone()).unwrap();
+        for i in order {
+            engine.put(key(i), value(&mut rng)).unwrap();
+        }
+        engine.sync().unwrap();
+        Fixture {
+            opts,
+            keys,
+            _dir: dir,
+        }
+    }
+
+    fn open(&self) -> Engine {
+        Engine::new(self.opts.clone()).unwrap()
+    }
+}
+
+struct Bencher {
+    filter: Option<String>,
+}
+
+impl Bencher {
+    fn from_args() -> Bencher {
+        // cargo passes `--bench`, the flags are left out
+        let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+        Bencher { filter }
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| name.contains(filter.as_str()))
+    }
+
+    /// Reports the time per operation of `sample`, which runs `ops` operations.
+    /// `setup` runs before every sample and is not timed.
+    fn run<S, F>(&self, name: &str, ops: u64, mut setup: impl FnMut() -> S, mut sample: F)
+    where
+        F: FnMut(S),
+    {
+        if !self.enabled(name) {
+            return;
+        }
+        // warm up
+        sample(setup());
+        let mut elapsed: Vec<Duration> = (0..SAMPLES)
+            .map(|_| {
+                let state = setup();
+                let start = Instant::now();
+                sample(state);
+                start.elapsed()
+            })
+            .collect();
+        elapsed.sort();
+        let median = elapsed[SAMPLES / 2];
+        let per_op = median / ops as u32;
+        println!(
+            "{:<40} {:>12?}/op {:>12.0} op/s  [{:?} .. {:?}]",
+            name,
+            per_op,
+            ops as f64 / median.as_secs_f64(),
+            elapsed[0] / ops as u32,
+            elapsed[SAMPLES - 1] / ops as u32,
+        );
+    }
+}
+
+/// Puts keys in order into a fresh store, syncing after every put or never
+fn sequential_put(bencher: &Bencher, index: &IndexType) {
+    for (policy, ops) in [(SyncPolicy::Never, 100_000), (SyncPolicy::Always, 1_000)] {
+        let name = match policy {
+            SyncPolicy::Always => "sync",
+            _ => "nosync",
+        };
+        bencher.run(
+            &format!("put/sequential/{}/{}", name, index),
+            ops,
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let opts = options(dir.path(), index)
+                    .sync_policy(policy)
+                    .build()
+                    .unwrap();
+                let payload = value(&mut fastrand::Rng::with_seed(0));
+                (Engine::new(opts).unwrap(), payload, dir)
+            },
+            |(engine, payload, _dir)| {
+                for i in 0..ops {
+                    engine.put(key(i), payload.clone()).unwrap();
+                }
+            },
+        );
+    }
+}
+
+/// Gets keys of the fixture in a random order
+fn random_get(bencher: &Bencher, index: &IndexType, fixture: &Fixture) {
+    let ops = 100_000;
+    let engine = fixture.open();
+    let mut rng = fastrand::Rng::with_seed(0);
+    bencher.run(
+        &format!("get/random/{}", index),
+        ops,
+        || {
+            (0..ops)
+                .map(|_| key(rng.u64(..fixture.keys)))
+                .collect::<Vec<_>>()
+        },
+        |keys| {
+            for key in keys {
+                black_box(engine.get(key).unwrap());
+            }
+        },
+    );
+}
+
+/// Iterates over every entry of the fixture, forward and backward
+fn full_scan(bencher: &Bencher, index: &IndexType, fixture: &Fixture) {
+    let engine = fixture.open();
+    for reverse in [false, true] {
+        let name = match reverse {
+            true => "reverse",
+            false => "forward",
+        };
+        bencher.run(
+            &format!("scan/{}/{}", name, index),
+            fixture.keys,
+            || (),
+            |()| {
+                let opts = IteratorOptions::new().reverse(reverse);
+                for entry in engine.iter(opts).unwrap() {
+                    black_box(entry.unwrap());
+                }
+            },
+        );
+    }
+}
+
+/// Opens stores of the same datafile size, rebuilding the index from more and more datafiles
+fn reopen(bencher: &Bencher, index: &IndexType) {
+    const FILE_SIZE: u64 = 256 * 1024;
+    // keys filling a datafile, about
+    let per_file = FILE_SIZE / (VALUE_LEN as u64 + 32);
+    for files in [1, 16, 64, 256] {
+        let name = format!("reopen/{}-files/{}", files, index);
+        if !bencher.enabled(&name) {
+            continue;
+        }
+        let mut builder = options(Path::new(""), index);
+        builder.data_file_size(FILE_SIZE).danger_small_files(true);
+        let fixture = Fixture::new(&mut builder, files * per_file);
+        bencher.run(&name, 1, || (), |()| drop(black_box(fixture.open())));
+    }
+}
+
+/// Commits batches of growing sizes, without syncing
+fn batch_commit(bencher: &Bencher, index: &IndexType) {
+    let ops = 100_000;
+    for size in [1, 16, 256] {
+        bencher.run(
+            &format!("batch/{}-writes/{}", size, index),
+            ops,
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let opts = options(dir.path(), index).build().unwrap();
+                let payload = value(&mut fastrand::Rng::with_seed(0));
+                (Engine::new(opts).unwrap(), payload, dir)
+            },
+            |(engine, payload, _dir)| {
+                let opts = WriteBatchOptions {
+                    sync_on_commit: false,
+                    ..Default::default()
+                };
+                for batch in 0..ops / size {
+                    let mut writes = engine.write_batch(opts.clone());
+                    for i in 0..size {
+                        writes.put(key(batch * size + i), payload.clone()).unwrap();
+                    }
+                    writes.commit().unwrap();
+                }
+            },
+        );
+    }
+}
+
+fn main() {
+    let bencher = Bencher::from_args();
+    let keys = std::env::var("AILURUS_BENCH_KEYS")
+        .map(|keys| keys.parse().expect("AILURUS_BENCH_KEYS is not a number"))
+        .unwrap_or(1_000_000);
+
+    for index in INDEXES {
+        sequential_put(&bencher, index);
+        batch_commit(&bencher, index);
+        reopen(&bencher, index);
+
+        let reads = ["get/random", "scan/forward", "scan/reverse"];
+        if reads
+            .iter()
+            .any(|name| bencher.enabled(&format!("{}/{}", name, index)))
+        {
+            let fixture = Fixture::new(&mut options(Path::new(""), index), keys);
+            random_get(&bencher, index, &fixture);
+            full_scan(&bencher, index, &fixture);
+        }
+    }
+}
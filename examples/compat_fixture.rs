@@ -0,0 +1,42 @@
+//! Writes the compatibility fixture of the current on-disk format, checked by
+//! `tests/compat.rs`. Run it once per format version with
+//! `cargo run --example compat_fixture -- tests/compat/v<N>`.
+//!
+//! The fixture is the database left by the workload in `tests/compat/workload.rs`, in
+//! `db/`, and the entries it holds, in `expected.txt`.
+
+#[path = "../tests/compat/workload.rs"]
+mod workload;
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let dir = match (args.next(), args.next()) {
+        (Some(dir), None) => PathBuf::from(dir),
+        _ => {
+            eprintln!("Usage: compat_fixture <DIR>");
+            return ExitCode::from(2);
+        }
+    };
+    if dir.exists() {
+        eprintln!(
+            "error: {:?} already exists, fixtures are never regenerated",
+            dir
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let expected = workload::write(&dir.join("db"));
+    let rendered = workload::render(
+        expected
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice())),
+    );
+    if let Err(e) = std::fs::write(dir.join("expected.txt"), rendered) {
+        eprintln!("error: fail to write the expected entries: {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
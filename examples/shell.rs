@@ -0,0 +1,311 @@
+//! An interactive shell over a database directory, run it with
+//! `cargo run --example shell -- --dir ./db`.
+//!
+//! Arguments are separated by spaces, quotes keep spaces in an argument and `\x` escapes
+//! such as `\x00` give arbitrary bytes, e.g. `set "my key" \x00\xff`.
+
+use ailurus_kv::engine::Engine;
+use ailurus_kv::errors::{Errors, Result};
+use ailurus_kv::options::{IteratorOptions, OptionsBuilder};
+use bytes::Bytes;
+use error_stack::ResultExt;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const HELP: &str = "\
+get <KEY>           Print the value of KEY
+set <KEY> <VALUE>   Set KEY to VALUE
+del <KEY>           Delete KEY
+scan [PREFIX]       Print the entries starting with PREFIX in key order
+stat                Print statistics of the datafiles
+merge               Merge all the datafiles
+verify              Check every record of every datafile
+help                Print this help
+quit                Leave the shell";
+
+/// Splits a line into its arguments, see the module documentation for the syntax
+fn tokenize(line: &str) -> std::result::Result<Vec<Vec<u8>>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' | '\'' if quote.is_none() => quote = Some(c),
+                c if quote == Some(c) => quote = None,
+                c if c.is_whitespace() && quote.is_none() => break,
+                '\\' => match chars.next() {
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        let byte = u8::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == 2)
+                            .ok_or(format!("invalid escape `\\x{}`", hex))?;
+                        arg.push(byte);
+                    }
+                    Some('n') => arg.push(b'\n'),
+                    Some('t') => arg.push(b'\t'),
+                    Some(c @ ('\\' | '"' | '\'' | ' ')) => arg.push(c as u8),
+                    Some(c) => return Err(format!("invalid escape `\\{}`", c)),
+                    None => return Err("dangling `\\`".to_string()),
+                },
+                c => arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        if quote.is_some() {
+            return Err("unterminated quote".to_string());
+        }
+        args.push(arg);
+    }
+}
+
+/// Text of bytes printed on a line of their own: as is if readable, a hex dump otherwise
+fn display(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\t') => text.to_string(),
+        _ => bytes
+            .chunks(16)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                let ascii: String = chunk
+                    .iter()
+                    .map(|byte| match byte.is_ascii_graphic() || *byte == b' ' {
+                        true => *byte as char,
+                        false => '.',
+                    })
+                    .collect();
+                format!("{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Text of bytes printed within a line, escaped the way [tokenize] reads them back
+fn inline(bytes: &[u8]) -> String {
+    bytes.escape_ascii().to_string()
+}
+
+struct Shell {
+    engine: Engine,
+}
+
+impl Shell {
+    /// Runs the command, returns `false` once the shell is left
+    fn execute(&mut self, args: &[Vec<u8>], out: &mut impl Write) -> Result<bool> {
+        let bytes = |arg: &Vec<u8>| Bytes::copy_from_slice(arg);
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        let mut print =
+            |line: String| writeln!(out, "{}", line).change_context(Errors::FailToWriteToFile);
+        match (name.as_str(), &args[1..]) {
+            ("get", [key]) => match self.engine.get(bytes(key))? {
+                Some(value) => print(display(&value))?,
+                None => print("(nil)".to_string())?,
+            },
+            ("set", [key, value]) => {
+                self.engine.put(bytes(key), bytes(value))?;
+                print("OK".to_string())?;
+            }
+            ("del", [key]) => match self.engine.delete(bytes(key)) {
+                Ok(()) => print("OK".to_string())?,
+                Err(report) if report.current_context() == &Errors::KeyNotFound => {
+                    print("(nil)".to_string())?
+                }
+                Err(report) => return Err(report),
+            },
+            ("scan", prefix @ ([] | [_])) => {
+                let opts = IteratorOptions::new().prefix(prefix.concat());
+                for entry in self.engine.iter(opts)? {
+                    let entry = entry?;
+                    print(format!(
+                        "{}\t{}",
+                        inline(entry.key()),
+                        inline(entry.value())
+                    ))?;
+                }
+            }
+            ("stat", []) => {
+                let stat = self.engine.stat()?;
+                let sealed = self.engine.compaction_candidates();
+                print(format!("keys: {}", stat.keys))?;
+                print(format!("datafiles: {}", stat.datafiles))?;
+                print(format!("disk_bytes: {}", stat.disk_bytes))?;
+                print(format!("reclaimable_bytes: {}", stat.reclaimable_bytes))?;
+                print(format!("sealed_files: {}", sealed.len()))?;
+                print(format!(
+                    "sealed_bytes: {}",
+                    sealed.iter().map(|file| file.size).sum::<u64>()
+                ))?;
+            }
+            ("merge", []) => {
+                let stats = self.engine.merge(None)?;
+                print(format!(
+                    "merged {} datafiles into {}, {} bytes reclaimed",
+                    stats.files_in, stats.files_out, stats.bytes_reclaimed
+                ))?;
+            }
+            ("verify", []) => {
+                let corruptions = self.engine.verify()?;
+                for corruption in &corruptions {
+                    print(corruption.to_string())?;
+                }
+                if corruptions.is_empty() {
+                    print("OK".to_string())?;
+                }
+            }
+            ("help", []) => print(HELP.to_string())?,
+            ("quit" | "exit", []) => return Ok(false),
+            ("get" | "set" | "del" | "scan" | "stat" | "merge" | "verify" | "help", _) => print(
+                format!("error: invalid arguments for `{}`, see `help`", name),
+            )?,
+            _ => print(format!("error: unknown command `{}`, see `help`", name))?,
+        }
+        Ok(true)
+    }
+
+    /// Runs the commands read from `input`, a prompt is printed before each one if
+    /// `prompt` is set
+    fn run(&mut self, input: impl BufRead, out: &mut impl Write, prompt: bool) -> Result<()> {
+        let flush = |out: &mut dyn Write| {
+            out.write_all(b"> ")
+                .and_then(|_| out.flush())
+                .change_context(Errors::FailToWriteToFile)
+        };
+        if prompt {
+            flush(out)?;
+        }
+        for line in input.lines() {
+            let line = line.change_context(Errors::FailToReadFromFile)?;
+            let result = match tokenize(&line) {
+                Ok(args) if args.is_empty() => Ok(true),
+                Ok(args) => self.execute(&args, out),
+                Err(message) => writeln!(out, "error: {}", message)
+                    .map(|_| true)
+                    .change_context(Errors::FailToWriteToFile),
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                // a failed command does not end the shell
+                Err(report) => {
+                    let _ = writeln!(out, "error: {}", report.current_context());
+                }
+            }
+            if prompt {
+                flush(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let dir = match (args.next().as_deref(), args.next(), args.next()) {
+        (Some("--dir"), Some(dir), None) => PathBuf::from(dir),
+        _ => {
+            eprintln!("Usage: shell --dir <DIR>");
+            return ExitCode::from(2);
+        }
+    };
+    let opened = OptionsBuilder::default()
+        .dir_path(dir)
+        .create_if_missing(true)
+        .build()
+        .and_then(Engine::new);
+    let mut shell = match opened {
+        Ok(engine) => Shell { engine },
+        Err(report) => {
+            eprintln!("error: fail to open the database: {:?}", report);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let stdin = std::io::stdin();
+    let prompt = stdin.is_terminal();
+    match shell.run(stdin.lock(), &mut std::io::stdout(), prompt) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            eprintln!("error: {:?}", report);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{display, tokenize, Shell};
+    use ailurus_kv::engine::Engine;
+
+    #[test]
+    fn tokenize_args() {
+        assert_eq!(
+            tokenize(r#"set "my key" 'a b'\x00\xFF"#).unwrap(),
+            vec![b"set".to_vec(), b"my key".to_vec(), b"a b\x00\xff".to_vec()]
+        );
+        assert_eq!(tokenize("  ").unwrap(), Vec::<Vec<u8>>::new());
+        assert!(tokenize(r#"get "key"#).is_err());
+        assert!(tokenize(r"get \x0").is_err());
+    }
+
+    #[test]
+    fn hex_dump() {
+        assert_eq!(display(b"Hello"), "Hello");
+        assert_eq!(
+            display(b"\x00\xffab"),
+            format!("00000000  00 ff 61 62{}  |..ab|", " ".repeat(36))
+        );
+    }
+
+    #[test]
+    fn script() {
+        let script = "\
+set user:1 alice
+set user:2 \"bob the builder\"
+set bin \\x00\\xff
+get user:2
+get bin
+get missing
+scan user:
+del user:1
+del user:1
+scan
+frobnicate
+get
+verify
+quit
+get user:2
+";
+        let mut shell = Shell {
+            engine: Engine::open_temporary().unwrap(),
+        };
+        let mut out = Vec::new();
+        shell.run(script.as_bytes(), &mut out, false).unwrap();
+        let expected = "\
+OK
+OK
+OK
+bob the builder
+00000000  00 ff                                            |..|
+(nil)
+user:1\talice
+user:2\tbob the builder
+OK
+(nil)
+bin\t\\x00\\xff
+user:2\tbob the builder
+error: unknown command `frobnicate`, see `help`
+error: invalid arguments for `get`, see `help`
+OK
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
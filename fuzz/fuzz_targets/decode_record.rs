@@ -0,0 +1,14 @@
+//! Decodes arbitrary bytes as the start of a datafile. Decoding never panics, and a record
+//! decoded is encoded back into the very bytes it was decoded from.
+
+#![no_main]
+
+use ailurus_kv::data::log_record::decode_record;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some((record, len))) = decode_record(data) {
+        assert!(len <= data.len());
+        assert_eq!(record.encode(), data[..len]);
+    }
+});
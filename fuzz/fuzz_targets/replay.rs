@@ -0,0 +1,27 @@
+//! Opens a store whose only datafile holds arbitrary bytes. Neither the replay of the
+//! datafile nor reading back what it indexed panics.
+
+#![no_main]
+
+use ailurus_kv::data::data_file::datafile_name;
+use ailurus_kv::engine::Engine;
+use ailurus_kv::options::{IteratorOptions, OptionsBuilder};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join(datafile_name(0)), data).unwrap();
+    let opts = OptionsBuilder::default()
+        .dir_path(dir.path().to_path_buf())
+        .create_if_missing(false)
+        .build()
+        .unwrap();
+    // a corrupted datafile fails the open
+    let Ok(engine) = Engine::new(opts) else {
+        return;
+    };
+    for key in engine.keys(IteratorOptions::default()).unwrap() {
+        engine.get(key).unwrap();
+    }
+    assert!(engine.verify().unwrap().is_empty());
+});
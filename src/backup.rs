@@ -0,0 +1,349 @@
+//! Backups of the datafiles streamed as a single archive.
+//!
+//! An archive starts with the magic bytes `AILURUSB` and the format version as a big
+//! endian `u32`, followed by one entry per datafile: the length of its name as a big endian
+//! `u16`, the name, the length of its content as a big endian `u64`, the content, and
+//! the CRC32 of the name and content as a big endian `u32`. A zero name length ends the
+//! entries, it is followed by the number of datafiles as a big endian `u32`.
+
+use crate::data::data_file::{datafile_name, DATAFILE_SUFFIX};
+use crate::engine::{mark_complete, mark_incomplete, Engine};
+use crate::errors::{Errors, Result};
+use bytes::BufMut;
+use error_stack::{Report, ResultExt};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"AILURUSB";
+const VERSION: u32 = 1;
+/// Size of the chunks the content of a datafile is copied by
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BackupStats {
+    pub files: u32,
+    /// Size of the datafiles, without the framing of the archive
+    pub bytes: u64,
+}
+
+/// Where an archive is invalid, attached to the [Report] of an [Errors::InvalidBackup]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidBackupInfo {
+    pub offset: u64,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for InvalidBackupInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {} of the backup", self.reason, self.offset)
+    }
+}
+
+fn invalid<T>(offset: u64, reason: &'static str) -> Result<T> {
+    Err(Report::new(Errors::InvalidBackup)).attach_printable(InvalidBackupInfo { offset, reason })
+}
+
+impl Engine {
+    /// Streams a backup of the datafiles to `writer`, see [crate::backup].
+    ///
+    /// The backup holds the sealed datafiles and the active one up to the point it is
+    /// synced at when the backup starts, which is all a store needs to open. Merges wait
+    /// for the backup to finish, writes go on meanwhile.
+    pub fn backup_to<W: Write>(&self, writer: W) -> Result<BackupStats> {
+        // a merge would remove the sealed datafiles
+        let _running = self.merger.state.running.lock();
+        let mut sizes: Vec<(u32, u64)> = {
+            let mut files = self.files.write();
+            files.sync_active()?;
+            let active = (files.active.id(), files.active.offset());
+            files
+                .idle
+                .values()
+                .map(|datafile| (datafile.id(), datafile.offset()))
+                .chain([active])
+                .collect()
+        };
+        sizes.sort_unstable();
+
+        let mut writer = BufWriter::new(writer);
+        let mut header = Vec::with_capacity(MAGIC.len() + 4);
+        header.put_slice(MAGIC);
+        header.put_u32(VERSION);
+        let write = |writer: &mut BufWriter<W>, buf: &[u8]| {
+            writer
+                .write_all(buf)
+                .change_context(Errors::FailToWriteToFile)
+        };
+        write(&mut writer, &header)?;
+
+        let mut stats = BackupStats::default();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        for (id, size) in sizes {
+            let name = datafile_name(id);
+            let path = self.options.dir_path.join(&name);
+            let mut file = File::open(&path)
+                .change_context(Errors::FailToOpenFile)
+                .attach_printable_lazy(|| format!("Fail to open {:?}", path))?
+                .take(size);
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(name.as_bytes());
+            let mut entry = Vec::new();
+            entry.put_u16(name.len() as u16);
+            entry.put_slice(name.as_bytes());
+            entry.put_u64(size);
+            write(&mut writer, &entry)?;
+
+            let mut copied = 0;
+            while copied < size {
+                let n = file
+                    .read(&mut chunk)
+                    .change_context(Errors::FailToReadFromFile)
+                    .attach_printable_lazy(|| format!("Fail to read {:?}", path))?;
+                if n == 0 {
+                    return Err(Report::new(Errors::FailToReadFromFile))
+                        .attach_printable(format!("{:?} is shorter than {} bytes", path, size));
+                }
+                hasher.update(&chunk[..n]);
+                write(&mut writer, &chunk[..n])?;
+                copied += n as u64;
+            }
+            write(&mut writer, &hasher.finalize().to_be_bytes())?;
+            stats.files += 1;
+            stats.bytes += size;
+        }
+
+        let mut trailer = Vec::new();
+        trailer.put_u16(0);
+        trailer.put_u32(stats.files);
+        write(&mut writer, &trailer)?;
+        writer.flush().change_context(Errors::FailToWriteToFile)?;
+        Ok(stats)
+    }
+
+    /// Restores a backup written by [Engine::backup_to] into `target_dir`, which must not
+    /// hold a database. A failed restore leaves a store that refuses to open with
+    /// [Errors::IncompleteDb].
+    pub fn restore_from<R: Read, P: AsRef<Path>>(reader: R, target_dir: P) -> Result<BackupStats> {
+        let dir = target_dir.as_ref();
+        let marker = mark_incomplete(dir)?;
+        let mut restored = Vec::new();
+        let stats = read_archive(reader, |name| {
+            let path = dir.join(name);
+            let file = File::create(&path)
+                .change_context(Errors::CreateDbFileFail)
+                .attach_printable_lazy(|| format!("Fail to create {:?}", path))?;
+            restored.push(path);
+            Ok(file)
+        })?;
+        for path in restored {
+            File::open(&path)
+                .and_then(|file| file.sync_all())
+                .change_context(Errors::FailToSyncFile)
+                .attach_printable_lazy(|| format!("Fail to sync {:?}", path))?;
+        }
+        mark_complete(&marker)?;
+        Ok(stats)
+    }
+}
+
+/// Checks every entry of an archive written by [Engine::backup_to] without unpacking it,
+/// an invalid archive fails with [Errors::InvalidBackup]
+pub fn verify<R: Read>(reader: R) -> Result<BackupStats> {
+    read_archive(reader, |_| Ok(std::io::sink()))
+}
+
+struct ArchiveReader<R: Read> {
+    reader: BufReader<R>,
+    offset: u64,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Fills `buf`, running out of bytes is reported at `start`
+    fn read(&mut self, buf: &mut [u8], start: u64) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => {
+                self.offset += buf.len() as u64;
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => invalid(start, "truncated backup"),
+            Err(e) => Err(Report::new(e).change_context(Errors::FailToReadFromFile)),
+        }
+    }
+
+    fn read_array<const N: usize>(&mut self, start: u64) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read(&mut buf, start)?;
+        Ok(buf)
+    }
+}
+
+/// Reads an archive, the content of each datafile is written to the writer `create` returns
+/// for its name. The content is handed out before its checksum is checked.
+fn read_archive<R: Read, W: Write>(
+    reader: R,
+    mut create: impl FnMut(&str) -> Result<W>,
+) -> Result<BackupStats> {
+    let mut input = ArchiveReader {
+        reader: BufReader::new(reader),
+        offset: 0,
+    };
+    if &input.read_array::<8>(0)? != MAGIC {
+        return invalid(0, "not a backup");
+    }
+    if u32::from_be_bytes(input.read_array(input.offset)?) != VERSION {
+        return invalid(MAGIC.len() as u64, "unsupported version");
+    }
+
+    let mut stats = BackupStats::default();
+    let mut names = HashSet::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let start = input.offset;
+        let name_len = u16::from_be_bytes(input.read_array(start)?) as usize;
+        if name_len == 0 {
+            break;
+        }
+        let mut name = vec![0; name_len];
+        input.read(&mut name, start)?;
+        // only names of datafiles, nothing may be written outside of the directory
+        let name = String::from_utf8(name)
+            .ok()
+            .filter(|name| {
+                name.strip_suffix(DATAFILE_SUFFIX)
+                    .and_then(|id| id.parse::<u32>().ok())
+                    .is_some_and(|id| datafile_name(id) == *name)
+            })
+            .map_or_else(|| invalid(start, "not a datafile name"), Ok)?;
+        if !names.insert(name.clone()) {
+            return invalid(start, "duplicate datafile");
+        }
+        let size = u64::from_be_bytes(input.read_array(start)?);
+
+        let mut writer = create(&name)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(name.as_bytes());
+        let mut remaining = size;
+        while remaining > 0 {
+            let n = remaining.min(CHUNK_SIZE as u64) as usize;
+            input.read(&mut chunk[..n], start)?;
+            hasher.update(&chunk[..n]);
+            writer
+                .write_all(&chunk[..n])
+                .change_context(Errors::FailToWriteToFile)?;
+            remaining -= n as u64;
+        }
+        let crc_start = input.offset;
+        if u32::from_be_bytes(input.read_array(crc_start)?) != hasher.finalize() {
+            return invalid(crc_start, "checksum mismatch");
+        }
+        writer.flush().change_context(Errors::FailToWriteToFile)?;
+        stats.files += 1;
+        stats.bytes += size;
+    }
+
+    let count_start = input.offset;
+    if u32::from_be_bytes(input.read_array(count_start)?) != stats.files {
+        return invalid(count_start, "datafile count mismatch");
+    }
+    let mut trailing = [0u8];
+    if input
+        .reader
+        .read(&mut trailing)
+        .change_context(Errors::FailToReadFromFile)?
+        != 0
+    {
+        return invalid(input.offset, "trailing data");
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::{verify, InvalidBackupInfo};
+    use crate::engine;
+    use crate::engine::Engine;
+    use crate::errors::Errors;
+    use crate::options::{IteratorOptions, OptionsBuilder};
+    use bytes::Bytes;
+    use std::path::Path;
+
+    fn entries(engine: &Engine) -> Vec<(Bytes, Bytes)> {
+        engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().into_parts())
+            .collect()
+    }
+
+    fn open(dir: &Path) -> crate::errors::Result<Engine> {
+        Engine::new(OptionsBuilder::default().dir_path(dir.into()).build()?)
+    }
+
+    fn backup() -> (Vec<u8>, Vec<(Bytes, Bytes)>) {
+        let engine = engine!();
+        for i in 0..500 {
+            engine
+                .put(format!("key-{}", i).into(), vec![i as u8; 100].into())
+                .unwrap();
+        }
+        for i in 0..100 {
+            engine.delete(format!("key-{}", i).into()).unwrap();
+        }
+        let mut buf = Vec::new();
+        let stats = engine.backup_to(&mut buf).unwrap();
+        assert!(stats.files > 1);
+        assert_eq!(verify(buf.as_slice()).unwrap(), stats);
+
+        // the backup is not affected by later writes
+        engine.put("key-0".into(), "later".into()).unwrap();
+        let mut expected = entries(&engine);
+        expected.retain(|(key, _)| key != "key-0");
+        (buf, expected)
+    }
+
+    fn invalid_at(buf: &[u8]) -> InvalidBackupInfo {
+        let report = verify(buf).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::InvalidBackup);
+        *report.downcast_ref::<InvalidBackupInfo>().unwrap()
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let (buf, expected) = backup();
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("restored");
+        let stats = Engine::restore_from(buf.as_slice(), &target).unwrap();
+        assert_eq!(verify(buf.as_slice()).unwrap(), stats);
+        assert_eq!(entries(&open(&target).unwrap()), expected);
+
+        let report = Engine::restore_from(buf.as_slice(), &target).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DbAlreadyExists);
+    }
+
+    #[test]
+    fn tampered_backup() {
+        let (buf, _) = backup();
+        let mut tampered = buf.clone();
+        // in the content of the first datafile, after the header and its entry header
+        tampered[12 + 2 + 14 + 8 + 10] ^= 0xff;
+        let info = invalid_at(&tampered);
+        assert_eq!(info.reason, "checksum mismatch");
+
+        let root = tempfile::tempdir().unwrap();
+        let report = Engine::restore_from(tampered.as_slice(), root.path()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::InvalidBackup);
+        let report = open(root.path()).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::IncompleteDb);
+
+        let info = invalid_at(&buf[..buf.len() - 1]);
+        assert_eq!(
+            (info.offset, info.reason),
+            (buf.len() as u64 - 4, "truncated backup")
+        );
+        let info = invalid_at(b"AILURUSB\x00\x00\x00\x01\x00\x0a../000.data");
+        assert_eq!((info.offset, info.reason), (12, "not a datafile name"));
+    }
+}
@@ -1,10 +1,656 @@
-use crate::data::log_record::LogRecord;
+use crate::data::log_record::{LogRecord, LogRecordType};
 use crate::engine::Engine;
-use crate::options::WriteBatchOptions;
-use std::collections::HashMap;
+use crate::errors::{Errors, Result};
+use crate::iterator::{EngineIterator, Entry};
+use crate::options;
+use crate::options::{IteratorOptions, KeyFilter, ValueFilter, WriteBatchOptions};
+use bytes::Bytes;
+use error_stack::Report;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::sync::atomic::Ordering;
 
 pub struct WriteBatch<'a> {
-    pending_writes: HashMap<Vec<u8>, LogRecord>,
+    pending_writes: Mutex<BTreeMap<Bytes, LogRecord>>,
     engine: &'a Engine,
     options: WriteBatchOptions,
 }
+
+impl Engine {
+    /// Creates a batch staging writes on top of the engine
+    pub fn write_batch(&self, options: WriteBatchOptions) -> WriteBatch<'_> {
+        WriteBatch {
+            pending_writes: Mutex::new(BTreeMap::new()),
+            engine: self,
+            options,
+        }
+    }
+}
+
+impl<'a> WriteBatch<'a> {
+    /// Stages a put of the key-value pair
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Report::new(Errors::EmptyKey));
+        }
+        self.engine.check_sizes(&key, &value)?;
+
+        self.stage(
+            key.clone(),
+            LogRecord {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                record_type: LogRecordType::Normal,
+                seq: None,
+            },
+        )
+    }
+
+    /// Stages a delete of the key
+    pub fn delete(&mut self, key: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Report::new(Errors::EmptyKey));
+        }
+
+        if self.engine.index.get(&key).is_none() {
+            // the key only lives in the batch, dropping the staged put is enough
+            return match self.pending_writes.get_mut().remove(key.as_ref()) {
+                Some(_) => Ok(()),
+                None => Err(Report::new(Errors::KeyNotFound)),
+            };
+        }
+
+        self.stage(
+            key.clone(),
+            LogRecord {
+                key: key.to_vec(),
+                value: Default::default(), // value can be anything
+                record_type: LogRecordType::Deleted,
+                seq: None,
+            },
+        )
+    }
+
+    /// Returns an iterator over the store as it will look like once the batch is committed,
+    /// i.e. the staged puts override the entries of the engine and the staged deletes hide them.
+    ///
+    /// Like [Engine::iter], the iterator works on a snapshot taken at creation.
+    pub fn iter(&self, mut options: IteratorOptions) -> Result<MergedIterator<'a>> {
+        options::check_iterator_options(&options)?;
+
+        // the key filter is shared by the staged writes and the engine iterator
+        let filter = options.filter.clone();
+
+        // staged values have to be checked as well, so the value filter is applied after merging
+        let value_filter = options.value_filter.take();
+        let keys_only = options.keys_only;
+        let reverse = options.reverse;
+
+        let mut staged: Vec<(Bytes, Option<Bytes>)> = match options.key_range() {
+            None => Vec::new(),
+            Some((lower, upper)) => self
+                .pending_writes
+                .lock()
+                .range::<[u8], _>((
+                    lower.as_ref().map(Vec::as_slice),
+                    upper.as_ref().map(Vec::as_slice),
+                ))
+                .map(|(key, record)| {
+                    let value = match record.record_type {
+                        LogRecordType::Deleted => None,
+                        _ if keys_only => Some(Bytes::new()),
+                        _ => Some(Bytes::copy_from_slice(&record.value)),
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        };
+        if reverse {
+            staged.reverse();
+        }
+
+        Ok(MergedIterator {
+            staged: staged.into_iter().peekable(),
+            engine: self.engine.iter(options)?.peekable(),
+            reverse,
+            filter,
+            value_filter,
+        })
+    }
+
+    /// Writes the staged records, syncing as told by [WriteBatchOptions::sync_on_commit]
+    pub fn commit(&self) -> Result<CommitInfo> {
+        self.commit_with(SyncOverride::Default)
+    }
+
+    /// Writes the staged records, then points the index at them. If a write fails the index
+    /// is left untouched and the records stay staged.
+    ///
+    /// The records are written with the sequence number of the batch and followed by a
+    /// [TxnFinished](LogRecordType::TxnFinished) marker, all in the same datafile. Reopening
+    /// the engine only replays the records of a batch whose marker was written. A batch
+    /// without staged records writes nothing.
+    ///
+    /// Whether the records are synced is decided by `sync` alone, the [SyncPolicy] of the
+    /// engine only applies to the datafiles sealed while writing them.
+    ///
+    /// [SyncPolicy]: options::SyncPolicy
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "commit", skip(self), fields(records, bytes))
+    )]
+    pub fn commit_with(&self, sync: SyncOverride) -> Result<CommitInfo> {
+        let mut pending = self.pending_writes.lock();
+        let synced = match sync {
+            SyncOverride::Default => self.options.sync_on_commit,
+            SyncOverride::Force => true,
+            SyncOverride::Skip => false,
+        };
+
+        if pending.is_empty() {
+            return Ok(CommitInfo {
+                records: 0,
+                bytes: 0,
+                synced: false,
+            });
+        }
+
+        let mut files = self.engine.files.write();
+        files.check_writable()?;
+        let active = files.active.id();
+        let seq = self.engine.seq_no.fetch_add(1, Ordering::SeqCst);
+        for record in pending.values_mut() {
+            record.seq = Some(seq);
+        }
+        let finished = LogRecord {
+            key: Vec::new(),
+            value: Vec::new(),
+            record_type: LogRecordType::TxnFinished,
+            seq: Some(seq),
+        };
+        let records: Vec<&LogRecord> = pending.values().chain([&finished]).collect();
+        let mut positions = self.engine.append_batch_unsynced(&mut files, &records)?;
+        let marker = positions.pop().unwrap();
+        if synced {
+            files.sync_active()?;
+        }
+
+        let info = CommitInfo {
+            records: positions.len(),
+            bytes: positions
+                .iter()
+                .chain([&marker])
+                .map(|pos| pos.size as u64)
+                .sum(),
+            synced,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("records", info.records)
+            .record("bytes", info.bytes);
+        for ((key, record), pos) in std::mem::take(&mut *pending).into_iter().zip(positions) {
+            self.engine
+                .update_index(&mut files, key, record.record_type, pos)?;
+        }
+        self.engine
+            .update_index(&mut files, Bytes::new(), LogRecordType::TxnFinished, marker)?;
+        let sealed = files.active.id() != active;
+        drop(files);
+        if sealed {
+            self.engine.merge_if_due();
+        }
+        Ok(info)
+    }
+
+    /// Number of records staged
+    pub(crate) fn staged_len(&mut self) -> usize {
+        self.pending_writes.get_mut().len()
+    }
+
+    pub(crate) fn is_staged(&mut self, key: &[u8]) -> bool {
+        self.pending_writes.get_mut().contains_key(key)
+    }
+
+    fn stage(&mut self, key: Bytes, record: LogRecord) -> Result<()> {
+        let pending = self.pending_writes.get_mut();
+        if !pending.contains_key(&key) && pending.len() >= self.options.batch_size as usize {
+            return Err(Report::new(Errors::ExceedMaxBatchSize));
+        }
+
+        pending.insert(key, record);
+        Ok(())
+    }
+}
+
+/// How [WriteBatch::commit_with] syncs, layered over [WriteBatchOptions::sync_on_commit]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SyncOverride {
+    /// Sync as told by [WriteBatchOptions::sync_on_commit]
+    #[default]
+    Default,
+    /// Sync, whatever the options say
+    Force,
+    /// Don't sync, whatever the options say
+    Skip,
+}
+
+/// What a commit wrote
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CommitInfo {
+    /// Number of records written
+    pub records: usize,
+    /// Bytes appended to the datafiles
+    pub bytes: u64,
+    /// Whether the records were synced before the commit returned
+    pub synced: bool,
+}
+
+/// Iterator over the merged view of a [WriteBatch] and its engine, see [WriteBatch::iter]
+pub struct MergedIterator<'a> {
+    /// staged writes in iteration order, `None` stands for a staged delete
+    staged: Peekable<std::vec::IntoIter<(Bytes, Option<Bytes>)>>,
+    engine: Peekable<EngineIterator<'a>>,
+    reverse: bool,
+    filter: Option<KeyFilter>,
+    value_filter: Option<ValueFilter>,
+}
+
+/// Like [EngineIterator], reading an entry of the engine fails with an error item
+impl Iterator for MergedIterator<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let staged_first = match (self.staged.peek(), self.engine.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => true,
+                (None, Some(_)) | (Some(_), Some(Err(_))) => false,
+                (Some((staged, _)), Some(Ok(entry))) => {
+                    let ord = match self.reverse {
+                        true => entry.key().cmp(staged),
+                        false => staged.cmp(entry.key()),
+                    };
+                    if ord.is_eq() {
+                        // the staged write shadows the entry of the engine
+                        self.engine.next();
+                    }
+                    ord.is_le()
+                }
+            };
+
+            let entry = match staged_first {
+                false => match self.engine.next().unwrap() {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                },
+                true => match self.staged.next().unwrap() {
+                    (_, None) => continue,
+                    (key, Some(value)) => {
+                        if !self.filter.as_ref().is_none_or(|f| f(&key)) {
+                            continue;
+                        }
+                        Entry::new(key, value)
+                    }
+                },
+            };
+
+            if let Some(filter) = self.value_filter.as_ref() {
+                if !filter(entry.value()) {
+                    continue;
+                }
+            }
+
+            return Some(Ok(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{CommitInfo, SyncOverride};
+    use crate::data::log_record::{LogRecord, LogRecordType, ReadLogRecord};
+    use crate::engine;
+    use crate::engine::Engine;
+    use crate::errors::Errors;
+    use crate::iterator::Entry;
+    use crate::mock::engine_wrapper::{self, EngineWrapper};
+    use crate::options::{IteratorOptions, SyncPolicy, WriteBatchOptions};
+    use std::sync::atomic::Ordering;
+
+    macro_rules! entry {
+        ($key:expr, $val:expr) => {{
+            $crate::iterator::Entry::new($key, $val)
+        }};
+    }
+
+    #[test]
+    fn merged_view() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["d", "val-d"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("c".into(), "staged-c".into()).unwrap();
+        batch.put("d".into(), "staged-d".into()).unwrap();
+        batch.delete("a".into()).unwrap();
+
+        let iter = batch.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![
+                entry!["b", "val-b"],
+                entry!["c", "staged-c"],
+                entry!["d", "staged-d"],
+            ]
+        );
+
+        // nothing is visible through the engine before commit
+        let iter = engine.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(iter.count(), 3);
+    }
+
+    #[test]
+    fn merged_view_reverse() {
+        let engine = engine!(["a", "val-a"], ["c", "val-c"], ["e", "val-e"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("f".into(), "staged-f".into()).unwrap();
+        batch.put("b".into(), "staged-b".into()).unwrap();
+        batch.put("c".into(), "staged-c".into()).unwrap();
+        batch.delete("e".into()).unwrap();
+
+        let iter = batch.iter(IteratorOptions::new().reverse(true)).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![
+                entry!["f", "staged-f"],
+                entry!["c", "staged-c"],
+                entry!["b", "staged-b"],
+                entry!["a", "val-a"],
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_view_with_prefix() {
+        let engine = engine!(["aa", "val-aa"], ["ab", "val-ab"], ["b", "val-b"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("ac".into(), "staged-ac".into()).unwrap();
+        batch.put("ba".into(), "staged-ba".into()).unwrap();
+        batch.delete("aa".into()).unwrap();
+
+        for reverse in [false, true] {
+            let iter = batch
+                .iter(IteratorOptions::new().reverse(reverse).prefix("a"))
+                .unwrap();
+            let mut expected = vec![entry!["ab", "val-ab"], entry!["ac", "staged-ac"]];
+            if reverse {
+                expected.reverse();
+            }
+            assert_eq!(iter.map(Result::unwrap).collect::<Vec<Entry>>(), expected);
+        }
+    }
+
+    #[test]
+    fn merged_view_with_filters() {
+        let engine = engine!(["a", "v1"], ["b", "v1"], ["c", "v3"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("a".into(), "v4".into()).unwrap();
+        batch.put("c".into(), "v2".into()).unwrap();
+        batch.put("d".into(), "v5".into()).unwrap();
+
+        let iter = batch
+            .iter(
+                IteratorOptions::new()
+                    .filter(|key| key != b"d")
+                    .value_filter(|value| value >= b"v3".as_slice()),
+            )
+            .unwrap();
+        // `c` is staged with a rejected value, which must not resurrect the engine value
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["a", "v4"]]
+        );
+    }
+
+    #[test]
+    fn staged_delete_of_staged_put() {
+        let engine = engine!(["a", "val-a"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("b".into(), "staged-b".into()).unwrap();
+        batch.delete("b".into()).unwrap();
+        let report = batch.delete("c".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::KeyNotFound);
+
+        let iter = batch.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["a", "val-a"]]
+        );
+    }
+
+    #[test]
+    fn commit() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("c".into(), "staged-c".into()).unwrap();
+        batch.put("b".into(), "staged-b".into()).unwrap();
+        batch.delete("a".into()).unwrap();
+        assert_eq!(engine.get("c".into()).unwrap(), None);
+
+        let info = batch.commit().unwrap();
+        assert_eq!((info.records, info.synced), (3, true));
+        assert_eq!(batch.iter(IteratorOptions::default()).unwrap().count(), 2);
+        // nothing left to write
+        assert_eq!(batch.commit().unwrap().records, 0);
+        drop(batch);
+
+        let iter = engine.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["b", "staged-b"], entry!["c", "staged-c"]]
+        );
+        let engine = engine.reopen();
+        assert_eq!(engine.get("b".into()).unwrap().unwrap(), "staged-b");
+        assert_eq!(engine.get("a".into()).unwrap(), None);
+    }
+
+    #[test]
+    fn commit_with_sync_override() {
+        let committed_syncs = |sync_on_commit, sync| {
+            let (engine, stats) = EngineWrapper::counting_with(
+                engine_wrapper::options()
+                    .sync_policy(SyncPolicy::Always)
+                    .build()
+                    .unwrap(),
+            );
+            let mut batch = engine.write_batch(WriteBatchOptions {
+                sync_on_commit,
+                ..Default::default()
+            });
+            for key in ["a", "b", "c"] {
+                batch.put(key.into(), "val".into()).unwrap();
+            }
+            let before = stats.syncs();
+            let info = batch.commit_with(sync).unwrap();
+            assert_eq!(info.synced, stats.syncs() > before);
+            stats.syncs() - before
+        };
+        assert_eq!(committed_syncs(true, SyncOverride::Default), 1);
+        assert_eq!(committed_syncs(false, SyncOverride::Default), 0);
+        assert_eq!(committed_syncs(false, SyncOverride::Force), 1);
+        assert_eq!(committed_syncs(true, SyncOverride::Skip), 0);
+    }
+
+    /// The key, type and sequence number of the records of the active datafile after `from`
+    fn records(engine: &Engine, from: u64) -> Vec<(Vec<u8>, LogRecordType, Option<u64>)> {
+        let files = engine.files.read();
+        let mut records = Vec::new();
+        let mut offset = from;
+        while let Some(ReadLogRecord { record, size }) = files.active.read(offset).unwrap() {
+            offset += size;
+            records.push((record.key, record.record_type, record.seq));
+        }
+        records
+    }
+
+    #[test]
+    fn commit_writes_marker() {
+        let engine = engine!(["a", "val-a"]);
+        let before = engine.files.read().active.offset();
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("b".into(), "val-b".into()).unwrap();
+        batch.delete("a".into()).unwrap();
+        let info = batch.commit().unwrap();
+
+        let offset = engine.files.read().active.offset();
+        assert_eq!(info.bytes, offset - before);
+        assert_eq!(
+            records(&engine, before),
+            vec![
+                (b"a".to_vec(), LogRecordType::Deleted, Some(0)),
+                (b"b".to_vec(), LogRecordType::Normal, Some(0)),
+                (vec![], LogRecordType::TxnFinished, Some(0)),
+            ]
+        );
+
+        // the next batch takes the next sequence number
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("c".into(), "val-c".into()).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(
+            records(&engine, offset)[0],
+            (b"c".to_vec(), LogRecordType::Normal, Some(1))
+        );
+    }
+
+    #[test]
+    fn seq_no_recovered_on_reopen() {
+        let commit = |engine: &Engine, key: &'static str| {
+            let mut batch = engine.write_batch(WriteBatchOptions::default());
+            batch.put(key.into(), "val".into()).unwrap();
+            batch.commit().unwrap();
+        };
+        let engine = engine!();
+        commit(&engine, "a");
+        commit(&engine, "b");
+        let engine = engine.reopen();
+        commit(&engine, "c");
+
+        for key in ["a", "b", "c"] {
+            assert_eq!(engine.get(key.into()).unwrap().unwrap(), "val");
+        }
+        let seqs: Vec<_> = records(&engine, 0)
+            .into_iter()
+            .filter(|(_, record_type, _)| *record_type == LogRecordType::Normal)
+            .map(|(key, _, seq)| (key, seq.unwrap()))
+            .collect();
+        assert_eq!(seqs.len(), 3);
+        assert!(seqs[2].1 > seqs[0].1.max(seqs[1].1), "{:?}", seqs);
+    }
+
+    #[test]
+    fn commit_empty_batch() {
+        let engine = engine!(["a", "val-a"]);
+        let before = engine.files.read().active.offset();
+        let batch = engine.write_batch(WriteBatchOptions::default());
+        assert_eq!(
+            batch.commit().unwrap(),
+            CommitInfo {
+                records: 0,
+                bytes: 0,
+                synced: false
+            }
+        );
+        assert_eq!(engine.files.read().active.offset(), before);
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "val-a");
+    }
+
+    #[test]
+    fn failed_commit_leaves_index() {
+        let (engine, faults) = EngineWrapper::faulty();
+        engine.put("a".into(), "val-a".into()).unwrap();
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("a".into(), "staged-a".into()).unwrap();
+        batch.put("b".into(), "staged-b".into()).unwrap();
+        batch.put("c".into(), "staged-c".into()).unwrap();
+
+        faults.fail_write(1);
+        let report = batch.commit().unwrap_err();
+        assert_eq!(report.current_context(), &Errors::FailToWriteToFile);
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "val-a");
+        assert_eq!(engine.get("b".into()).unwrap(), None);
+
+        // the records stay staged
+        let info = batch.commit().unwrap();
+        assert_eq!(info.records, 3);
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "staged-a");
+        assert_eq!(engine.get("c".into()).unwrap().unwrap(), "staged-c");
+    }
+
+    #[test]
+    fn batch_in_one_datafile() {
+        let engine = EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(8 * 1024)
+                .danger_small_files(true)
+                .build()
+                .unwrap(),
+        );
+        engine.put("a".into(), vec![0; 6 * 1024].into()).unwrap();
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        for key in ["b", "c", "d"] {
+            batch.put(key.into(), vec![0; 3 * 1024].into()).unwrap();
+        }
+        let info = batch.commit().unwrap();
+
+        // the batch does not fit after `a`, nor in a single datafile
+        assert_eq!(engine.files.read().active.offset(), info.bytes);
+        assert_eq!(records(&engine, 0).len(), 4);
+    }
+
+    #[test]
+    fn torn_commit_dropped_on_reopen() {
+        let engine = engine!(["a", "val-a"]);
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("b".into(), "val-b".into()).unwrap();
+        batch.commit().unwrap();
+
+        // the records of the next batch, cut before its marker
+        let seq = engine.seq_no.load(Ordering::SeqCst);
+        {
+            let mut files = engine.files.write();
+            for key in ["a", "c"] {
+                let record = LogRecord {
+                    key: key.into(),
+                    value: "torn".into(),
+                    record_type: LogRecordType::Normal,
+                    seq: Some(seq),
+                };
+                files.active.write(&record.encode()).unwrap();
+            }
+        }
+
+        let engine = engine.reopen();
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "val-a");
+        assert_eq!(engine.get("b".into()).unwrap().unwrap(), "val-b");
+        assert_eq!(engine.get("c".into()).unwrap(), None);
+        // nor are they applied by the marker of a later batch
+        let mut batch = engine.write_batch(WriteBatchOptions::default());
+        batch.put("d".into(), "val-d".into()).unwrap();
+        batch.commit().unwrap();
+        let engine = engine.reopen();
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "val-a");
+        assert_eq!(engine.keys(IteratorOptions::default()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn exceed_batch_size() {
+        let engine = engine!();
+        let mut batch = engine.write_batch(WriteBatchOptions {
+            batch_size: 1,
+            ..Default::default()
+        });
+        batch.put("a".into(), "val-a".into()).unwrap();
+        batch.put("a".into(), "val-a".into()).unwrap();
+        let report = batch.put("b".into(), "val-b".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::ExceedMaxBatchSize);
+    }
+}
@@ -0,0 +1,274 @@
+//! Command-line tool to inspect and edit a database directory
+
+use ailurus_kv::engine::Engine;
+use ailurus_kv::errors::{Errors, Result};
+use ailurus_kv::iterator::Entry;
+use ailurus_kv::options::{IteratorOptions, OptionsBuilder};
+use bytes::Bytes;
+use error_stack::Report;
+use serde_json::json;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: ailurus --dir <DIR> [--hex] [--json] <COMMAND>
+
+Commands:
+  get <KEY>                          Print the value of KEY
+  put <KEY> <VALUE>                  Set KEY to VALUE, creating the database if missing
+  del <KEY>                          Delete KEY
+  scan [--prefix <KEY>] [--limit <N>] Print the entries in key order
+  stat                               Print statistics of the datafiles
+  verify                             Check every record of every datafile
+  merge                              Merge all the datafiles
+
+Options:
+  --dir <DIR>  Directory of the database
+  --hex        Keys and values are given and printed hex encoded
+  --json       Print JSON instead of plain lines";
+
+enum Command {
+    Get(Bytes),
+    Put(Bytes, Bytes),
+    Del(Bytes),
+    Scan {
+        prefix: Option<Bytes>,
+        limit: Option<usize>,
+    },
+    Stat,
+    Verify,
+    Merge,
+}
+
+struct Args {
+    dir: PathBuf,
+    hex: bool,
+    json: bool,
+    command: Command,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> std::result::Result<Args, String> {
+    let mut dir = None;
+    let mut hex = false;
+    let mut json = false;
+    let mut prefix = None;
+    let mut limit = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("`{}` expects a value", name));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value("--dir")?)),
+            "--hex" => hex = true,
+            "--json" => json = true,
+            "--prefix" => prefix = Some(value("--prefix")?),
+            "--limit" => {
+                let limit_arg = value("--limit")?;
+                let parsed = limit_arg
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid limit `{}`", limit_arg))?;
+                limit = Some(parsed);
+            }
+            "-h" | "--help" => return Err(String::new()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option `{}`", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let dir = dir.ok_or("`--dir` is required")?;
+    let bytes = |arg: &String| match hex {
+        true => decode_hex(arg).ok_or(format!("invalid hex `{}`", arg)),
+        false => Ok(Bytes::copy_from_slice(arg.as_bytes())),
+    };
+    let command = match positional.as_slice() {
+        [cmd, key] if cmd == "get" => Command::Get(bytes(key)?),
+        [cmd, key, value] if cmd == "put" => Command::Put(bytes(key)?, bytes(value)?),
+        [cmd, key] if cmd == "del" => Command::Del(bytes(key)?),
+        [cmd] if cmd == "scan" => Command::Scan {
+            prefix: prefix.as_ref().map(bytes).transpose()?,
+            limit,
+        },
+        [cmd] if cmd == "stat" => Command::Stat,
+        [cmd] if cmd == "verify" => Command::Verify,
+        [cmd] if cmd == "merge" => Command::Merge,
+        [] => return Err("missing command".to_string()),
+        [cmd, ..] => return Err(format!("invalid arguments for `{}`", cmd)),
+    };
+    if (prefix.is_some() || limit.is_some()) && !matches!(command, Command::Scan { .. }) {
+        return Err("`--prefix` and `--limit` only apply to `scan`".to_string());
+    }
+
+    Ok(Args {
+        dir,
+        hex,
+        json,
+        command,
+    })
+}
+
+fn decode_hex(s: &str) -> Option<Bytes> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(Bytes::from)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Args {
+    /// Text of `bytes` in plain output
+    fn text(&self, bytes: &[u8]) -> String {
+        match self.hex {
+            true => encode_hex(bytes),
+            false => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// Opens the database, read-only unless the command writes
+    fn open(&self) -> Result<Engine> {
+        let writes = matches!(
+            self.command,
+            Command::Put(..) | Command::Del(..) | Command::Merge
+        );
+        let opts = OptionsBuilder::default()
+            .dir_path(self.dir.clone())
+            .create_if_missing(matches!(self.command, Command::Put(..)))
+            .read_only(!writes)
+            .build()?;
+        Engine::new(opts)
+    }
+
+    /// Runs the command, returns whether it succeeded
+    fn run(&self) -> Result<bool> {
+        let mut engine = self.open()?;
+        match &self.command {
+            Command::Get(key) => {
+                let value = engine.get_or_err(key.clone())?;
+                match self.json {
+                    true => println!("{}", to_json(&Entry::new(key.clone(), value))?),
+                    false => println!("{}", self.text(&value)),
+                }
+            }
+            Command::Put(key, value) => engine.put(key.clone(), value.clone())?,
+            Command::Del(key) => engine.delete(key.clone())?,
+            Command::Scan { prefix, limit } => {
+                let mut opts = IteratorOptions::new();
+                if let Some(prefix) = prefix {
+                    opts = opts.prefix(prefix.to_vec());
+                }
+                let entries = engine.iter(opts)?.take(limit.unwrap_or(usize::MAX));
+                for entry in entries {
+                    let entry = entry?;
+                    match self.json {
+                        true => println!("{}", to_json(&entry)?),
+                        false => {
+                            let (key, value) = entry.into_parts();
+                            println!("{}\t{}", self.text(&key), self.text(&value));
+                        }
+                    }
+                }
+            }
+            Command::Stat => {
+                let stat = engine.stat()?;
+                let sealed = engine.compaction_candidates();
+                let sealed_bytes: u64 = sealed.iter().map(|file| file.size).sum();
+                match self.json {
+                    true => println!(
+                        "{}",
+                        json!({
+                            "keys": stat.keys,
+                            "datafiles": stat.datafiles,
+                            "disk_bytes": stat.disk_bytes,
+                            "reclaimable_bytes": stat.reclaimable_bytes,
+                            "sealed_files": sealed.len(),
+                            "sealed_bytes": sealed_bytes,
+                        })
+                    ),
+                    false => {
+                        println!("keys: {}", stat.keys);
+                        println!("datafiles: {}", stat.datafiles);
+                        println!("disk_bytes: {}", stat.disk_bytes);
+                        println!("reclaimable_bytes: {}", stat.reclaimable_bytes);
+                        println!("sealed_files: {}", sealed.len());
+                        println!("sealed_bytes: {}", sealed_bytes);
+                    }
+                }
+            }
+            Command::Verify => {
+                let corruptions = engine.verify()?;
+                for corruption in &corruptions {
+                    match self.json {
+                        true => println!(
+                            "{}",
+                            json!({
+                                "file_id": corruption.file_id,
+                                "offset": corruption.offset,
+                                "reason": corruption.reason.to_string(),
+                                "recoverable": corruption.recoverable,
+                            })
+                        ),
+                        false => println!("{}", corruption),
+                    }
+                }
+                return Ok(corruptions.is_empty());
+            }
+            Command::Merge => {
+                let stats = engine.merge(None)?;
+                match self.json {
+                    true => println!(
+                        "{}",
+                        json!({
+                            "files_in": stats.files_in,
+                            "files_out": stats.files_out,
+                            "records_copied": stats.records_copied,
+                            "records_dropped": stats.records_dropped,
+                            "bytes_reclaimed": stats.bytes_reclaimed,
+                        })
+                    ),
+                    false => println!(
+                        "merged {} datafiles into {}, {} records copied, {} dropped, {} bytes reclaimed",
+                        stats.files_in,
+                        stats.files_out,
+                        stats.records_copied,
+                        stats.records_dropped,
+                        stats.bytes_reclaimed
+                    ),
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn to_json(entry: &Entry) -> Result<String> {
+    serde_json::to_string(entry)
+        .map_err(|e| Report::new(Errors::FailToSerialize).attach_printable(e))
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("error: {}\n", message);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match args.run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! The time the engine reads, see [Options::clock].
+//!
+//! Every feature depending on time reads it from the clock of its engine: the
+//! [SyncPolicy::Interval], the [merge_schedule], the timestamps and
+//! durations of the merges and the throttling of the open progress. A [MockClock], with the
+//! `testing` feature enabled, lets tests move time forward without waiting for it.
+//!
+//! [Options::clock]: crate::options::Options::clock
+//! [merge_schedule]: crate::options::Options::merge_schedule
+//! [SyncPolicy::Interval]: crate::options::SyncPolicy::Interval
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(any(test, feature = "testing"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Milliseconds elapsed since the UNIX epoch
+    fn now_millis(&self) -> u64;
+}
+
+/// A clock shared by an engine and its background threads
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The time of the system, the default clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
+
+/// A clock only moving when told to
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Default)]
+pub struct MockClock {
+    millis: AtomicU64,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    /// A clock reading `millis` milliseconds since the UNIX epoch
+    pub fn new(millis: u64) -> Arc<MockClock> {
+        Arc::new(MockClock {
+            millis: AtomicU64::new(millis),
+        })
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
+
+/// Time elapsed on `clock` since it read `since`, zero if it went backwards
+pub(crate) fn elapsed(clock: &dyn Clock, since: u64) -> Duration {
+    Duration::from_millis(clock.now_millis().saturating_sub(since))
+}
+
+/// The current time of `clock`
+pub(crate) fn now(clock: &dyn Clock) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(clock.now_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{elapsed, now, Clock, MockClock, SystemClock};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new(1000);
+        assert_eq!(clock.now_millis(), 1000);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(elapsed(&*clock, 1000), Duration::from_secs(2));
+        assert_eq!(now(&*clock), UNIX_EPOCH + Duration::from_secs(3));
+        clock.set(0);
+        assert_eq!(elapsed(&*clock, 1000), Duration::ZERO);
+    }
+
+    #[test]
+    fn system_clock() {
+        let now = SystemClock.now_millis();
+        assert!(now > 0 && SystemClock.now_millis() >= now);
+    }
+}
@@ -1,19 +1,24 @@
-use crate::data::log_record::LogRecord;
-use crate::errors::{Errors, Result};
+use crate::data::log_record;
+use crate::data::log_record::{DecodeError, ReadLogRecord};
+use crate::errors::{CorruptionInfo, Errors, RecordLocation, Result};
 use crate::fio;
-use crate::fio::io_manager;
-use bytes::{Buf, BytesMut};
-use error_stack::Report;
+use error_stack::{Report, ResultExt};
 use log::error;
-use prost::{decode_length_delimiter, length_delimiter_len};
 use std::fmt::{Debug, Formatter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub const DATAFILE_SUFFIX: &str = ".data";
 pub const INITIAL_DATAFILE_ID: u32 = 0;
 
+/// Name of the datafile with the given `id` inside the database directory
+pub fn datafile_name(id: u32) -> String {
+    std::format!("{:09}{}", id, DATAFILE_SUFFIX)
+}
+
 pub struct DataFile {
     id: u32,
+    /// directory holding the datafile
+    dir_path: PathBuf,
     offset: u64,
     io_manager: Box<dyn fio::IOManager>,
 }
@@ -29,44 +34,37 @@ impl Debug for DataFile {
 
 impl DataFile {
     pub fn new<P: AsRef<Path>>(path: P, id: u32) -> Result<DataFile> {
-        let fname = path.as_ref().to_path_buf();
-        let fname = match fname.is_dir() {
-            true => {
-                let datafile = std::format!("{:09}{}", id, DATAFILE_SUFFIX);
-                fname.join(datafile)
-            }
+        DataFile::with_io_manager(path, id, &fio::default_io_manager())
+    }
+
+    /// Opens the datafile with the [IOManager] constructed by `io_manager`
+    ///
+    /// [IOManager]: crate::fio::IOManager
+    pub fn with_io_manager<P: AsRef<Path>>(
+        path: P,
+        id: u32,
+        io_manager: &fio::IOManagerFactory,
+    ) -> Result<DataFile> {
+        let dir_path = path.as_ref().to_path_buf();
+        let fname = match dir_path.is_dir() {
+            true => dir_path.join(datafile_name(id)),
             false => {
-                error!("Database dir {:?} Not exist", fname);
+                error!("Database dir {:?} Not exist", dir_path);
                 return Err(Report::new(Errors::DatafileNotFound));
             }
         };
 
         // Check the existence of Datafile, if not exist, create one
         if !fname.is_file() {
-            let _ = std::fs::File::create(&fname).map_err(|e| {
-                error!("{}", e);
-                Errors::CreateDbFileFail
-            })?;
+            fio::atomic_create(&fname, &[])?;
         }
 
-        let offset = match std::fs::File::open(&fname) {
-            Ok(f) => f
-                .metadata()
-                .map_err(|e| {
-                    error!("{}", e);
-                    Errors::InternalError
-                })?
-                .len(),
-            Err(e) => {
-                error!("{}", e);
-                return Err(Report::new(Errors::FailToOpenFile));
-            }
-        };
-
-        let io_manager = Box::new(io_manager(fname)?);
+        let io_manager = io_manager(&fname)?;
+        let offset = io_manager.size()?;
 
         Ok(DataFile {
             id,
+            dir_path,
             offset,
             io_manager,
         })
@@ -80,6 +78,15 @@ impl DataFile {
         self.id
     }
 
+    pub(crate) fn dir_path(&self) -> &Path {
+        &self.dir_path
+    }
+
+    /// Size of the file as told by its [IOManager](fio::IOManager)
+    pub fn size(&self) -> Result<u64> {
+        self.io_manager.size()
+    }
+
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
         let bytes_read = self.io_manager.write(buf)?;
         self.offset += bytes_read as u64;
@@ -90,72 +97,83 @@ impl DataFile {
         self.io_manager.sync()
     }
 
-    pub fn read(&self, offset: u64) -> Result<Option<LogRecord>> {
-        // TODO: design decision, return Err(EOF) or Ok(None) when EOF reached
-        // Layout of LogRecord
-        // +-------+--------+-----------+-------------+-----------+-------------+
-        // |  4B   |   1B   |    mut    |     mut     |    mut    |     mut     |
-        // +-------+--------+-----------+-------------+-----------+-------------+
-        // |  CRC  |  Type  |  KeySize  |  ValueSize  |    Key    |    Value    |
-        // +-------+--------+-----------+-------------+-----------+-------------+
-
-        let max_header_sz = std::mem::size_of::<u32>() /* size of CRC */
-            + std::mem::size_of::<u8>() /* size of Type */
-            + length_delimiter_len(u32::MAX as usize) * 2 /* variable key size and value size */;
-
-        // if remaining bytes is zero, means EOF reached
-        let mut header = match (self.io_manager.size()? - offset) as usize {
-            remaining if remaining == 0 => return Ok(None),
-            remaining if remaining < max_header_sz => BytesMut::zeroed(remaining),
-            remaining if remaining > max_header_sz => BytesMut::zeroed(max_header_sz),
-            _ => unreachable!(),
-        };
-
-        self.io_manager.read(&mut header, offset)?;
-
-        let crc = header.get_u32();
-        let record_type = header.get_u8();
+    /// Releases the file handle, every later operation fails with [Errors::EngineClosed]
+    pub(crate) fn close(&mut self) {
+        self.io_manager = Box::new(Closed);
+    }
 
-        // bytes will advance automatically
-        let key_size =
-            decode_length_delimiter(&mut header).map_err(|_| Errors::DatafileCorrupted)?;
-        let value_size =
-            decode_length_delimiter(&mut header).map_err(|_| Errors::DatafileCorrupted)?;
+    /// Reads the record at `offset`, `None` past the last record
+    pub fn read(&self, offset: u64) -> Result<Option<ReadLogRecord>> {
+        self.decode_at(offset).attach_printable(RecordLocation {
+            file_id: self.id,
+            offset,
+        })
+    }
 
-        // EOF reached
-        if key_size == 0 && value_size == 0 {
-            return Ok(None);
+    fn decode_at(&self, offset: u64) -> Result<Option<ReadLogRecord>> {
+        // TODO: design decision, return Err(EOF) or Ok(None) when EOF reached
+        let file_size = self.io_manager.size()?;
+        let read = |buf: &mut [u8], at: usize| self.io_manager.read(buf, offset + at as u64);
+        // an offset past the end, e.g. of a datafile truncated since, reads as the EOF
+        match log_record::decode(file_size.saturating_sub(offset) as usize, read) {
+            Ok(decoded) => Ok(decoded.map(|(record, size)| ReadLogRecord {
+                record,
+                size: size as u64,
+            })),
+            Err(DecodeError::Read(report)) => Err(report),
+            Err(DecodeError::Corrupted {
+                reason,
+                recoverable,
+            }) => Err(
+                Report::new(Errors::DatafileCorrupted).attach_printable(CorruptionInfo {
+                    file_id: self.id,
+                    offset,
+                    reason,
+                    recoverable,
+                }),
+            ),
         }
+    }
+}
 
-        let header_size = std::mem::size_of::<u32>() /* size of CRC */
-            + std::mem::size_of::<u8>() /* size of Type */
-            + length_delimiter_len(key_size) /* length of key size */
-            + length_delimiter_len(value_size) /* length of key size */;
+/// The [IOManager](fio::IOManager) of a closed datafile
+struct Closed;
 
-        let mut kv_buf = BytesMut::zeroed(key_size + value_size);
-        self.io_manager
-            .read(&mut kv_buf, offset + header_size as u64)?;
+impl fio::IOManager for Closed {
+    fn read(&self, _buf: &mut [u8], _offset: u64) -> Result<()> {
+        Err(Report::new(Errors::EngineClosed))
+    }
 
-        let log_record = LogRecord {
-            key: kv_buf.get(..key_size).unwrap().to_vec(),
-            value: kv_buf.get(key_size..kv_buf.len()).unwrap().to_vec(),
-            record_type: record_type.try_into()?,
-        };
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Report::new(Errors::EngineClosed))
+    }
 
-        if crc != log_record.crc() {
-            error!("CRC does not match");
-            return Err(Report::new(Errors::DatafileCorrupted));
-        }
+    fn sync(&self) -> Result<()> {
+        Err(Report::new(Errors::EngineClosed))
+    }
 
-        Ok(Some(log_record))
+    fn size(&self) -> Result<u64> {
+        Err(Report::new(Errors::EngineClosed))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::data::log_record::{LogRecord, LogRecordType};
+    use crate::errors::{CorruptionInfo, CorruptionReason, Errors, RecordLocation};
     use crate::mock::datafile_wrapper::DataFileWrapper;
 
+    /// Classifies the corruption of the datafile made of `content` at `offset`
+    fn corruption(content: &[u8], offset: u64) -> (CorruptionReason, bool) {
+        let mut df = DataFileWrapper::default();
+        df.write(content).unwrap();
+        let report = df.read(offset).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+        let info = report.downcast_ref::<CorruptionInfo>().unwrap();
+        assert_eq!((info.file_id, info.offset), (df.id(), offset));
+        (info.reason, info.recoverable)
+    }
+
     #[test]
     fn get_one_key() {
         let mut df = DataFileWrapper::default();
@@ -163,8 +181,131 @@ mod tests {
             key: "ailurus-kv".as_bytes().to_vec(),
             value: "is Awesome".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            seq: None,
+        };
+        df.write(&record.encode()).unwrap();
+        let read = df.read(0).unwrap().unwrap();
+        assert_eq!((read.record, read.size), (record, 27));
+    }
+
+    #[test]
+    fn batch_records() {
+        let mut df = DataFileWrapper::default();
+        let record = LogRecord {
+            key: b"\x01\x80key".to_vec(),
+            value: "val".as_bytes().to_vec(),
+            record_type: LogRecordType::Deleted,
+            seq: Some(7),
+        };
+        let marker = LogRecord {
+            key: vec![],
+            value: vec![],
+            record_type: LogRecordType::TxnFinished,
+            seq: Some(7),
+        };
+        df.write(&record.encode()).unwrap();
+        df.write(&marker.encode()).unwrap();
+        assert_eq!(df.read(0).unwrap().unwrap().record, record);
+        assert_eq!(df.read(record.size()).unwrap().unwrap().record, marker);
+    }
+
+    #[test]
+    fn record_as_long_as_max_header() {
+        let mut df = DataFileWrapper::default();
+        // 4B CRC + 1B type + 1B key size + 1B value size + 8B kv == 15B == max header size
+        let record = LogRecord {
+            key: "bc".as_bytes().to_vec(),
+            value: "val-bc".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            seq: None,
         };
         df.write(&record.encode()).unwrap();
-        assert_eq!(df.read(0).unwrap().unwrap(), record);
+        assert_eq!(df.read(0).unwrap().unwrap().record, record);
+    }
+
+    #[test]
+    fn corrupted_record_reports_location() {
+        let mut df = DataFileWrapper::default();
+        let record = LogRecord {
+            key: "ailurus-kv".as_bytes().to_vec(),
+            value: "is Awesome".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            seq: None,
+        };
+        let mut encoded = record.encode();
+        df.write(&encoded).unwrap();
+        // flip a bit of the value so that the CRC no longer matches
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0x01;
+        df.write(&encoded).unwrap();
+
+        let offset = record.size();
+        let report = df.read(offset).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+
+        let location = RecordLocation {
+            file_id: df.id(),
+            offset,
+        };
+        assert_eq!(report.downcast_ref::<RecordLocation>(), Some(&location));
+        let rendered = format!("{:?}", report);
+        assert!(rendered.contains(&format!("offset {} of datafile {}", offset, df.id())));
+    }
+
+    #[test]
+    fn corruption_reasons() {
+        let record = LogRecord {
+            key: "ailurus-kv".as_bytes().to_vec(),
+            value: "is Awesome".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            seq: None,
+        }
+        .encode();
+        let followed = |damaged: Vec<u8>| [damaged, record.clone()].concat();
+
+        let mut crc = record.clone();
+        *crc.last_mut().unwrap() ^= 0x01;
+        assert_eq!(corruption(&crc, 0), (CorruptionReason::CrcMismatch, true));
+        assert_eq!(
+            corruption(&followed(crc), 0),
+            (CorruptionReason::CrcMismatch, false)
+        );
+
+        let mut record_type = record.clone();
+        record_type[4] = 0x7f;
+        assert_eq!(
+            corruption(&followed(record_type), 0),
+            (CorruptionReason::UnknownRecordType, false)
+        );
+
+        // a key size of more than 10 bytes is no varint
+        let delimiter = [&record[..5], &[0xff; 11]].concat();
+        assert_eq!(
+            corruption(&followed(delimiter), 0),
+            (CorruptionReason::InvalidLengthDelimiter, false)
+        );
+
+        // cut in the value, in the header and in the middle of the key size
+        let cut = [&record[..], &record[..record.len() - 3]].concat();
+        let offset = record.len() as u64;
+        assert_eq!(
+            corruption(&cut, offset),
+            (CorruptionReason::Truncated, true)
+        );
+        assert_eq!(
+            corruption(&record[..3], 0),
+            (CorruptionReason::Truncated, true)
+        );
+        let long_key = LogRecord {
+            key: vec![b'k'; 200],
+            value: vec![],
+            record_type: LogRecordType::Normal,
+            seq: None,
+        }
+        .encode();
+        assert_eq!(
+            corruption(&long_key[..6], 0),
+            (CorruptionReason::Truncated, true)
+        );
     }
 }
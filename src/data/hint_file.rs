@@ -0,0 +1,211 @@
+//! Hint files list the records of a datafile written by a merge without their values, the
+//! index is rebuilt from them instead of reading the whole datafile.
+//!
+//! A hint file is named after its datafile, with the [HINT_SUFFIX]. It holds one entry per
+//! record of the datafile, in order: the type of the record as a byte, the size of its key
+//! and the size of the record as varints, then the key. The offset of a record is the sum of
+//! the sizes before it. The entries are followed by their CRC32 as a big endian `u32`.
+
+use crate::data::data_file::DataFile;
+use crate::data::log_record::{LogRecordPos, LogRecordType};
+use crate::errors::{Errors, Result};
+use crate::fio;
+use bytes::{Buf, BufMut};
+use error_stack::ResultExt;
+use log::warn;
+use prost::encoding::{decode_varint, encode_varint};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+pub const HINT_SUFFIX: &str = ".hint";
+
+pub fn hint_name(id: u32) -> String {
+    std::format!("{:09}{}", id, HINT_SUFFIX)
+}
+
+/// A record of a datafile, as listed by its hint file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct HintEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) record_type: LogRecordType,
+    pub(crate) pos: LogRecordPos,
+}
+
+/// Encodes the `entries` of a datafile, given in the order of their records
+pub(crate) fn encode(entries: &[HintEntry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        buf.put_u8(entry.record_type.into());
+        encode_varint(entry.key.len() as u64, &mut buf);
+        encode_varint(entry.pos.size as u64, &mut buf);
+        buf.put_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.put_u32(crc);
+    buf
+}
+
+/// Decodes the entries of the datafile `file_id`, `None` if `buf` is not a valid hint file
+pub(crate) fn decode(buf: &[u8], file_id: u32) -> Option<Vec<HintEntry>> {
+    let (mut body, crc) = buf.split_at(buf.len().checked_sub(4)?);
+    if crc32fast::hash(body) != u32::from_be_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while body.has_remaining() {
+        // a merge copies no batch marker
+        let record_type = LogRecordType::try_from(body.get_u8())
+            .ok()
+            .filter(|record_type| *record_type != LogRecordType::TxnFinished)?;
+        let key_len = usize::try_from(decode_varint(&mut body).ok()?).ok()?;
+        let size = u32::try_from(decode_varint(&mut body).ok()?).ok()?;
+        if body.len() < key_len {
+            return None;
+        }
+        let key = body[..key_len].to_vec();
+        body.advance(key_len);
+        entries.push(HintEntry {
+            key,
+            record_type,
+            pos: LogRecordPos {
+                file_id,
+                offset,
+                size,
+            },
+        });
+        offset += size as u64;
+    }
+    Some(entries)
+}
+
+/// The records of `datafile` as listed by its hint file. `None` if it has no hint file, or
+/// one not matching the datafile as it is, the datafile has to be read instead.
+pub(crate) fn load(datafile: &DataFile) -> Option<Vec<HintEntry>> {
+    let path = datafile.dir_path().join(hint_name(datafile.id()));
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Fail to read hint file {:?}: {}", path, e);
+            return None;
+        }
+    };
+    let entries = decode(&buf, datafile.id());
+    let size = entries.as_ref().map(|entries| {
+        entries
+            .last()
+            .map_or(0, |last| last.pos.offset + last.pos.size as u64)
+    });
+    if size != Some(datafile.offset()) {
+        warn!(
+            "Ignoring hint file {:?}, it does not match its datafile",
+            path
+        );
+        return None;
+    }
+    entries
+}
+
+/// Writes the hint file of the datafile `id` of `dir_path`, listing its records `entries`
+pub(crate) fn write(dir_path: &Path, id: u32, entries: &[HintEntry]) -> Result<()> {
+    fio::atomic_create(dir_path.join(hint_name(id)), &encode(entries))
+}
+
+/// Removes the hint file of the datafile `id` of `dir_path`, if any. A datafile about to be
+/// rewritten or removed loses its hint file first.
+pub(crate) fn remove(dir_path: &Path, id: u32) -> Result<()> {
+    let path = dir_path.join(hint_name(id));
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        removed => removed
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to remove hint file {:?}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::hint_file::{decode, encode, load, remove, write, HintEntry};
+    use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType};
+    use crate::mock::datafile_wrapper::DataFileWrapper;
+
+    /// Writes `records` to a datafile, returns it along with its hint entries
+    fn datafile(records: &[(&str, LogRecordType)]) -> (DataFileWrapper, Vec<HintEntry>) {
+        let mut df = DataFileWrapper::new(3);
+        let mut entries = Vec::new();
+        for (key, record_type) in records {
+            let record = LogRecord {
+                key: key.as_bytes().to_vec(),
+                value: b"value".to_vec(),
+                record_type: *record_type,
+                seq: None,
+            };
+            let offset = df.offset();
+            df.write(&record.encode()).unwrap();
+            entries.push(HintEntry {
+                key: record.key,
+                record_type: record.record_type,
+                pos: LogRecordPos {
+                    file_id: 3,
+                    offset,
+                    size: (df.offset() - offset) as u32,
+                },
+            });
+        }
+        (df, entries)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_, entries) = datafile(&[
+            ("a", LogRecordType::Normal),
+            ("gone", LogRecordType::Deleted),
+            ("", LogRecordType::Normal),
+        ]);
+        assert_eq!(decode(&encode(&entries), 3).unwrap(), entries);
+        assert_eq!(decode(&encode(&[]), 3).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn decode_rejects_invalid() {
+        let (_, entries) = datafile(&[("a", LogRecordType::Normal), ("b", LogRecordType::Deleted)]);
+        let encoded = encode(&entries);
+        for i in 0..encoded.len() {
+            let mut flipped = encoded.clone();
+            flipped[i] ^= 0x01;
+            assert_eq!(decode(&flipped, 3), None, "{}", i);
+            assert_eq!(decode(&encoded[..i], 3), None, "{}", i);
+        }
+
+        let marker = HintEntry {
+            key: vec![],
+            record_type: LogRecordType::TxnFinished,
+            pos: entries[0].pos,
+        };
+        assert_eq!(decode(&encode(&[marker]), 3), None);
+    }
+
+    #[test]
+    fn load_checks_datafile() {
+        let (mut df, entries) =
+            datafile(&[("a", LogRecordType::Normal), ("b", LogRecordType::Normal)]);
+        assert_eq!(load(&df), None);
+
+        write(df.dir_path(), df.id(), &entries).unwrap();
+        assert_eq!(load(&df).unwrap(), entries);
+
+        // written to since the hint file
+        df.write(b"more").unwrap();
+        assert_eq!(load(&df), None);
+
+        write(df.dir_path(), df.id(), &entries[..1]).unwrap();
+        assert_eq!(load(&df), None);
+
+        remove(df.dir_path(), df.id()).unwrap();
+        remove(df.dir_path(), df.id()).unwrap();
+        assert_eq!(load(&df), None);
+    }
+}
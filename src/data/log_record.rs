@@ -1,12 +1,17 @@
-use crate::errors::Errors;
+use crate::errors::{CorruptionReason, Error, Errors, Result};
 use bytes::{Buf, BufMut, BytesMut};
-use prost::encode_length_delimiter;
+use error_stack::Report;
+use prost::encoding::{decode_varint, encode_varint, encoded_len_varint};
+use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len};
+use std::borrow::Cow;
 
 #[non_exhaustive]
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum LogRecordType {
     Normal,
     Deleted,
+    /// Marks the batch of its sequence number as committed, it has no key nor value
+    TxnFinished,
 }
 
 #[derive(Eq, PartialEq, Debug)]
@@ -14,8 +19,22 @@ pub struct LogRecord {
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
     pub(crate) record_type: LogRecordType,
+    /// Sequence number of the batch writing the record, encoded in front of its key,
+    /// `None` for a record written outside of a batch
+    pub(crate) seq: Option<u64>,
 }
 
+/// A record read from a datafile, along with the length of its encoding there
+#[derive(Eq, PartialEq, Debug)]
+pub struct ReadLogRecord {
+    pub(crate) record: LogRecord,
+    /// The size of the encoded record in bytes, i.e. [LogRecord::size]
+    pub(crate) size: u64,
+}
+
+/// Set in the type byte of a record whose key is prefixed with a sequence number
+const SEQ_FLAG: u8 = 0x80;
+
 impl TryFrom<u8> for LogRecordType {
     type Error = Errors;
 
@@ -23,6 +42,7 @@ impl TryFrom<u8> for LogRecordType {
         match value {
             1 => Ok(LogRecordType::Normal),
             2 => Ok(LogRecordType::Deleted),
+            3 => Ok(LogRecordType::TxnFinished),
             _ => Err(Errors::DatafileCorrupted),
         }
     }
@@ -33,6 +53,7 @@ impl From<LogRecordType> for u8 {
         match value {
             LogRecordType::Normal => 1,
             LogRecordType::Deleted => 2,
+            LogRecordType::TxnFinished => 3,
         }
     }
 }
@@ -43,6 +64,42 @@ pub struct LogRecordPos {
     pub(crate) file_id: u32,
     /// The byte offset within the log file where the record starts.
     pub(crate) offset: u64,
+    /// The size of the encoded record in bytes.
+    pub(crate) size: u32,
+}
+
+/// Prefixes `key` with the sequence number `seq` of its batch, as a varint
+pub fn encode_key_with_seq(key: &[u8], seq: u64) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(encoded_len_varint(seq) + key.len());
+    encode_varint(seq, &mut encoded);
+    encoded.extend_from_slice(key);
+    encoded
+}
+
+/// Splits a key encoded by [encode_key_with_seq] into the key and its sequence number.
+///
+/// # Panics
+///
+/// Panics if `encoded` does not start with a sequence number, see [try_decode_key_with_seq].
+pub fn decode_key_with_seq(encoded: &[u8]) -> (Vec<u8>, u64) {
+    try_decode_key_with_seq(encoded).expect("key not prefixed with a sequence number")
+}
+
+/// Like [decode_key_with_seq], `None` if it does not start with a sequence number encoded in as few bytes as it takes
+pub(crate) fn try_decode_key_with_seq(encoded: &[u8]) -> Option<(Vec<u8>, u64)> {
+    let mut buf = encoded;
+    let seq = decode_varint(&mut buf).ok()?;
+    match encoded.len() - buf.len() == encoded_len_varint(seq) {
+        true => Some((buf.to_vec(), seq)),
+        false => None,
+    }
+}
+
+/// Upper bound of the size of an encoded record header, see [LogRecord::encode]
+pub(crate) fn max_header_size() -> usize {
+    std::mem::size_of::<u32>() /* size of CRC */
+        + std::mem::size_of::<u8>() /* size of Type */
+        + length_delimiter_len(u32::MAX as usize) * 2 /* variable key size and value size */
 }
 
 impl LogRecord {
@@ -53,6 +110,9 @@ impl LogRecord {
     // |  CRC  |  Type  |  KeySize  |  ValueSize  |    Key    |    Value    |
     // +-------+--------+-----------+-------------+-----------+-------------+
     ///
+    /// The type of a record written by a batch has its highest bit set, its key is then
+    /// prefixed with the sequence number of the batch, see [encode_key_with_seq].
+    ///
     /// # Returns
     ///
     /// Returns a `Vec<u8>` containing the encoded representation of the `LogRecord`.
@@ -88,26 +148,36 @@ impl LogRecord {
         // +--------+-----------+-------------+-----------+-------------+
         // (Difference between the encoding result is CRC field is missing)
         let mut buf = BytesMut::new();
+        let (record_type, key) = match self.seq {
+            Some(seq) => (
+                u8::from(self.record_type) | SEQ_FLAG,
+                Cow::Owned(encode_key_with_seq(&self.key, seq)),
+            ),
+            None => (self.record_type.into(), Cow::Borrowed(&self.key[..])),
+        };
         // encode the record type
-        buf.put_u8(self.record_type.into());
+        buf.put_u8(record_type);
         // encode the key size and value size
-        encode_length_delimiter(self.key.len(), &mut buf).unwrap(); // TODO: deal with the error
+        encode_length_delimiter(key.len(), &mut buf).unwrap(); // TODO: deal with the error
         encode_length_delimiter(self.value.len(), &mut buf).unwrap();
         // encode key and value
-        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&key);
         buf.extend_from_slice(&self.value);
 
         buf
     }
 
-    /// Return the size of the `LogRecord`
-    ///
-    /// # Notes
-    ///
-    /// The time complexity of the call does *not* guarantee O(1) due to the implementation
+    /// Return the size of the encoded `LogRecord`, without encoding it
     pub fn size(&self) -> u64 {
-        // TODO: [perf] improve the performance
-        self.encode().len() as u64
+        // the key is prefixed with the sequence number of its batch, see `compress`
+        let key_size = self.key.len() + self.seq.map_or(0, encoded_len_varint);
+        let size = std::mem::size_of::<u32>() /* size of CRC */
+            + std::mem::size_of::<u8>() /* size of Type */
+            + length_delimiter_len(key_size) /* length of key size */
+            + length_delimiter_len(self.value.len()) /* length of value size */
+            + key_size
+            + self.value.len();
+        size as u64
     }
 
     pub fn crc(&self) -> u32 {
@@ -117,6 +187,141 @@ impl LogRecord {
     }
 }
 
+/// Why [decode] failed
+pub(crate) enum DecodeError {
+    /// The bytes of the record could not be read
+    Read(Error),
+    /// The bytes read are no record, `recoverable` as in [CorruptionInfo]
+    ///
+    /// [CorruptionInfo]: crate::errors::CorruptionInfo
+    Corrupted {
+        reason: CorruptionReason,
+        recoverable: bool,
+    },
+}
+
+/// Decodes the record at the start of `buf`, which holds the bytes of a datafile from the
+/// record to the end of the file. Returns `None` at the end of the records, otherwise the
+/// record along with the length of its encoding.
+///
+/// The report of a corrupted record is an [Errors::DatafileCorrupted] carrying its
+/// [CorruptionReason].
+pub fn decode_record(buf: &[u8]) -> Result<Option<(LogRecord, usize)>> {
+    let read = |dst: &mut [u8], at: usize| {
+        dst.copy_from_slice(&buf[at..at + dst.len()]);
+        Ok(())
+    };
+    decode(buf.len(), read).map_err(|error| match error {
+        DecodeError::Read(report) => report,
+        DecodeError::Corrupted { reason, .. } => {
+            Report::new(Errors::DatafileCorrupted).attach_printable(reason)
+        }
+    })
+}
+
+/// Decodes the record followed by `remaining` bytes up to the end of its datafile, see
+/// [decode_record]. `read` fills a buffer with the bytes at an offset from the start of the
+/// record, it is only asked for bytes among the `remaining` ones.
+pub(crate) fn decode(
+    remaining: usize,
+    mut read: impl FnMut(&mut [u8], usize) -> Result<()>,
+) -> std::result::Result<Option<(LogRecord, usize)>, DecodeError> {
+    let corrupted = |reason, recoverable| DecodeError::Corrupted {
+        reason,
+        recoverable,
+    };
+    let mut header = match remaining {
+        0 => return Ok(None),
+        remaining => BytesMut::zeroed(remaining.min(max_header_size())),
+    };
+    read(&mut header, 0).map_err(DecodeError::Read)?;
+
+    if header.len() < std::mem::size_of::<u32>() + std::mem::size_of::<u8>() {
+        return Err(corrupted(CorruptionReason::Truncated, true));
+    }
+    let crc = header.get_u32();
+    let record_type = header.get_u8();
+
+    // bytes will advance automatically
+    let cut = remaining < max_header_size();
+    let key_size = decode_size(&mut header, cut).map_err(|reason| corrupted(reason, cut))?;
+    let value_size = decode_size(&mut header, cut).map_err(|reason| corrupted(reason, cut))?;
+
+    // EOF reached
+    if key_size == 0 && value_size == 0 {
+        return Ok(None);
+    }
+
+    let header_size = std::mem::size_of::<u32>() /* size of CRC */
+        + std::mem::size_of::<u8>() /* size of Type */
+        + length_delimiter_len(key_size) /* length of key size */
+        + length_delimiter_len(value_size) /* length of value size */;
+    let record_size = header_size + key_size + value_size;
+
+    let batched = record_type & SEQ_FLAG != 0;
+    let record_type = LogRecordType::try_from(record_type & !SEQ_FLAG).map_err(|_| {
+        corrupted(
+            CorruptionReason::UnknownRecordType,
+            record_size >= remaining,
+        )
+    })?;
+
+    if record_size > remaining {
+        return Err(corrupted(CorruptionReason::Truncated, true));
+    }
+
+    let mut kv_buf = vec![0; key_size + value_size];
+    read(&mut kv_buf, header_size).map_err(DecodeError::Read)?;
+    let value = kv_buf.split_off(key_size);
+    let (key, seq) = match batched {
+        true => match try_decode_key_with_seq(&kv_buf) {
+            Some((key, seq)) => (key, Some(seq)),
+            None => {
+                return Err(corrupted(
+                    CorruptionReason::InvalidLengthDelimiter,
+                    record_size == remaining,
+                ))
+            }
+        },
+        false => (kv_buf, None),
+    };
+    let log_record = LogRecord {
+        key,
+        value,
+        record_type,
+        seq,
+    };
+
+    if crc != log_record.crc() {
+        log::error!("CRC does not match");
+        return Err(corrupted(
+            CorruptionReason::CrcMismatch,
+            record_size == remaining,
+        ));
+    }
+
+    Ok(Some((log_record, record_size)))
+}
+
+/// Decodes a key size or value size of a record header. `cut` tells whether the header
+/// was cut short by the end of the datafile, a size that runs into the cut is truncated.
+fn decode_size(header: &mut BytesMut, cut: bool) -> std::result::Result<usize, CorruptionReason> {
+    // every byte of an unfinished varint has its continuation bit set
+    let unfinished = header.iter().all(|byte| byte & 0x80 != 0);
+    let before = header.len();
+    match decode_length_delimiter(&mut *header) {
+        // the writer encodes sizes in as few bytes as they take, which the header size
+        // and the CRC rely on
+        Ok(size)
+            if size <= u32::MAX as usize && before - header.len() == length_delimiter_len(size) =>
+        {
+            Ok(size)
+        }
+        Err(_) if cut && unfinished => Err(CorruptionReason::Truncated),
+        _ => Err(CorruptionReason::InvalidLengthDelimiter),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,6 +332,7 @@ mod tests {
             key: "ailurus-kv".as_bytes().to_vec(), // 10 bytes
             value: "is Awesome".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            seq: None,
         };
 
         let expected = [
@@ -148,6 +354,7 @@ mod tests {
             key: vec![], // 10 bytes
             value: vec![],
             record_type: LogRecordType::Normal,
+            seq: None,
         };
 
         let expected = [
@@ -166,6 +373,7 @@ mod tests {
             key: "ailurus-kv".as_bytes().to_vec(), // 10 bytes
             value: "is Awesome".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            seq: None,
         };
 
         let expected = [
@@ -188,8 +396,187 @@ mod tests {
             key: "ailurus-kv".as_bytes().to_vec(), // 10 bytes
             value: "is Awesome".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            seq: None,
         };
 
         assert_eq!(record.crc(), 0x04cd63dd_u32);
     }
+
+    #[test]
+    fn golden_encodings() {
+        // changing any of these bytes changes the on-disk format, see `tests/compat.rs`
+        let record = |key: &[u8], value: &[u8], record_type| {
+            LogRecord {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                record_type,
+                seq: None,
+            }
+            .encode()
+        };
+        assert_eq!(
+            record(b"key", b"value", LogRecordType::Normal),
+            b"\xa8\xde\xbc\xef\x01\x03\x05keyvalue"
+        );
+        assert_eq!(
+            record(b"key", b"", LogRecordType::Deleted),
+            b"\xce\x7b\x08\xc3\x02\x03\x00key"
+        );
+        let long = [&[0x00, 0xff][..], &[b'x'; 130]].concat();
+        assert_eq!(
+            record(b"\x00\xff", &[b'x'; 130], LogRecordType::Normal),
+            [&b"\xcc\x38\xe4\x3b\x01\x02\x82\x01"[..], &long].concat()
+        );
+
+        // the records of the batch of sequence number 1 and their marker
+        let batched = |key: &[u8], value: &[u8], record_type| {
+            LogRecord {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                record_type,
+                seq: Some(1),
+            }
+            .encode()
+        };
+        assert_eq!(
+            batched(b"key", b"value", LogRecordType::Normal),
+            b"\x62\x4b\x65\xea\x81\x04\x05\x01keyvalue"
+        );
+        assert_eq!(
+            batched(b"", b"", LogRecordType::TxnFinished),
+            b"\xa8\x6d\x9c\x68\x83\x01\x00\x01"
+        );
+    }
+
+    #[test]
+    fn decode_records() {
+        let record = LogRecord {
+            key: "ailurus-kv".as_bytes().to_vec(),
+            value: "is Awesome".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            seq: None,
+        };
+        let encoded = record.encode();
+        let followed = [&encoded[..], &encoded[..]].concat();
+        let (decoded, len) = decode_record(&followed).unwrap().unwrap();
+        assert_eq!((&decoded, len), (&record, encoded.len()));
+
+        let batched = LogRecord {
+            seq: Some(300),
+            ..record
+        };
+        let encoded = batched.encode();
+        assert_eq!(
+            decode_record(&encoded).unwrap(),
+            Some((batched, encoded.len()))
+        );
+        assert!(decode_record(&[]).unwrap().is_none());
+
+        // a key size of 10 encoded in two bytes
+        let long = [&encoded[..5], &[0x8a, 0x00], &encoded[6..]].concat();
+        let report = decode_record(&long).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+        assert_eq!(
+            report.downcast_ref::<CorruptionReason>(),
+            Some(&CorruptionReason::InvalidLengthDelimiter)
+        );
+    }
+
+    #[test]
+    fn size_without_encoding() {
+        // lengths around the ones whose length delimiter takes one more byte
+        for key_len in [0, 1, 10, 127, 128, 16_383, 16_384] {
+            for value_len in [0, 1, 127, 128, 16_384, 2_097_152] {
+                for seq in [None, Some(0), Some(300), Some(u64::MAX)] {
+                    let record = LogRecord {
+                        key: vec![b'k'; key_len],
+                        value: vec![b'v'; value_len],
+                        record_type: LogRecordType::Normal,
+                        seq,
+                    };
+                    assert_eq!(
+                        record.size(),
+                        record.encode().len() as u64,
+                        "key of {}B, value of {}B, seq {:?}",
+                        key_len,
+                        value_len,
+                        seq
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn key_with_seq() {
+        let keys: [&[u8]; 6] = [
+            b"",
+            b"key",
+            // bytes that would be read as a varint
+            b"\x01",
+            b"\x80\x01",
+            b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+            b"\x00\xff\x80",
+        ];
+        for key in keys {
+            for seq in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+                let encoded = encode_key_with_seq(key, seq);
+                assert!(encoded.ends_with(key));
+                assert_eq!(decode_key_with_seq(&encoded), (key.to_vec(), seq));
+            }
+        }
+        assert_eq!(encode_key_with_seq(b"key", 300), b"\xac\x02key");
+
+        // no varint, or one longer than it takes
+        assert_eq!(try_decode_key_with_seq(b""), None);
+        assert_eq!(try_decode_key_with_seq(b"\x80"), None);
+        assert_eq!(try_decode_key_with_seq(b"\x81\x00key"), None);
+    }
+
+    #[test]
+    fn txn_finished_record() {
+        assert_eq!(LogRecordType::try_from(3), Ok(LogRecordType::TxnFinished));
+        assert_eq!(u8::from(LogRecordType::TxnFinished), 3);
+        let marker = LogRecord {
+            key: vec![],
+            value: vec![],
+            record_type: LogRecordType::TxnFinished,
+            seq: Some(42),
+        };
+        let encoded = marker.encode();
+        assert_eq!(encoded[4], 3 | SEQ_FLAG);
+        assert_eq!(
+            u32::from_be_bytes(encoded[..4].try_into().unwrap()),
+            marker.crc()
+        );
+        assert_eq!(
+            decode_record(&encoded).unwrap(),
+            Some((marker, encoded.len()))
+        );
+    }
+
+    #[test]
+    fn decode_damaged_records() {
+        // what the `decode_record` fuzz target checks, on damaged copies of a record
+        let encoded = LogRecord {
+            key: "ailurus-kv".as_bytes().to_vec(),
+            value: "is Awesome".as_bytes().to_vec(),
+            record_type: LogRecordType::Deleted,
+            seq: None,
+        }
+        .encode();
+        let mut rng = fastrand::Rng::with_seed(0);
+        for _ in 0..10_000 {
+            let mut bytes = encoded.clone();
+            let i = rng.usize(..bytes.len());
+            match rng.u8(..3) {
+                0 => bytes[i] = rng.u8(..),
+                1 => bytes.truncate(i),
+                _ => bytes.insert(i, rng.u8(..)),
+            }
+            if let Ok(Some((record, len))) = decode_record(&bytes) {
+                assert_eq!(record.encode(), bytes[..len]);
+            }
+        }
+    }
 }
@@ -1,2 +1,3 @@
 pub mod data_file;
+pub mod hint_file;
 pub mod log_record;
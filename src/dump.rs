@@ -0,0 +1,348 @@
+//! A portable stream of the live keyspace, to move a database between machines or to
+//! archive it.
+//!
+//! A dump starts with the magic bytes `AILURUSD` and the format version as a big endian
+//! `u32`, followed by one record per key: the key length and the value length as big
+//! endian `u32`, then the key and the value. A zero key length ends the records, it is
+//! followed by the number of records as a big endian `u64` and by the CRC32 of every
+//! byte before it as a big endian `u32`.
+
+use crate::engine::Engine;
+use crate::errors::{ErrorKey, Errors, Result};
+use crate::options::IteratorOptions;
+use bytes::Bytes;
+use error_stack::{Report, ResultExt};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 8] = b"AILURUSD";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DumpStats {
+    pub records: u64,
+    /// Size of the whole dump
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LoadStats {
+    pub loaded: u64,
+    /// Records of keys that already existed, see [LoadMode::SkipExisting]
+    pub skipped: u64,
+}
+
+/// What [Engine::load_from] does with a key that already exists in the engine
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoadMode {
+    Overwrite,
+    SkipExisting,
+    /// Fails with [Errors::KeyAlreadyExists]
+    ErrorOnConflict,
+}
+
+/// Where a dump is invalid, attached to the [Report] of an [Errors::InvalidDump]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidDumpInfo {
+    pub offset: u64,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for InvalidDumpInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {} of the dump", self.reason, self.offset)
+    }
+}
+
+fn invalid<T>(offset: u64, reason: &'static str) -> Result<T> {
+    Err(Report::new(Errors::InvalidDump)).attach_printable(InvalidDumpInfo { offset, reason })
+}
+
+/// Writes the dump, computing the checksum along the way
+struct DumpWriter<W: Write> {
+    writer: BufWriter<W>,
+    hasher: crc32fast::Hasher,
+    written: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(buf)
+            .change_context(Errors::FailToWriteToFile)?;
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_len(&mut self, len: usize) -> Result<()> {
+        let len = u32::try_from(len)
+            .change_context(Errors::ValueTooLarge)
+            .attach_printable_lazy(|| format!("Record of {} bytes", len))?;
+        self.write(&len.to_be_bytes())
+    }
+}
+
+/// Reads the dump, computing the checksum along the way
+struct DumpReader<R: Read> {
+    reader: BufReader<R>,
+    hasher: crc32fast::Hasher,
+    offset: u64,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Reads `len` bytes, running out of them is reported at `start`
+    fn read(&mut self, len: usize, start: u64) -> Result<Vec<u8>> {
+        // the length comes from the dump, nothing is allocated ahead of the bytes read
+        let mut buf = Vec::new();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .change_context(Errors::FailToReadFromFile)?;
+        if buf.len() < len {
+            return invalid(start, "truncated dump");
+        }
+        self.hasher.update(&buf);
+        self.offset += len as u64;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self, start: u64) -> Result<u32> {
+        let buf = self.read(4, start)?;
+        Ok(u32::from_be_bytes(buf.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self, start: u64) -> Result<u64> {
+        let buf = self.read(8, start)?;
+        Ok(u64::from_be_bytes(buf.try_into().unwrap()))
+    }
+}
+
+impl Engine {
+    /// Writes the live keyspace to `writer` in the [dump format](crate::dump). The dump
+    /// works on a snapshot, it holds exactly the keys live when it starts.
+    pub fn dump_to<W: Write>(&self, writer: W) -> Result<DumpStats> {
+        let mut iter = self.iter(IteratorOptions::default())?;
+        let mut out = DumpWriter {
+            writer: BufWriter::new(writer),
+            hasher: crc32fast::Hasher::new(),
+            written: 0,
+        };
+        out.write(MAGIC)?;
+        out.write(&VERSION.to_be_bytes())?;
+
+        let mut records = 0u64;
+        while let Some(entry) = iter.next_entry() {
+            let (key, value) = entry?.into_parts();
+            out.write_len(key.len())?;
+            out.write_len(value.len())?;
+            out.write(&key)?;
+            out.write(&value)?;
+            records += 1;
+        }
+        out.write(&0u32.to_be_bytes())?;
+        out.write(&records.to_be_bytes())?;
+
+        let crc = out.hasher.clone().finalize();
+        out.write(&crc.to_be_bytes())?;
+        out.writer
+            .flush()
+            .change_context(Errors::FailToWriteToFile)?;
+        Ok(DumpStats {
+            records,
+            bytes: out.written,
+        })
+    }
+
+    /// Loads a dump written by [Engine::dump_to].
+    ///
+    /// The records are written while the dump is read: an invalid dump fails with
+    /// [Errors::InvalidDump] and an [InvalidDumpInfo] locating the damage, the records
+    /// before it have been loaded by then.
+    pub fn load_from<R: Read>(&self, reader: R, mode: LoadMode) -> Result<LoadStats> {
+        let mut input = DumpReader {
+            reader: BufReader::new(reader),
+            hasher: crc32fast::Hasher::new(),
+            offset: 0,
+        };
+        if input.read(MAGIC.len(), 0)? != MAGIC {
+            return invalid(0, "not a dump");
+        }
+        if input.read_u32(input.offset)? != VERSION {
+            return invalid(MAGIC.len() as u64, "unsupported version");
+        }
+
+        let mut stats = LoadStats::default();
+        let mut records = 0u64;
+        loop {
+            let start = input.offset;
+            let key_len = input.read_u32(start)? as usize;
+            if key_len == 0 {
+                break;
+            }
+            let value_len = input.read_u32(start)? as usize;
+            let key = Bytes::from(input.read(key_len, start)?);
+            let value = Bytes::from(input.read(value_len, start)?);
+            records += 1;
+
+            match (self.index.get(&key).is_some(), mode) {
+                (true, LoadMode::SkipExisting) => stats.skipped += 1,
+                (true, LoadMode::ErrorOnConflict) => {
+                    return Err(Report::new(Errors::KeyAlreadyExists))
+                        .attach_printable(ErrorKey::new(&key));
+                }
+                _ => {
+                    self.put(key, value)?;
+                    stats.loaded += 1;
+                }
+            }
+        }
+
+        let count_start = input.offset;
+        let count = input.read_u64(count_start)?;
+        let crc = input.hasher.clone().finalize();
+        let crc_start = input.offset;
+        if input.read_u32(crc_start)? != crc {
+            return invalid(crc_start, "checksum mismatch");
+        }
+        if count != records {
+            return invalid(count_start, "record count mismatch");
+        }
+        let mut trailing = [0u8];
+        if input
+            .reader
+            .read(&mut trailing)
+            .change_context(Errors::FailToReadFromFile)?
+            != 0
+        {
+            return invalid(input.offset, "trailing data");
+        }
+
+        self.sync()?;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{InvalidDumpInfo, LoadMode, LoadStats};
+    use crate::engine::Engine;
+    use crate::errors::Errors;
+    use crate::options::IteratorOptions;
+    use bytes::Bytes;
+
+    fn entries(engine: &Engine) -> Vec<(Bytes, Bytes)> {
+        engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().into_parts())
+            .collect()
+    }
+
+    fn dump(engine: &Engine) -> Vec<u8> {
+        let mut buf = Vec::new();
+        engine.dump_to(&mut buf).unwrap();
+        buf
+    }
+
+    fn source() -> Engine {
+        let engine = Engine::open_temporary().unwrap();
+        engine
+            .put(
+                Bytes::from_static(b"\x00\xff"),
+                Bytes::from_static(b"\xfe\x00"),
+            )
+            .unwrap();
+        engine.put("large".into(), vec![7; 1 << 20].into()).unwrap();
+        engine.put("deleted".into(), "value".into()).unwrap();
+        engine.delete("deleted".into()).unwrap();
+        engine.put("empty".into(), Bytes::new()).unwrap();
+        engine
+    }
+
+    fn invalid_at(buf: &[u8]) -> InvalidDumpInfo {
+        let engine = Engine::open_temporary().unwrap();
+        let report = engine.load_from(buf, LoadMode::Overwrite).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::InvalidDump);
+        *report.downcast_ref::<InvalidDumpInfo>().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let engine = source();
+        let mut buf = Vec::new();
+        let stats = engine.dump_to(&mut buf).unwrap();
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.bytes, buf.len() as u64);
+
+        let copy = Engine::open_temporary().unwrap();
+        let stats = copy.load_from(buf.as_slice(), LoadMode::Overwrite).unwrap();
+        assert_eq!(
+            stats,
+            LoadStats {
+                loaded: 3,
+                skipped: 0
+            }
+        );
+        assert_eq!(entries(&copy), entries(&engine));
+        assert_eq!(dump(&copy), buf);
+    }
+
+    #[test]
+    fn load_modes() {
+        let buf = dump(&source());
+        let existing = || {
+            let engine = Engine::open_temporary().unwrap();
+            engine.put("empty".into(), "kept".into()).unwrap();
+            engine
+        };
+
+        let engine = existing();
+        engine
+            .load_from(buf.as_slice(), LoadMode::Overwrite)
+            .unwrap();
+        assert_eq!(engine.get("empty".into()).unwrap().unwrap(), "");
+
+        let engine = existing();
+        let stats = engine
+            .load_from(buf.as_slice(), LoadMode::SkipExisting)
+            .unwrap();
+        assert_eq!(
+            stats,
+            LoadStats {
+                loaded: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(engine.get("empty".into()).unwrap().unwrap(), "kept");
+
+        let engine = existing();
+        let report = engine
+            .load_from(buf.as_slice(), LoadMode::ErrorOnConflict)
+            .unwrap_err();
+        assert_eq!(report.current_context(), &Errors::KeyAlreadyExists);
+        assert_eq!(engine.get("empty".into()).unwrap().unwrap(), "kept");
+    }
+
+    #[test]
+    fn invalid_dump() {
+        let buf = dump(&source());
+        let len = buf.len() as u64;
+
+        let info = invalid_at(b"NOTADUMP\x00\x00\x00\x01");
+        assert_eq!((info.offset, info.reason), (0, "not a dump"));
+
+        let mut corrupted = buf.clone();
+        corrupted[100] ^= 0xff;
+        let info = invalid_at(&corrupted);
+        assert_eq!((info.offset, info.reason), (len - 4, "checksum mismatch"));
+
+        // cut in the middle of the first record, which starts after the header
+        let info = invalid_at(&buf[..16]);
+        assert_eq!((info.offset, info.reason), (12, "truncated dump"));
+
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        let info = invalid_at(&trailing);
+        assert_eq!((info.offset, info.reason), (len, "trailing data"));
+    }
+}
@@ -1,39 +1,192 @@
-use crate::data::data_file::{DataFile, DATAFILE_SUFFIX, INITIAL_DATAFILE_ID};
+use crate::clock::{self, SharedClock};
+use crate::data::data_file::{datafile_name, DataFile, DATAFILE_SUFFIX, INITIAL_DATAFILE_ID};
+use crate::data::hint_file::{self, HINT_SUFFIX};
 use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType};
-use crate::errors::{Errors, Result};
-use crate::index::indexer;
-use crate::{index, options};
+use crate::errors::{CorruptionInfo, CorruptionReason, ErrorKey, Errors, RecordLocation, Result};
+use crate::index::{indexer, ReplayProgress};
+use crate::options::{IteratorOptions, RuntimeOptions, SyncPolicy};
+use crate::{fio, index, merge, options};
 use bytes::Bytes;
 use error_stack::{Report, ResultExt};
+use log::{error, warn};
+use parking_lot::{Mutex, RwLock};
 
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
+/// A Bitcask store. The engine can be shared across threads, e.g. through an [Arc]: the
+/// writes take turns appending to the active datafile, the reads go along each other.
 pub struct Engine {
     pub(crate) options: options::Options,
-    active_file: DataFile,
-    idle_file: HashMap<u32, DataFile>,
-    pub(crate) index: Box<dyn index::Indexer>,
+    /// the live values of the options that can be changed at runtime
+    pub(crate) runtime: Arc<RwLock<RuntimeOptions>>,
+    /// writers hold the write lock until the index points at the appended records
+    pub(crate) files: Arc<RwLock<Datafiles>>,
+    pub(crate) index: Arc<dyn index::Indexer>,
+    pub(crate) io_manager: fio::IOManagerFactory,
+    /// sequence number of the next batch committed, see [WriteBatch::commit]
+    ///
+    /// [WriteBatch::commit]: crate::batch::WriteBatch::commit
+    pub(crate) seq_no: AtomicU64,
+    merge_enabled: Arc<AtomicBool>,
+    /// shares the state above with the background merges
+    pub(crate) merger: merge::Merger,
+    /// stopped before anything else when the engine is dropped
+    scheduler: Option<merge::MergeScheduler>,
+    /// holds the lock of [LOCK_FILE] until the engine is closed
+    lock: Option<fs::File>,
 }
 
+/// The datafiles of an engine and the bookkeeping of the writes appended to them
+pub(crate) struct Datafiles {
+    pub(crate) active: DataFile,
+    pub(crate) idle: HashMap<u32, DataFile>,
+    /// bytes appended to the active datafile since it was last synced
+    unsynced_bytes: u64,
+    /// when the active datafile was last synced, on [Datafiles::clock]
+    last_sync: u64,
+    clock: SharedClock,
+    /// bytes appended by writes since the engine was opened, see [Engine::metrics]
+    pub(crate) bytes_written: u64,
+    /// bytes of overwritten records and tombstones of each datafile, left for a merge to reclaim
+    pub(crate) dead_bytes: HashMap<u32, u64>,
+    /// records of each datafile the index points at
+    pub(crate) live_records: HashMap<u32, u64>,
+    /// iterators reading each datafile, see [merge::DatafilePins]
+    pub(crate) pins: Mutex<HashMap<u32, usize>>,
+    /// merged datafiles still pinned by an iterator
+    pub(crate) retired: HashMap<u32, DataFile>,
+    /// set by [Engine::close], the datafiles are no longer open
+    closed: bool,
+    /// see [Options::read_only](options::Options::read_only)
+    read_only: bool,
+}
+
+/// The state of an engine, see [Engine::stat]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stat {
+    /// Live keys, as held by the index
+    pub keys: usize,
+    /// Sealed datafiles and the active one
+    pub datafiles: usize,
+    /// Size of the datafiles on disk
+    pub disk_bytes: u64,
+    /// Bytes of the overwritten records and of the tombstones, left for a merge to reclaim
+    pub reclaimable_bytes: u64,
+}
+
+/// Left in the directory of a store being migrated into or restored until it completes
+pub(crate) const INCOMPLETE_FILE: &str = "incomplete";
+/// File of the database directory locked by the engine, see [Errors::DatabaseIsUsing]
+pub const LOCK_FILE: &str = "LOCK";
+
 impl Engine {
+    /// Opens the engine, a store whose migration or restore did not complete is refused with
+    /// [Errors::IncompleteDb]
     pub fn new(opts: options::Options) -> Result<Self> {
+        let marker = opts.dir_path.join(INCOMPLETE_FILE);
+        if marker.exists() {
+            return Err(Report::new(Errors::IncompleteDb))
+                .attach_printable_lazy(|| format!("Found {:?}", marker));
+        }
+        let io_manager = match opts.read_only {
+            true => fio::read_only_io_manager(),
+            false => fio::default_io_manager(),
+        };
+        Engine::with_io_manager(opts, io_manager)
+    }
+
+    /// Opens a fresh [temporary](options::Options::temporary) database in a new directory
+    /// under the system temp dir
+    pub fn open_temporary() -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("ailurus-kv-")
+            .tempdir()
+            .change_context(Errors::CreateDbDirFail)?;
+        let opts = options::OptionsBuilder::default()
+            .dir_path(dir.into_path())
+            .temporary(true)
+            .build()?;
+        Engine::new(opts)
+    }
+
+    /// Opens the engine, every datafile is accessed through the [IOManager] built by `io_manager`.
+    /// A database opened by another engine, in this process or not, is refused with
+    /// [Errors::DatabaseIsUsing].
+    ///
+    /// [IOManager]: crate::fio::IOManager
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "open", skip_all, fields(dir_path = ?opts.dir_path))
+    )]
+    pub(crate) fn with_io_manager(
+        opts: options::Options,
+        io_manager: fio::IOManagerFactory,
+    ) -> Result<Self> {
         // validate the configuration
         options::check_options(&opts)?;
 
-        if opts.dir_path.is_dir() {
-            fs::create_dir_all(&opts.dir_path).change_context(Errors::CreateDbDirFail)?;
+        // a directory without datafile, or no directory at all, holds no database
+        let exists = opts.dir_path.is_dir() && has_datafiles(&opts.dir_path)?;
+        if !exists && (!opts.create_if_missing || opts.read_only) {
+            return Err(Report::new(Errors::DbNotFound))
+                .attach_printable_lazy(|| format!("No datafile in {:?}", opts.dir_path));
+        }
+        if exists && opts.error_if_exists {
+            return Err(Report::new(Errors::DbAlreadyExists))
+                .attach_printable_lazy(|| format!("Datafiles found in {:?}", opts.dir_path));
+        }
+
+        if !opts.dir_path.is_dir() {
+            fs::create_dir_all(&opts.dir_path)
+                .change_context(Errors::CreateDbDirFail)
+                .attach_printable_lazy(|| format!("Fail to create {:?}", opts.dir_path))?;
+        }
+        let lock = lock_dir(&opts.dir_path, opts.read_only)?;
+
+        // leftover of a merge that crashed before installing its output,
+        // the merged datafiles are still in place
+        let staging = opts.dir_path.join(merge::MERGE_DIR);
+        if staging.is_dir() && !opts.read_only {
+            warn!(
+                "Removing the output of an unfinished merge in {:?}",
+                staging
+            );
+            fs::remove_dir_all(&staging)
+                .change_context(Errors::InternalError)
+                .attach_printable_lazy(|| format!("Fail to remove {:?}", staging))?;
+        }
+        // left in place when read-only, replaying them along the copies of their records
+        // yields the same records
+        if !opts.read_only {
+            merge::remove_retired(&opts.dir_path)?;
         }
 
         // load the datafiles (including active and inactive)
-        let mut datafiles = load_datafiles(&opts.dir_path)?;
-        let index = indexer(datafiles.values(), &opts.index_type)?;
+        let mut datafiles = load_datafiles(&opts.dir_path, &io_manager, !opts.read_only)?;
+        // later records override earlier ones, so the datafiles are replayed oldest first
+        let mut replay: Vec<&DataFile> = datafiles.values().collect();
+        replay.sort_unstable_by_key(|datafile| datafile.id());
+        let mut progress = ReplayProgress::new(&opts, &replay);
+        let index = indexer(replay, &opts.index_type, opts.expected_keys, &mut progress)
+            .inspect_err(|report| {
+                if let Some(info) = report.downcast_ref::<CorruptionInfo>() {
+                    error!("Fail to open the database: {}", info);
+                }
+            })?;
+
+        // a sequence number is never reused, the next batch takes the one after the last
+        let seq_no = progress.max_seq().map_or(0, |seq| seq + 1);
 
         let active = match datafiles.len() {
             0 => {
                 // Empty database, open a fresh new active datafile
-                DataFile::new(&opts.dir_path, INITIAL_DATAFILE_ID)?
+                DataFile::with_io_manager(&opts.dir_path, INITIAL_DATAFILE_ID, &io_manager)?
             }
             _ => {
                 // the datafile with the largest fid is the currently active datafile
@@ -42,139 +195,742 @@ impl Engine {
             }
         };
 
+        // whatever the index does not point at has been overwritten or deleted
+        let mut live_bytes = HashMap::<u32, u64>::new();
+        let mut live_records = HashMap::<u32, u64>::new();
+        let mut iter = index.iterator(IteratorOptions::default());
+        while let Some((_, pos)) = iter.next() {
+            *live_bytes.entry(pos.file_id).or_default() += pos.size as u64;
+            *live_records.entry(pos.file_id).or_default() += 1;
+        }
+        drop(iter);
+
+        let dead_bytes = datafiles
+            .values()
+            .chain([&active])
+            .map(|datafile| {
+                let live = live_bytes.get(&datafile.id()).copied().unwrap_or_default();
+                (datafile.id(), datafile.offset().saturating_sub(live))
+            })
+            .collect();
+        let files = Datafiles {
+            active,
+            idle: datafiles,
+            unsynced_bytes: 0,
+            last_sync: opts.clock.now_millis(),
+            clock: opts.clock.clone(),
+            bytes_written: 0,
+            dead_bytes,
+            live_records,
+            pins: Default::default(),
+            retired: HashMap::new(),
+            closed: false,
+            read_only: opts.read_only,
+        };
+
+        let runtime = Arc::new(RwLock::new(RuntimeOptions::from(&opts)));
+        let files = Arc::new(RwLock::new(files));
+        let index: Arc<dyn index::Indexer> = Arc::from(index);
+        let merge_enabled = Arc::new(AtomicBool::new(true));
+        let merger = merge::Merger {
+            dir_path: opts.dir_path.clone(),
+            data_file_size: opts.data_file_size,
+            verify: opts.verify_after_merge,
+            clock: opts.clock.clone(),
+            io_manager: io_manager.clone(),
+            runtime: runtime.clone(),
+            files: files.clone(),
+            index: index.clone(),
+            enabled: merge_enabled.clone(),
+            state: Default::default(),
+        };
+        let scheduler = opts
+            .merge_schedule
+            .filter(|_| !opts.read_only)
+            .map(|interval| merge::MergeScheduler::spawn(merger.clone(), interval))
+            .transpose()?;
+
         Ok(Engine {
+            runtime,
             options: opts,
-            active_file: active,
-            idle_file: datafiles,
+            files,
             index,
+            io_manager,
+            seq_no: AtomicU64::new(seq_no),
+            merge_enabled,
+            merger,
+            scheduler,
+            lock,
         })
     }
 
-    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
         if key.is_empty() {
             return Err(Report::new(Errors::EmptyKey));
         }
+        self.check_sizes(&key, &value)?;
 
         let record = LogRecord {
             key: key.to_vec(),
             value: value.to_vec(),
             record_type: LogRecordType::Normal,
+            seq: None,
         };
 
-        let log_record_pos = self.append_log_record(record)?;
-        match self.index.put(key.to_vec(), log_record_pos) {
-            true => Ok(()),
-            false => Err(Report::new(Errors::IndexUpdateFail)),
+        let mut files = self.files.write();
+        files.check_writable()?;
+        let active = files.active.id();
+        let log_record_pos = self.append_log_record(&mut files, record)?;
+        self.update_index(&mut files, key, LogRecordType::Normal, log_record_pos)?;
+        let sealed = files.active.id() != active;
+        drop(files);
+        if sealed {
+            self.merge_if_due();
         }
+        Ok(())
     }
 
-    pub fn delete(&mut self, key: Bytes) -> Result<()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    pub fn delete(&self, key: Bytes) -> Result<()> {
         if key.is_empty() {
             return Err(Report::new(Errors::EmptyKey));
         }
 
-        if self.index.get(key.to_vec()).is_none() {
-            return Err(Report::new(Errors::KeyNotFound));
+        let mut files = self.files.write();
+        files.check_writable()?;
+        if self.index.get(&key).is_none() {
+            return Err(Report::new(Errors::KeyNotFound)).attach_printable(ErrorKey::new(&key));
         };
 
         let record = LogRecord {
             key: key.to_vec(),
             value: Default::default(), // value can be anything
             record_type: LogRecordType::Deleted,
+            seq: None,
         };
 
-        self.append_log_record(record)?;
-
-        // update index
-        if !self.index.delete(key.to_vec()) {
-            return Err(Report::new(Errors::IndexUpdateFail));
+        let active = files.active.id();
+        let log_record_pos = self.append_log_record(&mut files, record)?;
+        self.update_index(&mut files, key, LogRecordType::Deleted, log_record_pos)?;
+        let sealed = files.active.id() != active;
+        drop(files);
+        if sealed {
+            self.merge_if_due();
         }
         Ok(())
     }
 
-    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    /// The value of `key`, `None` if the key is missing. An error is left for a read that
+    /// failed, or an invalid key.
+    pub fn get(&self, key: Bytes) -> Result<Option<Bytes>> {
         if key.is_empty() {
             return Err(Report::new(Errors::EmptyKey));
         }
 
+        // a merge moves the records under the write lock, the position looked up stays
+        // valid as long as the read lock is held
+        let files = self.files.read();
+        files.check_open()?;
         // Check the existence of the key
-        let pos = match self.index.get(key.to_vec()) {
-            None => return Err(Report::new(Errors::KeyNotFound)),
-            Some(x) => x,
+        let Some(pos) = self.index.get(&key) else {
+            return Ok(None);
         };
 
-        self.at(&pos)
+        let record = files
+            .record_at(&pos)
+            .attach_printable_lazy(|| ErrorKey::new(&key))?;
+        Ok(record.map(|record| record.value.into()))
+    }
+
+    /// Like [Engine::get], a missing key fails with [Errors::KeyNotFound]
+    pub fn get_or_err(&self, key: Bytes) -> Result<Bytes> {
+        match self.get(key.clone())? {
+            Some(value) => Ok(value),
+            None => Err(Report::new(Errors::KeyNotFound)).attach_printable(ErrorKey::new(&key)),
+        }
+    }
+
+    /// Whether `key` is present, answered by the index alone without reading its value
+    pub fn contains_key(&self, key: Bytes) -> Result<bool> {
+        if key.is_empty() {
+            return Err(Report::new(Errors::EmptyKey));
+        }
+        self.files.read().check_open()?;
+        Ok(self.index.get(&key).is_some())
+    }
+
+    /// The number of keys present
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
     }
 
     pub fn sync(&self) -> Result<()> {
-        self.active_file.sync()?;
-        for datafile in self.idle_file.values() {
+        let files = self.files.read();
+        files.check_open()?;
+        files.active.sync()?;
+        for datafile in files.idle.values() {
             datafile.sync()?;
         }
         Ok(())
     }
 
-    pub fn at(&self, pos: &LogRecordPos) -> Result<Bytes> {
-        let log_record = match self.active_file.id() == pos.file_id {
-            true => self.active_file.read(pos.offset)?,
-            false => match self.idle_file.get(&pos.file_id) {
-                None => return Err(Report::new(Errors::DatafileNotFound)),
-                Some(x) => x.read(pos.offset)?,
-            },
-        };
+    /// Syncs the datafiles and closes them, waiting for a running background merge to be
+    /// cancelled first. Every later operation fails with [Errors::EngineClosed], closing
+    /// again does nothing. There is nothing else to persist, the sequence numbers are
+    /// recovered from the datafiles when the engine is opened again.
+    ///
+    /// If syncing fails the engine is left open, closing it can be retried.
+    pub fn close(&mut self) -> Result<()> {
+        self.scheduler.take();
+        let _running = self.merger.state.running.lock();
+        let mut guard = self.files.write();
+        let files = &mut *guard;
+        if files.closed {
+            return Ok(());
+        }
+        files.sync_active()?;
+        for datafile in files.idle.values() {
+            datafile.sync()?;
+        }
+        files.closed = true;
+        files.active.close();
+        for datafile in files.idle.values_mut().chain(files.retired.values_mut()) {
+            datafile.close();
+        }
+        // another engine may open the database
+        self.lock = None;
+        Ok(())
+    }
 
-        match log_record {
-            // already check the existence of key, if we got a `None` from datafile (indicate an EOF),
-            // it means datafiles must have been destroyed or something unexpected happened
-            None => Err(Report::new(Errors::InternalError)),
-            Some(record) => {
-                match record.record_type {
-                    LogRecordType::Normal => Ok(record.value.into()),
-                    LogRecordType::Deleted => Err(Report::new(Errors::KeyNotFound)), // TODO: design decision, Result<Option<Bytes>> or Result<Bytes>
+    /// Changes the [RuntimeOptions] of the live engine, the change is rejected if it
+    /// leaves them invalid
+    pub fn update_options(&self, f: impl FnOnce(&mut RuntimeOptions)) -> Result<()> {
+        let mut runtime = self.runtime.write();
+        let mut updated = *runtime;
+        f(&mut updated);
+        options::check_runtime_options(&updated)?;
+        *runtime = updated;
+        Ok(())
+    }
+
+    /// The state of the engine, taken from the open datafiles and the counters the writes
+    /// keep up to date, no record is read
+    pub fn stat(&self) -> Result<Stat> {
+        let files = self.files.read();
+        let mut disk_bytes = files.active.size()?;
+        for datafile in files.idle.values() {
+            disk_bytes += datafile.size()?;
+        }
+        Ok(Stat {
+            keys: self.index.len(),
+            datafiles: files.idle.len() + 1,
+            disk_bytes,
+            reclaimable_bytes: files.reclaimable_bytes(),
+        })
+    }
+
+    /// Enables or disables automatic merges at runtime, e.g. during busy hours.
+    /// They are enabled whenever the engine is opened.
+    pub fn set_auto_merge(&self, enabled: bool) {
+        self.merge_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether an automatic merge is due: merges are enabled and the reclaimable bytes
+    /// reach both [merge_ratio] of the datafile bytes and [merge_min_bytes]
+    ///
+    /// [merge_ratio]: options::Options::merge_ratio
+    /// [merge_min_bytes]: options::Options::merge_min_bytes
+    pub fn merge_due(&self) -> bool {
+        self.merger.due()
+    }
+
+    /// Checks every record of every datafile, returning where the datafiles are corrupted,
+    /// oldest datafile first. A datafile is checked up to its first corruption, the records
+    /// after it cannot be located.
+    pub fn verify(&self) -> Result<Vec<CorruptionInfo>> {
+        let files = self.files.read();
+        let mut datafiles: Vec<&DataFile> = files.idle.values().collect();
+        datafiles.push(&files.active);
+        datafiles.sort_unstable_by_key(|datafile| datafile.id());
+
+        let mut corruptions = Vec::new();
+        for datafile in datafiles {
+            let mut offset = 0;
+            loop {
+                match datafile.read(offset) {
+                    Ok(None) => break,
+                    Ok(Some(read)) => offset += read.size,
+                    Err(report) => match report.downcast_ref::<CorruptionInfo>() {
+                        Some(info) => {
+                            corruptions.push(*info);
+                            break;
+                        }
+                        None => return Err(report),
+                    },
                 }
             }
         }
+        Ok(corruptions)
     }
 
-    fn append_log_record(&mut self, record: LogRecord) -> Result<LogRecordPos> {
-        let dir_path = &self.options.dir_path;
+    /// The value of the record at `pos`, `None` if the record is a tombstone
+    pub fn at(&self, pos: &LogRecordPos) -> Result<Option<Bytes>> {
+        Ok(self.record_at(pos)?.map(|record| record.value.into()))
+    }
 
+    /// Rejects a key or a value larger than allowed by the [Options](options::Options)
+    pub(crate) fn check_sizes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.options.max_key_size.is_some_and(|max| key.len() > max) {
+            return Err(Report::new(Errors::KeyTooLarge))
+                .attach_printable_lazy(|| format!("Key of {} bytes", key.len()));
+        }
+        if self
+            .options
+            .max_value_size
+            .is_some_and(|max| value.len() > max)
+        {
+            return Err(Report::new(Errors::ValueTooLarge))
+                .attach_printable_lazy(|| format!("Value of {} bytes", value.len()));
+        }
+        Ok(())
+    }
+
+    /// Reads the record stored at `pos`, see [Datafiles::record_at]
+    pub(crate) fn record_at(&self, pos: &LogRecordPos) -> Result<Option<LogRecord>> {
+        self.files.read().record_at(pos)
+    }
+
+    /// Appends the record, syncing as told by the [SyncPolicy]
+    fn append_log_record(&self, files: &mut Datafiles, record: LogRecord) -> Result<LogRecordPos> {
+        let pos = self.append_unsynced(files, &record)?;
+
+        let sync = match self.runtime.read().sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => {
+                clock::elapsed(&*files.clock, files.last_sync) >= interval
+            }
+            SyncPolicy::Bytes(bytes) => files.unsynced_bytes >= bytes,
+            _ => false,
+        };
+        if sync {
+            files.sync_active()?;
+        }
+
+        Ok(pos)
+    }
+
+    /// Appends the record, only a sealed datafile may be synced
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "append_log_record",
+            level = "debug",
+            skip_all,
+            fields(file_id, bytes)
+        )
+    )]
+    pub(crate) fn append_unsynced(
+        &self,
+        files: &mut Datafiles,
+        record: &LogRecord,
+    ) -> Result<LogRecordPos> {
         // encode the record using bitcask layout
         let record = record.encode();
         let record_len = record.len() as u64;
 
         // check if the datafile can hold the log record
-        if self.active_file.offset() + record_len > self.options.data_file_size {
-            self.active_file.sync()?;
-            let fid = self.active_file.id();
-            let fresh = DataFile::new(dir_path, fid + 1)?;
-            // swap out the currently full datafile, swap in a fresh one
-            self.idle_file
-                .insert(fid, std::mem::replace(&mut self.active_file, fresh));
+        if files.active.offset() + record_len > self.options.data_file_size {
+            self.rotate(files)?;
         }
 
         // append the log record to the fresh one
-        self.active_file.write(&record)?;
+        files.active.write(&record)?;
+        files.unsynced_bytes += record_len;
+        files.bytes_written += record_len;
 
-        if self.options.sync_writes {
-            self.active_file.sync()?;
-        }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("file_id", files.active.id())
+            .record("bytes", record_len);
 
         // indexing info
         Ok(LogRecordPos {
-            file_id: self.active_file.id(),
-            offset: self.active_file.offset() - record_len, // offset indicate the start position
+            file_id: files.active.id(),
+            offset: files.active.offset() - record_len, // offset indicate the start position
+            size: record_len as u32,
         })
     }
+
+    /// Appends the records in a single write, like [Engine::append_unsynced]. They all land
+    /// in the same datafile, which may thus grow past the datafile size.
+    pub(crate) fn append_batch_unsynced(
+        &self,
+        files: &mut Datafiles,
+        records: &[&LogRecord],
+    ) -> Result<Vec<LogRecordPos>> {
+        let mut buf = Vec::new();
+        let mut sizes = Vec::with_capacity(records.len());
+        for record in records {
+            let encoded = record.encode();
+            sizes.push(encoded.len() as u32);
+            buf.extend_from_slice(&encoded);
+        }
+        let batch_len = buf.len() as u64;
+
+        if files.active.offset() + batch_len > self.options.data_file_size {
+            self.rotate(files)?;
+        }
+
+        let mut offset = files.active.offset();
+        files.active.write(&buf)?;
+        files.unsynced_bytes += batch_len;
+        files.bytes_written += batch_len;
+
+        let file_id = files.active.id();
+        Ok(sizes
+            .into_iter()
+            .map(|size| {
+                let pos = LogRecordPos {
+                    file_id,
+                    offset,
+                    size,
+                };
+                offset += size as u64;
+                pos
+            })
+            .collect())
+    }
+
+    /// Seals the active datafile and opens the next one
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(file_id = files.active.id()))
+    )]
+    fn rotate(&self, files: &mut Datafiles) -> Result<()> {
+        if self.runtime.read().sync_policy != SyncPolicy::Never {
+            files.sync_active()?;
+        }
+        let fid = files.active.id();
+        let dir_path = &self.options.dir_path;
+        let fresh = DataFile::with_io_manager(dir_path, fid + 1, &self.io_manager)?;
+        // swap out the currently full datafile, swap in a fresh one
+        files
+            .idle
+            .insert(fid, std::mem::replace(&mut files.active, fresh));
+        Ok(())
+    }
+
+    /// Points the index at the record of `key` appended at `pos`
+    pub(crate) fn update_index(
+        &self,
+        files: &mut Datafiles,
+        key: Bytes,
+        record_type: LogRecordType,
+        pos: LogRecordPos,
+    ) -> Result<()> {
+        files.update_index(&*self.index, key, record_type, pos)
+    }
+}
+
+impl Datafiles {
+    /// Reads the record stored at `pos`, `None` if it is a tombstone
+    pub(crate) fn record_at(&self, pos: &LogRecordPos) -> Result<Option<LogRecord>> {
+        let location = RecordLocation {
+            file_id: pos.file_id,
+            offset: pos.offset,
+        };
+        let log_record = match self.get(pos.file_id) {
+            None => return Err(Report::new(Errors::DatafileNotFound)).attach_printable(location),
+            Some(x) => x.read(pos.offset)?.map(|read| read.record),
+        };
+
+        match log_record {
+            // already check the existence of key, if we got a `None` from datafile (indicate an EOF),
+            // the datafile must have been truncated underneath the index
+            None => Err(Report::new(Errors::DatafileCorrupted)).attach_printable(CorruptionInfo {
+                file_id: pos.file_id,
+                offset: pos.offset,
+                reason: CorruptionReason::Truncated,
+                recoverable: true,
+            }),
+            Some(record) => {
+                match record.record_type {
+                    LogRecordType::Normal => Ok(Some(record)),
+                    LogRecordType::Deleted => Ok(None),
+                    // the index never points at the marker of a batch
+                    LogRecordType::TxnFinished => {
+                        Err(Report::new(Errors::InternalError)).attach_printable(location)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fails with [Errors::EngineClosed] once the engine is closed
+    pub(crate) fn check_open(&self) -> Result<()> {
+        match self.closed {
+            true => Err(Report::new(Errors::EngineClosed)),
+            false => Ok(()),
+        }
+    }
+
+    /// Fails like [Datafiles::check_open], and with [Errors::ReadOnly] if the engine was
+    /// opened [read-only](options::Options::read_only)
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        self.check_open()?;
+        match self.read_only {
+            true => Err(Report::new(Errors::ReadOnly)),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn sync_active(&mut self) -> Result<()> {
+        self.active.sync()?;
+        self.unsynced_bytes = 0;
+        self.last_sync = self.clock.now_millis();
+        Ok(())
+    }
+
+    /// The datafile `id`, be it active, sealed or retired
+    pub(crate) fn get(&self, id: u32) -> Option<&DataFile> {
+        match self.active.id() == id {
+            true => Some(&self.active),
+            false => self.idle.get(&id).or_else(|| self.retired.get(&id)),
+        }
+    }
+
+    pub(crate) fn total_bytes(&self) -> u64 {
+        self.active.offset() + self.idle.values().map(DataFile::offset).sum::<u64>()
+    }
+
+    /// Bytes of all the datafiles a merge could reclaim
+    pub(crate) fn reclaimable_bytes(&self) -> u64 {
+        self.dead_bytes.values().sum()
+    }
+
+    /// Points `index` at the record of `key` appended at `pos`
+    pub(crate) fn update_index(
+        &mut self,
+        index: &dyn index::Indexer,
+        key: Bytes,
+        record_type: LogRecordType,
+        pos: LogRecordPos,
+    ) -> Result<()> {
+        let old = match record_type {
+            LogRecordType::Normal => {
+                *self.live_records.entry(pos.file_id).or_default() += 1;
+                index.put(key, pos)
+            }
+            LogRecordType::Deleted => {
+                // the tombstone is only needed until a merge drops the old record
+                self.add_dead(&pos);
+                index.delete(&key)
+            }
+            // the marker of a batch has no key, it is only needed until its records are merged
+            LogRecordType::TxnFinished => {
+                self.add_dead(&pos);
+                return Ok(());
+            }
+        };
+        // the record superseded is left for a merge to reclaim
+        if let Some(old) = old {
+            self.add_dead(&old);
+            if let Some(live) = self.live_records.get_mut(&old.file_id) {
+                *live -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The record at `pos` is no longer needed
+    pub(crate) fn add_dead(&mut self, pos: &LogRecordPos) {
+        *self.dead_bytes.entry(pos.file_id).or_default() += pos.size as u64;
+    }
+}
+
+/// Describes the state of the engine without any key or value, safe to log.
+/// The datafile fields are left out while a writer holds the datafiles.
+impl std::fmt::Debug for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("Engine");
+        s.field("dir_path", &self.options.dir_path);
+        if let Some(files) = self.files.try_read() {
+            s.field("active_file_id", &files.active.id())
+                .field("active_file_offset", &files.active.offset())
+                .field("idle_files", &files.idle.len())
+                .field("retired_files", &files.retired.len())
+                .field("total_bytes", &files.total_bytes())
+                .field("unsynced_bytes", &files.unsynced_bytes)
+                .field("reclaimable_bytes", &files.reclaimable_bytes());
+        }
+        s.field("index_type", &self.options.index_type)
+            .field("keys", &self.index.len())
+            .field("auto_merge", &self.merge_enabled.load(Ordering::Relaxed))
+            .field("runtime", &*self.runtime.read())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// Syncs the active datafile whatever the [SyncPolicy], writes that were not synced survive
+/// the engine being dropped like they survive [Engine::close]. A failure to sync is only logged.
+impl Drop for Engine {
+    fn drop(&mut self) {
+        // waits for a running background merge to be cancelled
+        self.scheduler.take();
+        if self.options.temporary {
+            self.remove_files();
+            return;
+        }
+        let files = self.files.write();
+        if files.closed || files.read_only {
+            return;
+        }
+        if let Err(e) = files.active.sync() {
+            error!("Fail to sync the active datafile on close: {:?}", e);
+        }
+    }
+}
+
+impl Engine {
+    /// Removes the datafiles of a temporary database, files this engine did not create are
+    /// left alone, and so is the directory holding them
+    fn remove_files(&mut self) {
+        let dir_path = &self.options.dir_path;
+        let files = self.files.read();
+        let fids = files.idle.keys().copied().chain([files.active.id()]);
+        for fid in fids {
+            if let Err(e) = hint_file::remove(dir_path, fid) {
+                error!("Fail to remove the hint file of datafile {}: {:?}", fid, e);
+            }
+            let path = dir_path.join(datafile_name(fid));
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Fail to remove datafile {:?}: {}", path, e);
+            }
+        }
+        let lock = dir_path.join(LOCK_FILE);
+        if let Err(e) = fs::remove_file(&lock) {
+            error!("Fail to remove lock file {:?}: {}", lock, e);
+        }
+        if let Err(e) = fs::remove_dir(dir_path) {
+            warn!("Fail to remove database dir {:?}: {}", dir_path, e);
+        }
+    }
+}
+
+/// Locks the [LOCK_FILE] of `dir`, the lock is released along with the returned file.
+/// A writing engine holds the lock alone and creates the file if missing. A `read_only`
+/// engine shares the lock, it creates no file: a database without one is not opened by a
+/// writing engine, nothing is locked then.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<fs::File>> {
+    let path = dir.join(LOCK_FILE);
+    let mut open = fs::OpenOptions::new();
+    match read_only {
+        true => open.read(true),
+        false => open.create(true).truncate(false).write(true),
+    };
+    let file = match open.open(&path) {
+        Err(e) if read_only && e.kind() == ErrorKind::NotFound => return Ok(None),
+        opened => opened
+            .change_context(Errors::FailToOpenFile)
+            .attach_printable_lazy(|| format!("Fail to open {:?}", path))?,
+    };
+    let locked = match read_only {
+        true => file.try_lock_shared(),
+        false => file.try_lock(),
+    };
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Err(Report::new(Errors::DatabaseIsUsing))
+            .attach_printable_lazy(|| format!("{:?} is locked", path)),
+        Err(fs::TryLockError::Error(e)) => Err(Report::new(e))
+            .change_context(Errors::FailToOpenFile)
+            .attach_printable_lazy(|| format!("Fail to lock {:?}", path)),
+    }
+}
+
+fn read_dir<P: AsRef<Path>>(path: P) -> Result<fs::ReadDir> {
+    fs::read_dir(&path)
+        .change_context(Errors::ReadDbDirFail)
+        .attach_printable_lazy(|| format!("Fail to read {:?}", path.as_ref()))
+}
+
+/// Creates the directory of a fresh store, marked with [INCOMPLETE_FILE] until
+/// [mark_complete] is called with the returned marker
+pub(crate) fn mark_incomplete(dir: &Path) -> Result<PathBuf> {
+    let marker = dir.join(INCOMPLETE_FILE);
+    if marker.exists() {
+        return Err(Report::new(Errors::IncompleteDb))
+            .attach_printable_lazy(|| format!("Found {:?}", marker));
+    }
+    // checked before the marker is written, not to block an existing store
+    if dir.is_dir() && has_datafiles(dir)? {
+        return Err(Report::new(Errors::DbAlreadyExists))
+            .attach_printable_lazy(|| format!("Datafiles found in {:?}", dir));
+    }
+    fs::create_dir_all(dir)
+        .change_context(Errors::CreateDbDirFail)
+        .attach_printable_lazy(|| format!("Fail to create {:?}", dir))?;
+    fs::File::create(&marker)
+        .and_then(|file| file.sync_all())
+        .change_context(Errors::CreateDbFileFail)
+        .attach_printable_lazy(|| format!("Fail to create {:?}", marker))?;
+    Ok(marker)
+}
+
+pub(crate) fn mark_complete(marker: &Path) -> Result<()> {
+    fs::remove_file(marker)
+        .change_context(Errors::InternalError)
+        .attach_printable_lazy(|| format!("Fail to remove {:?}", marker))
 }
 
-fn load_datafiles<P: AsRef<Path>>(path: P) -> Result<HashMap<u32, DataFile>> {
-    let dir = fs::read_dir(&path).map_err(|_| Errors::ReadDbDirFail)?;
+fn has_datafiles<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let dir = read_dir(path)?;
+    Ok(dir.flatten().any(|entry| {
+        let fname = entry.file_name();
+        fname.to_str().is_some_and(|x| x.ends_with(DATAFILE_SUFFIX))
+    }))
+}
+
+/// Opens the datafiles of `path`, the leftovers of a crash are removed if `clean_up`
+fn load_datafiles<P: AsRef<Path>>(
+    path: P,
+    io_manager: &fio::IOManagerFactory,
+    clean_up: bool,
+) -> Result<HashMap<u32, DataFile>> {
+    let dir = read_dir(&path)?;
     let mut datafiles = HashMap::<u32, DataFile>::new();
+    let mut hinted = Vec::new();
 
     for entry in dir.flatten() {
         let fname = entry.file_name();
 
+        // leftover of a crash during datafile creation, never contains acknowledged data
+        if fio::is_tmp(&fname) {
+            if !clean_up {
+                continue;
+            }
+            fs::remove_file(entry.path())
+                .change_context(Errors::InternalError)
+                .attach_printable_lazy(|| format!("Fail to remove stale {:?}", entry.path()))?;
+            continue;
+        }
+
         if fname.to_str().unwrap().ends_with(DATAFILE_SUFFIX) {
             // example datafile name: `00001.data`
             let split: Vec<&str> = fname.to_str().unwrap().split('.').collect();
@@ -182,7 +938,17 @@ fn load_datafiles<P: AsRef<Path>>(path: P) -> Result<HashMap<u32, DataFile>> {
                 .parse::<u32>()
                 .change_context(Errors::DatafileCorrupted)
                 .attach_printable_lazy(|| format!("Invalid datafile name: {:?}", fname))?;
-            datafiles.insert(fid, DataFile::new(&path, fid)?);
+            datafiles.insert(fid, DataFile::with_io_manager(&path, fid, io_manager)?);
+        } else if let Some(fid) = fname.to_str().unwrap().strip_suffix(HINT_SUFFIX) {
+            hinted.push(fid.parse::<u32>().ok());
+        }
+    }
+
+    // leftover of a crash while the datafile of the hint file was being removed,
+    // it must not be taken for the hint file of a later datafile of the same id
+    for fid in hinted.into_iter().flatten() {
+        if clean_up && !datafiles.contains_key(&fid) {
+            hint_file::remove(path.as_ref(), fid)?;
         }
     }
 
@@ -191,76 +957,112 @@ fn load_datafiles<P: AsRef<Path>>(path: P) -> Result<HashMap<u32, DataFile>> {
 
 #[cfg(test)]
 mod tests {
+    use crate::clock::MockClock;
     use crate::engine;
-    use crate::errors::Errors;
-    use crate::mock::engine_wrapper::{EngineWrapper, ENGINEDISTRIBUTOR};
+    use crate::engine::{Engine, LOCK_FILE};
+    use crate::errors::{
+        CorruptionInfo, CorruptionReason, ErrorKey, Errors, RecordLocation, Result,
+    };
+    use crate::mock::alloc::count_allocations;
+    use crate::mock::engine_wrapper::{self, EngineWrapper};
+    use crate::options::{InvalidField, IteratorOptions, OpenProgress, Options, SyncPolicy};
     use bytes::Bytes;
+    use parking_lot::Mutex;
     use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn simple_put_and_get() {
         let db = engine!(["Hello", "World"]);
-        assert_eq!(db.get("Hello".into()).unwrap(), Bytes::from("World"));
+        assert_eq!(
+            db.get("Hello".into()).unwrap().unwrap(),
+            Bytes::from("World")
+        );
     }
 
     #[test]
     fn put_many_get_many() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        assert_eq!(engine.get("a".into()).unwrap(), "val-a");
-        assert_eq!(engine.get("b".into()).unwrap(), "val-b");
-        assert_eq!(engine.get("c".into()).unwrap(), "val-c");
+        assert_eq!(engine.get("a".into()).unwrap().unwrap(), "val-a");
+        assert_eq!(engine.get("b".into()).unwrap().unwrap(), "val-b");
+        assert_eq!(engine.get("c".into()).unwrap().unwrap(), "val-c");
     }
 
     #[test]
     fn overwrite_put() {
         let db = engine!(["Hello", "Hello"], ["Hello", "World"]);
-        assert_eq!(db.get("Hello".into()).unwrap(), Bytes::from("World"));
+        assert_eq!(
+            db.get("Hello".into()).unwrap().unwrap(),
+            Bytes::from("World")
+        );
     }
 
     #[test]
     fn get_non_exist_key() {
         let db = engine!();
-        let x = db.get("Non Exist".into());
-        assert_eq!(
-            x.unwrap_err().downcast_ref::<Errors>().unwrap(),
-            &Errors::KeyNotFound
-        );
+        assert_eq!(db.get("Non Exist".into()).unwrap(), None);
+        let x = db.get_or_err("Non Exist".into());
+        assert_eq!(x.unwrap_err().current_context(), &Errors::KeyNotFound);
+    }
+
+    #[test]
+    fn contains_key() {
+        let (db, stats) = EngineWrapper::counting();
+        assert!(!db.contains_key("a".into()).unwrap());
+        assert!(db.is_empty());
+
+        db.put("a".into(), "val-a".into()).unwrap();
+        db.put("b".into(), "val-b".into()).unwrap();
+        db.delete("b".into()).unwrap();
+        let reads = stats.reads();
+        let (_, allocations) = count_allocations(|| {
+            assert!(db.contains_key("a".into()).unwrap());
+            assert!(!db.contains_key("b".into()).unwrap());
+        });
+        assert_eq!((stats.reads(), allocations), (reads, 0));
+        assert_eq!((db.len(), db.is_empty()), (1, false));
+        let report = db.contains_key(Bytes::new()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::EmptyKey);
+
+        let db = db.reopen();
+        let reads = stats.reads();
+        assert!(db.contains_key("a".into()).unwrap());
+        assert!(!db.contains_key("b".into()).unwrap());
+        assert_eq!(stats.reads(), reads);
+        assert_eq!(db.len(), 1);
     }
 
     #[test]
     fn delete_exist() {
-        let mut db = engine!(["Hello", "World"]);
+        let db = engine!(["Hello", "World"]);
         let report = db.delete("Hello".into());
         assert_eq!(report.unwrap(), ());
     }
 
     #[test]
     fn delete_non_exist() {
-        let mut db = engine!(["Hello", "World"]);
+        let db = engine!(["Hello", "World"]);
         let report = db.delete("non_exist".into());
-        assert_eq!(
-            report.unwrap_err().downcast_ref::<Errors>().unwrap(),
-            &Errors::KeyNotFound
-        );
+        assert_eq!(report.unwrap_err().current_context(), &Errors::KeyNotFound);
     }
 
     #[test]
     fn delete_non_exist_in_empty_db() {
-        let mut db = engine!();
+        let db = engine!();
         let report = db.delete("non_exist".into());
-        assert_eq!(
-            report.unwrap_err().downcast_ref::<Errors>().unwrap(),
-            &Errors::KeyNotFound,
-        );
+        assert_eq!(report.unwrap_err().current_context(), &Errors::KeyNotFound,);
     }
 
     #[test]
     fn fulfill_one_datafile() {
-        let mut db = EngineWrapper::new(
-            crate::options::OptionsBuilder::default()
-                .dir_path(ENGINEDISTRIBUTOR.path())
-                .sync_writes(false) // performance consideration
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .sync_policy(crate::options::SyncPolicy::Never) // performance consideration
                 .data_file_size(8 * 1000) // 8KB per datafile
+                .danger_small_files(true)
                 .build()
                 .unwrap(),
         );
@@ -283,6 +1085,7 @@ mod tests {
             fs::read_dir(&path)
                 .unwrap()
                 .flatten()
+                .filter(|entry| entry.file_name() != LOCK_FILE)
                 .collect::<Vec<_>>()
                 .len(),
             1
@@ -295,6 +1098,7 @@ mod tests {
             fs::read_dir(&path)
                 .unwrap()
                 .flatten()
+                .filter(|entry| entry.file_name() != LOCK_FILE)
                 .collect::<Vec<_>>()
                 .len(),
             2
@@ -303,11 +1107,11 @@ mod tests {
 
     #[test]
     fn datafile_remaining_not_enough() {
-        let mut db = EngineWrapper::new(
-            crate::options::OptionsBuilder::default()
-                .dir_path(ENGINEDISTRIBUTOR.path())
-                .sync_writes(false) // performance consideration
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .sync_policy(crate::options::SyncPolicy::Never) // performance consideration
                 .data_file_size(8 * 1000) // 8KB per datafile
+                .danger_small_files(true)
                 .build()
                 .unwrap(),
         );
@@ -330,6 +1134,7 @@ mod tests {
             fs::read_dir(&path)
                 .unwrap()
                 .flatten()
+                .filter(|entry| entry.file_name() != LOCK_FILE)
                 .collect::<Vec<_>>()
                 .len(),
             1
@@ -342,6 +1147,7 @@ mod tests {
             fs::read_dir(&path)
                 .unwrap()
                 .flatten()
+                .filter(|entry| entry.file_name() != LOCK_FILE)
                 .collect::<Vec<_>>()
                 .len(),
             2
@@ -350,11 +1156,11 @@ mod tests {
 
     #[test]
     fn reopen() {
-        let mut db = EngineWrapper::new(
-            crate::options::OptionsBuilder::default()
-                .dir_path(ENGINEDISTRIBUTOR.path())
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
                 .data_file_size(2 * 1000)
-                .sync_writes(false)
+                .danger_small_files(true)
+                .sync_policy(crate::options::SyncPolicy::Never)
                 .build()
                 .unwrap(),
         );
@@ -372,7 +1178,888 @@ mod tests {
         db.sync().unwrap();
 
         let db = db.reopen();
-        assert_eq!(db.get("0000".into()).unwrap(), "00000");
-        assert_eq!(db.get("1023".into()).unwrap(), "01023");
+        assert_eq!(db.get("0000".into()).unwrap().unwrap(), "00000");
+        assert_eq!(db.get("1023".into()).unwrap().unwrap(), "01023");
+    }
+    #[test]
+    fn stale_tmp_removed_on_open() {
+        let db = engine!(["Hello", "World"]);
+        let stale = db.path().join("000000001.data.tmp");
+        fs::write(&stale, b"half written garbage").unwrap();
+
+        let db = db.reopen();
+        assert!(!stale.exists());
+        assert_eq!(db.get("Hello".into()).unwrap().unwrap(), "World");
+
+        // the next datafile id is still available for rotation
+        assert!(db.path().join("000000000.data").is_file());
+        assert!(!db.path().join("000000001.data").exists());
+    }
+
+    /// Opens the engine at `path` with the given open flags
+    fn open(path: &Path, create_if_missing: bool, error_if_exists: bool) -> Result<Engine> {
+        let opts = crate::options::OptionsBuilder::default()
+            .dir_path(path.to_path_buf())
+            .create_if_missing(create_if_missing)
+            .error_if_exists(error_if_exists)
+            .build()
+            .unwrap();
+        Engine::new(opts)
+    }
+
+    fn open_err(path: &Path, create_if_missing: bool, error_if_exists: bool) -> Errors {
+        let report = open(path, create_if_missing, error_if_exists)
+            .err()
+            .unwrap();
+        report.current_context().clone()
+    }
+
+    #[test]
+    fn open_flags() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("missing");
+        let empty = root.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        let existing = root.path().join("existing");
+        let db = open(&existing, true, false).unwrap();
+        db.put("Hello".into(), "World".into()).unwrap();
+        drop(db);
+
+        // create_if_missing and !error_if_exists, the default
+        assert_eq!(
+            open(&existing, true, false)
+                .unwrap()
+                .get("Hello".into())
+                .unwrap()
+                .unwrap(),
+            "World"
+        );
+
+        // !create_if_missing and !error_if_exists
+        assert_eq!(open_err(&missing, false, false), Errors::DbNotFound);
+        assert!(!missing.exists());
+        assert_eq!(open_err(&empty, false, false), Errors::DbNotFound);
+        assert_eq!(
+            open(&existing, false, false)
+                .unwrap()
+                .get("Hello".into())
+                .unwrap()
+                .unwrap(),
+            "World"
+        );
+
+        // create_if_missing and error_if_exists
+        assert_eq!(open_err(&existing, true, true), Errors::DbAlreadyExists);
+        open(&missing, true, true).unwrap();
+        assert!(missing.is_dir());
+        // a directory without datafiles holds no database yet
+        open(&empty, true, true).unwrap();
+
+        // !create_if_missing and error_if_exists
+        assert_eq!(
+            open_err(&root.path().join("nothing"), false, true),
+            Errors::DbNotFound
+        );
+        assert_eq!(open_err(&existing, false, true), Errors::DbAlreadyExists);
+    }
+
+    #[test]
+    fn drop_syncs_active_datafile() {
+        let dropped_syncs = |opts: Options| {
+            let (db, stats) = EngineWrapper::counting_with(opts);
+            for i in 0..100 {
+                db.put(format!("{:04}", i).into(), "value".into()).unwrap();
+            }
+            assert_eq!(stats.syncs(), 0);
+            let db = db.reopen();
+            for i in 0..100 {
+                assert_eq!(
+                    db.get(format!("{:04}", i).into()).unwrap().unwrap(),
+                    "value"
+                );
+            }
+            stats.syncs()
+        };
+        #[allow(deprecated)]
+        let unsynced = engine_wrapper::options()
+            .sync_writes(false)
+            .build()
+            .unwrap();
+        assert_eq!(unsynced.sync_policy, SyncPolicy::Never);
+        assert_eq!(dropped_syncs(unsynced), 1);
+        for policy in [SyncPolicy::OnRotation, SyncPolicy::Bytes(1 << 20)] {
+            let opts = engine_wrapper::options()
+                .sync_policy(policy)
+                .build()
+                .unwrap();
+            assert_eq!(dropped_syncs(opts), 1);
+        }
+    }
+
+    #[test]
+    fn open_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("a").join("b").join("c");
+        open(&nested, true, false)
+            .unwrap()
+            .put("Hello".into(), "World".into())
+            .unwrap();
+        assert!(nested.is_dir());
+        assert_eq!(
+            open(&nested, true, false)
+                .unwrap()
+                .get("Hello".into())
+                .unwrap()
+                .unwrap(),
+            "World"
+        );
+
+        // the path turns into a file once the options are built
+        let file = root.path().join("file");
+        let opts = crate::options::OptionsBuilder::default()
+            .dir_path(file.clone())
+            .build()
+            .unwrap();
+        fs::write(&file, "").unwrap();
+        let report = Engine::new(opts).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::DbPathNotDir);
+        assert_eq!(
+            open_err(&file.join("db"), true, false),
+            Errors::CreateDbDirFail
+        );
+        assert_eq!(fs::read(&file).unwrap(), b"");
+    }
+
+    #[test]
+    fn temporary_removes_datafiles() {
+        let db = Engine::open_temporary().unwrap();
+        let path = db.options.dir_path.clone();
+        db.put("Hello".into(), "World".into()).unwrap();
+        assert!(super::has_datafiles(&path).unwrap());
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn temporary_keeps_unknown_files() {
+        let root = tempfile::tempdir().unwrap();
+        let opts = crate::options::OptionsBuilder::default()
+            .dir_path(root.path().to_path_buf())
+            .data_file_size(10 * 16)
+            .danger_small_files(true)
+            .temporary(true)
+            .build()
+            .unwrap();
+        let db = Engine::new(opts).unwrap();
+        for i in 0..25 {
+            db.put(format!("{:04}", i).into(), format!("{:05}", i).into())
+                .unwrap();
+        }
+        fs::write(root.path().join("notes.txt"), "foo").unwrap();
+        drop(db);
+        let left: Vec<_> = fs::read_dir(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["notes.txt"]);
+    }
+
+    #[test]
+    fn update_options() {
+        let (db, stats) = EngineWrapper::counting_with(
+            engine_wrapper::options()
+                .sync_policy(SyncPolicy::Never)
+                .merge_ratio(0.0)
+                .build()
+                .unwrap(),
+        );
+        overwrite(&db, 10);
+        overwrite(&db, 10);
+        assert_eq!(stats.syncs(), 0);
+        assert!(!db.merge_due());
+
+        db.update_options(|opts| {
+            opts.sync_policy = SyncPolicy::Always;
+            opts.merge_ratio = 0.5;
+        })
+        .unwrap();
+        overwrite(&db, 3);
+        assert_eq!(stats.syncs(), 3);
+        assert!(db.merge_due());
+
+        let report = db
+            .update_options(|opts| opts.merge_ratio = 2.0)
+            .unwrap_err();
+        assert_eq!(report.current_context(), &Errors::InvalidOptions);
+        let report = db
+            .update_options(|opts| opts.merge_bytes_per_sec = Some(0))
+            .unwrap_err();
+        assert_eq!(
+            report.downcast_ref::<InvalidField>(),
+            Some(&InvalidField("merge_bytes_per_sec"))
+        );
+        assert!(db.merge_due());
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn traced_operations() {
+        use crate::mock::spans::collect_spans;
+
+        let db = engine!();
+        let ((), spans) = collect_spans(|| {
+            db.put("Hello".into(), "World".into()).unwrap();
+            db.get("Hello".into()).unwrap();
+            db.delete("Hello".into()).unwrap();
+        });
+        let names: Vec<_> = spans.iter().map(|span| span.name).collect();
+        assert_eq!(
+            names,
+            [
+                "put",
+                "append_log_record",
+                "get",
+                "delete",
+                "append_log_record"
+            ]
+        );
+        for i in [0, 2, 3] {
+            assert_eq!(spans[i].field("key_len"), Some("5"));
+        }
+        let put = &spans[1];
+        assert_eq!(put.field("file_id"), Some("0"));
+        assert_eq!(put.field("bytes"), Some("17"));
+        assert_eq!(spans[4].field("bytes"), Some("12"));
+    }
+
+    #[test]
+    fn debug_without_data() {
+        let db = engine!(["secret-key", "secret-value"], ["other-key", "other-value"]);
+        let debug = format!("{:?}", *db);
+        assert!(debug.starts_with(&format!("Engine {{ dir_path: {:?}, ", db.path())));
+        for field in [
+            "active_file_id: 0, ",
+            "idle_files: 0, ",
+            "index_type: BTree, ",
+            "keys: 2, ",
+            "auto_merge: true, ",
+            "runtime: RuntimeOptions { ",
+            "options: Options { ",
+        ] {
+            assert!(debug.contains(field), "{} missing from {}", field, debug);
+        }
+        assert!(!debug.contains("secret") && !debug.contains("other"));
+
+        // the datafiles are skipped rather than waited for
+        let files = db.files.write();
+        let debug = format!("{:?}", *db);
+        assert!(!debug.contains("active_file_id") && debug.contains("keys: 2"));
+        drop(files);
+    }
+
+    #[test]
+    fn stat_reclaimable_bytes() {
+        let db = engine!(["a", "1"], ["b", "2"]);
+        assert_eq!(db.stat().unwrap().reclaimable_bytes, 0);
+        let size =
+            |db: &EngineWrapper, key: &str| db.index.get(key.as_bytes()).unwrap().size as u64;
+
+        let first = size(&db, "a");
+        db.put("a".into(), "11".into()).unwrap();
+        assert_eq!(db.stat().unwrap().reclaimable_bytes, first);
+
+        // the record deleted and its tombstone
+        let deleted = size(&db, "b");
+        db.delete("b".into()).unwrap();
+        let tombstone =
+            db.files.read().active.offset() - db.index.get(b"a").unwrap().offset - size(&db, "a");
+        let reclaimable = first + deleted + tombstone;
+        assert_eq!(db.stat().unwrap().reclaimable_bytes, reclaimable);
+
+        let db = db.reopen();
+        assert_eq!(db.stat().unwrap().reclaimable_bytes, reclaimable);
+    }
+
+    #[test]
+    fn close() {
+        let opts = engine_wrapper::options()
+            .data_file_size(10 * 16)
+            .danger_small_files(true)
+            .sync_policy(SyncPolicy::Never)
+            .merge_ratio(0.0)
+            .build()
+            .unwrap();
+        let (mut db, stats) = EngineWrapper::counting_with(opts);
+        for i in 0..25 {
+            db.put(format!("{:04}", i).into(), "value".into()).unwrap();
+        }
+        let syncs = stats.syncs();
+        db.close().unwrap();
+        // the two sealed datafiles and the active one
+        assert_eq!(stats.syncs(), syncs + 3);
+
+        let closed = |result: Result<()>| {
+            assert_eq!(result.unwrap_err().current_context(), &Errors::EngineClosed)
+        };
+        closed(db.put("0000".into(), "value".into()));
+        closed(db.put("new".into(), "value".into()));
+        closed(db.delete("0000".into()));
+        closed(db.get("0000".into()).map(|_| ()));
+        closed(db.get("missing".into()).map(|_| ()));
+        closed(db.sync());
+        closed(db.merge(None).map(|_| ()));
+        let mut batch = db.write_batch(Default::default());
+        batch.put("new".into(), "value".into()).unwrap();
+        closed(batch.commit().map(|_| ()));
+        drop(batch);
+
+        let syncs = stats.syncs();
+        db.close().unwrap();
+        assert_eq!(stats.syncs(), syncs);
+
+        let db = db.reopen();
+        assert_eq!(db.index.len(), 25);
+        assert_eq!(db.get("0024".into()).unwrap().unwrap(), "value");
+    }
+
+    #[test]
+    fn lock_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let opts = crate::options::OptionsBuilder::default()
+            .dir_path(root.path().to_path_buf())
+            .build()
+            .unwrap();
+        let db = Engine::new(opts.clone()).unwrap();
+        db.put("Hello".into(), "World".into()).unwrap();
+        let report = Engine::new(opts.clone()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatabaseIsUsing);
+        assert_eq!(db.get("Hello".into()).unwrap().unwrap(), "World");
+
+        drop(db);
+        let mut db = Engine::new(opts.clone()).unwrap();
+        assert_eq!(db.get("Hello".into()).unwrap().unwrap(), "World");
+
+        // closing releases the lock too
+        db.close().unwrap();
+        let db = Engine::new(opts).unwrap();
+        assert_eq!(db.get("Hello".into()).unwrap().unwrap(), "World");
+    }
+
+    #[test]
+    fn read_only() {
+        let root = tempfile::tempdir().unwrap();
+        let opts = |read_only| {
+            crate::options::OptionsBuilder::default()
+                .dir_path(root.path().to_path_buf())
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(0.0)
+                .read_only(read_only)
+                .build()
+                .unwrap()
+        };
+        let db = Engine::new(opts(false)).unwrap();
+        for i in 0..25 {
+            db.put(format!("{:04}", i).into(), format!("{:05}", i).into())
+                .unwrap();
+        }
+        db.delete("0000".into()).unwrap();
+        drop(db);
+        // a stale leftover a writing engine would remove
+        fs::write(root.path().join("000000009.data.tmp"), "torn").unwrap();
+        let snapshot = || {
+            let mut files: Vec<_> = fs::read_dir(root.path())
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.file_name(), fs::read(entry.path()).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let before = snapshot();
+
+        let mut db = Engine::new(opts(true)).unwrap();
+        // shared with another read-only engine, not with a writing one
+        let other = Engine::new(opts(true)).unwrap();
+        let report = Engine::new(opts(false)).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatabaseIsUsing);
+
+        assert_eq!(db.get("0001".into()).unwrap().unwrap(), "00001");
+        assert_eq!(other.get("0024".into()).unwrap().unwrap(), "00024");
+        assert_eq!(db.keys(IteratorOptions::default()).unwrap().count(), 24);
+        assert_eq!(db.iter(IteratorOptions::default()).unwrap().count(), 24);
+        assert_eq!(db.stat().unwrap().keys, 24);
+
+        let read_only = |result: Result<()>| {
+            assert_eq!(result.unwrap_err().current_context(), &Errors::ReadOnly)
+        };
+        read_only(db.put("0001".into(), "value".into()));
+        read_only(db.delete("0001".into()));
+        let mut batch = db.write_batch(Default::default());
+        batch.put("new".into(), "value".into()).unwrap();
+        read_only(batch.commit().map(|_| ()));
+        drop(batch);
+        read_only(db.merge(None).map(|_| ()));
+        read_only(db.gc_dead_files().map(|_| ()));
+        drop((db, other));
+        assert_eq!(snapshot(), before);
+
+        // without a lock file, none is created
+        fs::remove_file(root.path().join(LOCK_FILE)).unwrap();
+        let db = Engine::new(opts(true)).unwrap();
+        assert_eq!(db.get("0001".into()).unwrap().unwrap(), "00001");
+        drop(db);
+        assert!(!root.path().join(LOCK_FILE).exists());
+
+        let missing = root.path().join("missing");
+        let report = Engine::new(Options {
+            dir_path: missing.clone(),
+            ..opts(true)
+        })
+        .unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DbNotFound);
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<Engine>();
+
+        // datafiles of a few hundred records, the writers keep sealing them
+        let opts = engine_wrapper::options()
+            .data_file_size(4 * 1024)
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        let db = Arc::new(EngineWrapper::new(opts));
+        let (writers, rounds) = (4, 1000);
+        let shared = |i: usize| Bytes::from(format!("shared-{:02}", i % 50));
+        let own = |writer: usize, i: usize| Bytes::from(format!("own-{}-{:04}", writer, i));
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let workload = db.clone();
+        std::thread::spawn(move || {
+            let db = workload;
+            let stop = Arc::new(AtomicUsize::new(0));
+            let readers: Vec<_> = (0..writers)
+                .map(|_| {
+                    let (db, stop) = (db.clone(), stop.clone());
+                    std::thread::spawn(move || {
+                        while stop.load(Ordering::Relaxed) == 0 {
+                            for i in 0..50 {
+                                if let Some(value) = db.get(shared(i)).unwrap() {
+                                    assert!(value.starts_with(b"w"));
+                                }
+                            }
+                            for entry in db.iter(IteratorOptions::default()).unwrap() {
+                                assert!(entry.unwrap().value().starts_with(b"w"));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let handles: Vec<_> = (0..writers)
+                .map(|writer| {
+                    let db = db.clone();
+                    std::thread::spawn(move || {
+                        for i in 0..rounds {
+                            db.put(own(writer, i), format!("w{}", i).into()).unwrap();
+                            db.put(shared(i), format!("w{}-{}", writer, i).into())
+                                .unwrap();
+                            if i % 3 == 0 {
+                                db.delete(own(writer, i)).unwrap();
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            stop.store(1, Ordering::Relaxed);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            done.send(()).unwrap();
+        });
+        finished
+            .recv_timeout(Duration::from_secs(60))
+            .expect("the writers and readers deadlocked");
+
+        assert!(db.files.read().active.id() > 10);
+        for writer in 0..writers {
+            for i in 0..rounds {
+                match i % 3 {
+                    0 => assert_eq!(db.get(own(writer, i)).unwrap(), None),
+                    _ => assert_eq!(db.get(own(writer, i)).unwrap().unwrap(), format!("w{}", i)),
+                }
+            }
+        }
+        // the last write of each shared key is one of the last round of a writer
+        for i in rounds - 50..rounds {
+            let value = db.get(shared(i)).unwrap().unwrap();
+            assert!(
+                (0..writers).any(|writer| value == format!("w{}-{}", writer, i)),
+                "{:?}",
+                value
+            );
+        }
+        let db = Arc::into_inner(db).unwrap().reopen();
+        assert_eq!(db.index.len(), writers * (rounds - rounds.div_ceil(3)) + 50);
+    }
+
+    #[test]
+    fn hashmap_index() {
+        let opts = engine_wrapper::options()
+            .index_type(crate::options::IndexType::HashMap)
+            .expected_keys(Some(10_000))
+            .build()
+            .unwrap();
+        let engine = EngineWrapper::new(opts);
+        let key = |i: u32| Bytes::from(format!("key-{:05}", i));
+        for i in (0..10_000).rev() {
+            engine.put(key(i), key(i)).unwrap();
+        }
+        for i in (0..10_000).step_by(3) {
+            engine.delete(key(i)).unwrap();
+        }
+        let engine = engine.reopen();
+        for i in 0..10_000 {
+            match i % 3 {
+                0 => assert_eq!(engine.get(key(i)).unwrap(), None),
+                _ => assert_eq!(engine.get(key(i)).unwrap(), Some(key(i))),
+            }
+        }
+        // iterated in key order all the same
+        let keys: Vec<Bytes> = engine.keys(IteratorOptions::default()).unwrap().collect();
+        assert_eq!(keys.len(), 6_666);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        iter.seek(key(5_000).to_vec());
+        assert_eq!(iter.next().unwrap().unwrap().into_parts().0, key(5_000));
+    }
+
+    #[test]
+    fn skiplist_index() {
+        let opts = engine_wrapper::options()
+            .index_type(crate::options::IndexType::SkipList)
+            .build()
+            .unwrap();
+        let engine = EngineWrapper::new(opts);
+        for key in ["b", "a", "c"] {
+            engine.put(key.into(), key.into()).unwrap();
+        }
+        engine.delete("b".into()).unwrap();
+        let engine = engine.reopen();
+        let keys: Vec<Bytes> = engine.keys(IteratorOptions::default()).unwrap().collect();
+        assert_eq!(keys, vec!["a", "c"]);
+        assert_eq!(engine.get("c".into()).unwrap().unwrap(), "c");
+    }
+
+    #[test]
+    fn open_error_keeps_io_error() {
+        // a directory under a regular file cannot be created, whatever the privileges
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+        let report = open(&file.join("db"), true, false).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::CreateDbDirFail);
+        let source = report.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn corrupted_record_context() {
+        let mut db = engine!(["a", "val-a"], ["b", "val-b"]);
+        let pos = db.index.get(b"a").unwrap();
+        // flip a bit of the value of `a` so that the CRC no longer matches
+        let path = db.path().join(super::datafile_name(pos.file_id));
+        let mut content = fs::read(&path).unwrap();
+        content[pos.offset as usize + pos.size as usize - 1] ^= 0x01;
+        fs::write(&path, content).unwrap();
+        let location = RecordLocation {
+            file_id: pos.file_id,
+            offset: pos.offset,
+        };
+
+        let report = db.get("a".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+        assert_eq!(report.downcast_ref::<RecordLocation>(), Some(&location));
+        assert_eq!(
+            report.downcast_ref::<ErrorKey>(),
+            Some(&ErrorKey::new(b"a"))
+        );
+        let rendered = format!("{:?}", report);
+        assert!(rendered.contains("record at offset 0 of datafile 0"));
+        assert!(rendered.contains(r#"key b"a""#));
+        assert_eq!(db.get("b".into()).unwrap().unwrap(), "val-b");
+
+        // the same record fails the replay
+        db.close().unwrap();
+        let report = Engine::new(db.options.clone()).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+        assert_eq!(report.downcast_ref::<RecordLocation>(), Some(&location));
+        assert!(format!("{:?}", report).contains("Fail to rebuild the index"));
+    }
+
+    #[test]
+    fn verify_reports_corruptions() {
+        let db = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        assert_eq!(db.verify().unwrap(), vec![]);
+
+        let a = db.index.get(b"a").unwrap();
+        let path = db.path().join(super::datafile_name(a.file_id));
+        let mut content = fs::read(&path).unwrap();
+        content[a.offset as usize + a.size as usize - 1] ^= 0x01;
+        fs::write(&path, content).unwrap();
+        let expected = CorruptionInfo {
+            file_id: a.file_id,
+            offset: a.offset,
+            reason: CorruptionReason::CrcMismatch,
+            recoverable: false,
+        };
+        assert_eq!(db.verify().unwrap(), vec![expected]);
+
+        let report = db.get("a".into()).unwrap_err();
+        assert_eq!(report.downcast_ref::<CorruptionInfo>(), Some(&expected));
+        assert!(format!("{:?}", report)
+            .contains("CRC mismatch at offset 0 of datafile 0, not recoverable"));
+    }
+
+    #[test]
+    fn max_record_sizes() {
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .max_key_size(Some(4))
+                .max_value_size(Some(8))
+                .build()
+                .unwrap(),
+        );
+        db.put("1234".into(), "12345678".into()).unwrap();
+        let report = db.put("12345".into(), "v".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::KeyTooLarge);
+        let report = db.put("k".into(), "123456789".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::ValueTooLarge);
+
+        let mut batch = db.write_batch(Default::default());
+        let report = batch.put("k".into(), "123456789".into()).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::ValueTooLarge);
+    }
+
+    #[test]
+    fn open_progress_throttled() {
+        let reports = Arc::new(Mutex::new(Vec::<OpenProgress>::new()));
+        let sink = reports.clone();
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .open_progress(Some(Arc::new(move |progress| sink.lock().push(progress))))
+                .clock(MockClock::new(0))
+                .build()
+                .unwrap(),
+        );
+        for i in 0..100 {
+            db.put("0000".into(), format!("{:05}", i).into()).unwrap();
+        }
+        // the clock never moves, only the completion is reported
+        let _db = db.reopen();
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].files_done, reports[0].files_total);
+    }
+
+    #[test]
+    fn open_progress() {
+        let reports = Arc::new(Mutex::new(Vec::<OpenProgress>::new()));
+        let sink = reports.clone();
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(0.0)
+                .open_progress(Some(Arc::new(move |progress| sink.lock().push(progress))))
+                .open_progress_interval(Duration::ZERO)
+                .build()
+                .unwrap(),
+        );
+        for i in 0..100 {
+            db.put("0000".into(), format!("{:05}", i).into()).unwrap();
+        }
+        assert!(reports.lock().is_empty());
+
+        let total = db.files.read().total_bytes();
+        let db = db.reopen();
+        let reports = reports.lock();
+        assert!(reports.windows(2).all(|w| {
+            w[0].files_done <= w[1].files_done
+                && w[0].records_done <= w[1].records_done
+                && w[0].bytes_done <= w[1].bytes_done
+        }));
+        let last = reports.last().unwrap();
+        assert_eq!(
+            (last.files_done, last.records_done, last.bytes_done),
+            (10, 100, total)
+        );
+        assert!(reports
+            .iter()
+            .all(|progress| progress.files_total == 10 && progress.bytes_total == total));
+        assert_eq!(db.get("0000".into()).unwrap().unwrap(), "00099");
+    }
+
+    #[test]
+    fn open_progress_panic() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut db = engine!(["a", "val-a"], ["b", "val-b"]);
+        db.close().unwrap();
+        let mut opts = db.options.clone();
+        opts.open_progress = Some(Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            panic!("progress is not welcome");
+        }));
+        opts.open_progress_interval = Duration::ZERO;
+        opts.temporary = false;
+        let reopened = Engine::new(opts).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(reopened.get("b".into()).unwrap().unwrap(), "val-b");
+    }
+
+    #[test]
+    fn reopen_replays_datafiles_in_order() {
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .build()
+                .unwrap(),
+        );
+        // the same key overwritten across 10 datafiles
+        for i in 0..100 {
+            db.put("0000".into(), format!("{:05}", i).into()).unwrap();
+        }
+        let db = db.reopen();
+        assert_eq!(db.get("0000".into()).unwrap().unwrap(), "00099");
+    }
+
+    /// Puts the keys `0..n` as 16-byte records, overwriting any previous value
+    fn overwrite(db: &Engine, n: usize) {
+        for i in 0..n {
+            db.put(format!("{:04}", i).into(), format!("{:05}", i).into())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn merge_thresholds() {
+        let opts = |min_bytes| {
+            engine_wrapper::options()
+                .merge_ratio(0.5)
+                .merge_min_bytes(min_bytes)
+                .build()
+                .unwrap()
+        };
+        let db = EngineWrapper::new(opts(0));
+        overwrite(&db, 10);
+        assert!(!db.merge_due());
+        // half of the 320 bytes written are now overwritten
+        overwrite(&db, 10);
+        assert!(db.merge_due());
+        db.set_auto_merge(false);
+        assert!(!db.merge_due());
+        db.set_auto_merge(true);
+        // the reclaimable bytes are recovered on reopen
+        let db = db.reopen();
+        assert!(db.merge_due());
+
+        let db = EngineWrapper::new(opts(200));
+        overwrite(&db, 10);
+        overwrite(&db, 10);
+        assert!(!db.merge_due());
+        // 10 overwritten records and 10 tombstones
+        for i in 0..10 {
+            db.delete(format!("{:04}", i).into()).unwrap();
+        }
+        assert!(db.merge_due());
+    }
+
+    /// Puts `n` records of 16 bytes into an engine holding 10 records per datafile,
+    /// returning the syncs observed after the puts and after closing the engine
+    fn syncs_with(policy: SyncPolicy, n: usize) -> (usize, usize) {
+        let (db, stats) = EngineWrapper::counting_with(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .sync_policy(policy)
+                .build()
+                .unwrap(),
+        );
+        let before = stats.syncs();
+        for i in 0..n {
+            let key = format!("{:04}", i);
+            let val = format!("{:05}", i);
+            db.put(key.into(), val.into()).unwrap();
+        }
+        let after_puts = stats.syncs() - before;
+        // the wrapper is temporary, reopening drops the engine without removing its datafiles
+        let _db = db.reopen();
+        (after_puts, stats.syncs() - before)
+    }
+
+    #[test]
+    fn sync_policy_always() {
+        assert_eq!(syncs_with(SyncPolicy::Always, 5), (5, 6));
+        // sealing a datafile syncs it once more
+        assert_eq!(syncs_with(SyncPolicy::Always, 15), (16, 17));
+    }
+
+    #[test]
+    fn sync_policy_on_rotation() {
+        assert_eq!(syncs_with(SyncPolicy::OnRotation, 5), (0, 1));
+        assert_eq!(syncs_with(SyncPolicy::OnRotation, 25), (2, 3));
+    }
+
+    #[test]
+    fn sync_policy_interval() {
+        assert_eq!(syncs_with(SyncPolicy::Interval(Duration::ZERO), 5), (5, 6));
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(syncs_with(SyncPolicy::Interval(hour), 15), (1, 2));
+
+        let clock = MockClock::new(0);
+        let (db, stats) = EngineWrapper::counting_with(
+            engine_wrapper::options()
+                .sync_policy(SyncPolicy::Interval(hour))
+                .clock(clock.clone())
+                .build()
+                .unwrap(),
+        );
+        let before = stats.syncs();
+        db.put("a".into(), "val-a".into()).unwrap();
+        clock.advance(hour - Duration::from_millis(1));
+        db.put("b".into(), "val-b".into()).unwrap();
+        assert_eq!(stats.syncs(), before);
+        clock.advance(Duration::from_millis(1));
+        db.put("c".into(), "val-c".into()).unwrap();
+        assert_eq!(stats.syncs(), before + 1);
+        // the interval starts over from the sync
+        clock.advance(hour / 2);
+        db.put("d".into(), "val-d".into()).unwrap();
+        assert_eq!(stats.syncs(), before + 1);
+    }
+
+    #[test]
+    fn sync_policy_bytes() {
+        assert_eq!(syncs_with(SyncPolicy::Bytes(3 * 16), 9), (3, 4));
+        // sealing the datafile syncs it and resets the count
+        assert_eq!(syncs_with(SyncPolicy::Bytes(3 * 16), 12), (4, 5));
+    }
+
+    #[test]
+    fn sync_policy_never() {
+        // no write nor sealed datafile is synced, dropping the engine still syncs once
+        assert_eq!(syncs_with(SyncPolicy::Never, 25), (0, 1));
     }
 }
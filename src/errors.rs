@@ -1,7 +1,12 @@
+use bytes::Bytes;
 use error_stack::Report;
 use thiserror::Error;
 
-#[derive(Error, Debug, Eq, PartialEq)]
+/// Kinds of errors, new kinds may be added in any release. Rather than matching
+/// on them, deciding how to handle an error is easier through the classification
+/// helpers like [Errors::is_not_found] and [Errors::is_retryable].
+#[non_exhaustive]
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
 pub enum Errors {
     #[error("Fail to open file")]
     FailToOpenFile,
@@ -15,6 +20,10 @@ pub enum Errors {
     EmptyKey,
     #[error("Key not found in storage")]
     KeyNotFound,
+    #[error("Key exceeds the maximum key size")]
+    KeyTooLarge,
+    #[error("Value exceeds the maximum value size")]
+    ValueTooLarge,
     #[error("Datafile not found in storage")]
     DatafileNotFound,
     #[error("Datafile size is too small")]
@@ -31,8 +40,316 @@ pub enum Errors {
     ReadDbDirFail,
     #[error("Path to database is invalid")]
     InvalidDbPath,
+    #[error("Path to database is not a directory")]
+    DbPathNotDir,
+    #[error("Database does not exist")]
+    DbNotFound,
+    #[error("Database already exists")]
+    DbAlreadyExists,
+    #[error("Options are invalid")]
+    InvalidOptions,
+    #[error("Index type is not supported yet")]
+    UnsupportedIndexType,
+    #[error("Fail to load config")]
+    FailToLoadConfig,
+    #[error("Iterator options are invalid")]
+    InvalidIteratorOptions,
+    #[error("Fail to serialize")]
+    FailToSerialize,
+    #[error("Fail to deserialize")]
+    FailToDeserialize,
+    #[error("Exceed the maximum size of a write batch")]
+    ExceedMaxBatchSize,
+    #[error("Merge has been cancelled")]
+    MergeCancelled,
+    #[error("Merge output differs from the merged datafiles")]
+    MergeVerificationFailed,
+    #[error("Key already exists")]
+    KeyAlreadyExists,
+    #[error("Dump is invalid")]
+    InvalidDump,
+    #[error("Database is incomplete, its migration or restore failed")]
+    IncompleteDb,
+    #[error("Backup is invalid")]
+    InvalidBackup,
+    #[error("Fail to bind the server socket")]
+    FailToBind,
+    #[error("Engine is closed")]
+    EngineClosed,
+    #[error("Database is used by another engine")]
+    DatabaseIsUsing,
+    #[error("Database is opened read-only")]
+    ReadOnly,
     #[error("Something unexpected happen")]
     InternalError,
 }
 
-pub type Result<T> = std::result::Result<T, Report<Errors>>;
+impl Errors {
+    /// Something looked up does not exist: [Errors::KeyNotFound], [Errors::DatafileNotFound]
+    /// and [Errors::DbNotFound]
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            Errors::KeyNotFound | Errors::DatafileNotFound | Errors::DbNotFound
+        )
+    }
+
+    /// The data on disk is damaged: [Errors::DatafileCorrupted]
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Errors::DatafileCorrupted)
+    }
+
+    /// An operation on the filesystem failed, the [std::io::Error] can be downcast
+    /// from the report: [Errors::FailToOpenFile], [Errors::FailToReadFromFile],
+    /// [Errors::FailToWriteToFile], [Errors::FailToSyncFile], [Errors::CreateDbDirFail],
+    /// [Errors::CreateDbFileFail], [Errors::ReadDbDirFail] and [Errors::FailToBind]
+    pub fn is_io(&self) -> bool {
+        matches!(
+            self,
+            Errors::FailToOpenFile
+                | Errors::FailToReadFromFile
+                | Errors::FailToWriteToFile
+                | Errors::FailToSyncFile
+                | Errors::CreateDbDirFail
+                | Errors::CreateDbFileFail
+                | Errors::ReadDbDirFail
+                | Errors::FailToBind
+        )
+    }
+
+    /// The failed operation may succeed once retried, e.g. after file descriptors were
+    /// released: [Errors::FailToOpenFile], [Errors::FailToReadFromFile],
+    /// [Errors::FailToSyncFile] and [Errors::ReadDbDirFail]. A failed write is not
+    /// retryable, part of the record may already be on disk.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Errors::FailToOpenFile
+                | Errors::FailToReadFromFile
+                | Errors::FailToSyncFile
+                | Errors::ReadDbDirFail
+        )
+    }
+}
+
+/// The error type of the whole public API, whatever the enabled features.
+/// The kind of the error is its [current context](Report::current_context),
+/// the attachments give details on where it occurred.
+pub type Error = Report<Errors>;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Location of the record an error occurred on, attached to the [Report]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RecordLocation {
+    pub file_id: u32,
+    pub offset: u64,
+}
+
+impl std::fmt::Display for RecordLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "record at offset {} of datafile {}",
+            self.offset, self.file_id
+        )
+    }
+}
+
+/// Why a record was found corrupted, see [CorruptionInfo]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CorruptionReason {
+    /// The record decodes but its content does not match the CRC
+    CrcMismatch,
+    /// The key size or value size of the header is not a valid length delimiter
+    InvalidLengthDelimiter,
+    /// The type of the record is neither a normal record nor a tombstone
+    UnknownRecordType,
+    /// The record extends past the end of the datafile
+    Truncated,
+}
+
+impl std::fmt::Display for CorruptionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            CorruptionReason::CrcMismatch => "CRC mismatch",
+            CorruptionReason::InvalidLengthDelimiter => "undecodable length delimiter",
+            CorruptionReason::UnknownRecordType => "unknown record type",
+            CorruptionReason::Truncated => "truncated record",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Details of an [Errors::DatafileCorrupted], attached to the [Report].
+///
+/// Records before `offset` in the datafile are intact. The corruption is `recoverable`
+/// when the damaged record is the last one of the datafile, as a torn write at a crash
+/// leaves it: truncating the datafile at `offset` loses no other record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CorruptionInfo {
+    pub file_id: u32,
+    pub offset: u64,
+    pub reason: CorruptionReason,
+    pub recoverable: bool,
+}
+
+impl std::fmt::Display for CorruptionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at offset {} of datafile {}, ",
+            self.reason, self.offset, self.file_id
+        )?;
+        match self.recoverable {
+            true => write!(
+                f,
+                "recoverable by truncating the datafile at offset {}",
+                self.offset
+            ),
+            false => write!(
+                f,
+                "not recoverable, records before offset {} are intact",
+                self.offset
+            ),
+        }
+    }
+}
+
+/// Key of the operation an error occurred on, attached to the [Report].
+/// Only the first [ErrorKey::MAX_LEN] bytes of the key are kept.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorKey {
+    pub key: Bytes,
+    pub len: usize,
+}
+
+impl ErrorKey {
+    pub const MAX_LEN: usize = 64;
+
+    pub fn new(key: &[u8]) -> Self {
+        ErrorKey {
+            key: Bytes::copy_from_slice(&key[..key.len().min(ErrorKey::MAX_LEN)]),
+            len: key.len(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "key {:?}", self.key)?;
+        if self.len > self.key.len() {
+            write!(f, " (truncated from {} bytes)", self.len)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::{ErrorKey, Errors};
+
+    #[test]
+    fn classification() {
+        // (is_not_found, is_corruption, is_io, is_retryable), the match fails to compile
+        // until a new variant is classified here
+        let expected = |e: &Errors| -> (bool, bool, bool, bool) {
+            match e {
+                Errors::FailToOpenFile => (false, false, true, true),
+                Errors::FailToReadFromFile => (false, false, true, true),
+                Errors::FailToWriteToFile => (false, false, true, false),
+                Errors::FailToSyncFile => (false, false, true, true),
+                Errors::EmptyKey => (false, false, false, false),
+                Errors::KeyNotFound => (true, false, false, false),
+                Errors::KeyTooLarge => (false, false, false, false),
+                Errors::ValueTooLarge => (false, false, false, false),
+                Errors::DatafileNotFound => (true, false, false, false),
+                Errors::DatafileSizeTooSmall => (false, false, false, false),
+                Errors::DatafileCorrupted => (false, true, false, false),
+                Errors::IndexUpdateFail => (false, false, false, false),
+                Errors::CreateDbDirFail => (false, false, true, false),
+                Errors::CreateDbFileFail => (false, false, true, false),
+                Errors::ReadDbDirFail => (false, false, true, true),
+                Errors::InvalidDbPath => (false, false, false, false),
+                Errors::DbPathNotDir => (false, false, false, false),
+                Errors::DbNotFound => (true, false, false, false),
+                Errors::DbAlreadyExists => (false, false, false, false),
+                Errors::InvalidOptions => (false, false, false, false),
+                Errors::UnsupportedIndexType => (false, false, false, false),
+                Errors::FailToLoadConfig => (false, false, false, false),
+                Errors::InvalidIteratorOptions => (false, false, false, false),
+                Errors::FailToSerialize => (false, false, false, false),
+                Errors::FailToDeserialize => (false, false, false, false),
+                Errors::ExceedMaxBatchSize => (false, false, false, false),
+                Errors::MergeCancelled => (false, false, false, false),
+                Errors::MergeVerificationFailed => (false, false, false, false),
+                Errors::KeyAlreadyExists => (false, false, false, false),
+                Errors::InvalidDump => (false, false, false, false),
+                Errors::IncompleteDb => (false, false, false, false),
+                Errors::InvalidBackup => (false, false, false, false),
+                Errors::FailToBind => (false, false, true, false),
+                Errors::EngineClosed => (false, false, false, false),
+                Errors::DatabaseIsUsing => (false, false, false, false),
+                Errors::ReadOnly => (false, false, false, false),
+                Errors::InternalError => (false, false, false, false),
+            }
+        };
+        let all = [
+            Errors::FailToOpenFile,
+            Errors::FailToReadFromFile,
+            Errors::FailToWriteToFile,
+            Errors::FailToSyncFile,
+            Errors::EmptyKey,
+            Errors::KeyNotFound,
+            Errors::KeyTooLarge,
+            Errors::ValueTooLarge,
+            Errors::DatafileNotFound,
+            Errors::DatafileSizeTooSmall,
+            Errors::DatafileCorrupted,
+            Errors::IndexUpdateFail,
+            Errors::CreateDbDirFail,
+            Errors::CreateDbFileFail,
+            Errors::ReadDbDirFail,
+            Errors::InvalidDbPath,
+            Errors::DbPathNotDir,
+            Errors::DbNotFound,
+            Errors::DbAlreadyExists,
+            Errors::InvalidOptions,
+            Errors::UnsupportedIndexType,
+            Errors::FailToLoadConfig,
+            Errors::InvalidIteratorOptions,
+            Errors::FailToSerialize,
+            Errors::FailToDeserialize,
+            Errors::ExceedMaxBatchSize,
+            Errors::MergeCancelled,
+            Errors::MergeVerificationFailed,
+            Errors::KeyAlreadyExists,
+            Errors::InvalidDump,
+            Errors::IncompleteDb,
+            Errors::InvalidBackup,
+            Errors::FailToBind,
+            Errors::EngineClosed,
+            Errors::DatabaseIsUsing,
+            Errors::ReadOnly,
+            Errors::InternalError,
+        ];
+        for e in all {
+            let actual = (
+                e.is_not_found(),
+                e.is_corruption(),
+                e.is_io(),
+                e.is_retryable(),
+            );
+            assert_eq!(actual, expected(&e), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn error_key_truncated() {
+        let key = ErrorKey::new(&[b'k'; 100]);
+        assert_eq!(key.key.len(), ErrorKey::MAX_LEN);
+        assert!(key.to_string().ends_with("(truncated from 100 bytes)"));
+        assert_eq!(ErrorKey::new(b"a").to_string(), r#"key b"a""#);
+    }
+}
@@ -1,29 +1,45 @@
 use crate::errors::{Errors, Result};
 use crate::fio::IOManager;
 use error_stack::ResultExt;
-use log::error;
 use parking_lot::RwLock;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::os::unix::fs::{FileExt, MetadataExt};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub struct FileIO {
     /// file io wrapper
     fd: Arc<RwLock<File>>,
+    /// path of the underlying file, attached to every error report
+    path: PathBuf,
 }
 
 impl FileIO {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .append(true)
-            .open(path)
-            .change_context(Errors::FailToOpenFile)?;
+            .open(&path)
+            .change_context(Errors::FailToOpenFile)
+            .attach_printable_lazy(|| format!("Fail to open {:?}", path))?;
         Ok(FileIO {
             fd: Arc::new(RwLock::new(file)),
+            path,
+        })
+    }
+
+    /// Opens the existing file at `path` for reading only
+    pub fn read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)
+            .change_context(Errors::FailToOpenFile)
+            .attach_printable_lazy(|| format!("Fail to open {:?}", path))?;
+        Ok(FileIO {
+            fd: Arc::new(RwLock::new(file)),
+            path,
         })
     }
 }
@@ -33,7 +49,15 @@ impl IOManager for FileIO {
         let reader = self.fd.read();
         reader
             .read_exact_at(buf, offset)
-            .change_context(Errors::FailToReadFromFile)?;
+            .change_context(Errors::FailToReadFromFile)
+            .attach_printable_lazy(|| {
+                format!(
+                    "Fail to read {} bytes at offset {} of {:?}",
+                    buf.len(),
+                    offset,
+                    self.path
+                )
+            })?;
         Ok(())
     }
 
@@ -41,13 +65,19 @@ impl IOManager for FileIO {
         let mut writer = self.fd.write();
         let bytes_read = writer
             .write(buf)
-            .change_context(Errors::FailToWriteToFile)?;
+            .change_context(Errors::FailToWriteToFile)
+            .attach_printable_lazy(|| {
+                format!("Fail to write {} bytes to {:?}", buf.len(), self.path)
+            })?;
         Ok(bytes_read)
     }
 
     fn sync(&self) -> Result<()> {
         let reader = self.fd.read();
-        reader.sync_all().change_context(Errors::FailToSyncFile)?;
+        reader
+            .sync_all()
+            .change_context(Errors::FailToSyncFile)
+            .attach_printable_lazy(|| format!("Fail to sync {:?}", self.path))?;
         Ok(())
     }
 
@@ -56,10 +86,8 @@ impl IOManager for FileIO {
             .fd
             .read()
             .metadata()
-            .map_err(|e| {
-                error!("{}", e);
-                Errors::InternalError
-            })?
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to read metadata of {:?}", self.path))?
             .size();
         Ok(size)
     }
@@ -71,21 +99,16 @@ mod tests {
     use std::fs;
     use std::path::PathBuf;
 
-    fn tmp_file() -> PathBuf {
-        if !Path::new("tmp").is_dir() {
-            let _ = fs::create_dir("tmp");
-        }
-
-        let temp_file = tempfile::Builder::new()
-            .prefix("ailurus_kv")
-            .tempfile_in("tmp")
-            .unwrap();
-        temp_file.path().to_owned()
+    /// A path to a file yet to be created, in a directory removed once dropped
+    fn tmp_file() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ailurus_kv");
+        (dir, path)
     }
 
     #[test]
     fn test_read_success() {
-        let file_path = tmp_file();
+        let (_dir, file_path) = tmp_file();
         let mut file = FileIO::new(&file_path).unwrap();
         let data = b"Hello, World!";
         file.write(data).unwrap();
@@ -100,7 +123,7 @@ mod tests {
 
     #[test]
     fn test_write_success() {
-        let file_path = tmp_file();
+        let (_dir, file_path) = tmp_file();
         let mut file = FileIO::new(&file_path).unwrap();
         let data = b"Hello, World!";
 
@@ -115,4 +138,30 @@ mod tests {
 
         fs::remove_file(&file_path).unwrap();
     }
+
+    #[test]
+    fn read_error_contains_path_and_offset() {
+        let (_dir, file_path) = tmp_file();
+        let mut file = FileIO::new(&file_path).unwrap();
+        file.write(b"Hello").unwrap();
+
+        let mut buf = vec![0; 16];
+        let report = file.read(&mut buf, 42).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::FailToReadFromFile);
+
+        let rendered = format!("{:?}", report);
+        assert!(rendered.contains(&format!("{:?}", file_path)));
+        assert!(rendered.contains("offset 42"));
+        assert!(rendered.contains("16 bytes"));
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn open_error_keeps_io_error() {
+        let report = FileIO::new("tmp/missing/ailurus_kv").err().unwrap();
+        assert_eq!(report.current_context(), &Errors::FailToOpenFile);
+        let source = report.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+    }
 }
@@ -1,8 +1,16 @@
+#[allow(clippy::module_inception)]
 mod fio;
 
-use crate::errors::Result;
+use crate::errors::{Errors, Result};
 use crate::fio::fio::FileIO;
-use std::path::Path;
+use error_stack::ResultExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Suffix of the files that are still being created by [atomic_create]
+pub const TMP_SUFFIX: &str = ".tmp";
 
 pub trait IOManager: Send + Sync {
     /// Reads data from the underlying storage into the provided buffer.
@@ -49,3 +57,113 @@ pub trait IOManager: Send + Sync {
 pub fn io_manager<'a, 'b, P: AsRef<Path> + 'a>(path: P) -> Result<impl IOManager + 'b> {
     FileIO::new(path)
 }
+
+/// Opens the [IOManager] of the file located at the given path,
+/// used by the engine whenever it opens or creates a datafile
+pub type IOManagerFactory = Arc<dyn Fn(&Path) -> Result<Box<dyn IOManager>> + Send + Sync>;
+
+/// The factory of the default [IOManager]
+pub fn default_io_manager() -> IOManagerFactory {
+    Arc::new(|path| Ok(Box::new(io_manager(path)?)))
+}
+
+/// Like [default_io_manager], the files are opened read-only: a missing file is not
+/// created and writing fails
+pub fn read_only_io_manager() -> IOManagerFactory {
+    Arc::new(|path| Ok(Box::new(FileIO::read_only(path)?)))
+}
+
+/// Atomically creates a file at `path` holding `buf`.
+///
+/// The content is written to a sibling file suffixed with [TMP_SUFFIX] first, which is
+/// synced and then renamed to `path`, finally the parent directory is synced as well.
+/// A crash at any point leaves either no file at all or a stale temporary file behind,
+/// but never a partially created `path`.
+///
+/// # Arguments
+///
+/// * `path` - The final location of the file.
+/// * `buf` - The initial content of the file.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the file is durably in place, or `Err(error)` with an associated error value.
+pub fn atomic_create<P: AsRef<Path>>(path: P, buf: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let tmp = tmp_path(path);
+
+    let mut file = File::create(&tmp)
+        .change_context(Errors::CreateDbFileFail)
+        .attach_printable_lazy(|| format!("Fail to create {:?}", tmp))?;
+    file.write_all(buf)
+        .change_context(Errors::FailToWriteToFile)
+        .attach_printable_lazy(|| format!("Fail to write {} bytes to {:?}", buf.len(), tmp))?;
+    file.sync_all()
+        .change_context(Errors::FailToSyncFile)
+        .attach_printable_lazy(|| format!("Fail to sync {:?}", tmp))?;
+
+    fs::rename(&tmp, path)
+        .change_context(Errors::CreateDbFileFail)
+        .attach_printable_lazy(|| format!("Fail to rename {:?} to {:?}", tmp, path))?;
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    sync_dir(parent)
+}
+
+/// Syncs the directory at `path`, making the files created, renamed or removed
+/// in it durable
+pub fn sync_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .change_context(Errors::FailToSyncFile)
+        .attach_printable_lazy(|| format!("Fail to sync directory {:?}", path))
+}
+
+/// Returns `true` if `path` is a leftover of an unfinished [atomic_create].
+pub fn is_tmp<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_str()
+        .is_some_and(|name| name.ends_with(TMP_SUFFIX))
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_create_leaves_no_tmp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000000000.data");
+        atomic_create(&path, b"Hello").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"Hello");
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn atomic_create_replaces_stale_tmp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000000000.data");
+        fs::write(tmp_path(&path), b"half written garbage").unwrap();
+
+        atomic_create(&path, b"").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn detect_tmp() {
+        assert!(is_tmp("000000001.data.tmp"));
+        assert!(!is_tmp("000000001.data"));
+    }
+}
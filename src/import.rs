@@ -0,0 +1,275 @@
+//! Bulk import of delimited files, such as CSV or TSV
+
+use crate::batch::SyncOverride;
+use crate::dump::LoadMode;
+use crate::engine::Engine;
+use crate::errors::{ErrorKey, Errors, Result};
+use crate::options::{ImportOptions, ValueColumn, WriteBatchOptions};
+use bytes::Bytes;
+use error_stack::{Report, ResultExt};
+use std::io::BufRead;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportStats {
+    /// Lines read, without the header and the blank lines
+    pub lines: u64,
+    pub imported: u64,
+    /// Lines of keys that already existed, see [LoadMode::SkipExisting]
+    pub skipped: u64,
+    pub malformed: u64,
+    /// The first [ImportOptions::max_malformed_kept] malformed lines
+    pub malformed_lines: Vec<MalformedLine>,
+}
+
+/// A line that could not be imported
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MalformedLine {
+    /// Number of the line in the file, counting from one
+    pub line: u64,
+    pub reason: &'static str,
+}
+
+/// Splits a line into its key and value
+fn parse(line: &[u8], opts: &ImportOptions) -> std::result::Result<(Bytes, Bytes), &'static str> {
+    let delimiter = |byte: &u8| *byte == opts.delimiter;
+    let key = line
+        .split(delimiter)
+        .nth(opts.key_column)
+        .ok_or("missing key column")?;
+    let value = match opts.value_column {
+        ValueColumn::Index(index) => line.split(delimiter).nth(index),
+        ValueColumn::Rest => line
+            .splitn(opts.key_column + 2, delimiter)
+            .nth(opts.key_column + 1),
+    }
+    .ok_or("missing value column")?;
+    if key.is_empty() {
+        return Err("empty key");
+    }
+    Ok((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)))
+}
+
+/// Feeds the key-value pairs of the lines to `write`, which tells whether the pair was
+/// imported or skipped
+fn each_pair<R: BufRead>(
+    mut reader: R,
+    opts: &ImportOptions,
+    stats: &mut ImportStats,
+    mut write: impl FnMut(Bytes, Bytes) -> Result<bool>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut number = 0;
+    loop {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .change_context(Errors::FailToReadFromFile)?
+            == 0
+        {
+            return Ok(());
+        }
+        number += 1;
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || (number == 1 && opts.skip_header) {
+            continue;
+        }
+        stats.lines += 1;
+
+        let reason = match parse(line, opts) {
+            Ok((key, value)) => match write(key, value) {
+                Ok(true) => {
+                    stats.imported += 1;
+                    continue;
+                }
+                Ok(false) => {
+                    stats.skipped += 1;
+                    continue;
+                }
+                Err(report) => match report.current_context() {
+                    Errors::KeyTooLarge => "key too large",
+                    Errors::ValueTooLarge => "value too large",
+                    _ => return Err(report.attach_printable(format!("At line {}", number))),
+                },
+            },
+            Err(reason) => reason,
+        };
+        stats.malformed += 1;
+        if stats.malformed_lines.len() < opts.max_malformed_kept {
+            stats.malformed_lines.push(MalformedLine {
+                line: number,
+                reason,
+            });
+        }
+    }
+}
+
+/// Whether a key that `exists` is written, as told by `mode`
+fn overwrite(exists: bool, mode: LoadMode, key: &[u8]) -> Result<bool> {
+    match (exists, mode) {
+        (true, LoadMode::SkipExisting) => Ok(false),
+        (true, LoadMode::ErrorOnConflict) => {
+            Err(Report::new(Errors::KeyAlreadyExists)).attach_printable(ErrorKey::new(key))
+        }
+        _ => Ok(true),
+    }
+}
+
+impl Engine {
+    /// Imports the lines of a delimited file as key-value pairs, see [ImportOptions].
+    ///
+    /// Malformed lines are counted and skipped. Any other failure, such as a conflict
+    /// under [LoadMode::ErrorOnConflict], aborts the import: when batched, the lines
+    /// of the current batch are dropped, the earlier ones have been imported.
+    pub fn import_delimited<R: BufRead>(
+        &mut self,
+        reader: R,
+        opts: ImportOptions,
+    ) -> Result<ImportStats> {
+        let mut stats = ImportStats::default();
+        if !opts.batched {
+            each_pair(reader, &opts, &mut stats, |key, value| {
+                let exists = self.index.get(&key).is_some();
+                if !overwrite(exists, opts.mode, &key)? {
+                    return Ok(false);
+                }
+                self.put(key, value)?;
+                Ok(true)
+            })?;
+            return Ok(stats);
+        }
+
+        let engine: &Engine = self;
+        let mut batch = engine.write_batch(WriteBatchOptions {
+            batch_size: opts.batch_size,
+            sync_on_commit: false,
+        });
+        each_pair(reader, &opts, &mut stats, |key, value| {
+            let staged = batch.is_staged(&key);
+            let exists = staged || engine.index.get(&key).is_some();
+            if !overwrite(exists, opts.mode, &key)? {
+                return Ok(false);
+            }
+            if !staged && batch.staged_len() >= opts.batch_size as usize {
+                batch.commit_with(SyncOverride::Skip)?;
+            }
+            batch.put(key, value)?;
+            Ok(true)
+        })?;
+        batch.commit_with(SyncOverride::Skip)?;
+        engine.sync()?;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::LoadMode;
+    use crate::engine;
+    use crate::errors::Errors;
+    use crate::import::{ImportStats, MalformedLine};
+    use crate::options::{ImportOptions, ImportOptionsBuilder, ValueColumn};
+
+    const FIXTURE: &str = "\
+id,name,city
+1,alice,paris
+2,bob,berlin
+
+3
+,nobody,nowhere
+2,bobby,bern
+4,carol,rome,italy\r
+";
+
+    fn options() -> ImportOptionsBuilder {
+        let mut builder = ImportOptionsBuilder::default();
+        builder.skip_header(true).batch_size(2);
+        builder
+    }
+
+    fn malformed(lines: &[(u64, &'static str)]) -> Vec<MalformedLine> {
+        lines
+            .iter()
+            .map(|(line, reason)| MalformedLine {
+                line: *line,
+                reason,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn import() {
+        for batched in [true, false] {
+            let mut engine = engine!(["4", "existing"]);
+            let opts = options().batched(batched).build().unwrap();
+            let stats = engine.import_delimited(FIXTURE.as_bytes(), opts).unwrap();
+            assert_eq!(
+                stats,
+                ImportStats {
+                    lines: 6,
+                    imported: 4,
+                    skipped: 0,
+                    malformed: 2,
+                    malformed_lines: malformed(&[(5, "missing value column"), (6, "empty key")]),
+                }
+            );
+            assert_eq!(engine.get("1".into()).unwrap().unwrap(), "alice");
+            assert_eq!(engine.get("2".into()).unwrap().unwrap(), "bobby");
+            assert_eq!(engine.get("4".into()).unwrap().unwrap(), "carol");
+        }
+    }
+
+    #[test]
+    fn import_columns() {
+        let mut engine = engine!();
+        let opts = options()
+            .key_column(1)
+            .value_column(ValueColumn::Rest)
+            .max_malformed_kept(1)
+            .build()
+            .unwrap();
+        let stats = engine.import_delimited(FIXTURE.as_bytes(), opts).unwrap();
+        assert_eq!(stats.imported, 5);
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(engine.get("carol".into()).unwrap().unwrap(), "rome,italy");
+        assert_eq!(engine.get("nobody".into()).unwrap().unwrap(), "nowhere");
+
+        let tsv = "a\t1\nb\t2\n";
+        let opts = ImportOptionsBuilder::default()
+            .delimiter(b'\t')
+            .build()
+            .unwrap();
+        engine.import_delimited(tsv.as_bytes(), opts).unwrap();
+        assert_eq!(engine.get("b".into()).unwrap().unwrap(), "2");
+    }
+
+    #[test]
+    fn import_modes() {
+        for batched in [true, false] {
+            let mut engine = engine!(["4", "existing"]);
+            let opts = options()
+                .batched(batched)
+                .mode(LoadMode::SkipExisting)
+                .build()
+                .unwrap();
+            let stats = engine.import_delimited(FIXTURE.as_bytes(), opts).unwrap();
+            assert_eq!((stats.imported, stats.skipped), (2, 2));
+            assert_eq!(engine.get("2".into()).unwrap().unwrap(), "bob");
+            assert_eq!(engine.get("4".into()).unwrap().unwrap(), "existing");
+
+            let mut engine = engine!();
+            let opts = options()
+                .batched(batched)
+                .mode(LoadMode::ErrorOnConflict)
+                .build()
+                .unwrap();
+            let report = engine
+                .import_delimited(FIXTURE.as_bytes(), opts)
+                .unwrap_err();
+            assert_eq!(report.current_context(), &Errors::KeyAlreadyExists);
+            // the batch holding the first lines is dropped along
+            assert_eq!(engine.get("1".into()).unwrap().is_some(), !batched);
+        }
+        assert_eq!(ImportOptions::default().mode, LoadMode::Overwrite);
+    }
+}
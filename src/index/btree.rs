@@ -1,314 +1,237 @@
 use crate::data::data_file::DataFile;
-use crate::data::log_record::{LogRecordPos, LogRecordType};
+use crate::data::log_record::LogRecordPos;
 use crate::errors::Result;
-use crate::index::{IndexIterator, Indexable, Indexer};
-use crate::options::IteratorOptions;
+use crate::index::{replay, IndexIterator, Indexable, Indexer, ReplayProgress};
+use crate::options::{is_empty_range, max_lower, min_upper, IteratorOptions, KeyRange};
 use bytes::Bytes;
 use parking_lot::RwLock;
+use std::collections::btree_map::Range;
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::sync::Arc;
 
+type Tree = BTreeMap<Bytes, LogRecordPos>;
+
 pub struct BTree {
-    /// A wrapper around a BTreeMap to provide concurrent access.
-    tree: Arc<RwLock<BTreeMap<Vec<u8>, LogRecordPos>>>,
+    /// The tree is shared with the iterators created since it was last written to, a write
+    /// copies it first if any of them is still alive.
+    tree: RwLock<Arc<Tree>>,
 }
 
 impl BTree {
     pub fn new() -> Self {
         BTree {
-            tree: Arc::new(RwLock::new(BTreeMap::new())),
+            tree: RwLock::new(Arc::new(BTreeMap::new())),
         }
     }
 }
 
 impl Indexable for BTree {
-    fn index<'a, D>(datafiles: D) -> Result<Box<dyn Indexer>>
+    fn index<'a, D>(
+        datafiles: D,
+        _expected_keys: Option<usize>,
+        progress: &mut ReplayProgress,
+    ) -> Result<Box<dyn Indexer>>
     where
         D: IntoIterator<Item = &'a DataFile>,
         Self: Sized,
     {
-        // return a btree index using the given Datafile
-        let mut index = BTree::new();
-        for datafile in datafiles {
-            let mut offset = 0;
-            loop {
-                let log_record = match datafile.read(offset)? {
-                    None => break,
-                    Some(record) => record,
-                };
-
-                let pos = LogRecordPos {
-                    file_id: datafile.id(),
-                    offset,
-                };
-
-                match log_record.record_type {
-                    LogRecordType::Normal => index.put(log_record.key.to_vec(), pos),
-                    LogRecordType::Deleted => index.delete(log_record.key.to_vec()),
-                };
-
-                offset += log_record.size(); // TODO: [perf]: size() call is costly
-            }
-        }
+        // return a btree index using the given Datafile, a btree has no capacity to reserve
+        let index = BTree::new();
+        replay(&index, datafiles, progress)?;
         Ok(Box::new(index))
     }
 }
 
 impl Indexer for BTree {
-    fn put(&mut self, key: Vec<u8>, pos: LogRecordPos) -> bool {
+    fn put(&self, key: Bytes, pos: LogRecordPos) -> Option<LogRecordPos> {
         let mut writer = self.tree.write();
-        writer.insert(key, pos);
-        true
+        Arc::make_mut(&mut writer).insert(key, pos)
     }
 
-    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+    fn get(&self, key: &[u8]) -> Option<LogRecordPos> {
         let reader = self.tree.read();
-        reader.get(&key).copied()
+        reader.get(key).copied()
     }
 
-    fn delete(&mut self, key: Vec<u8>) -> bool {
+    fn delete(&self, key: &[u8]) -> Option<LogRecordPos> {
         let mut writer = self.tree.write();
-        writer.remove(&key).is_some()
+        // nothing to copy the tree for if the key is missing
+        writer.get(key)?;
+        Arc::make_mut(&mut writer).remove(key)
     }
 
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
-        let read = self.tree.read();
-        // TODO: [perf] memory usage maybe very large
-        let mut items: Vec<_> = read.iter().map(|x| (x.0.clone(), *x.1)).collect();
-
-        if options.reverse {
-            items.reverse();
-        }
-
-        Box::new(BtreeIterator {
-            items,
-            index: 0,
+        Box::new(BTreeIterator {
+            tree: self.tree.read().clone(),
+            range: options.key_range(),
+            cursor: Some(Bound::Unbounded),
+            yielded: 0,
             options,
         })
     }
 
     fn keys(&self) -> Result<Vec<Bytes>> {
         let read = self.tree.read();
-        Ok(read
-            .iter()
-            .map(|x| Bytes::copy_from_slice(x.0))
-            .collect::<Vec<Bytes>>())
+        Ok(read.iter().map(|x| x.0.clone()).collect::<Vec<Bytes>>())
+    }
+
+    fn len(&self) -> usize {
+        self.tree.read().len()
     }
 }
 
-pub struct BtreeIterator {
-    items: Vec<(Vec<u8>, LogRecordPos)>,
-    index: usize,
+/// Iterates over the tree as it was when the iterator was created, without copying it.
+/// Every key is looked up in the tree from the previous one, in `O(log n)`.
+struct BTreeIterator {
+    tree: Arc<Tree>,
+    /// the key range of the options, `None` if it is empty
+    range: Option<KeyRange>,
+    /// where the next key is looked up from in iteration order, `None` once exhausted
+    cursor: Option<Bound<Bytes>>,
+    /// keys yielded since the iterator was last positioned, bounded by the limit
+    yielded: usize,
     options: IteratorOptions,
 }
 
-impl IndexIterator for BtreeIterator {
-    fn rewind(&mut self) {
-        self.index = 0
-    }
-
-    fn seek(&mut self, key: Vec<u8>) {
-        self.index = match self.items.binary_search_by(|(x, _)| {
-            if self.options.reverse {
-                x.cmp(&key).reverse()
-            } else {
-                x.cmp(&key)
-            }
-        }) {
-            Ok(x) => x,
-            Err(x) => x,
-        };
-    }
-
-    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.index >= self.items.len() {
-            return None;
-        }
-
-        while let Some(item) = self.items.get(self.index) {
-            self.index += 1;
-            if (self.options.filter)(&item.0) {
-                return Some((&item.0, &item.1));
-            }
-        }
-
-        None
+impl BTreeIterator {
+    /// Positions the iterator at the first key from `from` on in the order told by
+    /// `reverse`, exhausts it if there is none
+    fn position_at_first(&mut self, from: Bound<&[u8]>, reverse: bool) {
+        let mut keys = candidates(&self.tree, &self.range, from, reverse);
+        let first = keys.as_mut().and_then(|keys| match reverse {
+            false => keys.next(),
+            true => keys.next_back(),
+        });
+        self.cursor = first.map(|(key, _)| Bound::Included(key.clone()));
+        self.yielded = 0;
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    macro_rules! btree {
-        // Construct btree, cares about key value pair
-        ($({$key:expr, {$id:expr, $offset:expr}}),* $(,)?) => {{
-            #[allow(unused_mut)]
-            let mut b = $crate::index::btree::BTree::new();
-            $(b.put(
-                $key.as_bytes().to_vec(),
-                crate::data::log_record::LogRecordPos {
-                    file_id: $id,
-                    offset: $offset,
-                },
-            );)*
-            b
-        }};
-        // Construct btree, only cares about keys
-        ($($key:expr),* $(,)?) => {{
-            let mut b = $crate::index::btree::BTree::new();
-            $(b.put(
-                $key.as_bytes().to_vec(),
-                crate::data::log_record::LogRecordPos {
-                    file_id: 0,
-                    offset: 0,
-                },
-            );)*
-            b
-        }}
-    }
-
-    #[test]
-    fn put() {
-        let mut b = BTree::new();
-        assert!(b.put(
-            "".as_bytes().to_vec(),
-            LogRecordPos {
-                file_id: 42,
-                offset: 42,
-            },
-        ));
-        assert!(b.put(
-            "".as_bytes().to_vec(),
-            LogRecordPos {
-                file_id: 1024,
-                offset: 1024,
-            },
-        ));
-    }
-
-    #[test]
-    fn get() {
-        let b = btree!({"42", { 42, 42 }}, {"1024", {1024, 1024}});
-
-        assert_eq!(
-            b.get("42".as_bytes().to_vec()).unwrap(),
-            LogRecordPos {
-                file_id: 42,
-                offset: 42,
-            }
-        );
-
-        assert_eq!(
-            b.get("1024".as_bytes().to_vec()).unwrap(),
-            LogRecordPos {
-                file_id: 1024,
-                offset: 1024,
-            }
-        );
-
-        assert_eq!(b.get("".as_bytes().to_vec()), None);
+/// The keys of `tree` within `range` from `from` on, in the order told by `reverse`, i.e.
+/// from the first key to use with [Range::next], or with [Range::next_back] if `reverse`.
+/// `None` if there is none.
+fn candidates<'a>(
+    tree: &'a Tree,
+    range: &Option<KeyRange>,
+    from: Bound<&[u8]>,
+    reverse: bool,
+) -> Option<Range<'a, Bytes, LogRecordPos>> {
+    let (lower, upper) = range.as_ref()?;
+    let (mut lower, mut upper) = (
+        lower.as_ref().map(Vec::as_slice),
+        upper.as_ref().map(Vec::as_slice),
+    );
+    match reverse {
+        false => lower = max_lower(lower, from),
+        true => upper = min_upper(upper, from),
+    }
+    // `range` panics on an empty range
+    match is_empty_range(&lower, &upper) {
+        true => None,
+        false => Some(tree.range::<[u8], _>((lower, upper))),
     }
+}
 
-    #[test]
-    fn delete() {
-        let mut b = btree!({"42", { 42, 42 }}, {"1024", {1024, 1024}});
-
-        b.delete("42".as_bytes().to_vec());
-        assert_eq!(b.get("42".as_bytes().to_vec()), None);
-
-        assert_eq!(
-            b.get("1024".as_bytes().to_vec()).unwrap(),
-            LogRecordPos {
-                file_id: 1024,
-                offset: 1024,
-            }
-        );
-
-        b.delete("1024".as_bytes().to_vec());
-        assert_eq!(b.get("1024".as_bytes().to_vec()), None);
+impl IndexIterator for BTreeIterator {
+    fn rewind(&mut self) {
+        self.seek_to_first();
     }
 
-    #[test]
-    fn seek_when_empty() {
-        let bt = BTree::new();
-        let mut iter = bt.iterator(IteratorOptions::default());
-        assert_eq!(iter.next(), None);
+    fn seek_to_first(&mut self) {
+        self.cursor = Some(Bound::Unbounded);
+        self.yielded = 0;
     }
 
-    #[test]
-    fn seek_larger_than() {
-        let bt = btree!("a", "c");
-        let mut iter = bt.iterator(IteratorOptions::default());
-        iter.seek("b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+    fn seek_to_last(&mut self) {
+        // the last key in iteration order is the first one in the opposite order
+        self.position_at_first(Bound::Unbounded, !self.options.reverse);
     }
 
-    #[test]
-    fn seek_equal() {
-        let bt = btree!("a", "b", "c");
-        let mut iter = bt.iterator(IteratorOptions::default());
-        iter.seek("b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+    fn seek(&mut self, key: Vec<u8>) {
+        self.cursor = Some(Bound::Included(Bytes::from(key)));
+        self.yielded = 0;
     }
 
-    #[test]
-    fn seek_larger_than_reverse() {
-        let bt = btree!("a", "c");
-        let mut iter = bt.iterator(IteratorOptions {
-            filter: Box::new(|_| true),
-            reverse: true,
-        });
-        iter.seek("b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+    fn seek_for_prev(&mut self, key: Vec<u8>) {
+        self.position_at_first(Bound::Included(&key), !self.options.reverse);
     }
 
-    #[test]
-    fn seek_equal_reverse() {
-        let bt = btree!("a", "b", "c");
-        let mut iter = bt.iterator(IteratorOptions {
-            filter: Box::new(|_| true),
-            reverse: true,
+    fn next(&mut self) -> Option<(&Bytes, &LogRecordPos)> {
+        if self
+            .options
+            .limit
+            .is_some_and(|limit| self.yielded >= limit)
+        {
+            return None;
+        }
+        let from = self.cursor.as_ref()?.as_ref().map(|key| &key[..]);
+        let reverse = self.options.reverse;
+        let mut keys = candidates(&self.tree, &self.range, from, reverse);
+
+        let filter = self.options.filter.as_ref();
+        let next = keys.as_mut().and_then(|keys| loop {
+            let (key, pos) = match reverse {
+                false => keys.next()?,
+                true => keys.next_back()?,
+            };
+            if filter.is_none_or(|f| f(key)) {
+                break Some((key, pos));
+            }
         });
-        iter.seek("b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
-        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
-    }
 
-    #[test]
-    fn rewind() {
-        let bt = btree!("a");
-        let mut iter = bt.iterator(IteratorOptions::default());
-        iter.next();
-        iter.rewind();
-        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
-    }
-
-    #[test]
-    fn filter_iter() {
-        let bt = btree!("a", "b");
-        let mut iter = bt.iterator(IteratorOptions {
-            filter: Box::new(|x| x == &"b".as_bytes().to_vec()),
-            reverse: false,
-        });
-        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+        match next {
+            None => self.cursor = None,
+            Some((key, _)) => {
+                self.cursor = Some(Bound::Excluded(key.clone()));
+                self.yielded += 1;
+            }
+        }
+        next
     }
+}
 
-    #[test]
-    fn some_keys() {
-        let bt = btree!("a", "b", "c");
-        let expected: Vec<Bytes> = vec!["a", "b", "c"]
-            .into_iter()
-            .map(bytes::Bytes::from)
-            .collect();
-        assert_eq!(bt.keys().unwrap(), expected);
+#[cfg(test)]
+mod tests {
+    use crate::data::log_record::LogRecordPos;
+    use crate::index::btree::BTree;
+    use crate::index::Indexer;
+    use crate::mock::alloc::count_allocated_bytes;
+    use crate::options::IteratorOptions;
+    use bytes::Bytes;
+
+    fn pos(offset: u64) -> LogRecordPos {
+        LogRecordPos {
+            file_id: 0,
+            offset,
+            size: 0,
+        }
     }
 
     #[test]
-    fn no_keys() {
-        let bt = btree!();
-        let expected: Vec<Bytes> = vec![];
-        assert_eq!(bt.keys().unwrap(), expected);
+    fn iterator_shares_the_tree() {
+        let mut allocated = Vec::new();
+        for keys in [1_000, 100_000] {
+            let index = BTree::new();
+            for i in 0..keys {
+                index.put(Bytes::from(format!("key-{:06}", i)), pos(i));
+            }
+            let (mut iter, bytes) =
+                count_allocated_bytes(|| index.iterator(IteratorOptions::new().prefix("key-")));
+            // the options and the iterator itself, whatever the size of the index
+            assert!(bytes < 1024, "{} bytes for {} keys", bytes, keys);
+            allocated.push(bytes);
+            assert_eq!(iter.next().unwrap().1, &pos(0));
+
+            // a write copies the tree the iterator holds on to, not the other way around
+            index.put(Bytes::from_static(b"key-000000"), pos(keys));
+            index.delete(b"key-000001");
+            assert_eq!(iter.next().unwrap().1, &pos(1));
+            drop(iter);
+            let mut iter = index.iterator(IteratorOptions::default());
+            assert_eq!(iter.next().unwrap().1, &pos(keys));
+            assert_eq!(iter.next().unwrap().1, &pos(2));
+        }
+        assert_eq!(allocated[0], allocated[1]);
     }
 }
@@ -0,0 +1,83 @@
+use crate::data::data_file::DataFile;
+use crate::data::log_record::LogRecordPos;
+use crate::errors::Result;
+use crate::index::{replay, IndexIterator, Indexable, Indexer, ReplayProgress, SnapshotIterator};
+use crate::options::IteratorOptions;
+use bytes::Bytes;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// An index for point lookups, iterating over it sorts its keys
+pub struct HashIndex {
+    map: Arc<RwLock<HashMap<Bytes, LogRecordPos>>>,
+}
+
+impl HashIndex {
+    pub fn with_capacity(capacity: usize) -> Self {
+        HashIndex {
+            map: Arc::new(RwLock::new(HashMap::with_capacity(capacity))),
+        }
+    }
+}
+
+impl Indexable for HashIndex {
+    fn index<'a, D>(
+        datafiles: D,
+        expected_keys: Option<usize>,
+        progress: &mut ReplayProgress,
+    ) -> Result<Box<dyn Indexer>>
+    where
+        D: IntoIterator<Item = &'a DataFile>,
+        Self: Sized,
+    {
+        let index = HashIndex::with_capacity(expected_keys.unwrap_or_default());
+        replay(&index, datafiles, progress)?;
+        Ok(Box::new(index))
+    }
+}
+
+impl Indexer for HashIndex {
+    fn put(&self, key: Bytes, pos: LogRecordPos) -> Option<LogRecordPos> {
+        self.map.write().insert(key, pos)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<LogRecordPos> {
+        self.map.read().get(key).copied()
+    }
+
+    fn delete(&self, key: &[u8]) -> Option<LogRecordPos> {
+        self.map.write().remove(key)
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        let read = self.map.read();
+        let mut items: Vec<_> = match options.key_range() {
+            None => Vec::new(),
+            Some((lower, upper)) => {
+                let range = (
+                    lower.as_ref().map(Vec::as_slice),
+                    upper.as_ref().map(Vec::as_slice),
+                );
+                read.iter()
+                    .filter(|(key, _)| RangeBounds::<[u8]>::contains(&range, &key[..]))
+                    .map(|(key, pos)| (key.clone(), *pos))
+                    .collect()
+            }
+        };
+        drop(read);
+        items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Box::new(SnapshotIterator::new(items, options))
+    }
+
+    fn keys(&self) -> Result<Vec<Bytes>> {
+        let mut keys: Vec<Bytes> = self.map.read().keys().cloned().collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().len()
+    }
+}
@@ -1,50 +1,63 @@
 mod btree;
+mod hashmap;
+mod skiplist;
+use crate::clock::{self, SharedClock};
 use crate::data::data_file::DataFile;
-use crate::data::log_record::LogRecordPos;
+use crate::data::hint_file;
+use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType, ReadLogRecord};
 use crate::errors::Result;
 use crate::index::btree::BTree;
-use crate::options::{IndexType, IteratorOptions};
+use crate::index::hashmap::HashIndex;
+use crate::index::skiplist::SkipList;
+use crate::options::{IndexType, IteratorOptions, OpenProgress, OpenProgressFn, Options};
 use bytes::Bytes;
+use error_stack::ResultExt;
+use log::warn;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
 
-pub trait Indexer {
+pub trait Indexer: Send + Sync {
     /// Inserts a key-value pair into the index.
     ///
     /// # Arguments
     ///
-    /// * `key` - A vector of bytes representing the key.
+    /// * `key` - The key, shared with the caller rather than copied.
     /// * `pos` - The position of the log record in the index.
     ///
     /// # Returns
     ///
-    /// Returns `true` if the insertion was successful, `false` otherwise.
-    fn put(&mut self, key: Vec<u8>, pos: LogRecordPos) -> bool;
+    /// Returns the position the key was at before, `None` if it was not in the index.
+    fn put(&self, key: Bytes, pos: LogRecordPos) -> Option<LogRecordPos>;
 
     /// Retrieves the position of a key in the index, if it exists.
     ///
     /// # Arguments
     ///
-    /// * `key` - A vector of bytes representing the key.
+    /// * `key` - The bytes of the key.
     ///
     /// # Returns
     ///
     /// Returns an `Option` containing the position of the key if it exists in the index,
     /// or `None` if the key is not found.
-    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos>;
+    fn get(&self, key: &[u8]) -> Option<LogRecordPos>;
 
     /// Removes a key-value pair from the index.
     ///
     /// # Arguments
     ///
-    /// * `key` - A vector of bytes representing the key.
+    /// * `key` - The bytes of the key.
     ///
     /// # Returns
     ///
-    /// Returns `true` if the deletion was successful, `false` otherwise.
-    fn delete(&mut self, key: Vec<u8>) -> bool;
+    /// Returns the position the key was at, `None` if it was not in the index.
+    fn delete(&self, key: &[u8]) -> Option<LogRecordPos>;
 
     /// Returns an iterator over the index.
     ///
     /// This method returns an iterator that implements the `IndexIterator` trait.
+    /// The iterator works on a snapshot of the index taken when this method is called,
+    /// modifications made to the index afterward are never observed by the iterator.
     ///
     /// # Arguments
     ///
@@ -65,19 +78,46 @@ pub trait Indexer {
     /// * `Ok(Vec<Bytes>)`: A vector of `Bytes` representing the keys if the operation is successful.
     /// * `Err(Error)`: An error variant if there is a failure in retrieving the keys.
     fn keys(&self) -> Result<Vec<Bytes>>;
+
+    /// Returns the number of keys in the index.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the index holds no key.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 pub trait Indexable {
-    fn index<'a, D>(datafiles: D) -> Result<Box<dyn Indexer>>
+    /// Builds the index from the records of `datafiles`, replayed in the given order, read
+    /// from the [hint file](crate::data::hint_file) of a datafile when it has one.
+    /// `expected_keys` is a hint for the number of keys, see [Options::expected_keys].
+    /// Each replayed record and datafile is reported to `progress`.
+    fn index<'a, D>(
+        datafiles: D,
+        expected_keys: Option<usize>,
+        progress: &mut ReplayProgress,
+    ) -> Result<Box<dyn Indexer>>
     where
         D: IntoIterator<Item = &'a DataFile>,
         Self: Sized;
 }
 
-pub trait IndexIterator {
+pub trait IndexIterator: Send {
     /// Rewinds the iterator to the beginning.
     fn rewind(&mut self);
 
+    /// Positions the iterator at the first key, the next call to `next` yields it.
+    ///
+    /// First refers to the iteration order, i.e. it is the *largest* key of a reverse iterator.
+    fn seek_to_first(&mut self);
+
+    /// Positions the iterator at the last key, the next call to `next` yields it,
+    /// after which the iterator is exhausted.
+    ///
+    /// Last refers to the iteration order, i.e. it is the *smallest* key of a reverse iterator.
+    fn seek_to_last(&mut self);
+
     /// Seeks the iterator to a specific key.
     /// If key not found, seeks the iterator to the key *greater* than the given key,
     /// the order is define in [IteratorOptions]
@@ -89,19 +129,839 @@ pub trait IndexIterator {
     /// * `key` - A vector of bytes representing the key to seek.
     fn seek(&mut self, key: Vec<u8>);
 
+    /// Seeks the iterator to the last key that does not come after the given key
+    /// in iteration order, i.e. the greatest key *less than or equal* to the given key,
+    /// or the smallest key *greater than or equal* to it for a reverse iterator.
+    /// If there is no such key, the iterator is exhausted.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A vector of bytes representing the key to seek.
+    fn seek_for_prev(&mut self, key: Vec<u8>);
+
     /// Retrieves the next key-value pair from the iterator.
     ///
     /// Returns `Some` with a reference to the key and value if there is a next element,
-    /// or `None` if the iterator has reached the end.
-    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+    /// or `None` if the iterator has reached the end. The key shares its buffer with
+    /// the index, cloning it is cheap.
+    fn next(&mut self) -> Option<(&Bytes, &LogRecordPos)>;
 }
 
-pub fn indexer<'a, D>(datafiles: D, index_type: &IndexType) -> Result<Box<dyn Indexer>>
+pub fn indexer<'a, D>(
+    datafiles: D,
+    index_type: &IndexType,
+    expected_keys: Option<usize>,
+    progress: &mut ReplayProgress,
+) -> Result<Box<dyn Indexer>>
 where
     D: IntoIterator<Item = &'a DataFile>,
 {
     match index_type {
-        IndexType::BTree => Ok(BTree::index(datafiles)?),
-        IndexType::SkipList => todo!(),
+        IndexType::BTree => Ok(BTree::index(datafiles, expected_keys, progress)?),
+        IndexType::SkipList => Ok(SkipList::index(datafiles, expected_keys, progress)?),
+        IndexType::HashMap => Ok(HashIndex::index(datafiles, expected_keys, progress)?),
+    }
+}
+
+/// Replays the records of `datafiles` into `index`, in the given order, see [Indexable::index]
+pub(crate) fn replay<'a, D>(
+    index: &dyn Indexer,
+    datafiles: D,
+    progress: &mut ReplayProgress,
+) -> Result<()>
+where
+    D: IntoIterator<Item = &'a DataFile>,
+{
+    let mut batches = PendingBatches::default();
+    for datafile in datafiles {
+        // a datafile written by a merge lists its records in its hint file
+        if let Some(entries) = hint_file::load(datafile) {
+            for entry in entries {
+                progress.record(None, entry.pos.size as u64);
+                match entry.record_type {
+                    LogRecordType::Normal => index.put(Bytes::from(entry.key), entry.pos),
+                    LogRecordType::Deleted => index.delete(&entry.key),
+                    LogRecordType::TxnFinished => None,
+                };
+            }
+            progress.file_done();
+            continue;
+        }
+
+        let mut offset = 0;
+        loop {
+            let ReadLogRecord {
+                record: log_record,
+                size,
+            } = match datafile
+                .read(offset)
+                .attach_printable("Fail to rebuild the index")?
+            {
+                None => {
+                    progress.file_done();
+                    break;
+                }
+                Some(read) => read,
+            };
+
+            let pos = LogRecordPos {
+                file_id: datafile.id(),
+                offset,
+                size: size as u32,
+            };
+
+            offset += size;
+            progress.record(log_record.seq, size);
+            batches.replay(log_record, pos, |record, pos| {
+                match record.record_type {
+                    LogRecordType::Normal => index.put(Bytes::from(record.key), pos),
+                    LogRecordType::Deleted => index.delete(&record.key),
+                    LogRecordType::TxnFinished => None,
+                };
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Iterates over a snapshot of the entries of an index, taken in key order
+pub(crate) struct SnapshotIterator {
+    items: Vec<(Bytes, LogRecordPos)>,
+    index: usize,
+    /// keys yielded since the iterator was last positioned, bounded by the limit
+    yielded: usize,
+    options: IteratorOptions,
+}
+
+impl SnapshotIterator {
+    /// Iterates over `items`, sorted by key, in the order of `options`
+    pub(crate) fn new(mut items: Vec<(Bytes, LogRecordPos)>, options: IteratorOptions) -> Self {
+        if options.reverse {
+            items.reverse();
+        }
+        SnapshotIterator {
+            items,
+            index: 0,
+            yielded: 0,
+            options,
+        }
+    }
+
+    /// Binary searches the key in iteration order
+    fn search(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.items.binary_search_by(|(x, _)| {
+            if self.options.reverse {
+                x[..].cmp(key).reverse()
+            } else {
+                x[..].cmp(key)
+            }
+        })
+    }
+}
+
+impl IndexIterator for SnapshotIterator {
+    fn rewind(&mut self) {
+        self.index = 0;
+        self.yielded = 0;
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index = 0;
+        self.yielded = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index = self.items.len().saturating_sub(1);
+        self.yielded = 0;
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        self.yielded = 0;
+        // `search` works in iteration order, so the insertion point is the first key not
+        // coming before the target in both directions, or `len` (exhausted) if there is none
+        self.index = match self.search(&key) {
+            Ok(x) => x,
+            Err(x) => x,
+        };
+    }
+
+    fn seek_for_prev(&mut self, key: Vec<u8>) {
+        self.yielded = 0;
+        self.index = match self.search(&key) {
+            Ok(x) => x,
+            Err(0) => self.items.len(), // every key comes after the given key
+            Err(x) => x - 1,
+        };
+    }
+
+    fn next(&mut self) -> Option<(&Bytes, &LogRecordPos)> {
+        if self.index >= self.items.len() {
+            return None;
+        }
+        if self
+            .options
+            .limit
+            .is_some_and(|limit| self.yielded >= limit)
+        {
+            return None;
+        }
+
+        while let Some(item) = self.items.get(self.index) {
+            self.index += 1;
+            if self.options.filter.as_ref().is_none_or(|f| f(&item.0)) {
+                self.yielded += 1;
+                return Some((&item.0, &item.1));
+            }
+        }
+
+        None
+    }
+}
+
+/// Holds back the records of the batches replayed until the marker of their batch is, the
+/// records of a batch whose marker was never written, e.g. cut by a crash, are dropped
+#[derive(Default)]
+pub(crate) struct PendingBatches {
+    batches: HashMap<u64, Vec<(LogRecord, LogRecordPos)>>,
+}
+
+impl PendingBatches {
+    /// Replays `record`, read at `pos`, calling `apply` with the records to apply to the
+    /// index from then on: none for a record of a batch not committed yet, those of the
+    /// batch for its marker, the record itself otherwise
+    pub(crate) fn replay(
+        &mut self,
+        record: LogRecord,
+        pos: LogRecordPos,
+        mut apply: impl FnMut(LogRecord, LogRecordPos),
+    ) {
+        match (record.record_type, record.seq) {
+            (LogRecordType::TxnFinished, Some(seq)) => {
+                for (record, pos) in self.batches.remove(&seq).unwrap_or_default() {
+                    apply(record, pos);
+                }
+            }
+            (_, Some(seq)) => self.batches.entry(seq).or_default().push((record, pos)),
+            (_, None) => apply(record, pos),
+        }
+    }
+}
+
+/// Reports the progress of an index rebuild to [Options::open_progress],
+/// throttled by [Options::open_progress_interval]. It also keeps the largest sequence
+/// number of the batches replayed.
+pub struct ReplayProgress {
+    callback: Option<OpenProgressFn>,
+    interval: Duration,
+    clock: SharedClock,
+    /// when the progress was last reported, on the clock above
+    last_report: u64,
+    progress: OpenProgress,
+    max_seq: Option<u64>,
+}
+
+impl ReplayProgress {
+    pub(crate) fn new(opts: &Options, datafiles: &[&DataFile]) -> Self {
+        ReplayProgress {
+            callback: opts.open_progress.clone(),
+            interval: opts.open_progress_interval,
+            clock: opts.clock.clone(),
+            last_report: opts.clock.now_millis(),
+            progress: OpenProgress {
+                files_total: datafiles.len(),
+                bytes_total: datafiles.iter().map(|datafile| datafile.offset()).sum(),
+                ..OpenProgress::default()
+            },
+            max_seq: None,
+        }
+    }
+
+    /// A record of `size` bytes, written by the batch `seq` if any, has been replayed
+    pub(crate) fn record(&mut self, seq: Option<u64>, size: u64) {
+        self.max_seq = self.max_seq.max(seq);
+        self.progress.records_done += 1;
+        self.progress.bytes_done += size;
+        if clock::elapsed(&*self.clock, self.last_report) >= self.interval {
+            self.report();
+        }
+    }
+
+    /// The current datafile has been fully replayed
+    pub(crate) fn file_done(&mut self) {
+        self.progress.files_done += 1;
+        match self.progress.files_done == self.progress.files_total {
+            true => self.report(),
+            false if clock::elapsed(&*self.clock, self.last_report) >= self.interval => {
+                self.report()
+            }
+            false => {}
+        }
+    }
+
+    /// The largest sequence number of the batch records replayed, if any
+    pub(crate) fn max_seq(&self) -> Option<u64> {
+        self.max_seq
+    }
+
+    fn report(&mut self) {
+        if let Some(callback) = &self.callback {
+            let progress = self.progress;
+            if std::panic::catch_unwind(AssertUnwindSafe(|| callback(progress))).is_err() {
+                warn!("Open progress callback panicked, it will not be called again");
+                self.callback = None;
+            }
+        }
+        self.last_report = self.clock.now_millis();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::alloc::count_allocations;
+    use std::ops::Bound;
+
+    /// An index built by `new` holding the keys, every key at the given position or at 0
+    macro_rules! index {
+        // Construct the index, cares about key value pair
+        ($new:expr; $({$key:expr, {$id:expr, $offset:expr}}),* $(,)?) => {{
+            #[allow(unused_mut)]
+            let b = $new();
+            $(b.put(
+                bytes::Bytes::copy_from_slice($key.as_bytes()),
+                crate::data::log_record::LogRecordPos {
+                    file_id: $id,
+                    offset: $offset,
+                    size: 0,
+                },
+            );)*
+            b
+        }};
+        // Construct the index, only cares about keys
+        ($new:expr; $($key:expr),* $(,)?) => {{
+            let b = $new();
+            $(b.put(
+                bytes::Bytes::copy_from_slice($key.as_bytes()),
+                crate::data::log_record::LogRecordPos {
+                    file_id: 0,
+                    offset: 0,
+                    size: 0,
+                },
+            );)*
+            b
+        }}
+    }
+
+    fn put(new: fn() -> Box<dyn Indexer>) {
+        let b = new();
+        let first = LogRecordPos {
+            file_id: 42,
+            offset: 42,
+            size: 7,
+        };
+        assert_eq!(b.put(Bytes::from(""), first), None);
+        assert_eq!(
+            b.put(
+                Bytes::from(""),
+                LogRecordPos {
+                    file_id: 1024,
+                    offset: 1024,
+                    size: 0,
+                },
+            ),
+            Some(first)
+        );
+    }
+
+    fn get(new: fn() -> Box<dyn Indexer>) {
+        let b = index!(new; {"42", { 42, 42 }}, {"1024", {1024, 1024}});
+
+        assert_eq!(
+            b.get("42".as_bytes()).unwrap(),
+            LogRecordPos {
+                file_id: 42,
+                offset: 42,
+                size: 0,
+            }
+        );
+
+        assert_eq!(
+            b.get("1024".as_bytes()).unwrap(),
+            LogRecordPos {
+                file_id: 1024,
+                offset: 1024,
+                size: 0,
+            }
+        );
+
+        assert_eq!(b.get("".as_bytes()), None);
+    }
+
+    fn delete(new: fn() -> Box<dyn Indexer>) {
+        let b = index!(new; {"42", { 42, 42 }}, {"1024", {1024, 1024}});
+
+        assert_eq!(
+            b.delete("42".as_bytes()),
+            Some(LogRecordPos {
+                file_id: 42,
+                offset: 42,
+                size: 0,
+            })
+        );
+        assert_eq!(b.get("42".as_bytes()), None);
+        assert_eq!(b.delete("42".as_bytes()), None);
+
+        assert_eq!(
+            b.get("1024".as_bytes()).unwrap(),
+            LogRecordPos {
+                file_id: 1024,
+                offset: 1024,
+                size: 0,
+            }
+        );
+
+        b.delete("1024".as_bytes());
+        assert_eq!(b.get("1024".as_bytes()), None);
     }
+
+    fn lookups_do_not_allocate(new: fn() -> Box<dyn Indexer>) {
+        let b = index!(new; "a", "b");
+        let key = Bytes::from("a");
+        let pos = LogRecordPos {
+            file_id: 1,
+            offset: 1,
+            size: 0,
+        };
+        let (_, allocations) = count_allocations(|| {
+            // the key of the caller is shared, not copied
+            b.put(key.clone(), pos);
+            assert_eq!(b.get(&key), Some(pos));
+            assert_eq!(b.get(b"c"), None);
+            assert_eq!(b.delete(b"c"), None);
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    fn seek_when_empty(new: fn() -> Box<dyn Indexer>) {
+        let bt = new();
+        let mut iter = bt.iterator(IteratorOptions::default());
+        assert_eq!(iter.next(), None);
+    }
+
+    fn seek_larger_than(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "c");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+    }
+
+    fn seek_equal(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+    }
+
+    fn seek_larger_than_reverse(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "c");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+    }
+
+    fn seek_equal_reverse(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+    }
+
+    fn iterator_is_a_snapshot(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+
+        bt.delete("b".as_bytes());
+        bt.put(
+            Bytes::from("d"),
+            LogRecordPos {
+                file_id: 0,
+                offset: 0,
+                size: 0,
+            },
+        );
+
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        iter.rewind();
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+    }
+
+    fn rewind(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.next();
+        iter.rewind();
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+    }
+
+    fn filter_iter(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b");
+        let mut iter = bt.iterator(IteratorOptions::new().filter(|x| x == b"b"));
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+    }
+
+    fn prefix_iter(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "aa", "ab", "b");
+        let mut iter = bt.iterator(IteratorOptions::new().prefix("a"));
+        assert_eq!(iter.next().unwrap().0, &"aa".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"ab".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+    }
+
+    fn prefix_iter_reverse(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "aa", "ab", "b");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true).prefix("a"));
+        assert_eq!(iter.next().unwrap().0, &"ab".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"aa".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+    }
+
+    fn prefix_seek_clamped(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "ba", "bb", "c");
+        let mut iter = bt.iterator(IteratorOptions::new().prefix("b"));
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"ba".as_bytes().to_vec());
+        iter.seek("bz".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true).prefix("b"));
+        iter.seek("c".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"bb".as_bytes().to_vec());
+    }
+
+    fn limited_iter(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c", "d");
+        let mut iter = bt.iterator(IteratorOptions::new().limit(2));
+        assert_eq!(collect(&mut iter), keys(&["a", "b"]));
+        // counted again from the new position
+        iter.seek("c".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["c", "d"]));
+        iter.rewind();
+        assert_eq!(collect(&mut iter), keys(&["a", "b"]));
+
+        let opts = IteratorOptions::new()
+            .reverse(true)
+            .filter(|key| key != b"c")
+            .limit(2);
+        let mut iter = bt.iterator(opts);
+        assert_eq!(collect(&mut iter), keys(&["d", "b"]));
+
+        let mut iter = bt.iterator(IteratorOptions::new().limit(0));
+        assert_eq!(iter.next(), None);
+    }
+
+    fn collect(iter: &mut Box<dyn IndexIterator>) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key.to_vec());
+        }
+        keys
+    }
+
+    fn keys(keys: &[&str]) -> Vec<Vec<u8>> {
+        keys.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    fn reverse_seek_below_min(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "b", "c", "d");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    fn reverse_seek_above_max(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "b", "c", "d");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek("e".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["d", "c", "b"]));
+    }
+
+    fn reverse_seek_at_boundaries(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "b", "c", "d");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        // first element in iteration order
+        iter.seek("d".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["d", "c", "b"]));
+        // last element in iteration order
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["b"]));
+        // in between two keys
+        iter.seek("cc".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["c", "b"]));
+    }
+
+    fn reverse_seek_when_empty(new: fn() -> Box<dyn Indexer>) {
+        let bt = new();
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        for key in ["", "a", "\u{ff}"] {
+            iter.seek(key.as_bytes().to_vec());
+            assert_eq!(iter.next(), None);
+        }
+    }
+
+    fn forward_seek_out_of_snapshot(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "b", "c", "d");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["b", "c", "d"]));
+        iter.seek("e".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        iter.seek("d".as_bytes().to_vec());
+        assert_eq!(collect(&mut iter), keys(&["d"]));
+    }
+
+    fn seek_for_prev(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "10", "20", "30");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek_for_prev("25".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"20".as_bytes().to_vec());
+        iter.seek_for_prev("30".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"30".as_bytes().to_vec());
+        iter.seek_for_prev("99".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"30".as_bytes().to_vec());
+        iter.seek_for_prev("05".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+    }
+
+    fn seek_for_prev_reverse(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "10", "20", "30");
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek_for_prev("25".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"30".as_bytes().to_vec());
+        iter.seek_for_prev("20".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"20".as_bytes().to_vec());
+        iter.seek_for_prev("05".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"10".as_bytes().to_vec());
+        iter.seek_for_prev("99".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+    }
+
+    fn seek_to_first_and_last(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c");
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek_to_last();
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        iter.seek_to_first();
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+        iter.seek("b".as_bytes().to_vec());
+        iter.seek_to_last();
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+
+        let mut iter = bt.iterator(IteratorOptions::new().reverse(true));
+        iter.seek_to_last();
+        assert_eq!(iter.next().unwrap().0, &"a".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        iter.seek_to_first();
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+    }
+
+    fn seek_to_first_and_last_when_empty(new: fn() -> Box<dyn Indexer>) {
+        let bt = new();
+        let mut iter = bt.iterator(IteratorOptions::default());
+        iter.seek_to_last();
+        assert_eq!(iter.next(), None);
+        iter.seek_to_first();
+        assert_eq!(iter.next(), None);
+    }
+
+    fn bounded_iter(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c", "d");
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .lower_bound(Bound::Included("b".into()))
+                .upper_bound(Bound::Excluded("d".into())),
+        );
+        assert_eq!(collect(&mut iter), keys(&["b", "c"]));
+
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .reverse(true)
+                .lower_bound(Bound::Excluded("a".into()))
+                .upper_bound(Bound::Included("c".into())),
+        );
+        assert_eq!(collect(&mut iter), keys(&["c", "b"]));
+    }
+
+    fn bounded_iter_empty_intersection(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "aa", "ab", "b");
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .prefix("a")
+                .lower_bound(Bound::Included("b".into())),
+        );
+        assert_eq!(collect(&mut iter), keys(&[]));
+
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .reverse(true)
+                .lower_bound(Bound::Excluded("ab".into()))
+                .upper_bound(Bound::Excluded("ab".into())),
+        );
+        assert_eq!(collect(&mut iter), keys(&[]));
+    }
+
+    fn bounded_iter_with_prefix(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "ba", "bb", "bc", "c");
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .reverse(true)
+                .prefix("b")
+                .upper_bound(Bound::Excluded("bc".into())),
+        );
+        assert_eq!(collect(&mut iter), keys(&["bb", "ba"]));
+    }
+
+    fn bounded_rewind_and_seek(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c", "d");
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .lower_bound(Bound::Included("b".into()))
+                .upper_bound(Bound::Included("c".into())),
+        );
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"b".as_bytes().to_vec());
+        iter.seek("z".as_bytes().to_vec());
+        assert_eq!(iter.next(), None);
+        iter.rewind();
+        assert_eq!(collect(&mut iter), keys(&["b", "c"]));
+
+        let mut iter = bt.iterator(
+            IteratorOptions::new()
+                .reverse(true)
+                .lower_bound(Bound::Included("b".into()))
+                .upper_bound(Bound::Included("c".into())),
+        );
+        iter.seek("z".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0, &"c".as_bytes().to_vec());
+        iter.rewind();
+        assert_eq!(collect(&mut iter), keys(&["c", "b"]));
+    }
+
+    fn some_keys(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new; "a", "b", "c");
+        let expected: Vec<Bytes> = vec!["a", "b", "c"]
+            .into_iter()
+            .map(bytes::Bytes::from)
+            .collect();
+        assert_eq!(bt.keys().unwrap(), expected);
+    }
+
+    fn no_keys(new: fn() -> Box<dyn Indexer>) {
+        let bt = index!(new;);
+        let expected: Vec<Bytes> = vec![];
+        assert_eq!(bt.keys().unwrap(), expected);
+    }
+
+    #[test]
+    fn hashmap_seeks_like_btree() {
+        let (btree, hashmap) = (BTree::new(), HashIndex::with_capacity(0));
+        for i in 0..200 {
+            let pos = LogRecordPos {
+                file_id: 0,
+                offset: i,
+                size: 0,
+            };
+            let key = Bytes::from(format!("key-{:03}", i * 5));
+            btree.put(key.clone(), pos);
+            hashmap.put(key, pos);
+        }
+
+        let mut rng = fastrand::Rng::with_seed(0);
+        for reverse in [false, true] {
+            for prefix in [None, Some("key-1")] {
+                let opts = || {
+                    let opts = IteratorOptions::new().reverse(reverse);
+                    match prefix {
+                        Some(prefix) => opts.prefix(prefix),
+                        None => opts,
+                    }
+                };
+                let (mut expected, mut iter) = (btree.iterator(opts()), hashmap.iterator(opts()));
+                for _ in 0..100 {
+                    let target = format!("key-{:03}", rng.u32(..1100)).into_bytes();
+                    expected.seek(target.clone());
+                    iter.seek(target.clone());
+                    assert_eq!(iter.next(), expected.next());
+                    assert_eq!(iter.next(), expected.next());
+                    expected.seek_for_prev(target.clone());
+                    iter.seek_for_prev(target);
+                    assert_eq!(iter.next(), expected.next());
+                    assert_eq!(iter.next(), expected.next());
+                }
+            }
+        }
+    }
+
+    macro_rules! index_suite {
+        ($index:ident => $new:expr; $($test:ident),*) => {
+            mod $index {
+                $(
+                    #[test]
+                    fn $test() {
+                        super::$test(|| Box::new($new))
+                    }
+                )*
+            }
+        };
+    }
+
+    /// Runs each of the tests, given how to build an empty index, for every index type
+    macro_rules! for_each_index {
+        ($($test:ident),* $(,)?) => {
+            index_suite!(btree => crate::index::btree::BTree::new(); $($test),*);
+            index_suite!(skiplist => crate::index::skiplist::SkipList::new(); $($test),*);
+            index_suite!(hashmap => crate::index::hashmap::HashIndex::with_capacity(0); $($test),*);
+        };
+    }
+
+    for_each_index!(
+        put,
+        get,
+        delete,
+        lookups_do_not_allocate,
+        seek_when_empty,
+        seek_larger_than,
+        seek_equal,
+        seek_larger_than_reverse,
+        seek_equal_reverse,
+        iterator_is_a_snapshot,
+        rewind,
+        filter_iter,
+        limited_iter,
+        prefix_iter,
+        prefix_iter_reverse,
+        prefix_seek_clamped,
+        reverse_seek_below_min,
+        reverse_seek_above_max,
+        reverse_seek_at_boundaries,
+        reverse_seek_when_empty,
+        forward_seek_out_of_snapshot,
+        seek_for_prev,
+        seek_for_prev_reverse,
+        seek_to_first_and_last,
+        seek_to_first_and_last_when_empty,
+        bounded_iter,
+        bounded_iter_empty_intersection,
+        bounded_iter_with_prefix,
+        bounded_rewind_and_seek,
+        some_keys,
+        no_keys,
+    );
 }
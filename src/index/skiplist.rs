@@ -0,0 +1,290 @@
+use crate::data::data_file::DataFile;
+use crate::data::log_record::LogRecordPos;
+use crate::errors::Result;
+use crate::index::{replay, IndexIterator, Indexable, Indexer, ReplayProgress, SnapshotIterator};
+use crate::options::IteratorOptions;
+use bytes::Bytes;
+use parking_lot::RwLock;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Levels of the skip list, enough for 4^16 keys
+const MAX_HEIGHT: usize = 16;
+
+/// An ordered index on a single-threaded skip list behind one lock, it is *not* a lock-free
+/// concurrent skip list: like the [BTree](crate::index::btree::BTree) index, a write blocks
+/// every lookup until it is done. Iterating over it copies the keys in range first.
+pub struct SkipList {
+    list: Arc<RwLock<SkipMap>>,
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        SkipList {
+            list: Arc::new(RwLock::new(SkipMap::new())),
+        }
+    }
+}
+
+impl Indexable for SkipList {
+    fn index<'a, D>(
+        datafiles: D,
+        _expected_keys: Option<usize>,
+        progress: &mut ReplayProgress,
+    ) -> Result<Box<dyn Indexer>>
+    where
+        D: IntoIterator<Item = &'a DataFile>,
+        Self: Sized,
+    {
+        // a skip list allocates its nodes one at a time, it has no capacity to reserve
+        let index = SkipList::new();
+        replay(&index, datafiles, progress)?;
+        Ok(Box::new(index))
+    }
+}
+
+impl Indexer for SkipList {
+    fn put(&self, key: Bytes, pos: LogRecordPos) -> Option<LogRecordPos> {
+        self.list.write().insert(key, pos)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<LogRecordPos> {
+        self.list.read().get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Option<LogRecordPos> {
+        self.list.write().remove(key)
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        let read = self.list.read();
+        let items = match options.key_range() {
+            None => Vec::new(),
+            Some((lower, upper)) => read
+                .range(
+                    lower.as_ref().map(Vec::as_slice),
+                    upper.as_ref().map(Vec::as_slice),
+                )
+                .map(|(key, pos)| (key.clone(), *pos))
+                .collect(),
+        };
+        Box::new(SnapshotIterator::new(items, options))
+    }
+
+    fn keys(&self) -> Result<Vec<Bytes>> {
+        let read = self.list.read();
+        Ok(read
+            .range(Bound::Unbounded, Bound::Unbounded)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn len(&self) -> usize {
+        self.list.read().len
+    }
+}
+
+struct Node {
+    key: Bytes,
+    pos: LogRecordPos,
+    /// The next node of each level the node is linked in, its height is the length
+    next: Vec<Option<usize>>,
+}
+
+/// A skip list of nodes kept in an arena, linked by their slot. `None` links to the end
+/// of a level, or stands for the head of the list as a predecessor.
+struct SkipMap {
+    head: [Option<usize>; MAX_HEIGHT],
+    nodes: Vec<Option<Node>>,
+    /// slots of the removed nodes, reused first
+    free: Vec<usize>,
+    len: usize,
+    /// state of the xorshift drawing the height of the nodes
+    seed: u64,
+}
+
+impl SkipMap {
+    fn new() -> Self {
+        SkipMap {
+            head: [None; MAX_HEIGHT],
+            nodes: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn node(&self, slot: usize) -> &Node {
+        self.nodes[slot].as_ref().unwrap()
+    }
+
+    /// The node following `prev` at `level`
+    fn next(&self, prev: Option<usize>, level: usize) -> Option<usize> {
+        match prev {
+            None => self.head[level],
+            Some(slot) => self.node(slot).next[level],
+        }
+    }
+
+    fn link(&mut self, prev: Option<usize>, level: usize, next: Option<usize>) {
+        match prev {
+            None => self.head[level] = next,
+            Some(slot) => self.nodes[slot].as_mut().unwrap().next[level] = next,
+        }
+    }
+
+    /// The last node of each level whose key is less than `key`
+    fn predecessors(&self, key: &[u8]) -> [Option<usize>; MAX_HEIGHT] {
+        let mut preds = [None; MAX_HEIGHT];
+        let mut prev = None;
+        for level in (0..MAX_HEIGHT).rev() {
+            while let Some(next) = self.next(prev, level) {
+                if self.node(next).key[..] >= *key {
+                    break;
+                }
+                prev = Some(next);
+            }
+            preds[level] = prev;
+        }
+        preds
+    }
+
+    /// The node of `key` following its predecessors, if any
+    fn find(&self, key: &[u8], preds: &[Option<usize>; MAX_HEIGHT]) -> Option<usize> {
+        self.next(preds[0], 0)
+            .filter(|slot| self.node(*slot).key[..] == *key)
+    }
+
+    /// Each level holds about a quarter of the nodes of the level below
+    fn random_height(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let height = 1 + (self.seed.trailing_zeros() / 2) as usize;
+        height.min(MAX_HEIGHT)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<LogRecordPos> {
+        let preds = self.predecessors(key);
+        self.find(key, &preds).map(|slot| self.node(slot).pos)
+    }
+
+    /// Inserts or updates the entry of `key`, returns the position it replaced
+    fn insert(&mut self, key: Bytes, pos: LogRecordPos) -> Option<LogRecordPos> {
+        let preds = self.predecessors(&key);
+        if let Some(slot) = self.find(&key, &preds) {
+            let node = self.nodes[slot].as_mut().unwrap();
+            return Some(std::mem::replace(&mut node.pos, pos));
+        }
+
+        let height = self.random_height();
+        let node = Node {
+            key,
+            pos,
+            next: (0..height)
+                .map(|level| self.next(preds[level], level))
+                .collect(),
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        for (level, prev) in preds.iter().enumerate().take(height) {
+            self.link(*prev, level, Some(slot));
+        }
+        self.len += 1;
+        None
+    }
+
+    /// Removes the entry of `key`, returns its position
+    fn remove(&mut self, key: &[u8]) -> Option<LogRecordPos> {
+        let preds = self.predecessors(key);
+        let slot = self.find(key, &preds)?;
+        let node = self.nodes[slot].take().unwrap();
+        for (level, next) in node.next.into_iter().enumerate() {
+            self.link(preds[level], level, next);
+        }
+        self.free.push(slot);
+        self.len -= 1;
+        Some(node.pos)
+    }
+
+    /// The entries within the bounds, in key order
+    fn range<'a>(
+        &'a self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = (&'a Bytes, &'a LogRecordPos)> + 'a {
+        let mut first = match lower {
+            Bound::Unbounded => self.head[0],
+            Bound::Included(key) | Bound::Excluded(key) => self.next(self.predecessors(key)[0], 0),
+        };
+        if let (Bound::Excluded(key), Some(slot)) = (lower, first) {
+            if self.node(slot).key[..] == *key {
+                first = self.node(slot).next[0];
+            }
+        }
+
+        std::iter::successors(first, |slot| self.node(*slot).next[0])
+            .map(|slot| self.node(slot))
+            .take_while(move |node| match upper {
+                Bound::Unbounded => true,
+                Bound::Included(key) => node.key[..] <= *key,
+                Bound::Excluded(key) => node.key[..] < *key,
+            })
+            .map(|node| (&node.key, &node.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(offset: u64) -> LogRecordPos {
+        LogRecordPos {
+            file_id: 0,
+            offset,
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn skip_map_against_btree_map() {
+        // the index test suite runs on few keys, the levels only show up with many
+        let mut list = SkipMap::new();
+        let mut model = std::collections::BTreeMap::new();
+        let mut rng = fastrand::Rng::with_seed(0);
+        for i in 0..20_000 {
+            let key = Bytes::from(format!("{:05}", rng.u32(..5_000)));
+            match rng.u8(..3) {
+                0 => assert_eq!(list.remove(&key), model.remove(&key)),
+                _ => assert_eq!(list.insert(key.clone(), pos(i)), model.insert(key, pos(i))),
+            }
+            assert_eq!(list.len, model.len());
+        }
+        assert!(list.head.iter().filter(|next| next.is_some()).count() > 4);
+        assert!(list
+            .range(Bound::Unbounded, Bound::Unbounded)
+            .eq(model.iter()));
+        for key in ["00000", "01234", "02500", "04999", "5"] {
+            let key = key.as_bytes();
+            assert_eq!(list.get(key), model.get(key).copied());
+            for (lower, upper) in [
+                (Bound::Excluded(key), Bound::Unbounded),
+                (Bound::Included(key), Bound::Unbounded),
+                (Bound::Unbounded, Bound::Included(key)),
+                (Bound::Unbounded, Bound::Excluded(key)),
+            ] {
+                assert!(list
+                    .range(lower, upper)
+                    .eq(model.range::<[u8], _>((lower, upper))));
+            }
+        }
+    }
+}
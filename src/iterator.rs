@@ -1,95 +1,649 @@
+use crate::data::log_record::LogRecordPos;
 use crate::engine::Engine;
-use crate::errors::Result;
+use crate::errors::{ErrorKey, Errors, Result};
 use crate::index::IndexIterator;
-use crate::options::IteratorOptions;
+use crate::merge::DatafilePins;
+use crate::options;
+use crate::options::{max_lower, min_upper, IteratorOptions, ValueFilter};
 use bytes::Bytes;
+use error_stack::Report;
+#[cfg(feature = "serde")]
+use error_stack::ResultExt;
+use std::ops::{Bound, ControlFlow, RangeBounds};
+use std::sync::Arc;
 
-#[derive(Debug, Eq, PartialEq)]
+/// A key-value pair yielded by [EngineIterator].
+///
+/// With the `serde` feature enabled, an entry (de)serializes as `{"key": .., "value": ..}`
+/// where both the key and the value are tagged with their encoding: bytes that are valid
+/// UTF-8 are kept as is, e.g. `{"encoding": "utf8", "data": "Hello"}`, anything else is
+/// encoded with the standard padded base64 alphabet, e.g. `{"encoding": "base64", "data": "AP8="}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entry {
+    #[cfg_attr(feature = "serde", serde(with = "encoded"))]
     key: Bytes,
+    #[cfg_attr(feature = "serde", serde(with = "encoded"))]
     value: Bytes,
 }
 
-pub struct EngineIterator<'a> {
+impl Entry {
+    /// Creates an entry, mostly useful to build the expected output of an iteration.
+    ///
+    /// ```
+    /// use ailurus_kv::iterator::Entry;
+    ///
+    /// let entry = Entry::new("Hello", "World");
+    /// assert_eq!(entry.key(), "Hello");
+    /// assert_eq!(entry.value(), "World");
+    /// ```
+    pub fn new<K: Into<Bytes>, V: Into<Bytes>>(key: K, value: V) -> Self {
+        Entry {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    /// Returns the value of the entry.
+    pub fn value(&self) -> &Bytes {
+        &self.value
+    }
+
+    /// Consumes the entry, returning its key and value.
+    ///
+    /// ```
+    /// use ailurus_kv::iterator::Entry;
+    ///
+    /// let (key, value) = Entry::new("Hello", "World").into_parts();
+    /// assert_eq!(key, "Hello");
+    /// assert_eq!(value, "World");
+    /// ```
+    pub fn into_parts(self) -> (Bytes, Bytes) {
+        (self.key, self.value)
+    }
+}
+
+/// Same as [Entry::into_parts], so that entries collect into a map or a vector of pairs.
+///
+/// ```
+/// use ailurus_kv::engine::Engine;
+/// use ailurus_kv::errors::Result;
+/// use ailurus_kv::options::IteratorOptions;
+/// use bytes::Bytes;
+/// use std::collections::HashMap;
+///
+/// let engine = Engine::open_temporary().unwrap();
+/// engine.put("b".into(), "2".into()).unwrap();
+/// engine.put("a".into(), "1".into()).unwrap();
+///
+/// let pairs: Vec<(Bytes, Bytes)> = engine
+///     .iter(IteratorOptions::default())
+///     .unwrap()
+///     .map(|entry| entry.map(Into::into))
+///     .collect::<Result<_>>()
+///     .unwrap();
+/// assert_eq!(pairs, vec![("a".into(), "1".into()), ("b".into(), "2".into())]);
+///
+/// let map: HashMap<Bytes, Bytes> = pairs.into_iter().collect();
+/// assert_eq!(map[&Bytes::from("b")], "2");
+/// ```
+impl From<Entry> for (Bytes, Bytes) {
+    fn from(entry: Entry) -> Self {
+        entry.into_parts()
+    }
+}
+
+/// Position of an iteration in the index, shared by [EngineIterator] and [OwnedEngineIterator]
+struct Cursor {
     index_iterator: Box<dyn IndexIterator>,
+    /// the datafiles the positions of the index iterator point into
+    _pins: DatafilePins,
+    keys_only: bool,
+    report_tombstones: bool,
+    value_filter: Option<ValueFilter>,
+}
+
+impl Cursor {
+    fn new(engine: &Engine, mut options: IteratorOptions) -> Result<Cursor> {
+        options::check_iterator_options(&options)?;
+
+        let value_filter = options.value_filter.take();
+        let keys_only = options.keys_only;
+        let report_tombstones = options.report_tombstones;
+        let (pins, index_iterator) = engine.pinned(|| engine.index.iterator(options));
+        Ok(Cursor {
+            keys_only,
+            report_tombstones,
+            value_filter,
+            index_iterator,
+            _pins: pins,
+        })
+    }
+
+    /// Returns the next entry accepted by the value filter along with its position,
+    /// the candidates rejected by the filter are skipped.
+    fn next_entry(&mut self, engine: &Engine) -> Option<(LogRecordPos, Result<Entry>)> {
+        while let Some((key, pos)) = self.index_iterator.next() {
+            let value = match self.keys_only {
+                true => Bytes::new(),
+                false => match engine.at(pos) {
+                    Ok(Some(value)) => value,
+                    // the index never points at a tombstone unless out of sync with the
+                    // datafiles, there is no value to yield
+                    Ok(None) if self.report_tombstones => {
+                        let report = Report::new(Errors::KeyNotFound)
+                            .attach_printable(ErrorKey::new(key))
+                            .attach_printable("The index points at a tombstone");
+                        return Some((*pos, Err(report)));
+                    }
+                    Ok(None) => continue,
+                    Err(e) => return Some((*pos, Err(e))),
+                },
+            };
+
+            if let Some(filter) = self.value_filter.as_ref() {
+                if !filter(&value) {
+                    continue;
+                }
+            }
+
+            let entry = Entry {
+                key: key.clone(),
+                value,
+            };
+            return Some((*pos, Ok(entry)));
+        }
+        None
+    }
+}
+
+pub struct EngineIterator<'a> {
+    cursor: Cursor,
     engine: &'a Engine,
+    /// entry read ahead by [EngineIterator::peek], yielded before reading the index again
+    peeked: Option<(LogRecordPos, Result<Entry>)>,
 }
 
 impl Engine {
-    pub fn iter(&self, options: IteratorOptions) -> EngineIterator {
-        EngineIterator {
-            index_iterator: self.index.iterator(options),
+    /// Returns an iterator over the entries of the engine.
+    ///
+    /// The iterator captures a snapshot of the index at creation, so it yields exactly the
+    /// keys that were live at that moment, no matter what is written afterward.
+    pub fn iter(&self, options: IteratorOptions) -> Result<EngineIterator<'_>> {
+        Ok(EngineIterator {
+            cursor: Cursor::new(self, options)?,
             engine: self,
+            peeked: None,
+        })
+    }
+
+    /// Returns an iterator over the keys starting with `prefix`, in ascending order.
+    /// Same as [Engine::iter] with [IteratorOptions::prefix], only the keys of the prefix
+    /// range are ever visited.
+    pub fn prefix_iter<P: Into<Vec<u8>>>(&self, prefix: P) -> Result<EngineIterator<'_>> {
+        self.iter(IteratorOptions::new().prefix(prefix))
+    }
+
+    /// Returns an iterator over the keys within `range`, from the largest one if `reverse`.
+    /// Same as [Engine::iter] with the bounds of the range as [IteratorOptions::lower_bound]
+    /// and [IteratorOptions::upper_bound], no key out of the range is ever visited.
+    ///
+    /// ```
+    /// use ailurus_kv::engine::Engine;
+    ///
+    /// let engine = Engine::open_temporary().unwrap();
+    /// for key in ["a", "b", "c", "d"] {
+    ///     engine.put(key.into(), key.into()).unwrap();
+    /// }
+    /// let keys: Vec<_> = engine.range(b"b".to_vec()..b"d".to_vec(), true).unwrap().keys().collect();
+    /// assert_eq!(keys, vec!["c", "b"]);
+    /// ```
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        reverse: bool,
+    ) -> Result<EngineIterator<'_>> {
+        let options = IteratorOptions::new()
+            .reverse(reverse)
+            .lower_bound(range.start_bound().cloned())
+            .upper_bound(range.end_bound().cloned());
+        self.iter(options)
+    }
+
+    /// Returns an iterator owning a handle to the engine, which unlike [Engine::iter]
+    /// can outlive the borrow of the engine, e.g. be returned or moved to another thread.
+    ///
+    /// The iterator works on a snapshot as well, reading a datafile fails with an error
+    /// item instead of ending the iteration.
+    pub fn iter_owned(self: &Arc<Self>, options: IteratorOptions) -> Result<OwnedEngineIterator> {
+        Ok(OwnedEngineIterator {
+            cursor: Cursor::new(self, options)?,
+            engine: self.clone(),
+        })
+    }
+
+    /// Returns an iterator over the keys selected by `options`, see [EngineIterator::keys].
+    /// The keys come from the index alone, no value is read. A value filter is rejected with
+    /// [Errors::InvalidIteratorOptions], [Engine::try_keys] evaluates it.
+    pub fn keys(&self, options: IteratorOptions) -> Result<impl Iterator<Item = Bytes> + '_> {
+        if options.value_filter.is_some() {
+            return Err(
+                Report::new(Errors::InvalidIteratorOptions).attach_printable(
+                    "`keys` can not evaluate a `value_filter`, reading the values may fail",
+                ),
+            );
         }
+        Ok(self.iter(options)?.keys())
+    }
+
+    /// Returns an iterator over the keys selected by `options`, see [EngineIterator::try_keys].
+    /// Reading a value for the value filter fails with an error item.
+    pub fn try_keys(
+        &self,
+        options: IteratorOptions,
+    ) -> Result<impl Iterator<Item = Result<Bytes>> + '_> {
+        Ok(self.iter(options)?.try_keys())
     }
 
-    pub fn keys(&self) -> Result<Vec<Bytes>> {
-        self.index.keys()
+    /// Returns an iterator over the values selected by `options`, in the order of their
+    /// keys. Reading a datafile fails with an error item instead of ending the iteration.
+    pub fn values(
+        &self,
+        options: IteratorOptions,
+    ) -> Result<impl Iterator<Item = Result<Bytes>> + '_> {
+        Ok(self.iter(options)?.values())
+    }
+
+    /// Returns up to `limit` entries coming strictly after the key `after` in iteration order,
+    /// together with the token to resume from, which is `None` once the iteration is exhausted.
+    ///
+    /// No state is kept between calls, passing the returned token as `after` fetches the next page.
+    /// The limit of `opts`, if any, bounds the size of every page along `limit`.
+    pub fn scan_page(
+        &self,
+        after: Option<Bytes>,
+        limit: usize,
+        mut opts: IteratorOptions,
+    ) -> Result<(Vec<Entry>, Option<Bytes>)> {
+        let limit = opts.limit.take().map_or(limit, |max| max.min(limit));
+        if limit == 0 {
+            return Ok((Vec::new(), after));
+        }
+
+        // resume strictly after the token by narrowing the range of the index iterator
+        if let Some(after) = after {
+            let after = Bound::Excluded(after.to_vec());
+            match opts.reverse {
+                true => opts.upper_bound = min_upper(opts.upper_bound, after),
+                false => opts.lower_bound = max_lower(opts.lower_bound, after),
+            }
+        }
+
+        let mut iter = self.iter(opts)?;
+        let mut entries = Vec::with_capacity(limit);
+
+        while entries.len() < limit {
+            match iter.next_entry() {
+                None => return Ok((entries, None)),
+                Some(entry) => entries.push(entry?),
+            }
+        }
+
+        // only a candidate accepted by the value filter proves there is a next page
+        let exhausted = match iter.cursor.value_filter {
+            None => iter.cursor.index_iterator.next().is_none(),
+            Some(_) => iter.next_entry().transpose()?.is_none(),
+        };
+        let token = match exhausted {
+            true => None,
+            false => entries.last().map(|entry| entry.key.clone()),
+        };
+        Ok((entries, token))
+    }
+
+    /// Calls `f` with every key-value pair selected by `opts` in iteration order,
+    /// until `f` returns [ControlFlow::Break] or the entries are exhausted.
+    ///
+    /// Unlike [Engine::iter] no [Entry] is built, the key is borrowed from the index
+    /// and the value from the record read off the datafile. The value is empty if
+    /// `keys_only` is set. Errors from reading a datafile stop the scan.
+    pub fn scan_with<F>(&self, mut opts: IteratorOptions, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<()>,
+    {
+        options::check_iterator_options(&opts)?;
+
+        let keys_only = opts.keys_only;
+        let value_filter = opts.value_filter.take();
+        let (_pins, mut iter) = self.pinned(|| self.index.iterator(opts));
+
+        while let Some((key, pos)) = iter.next() {
+            let record = match keys_only {
+                true => None,
+                false => match self.record_at(pos)? {
+                    Some(record) => Some(record),
+                    None => continue,
+                },
+            };
+            let value = record.as_ref().map_or(&[][..], |x| x.value.as_slice());
+
+            if let Some(filter) = value_filter.as_ref() {
+                if !filter(value) {
+                    continue;
+                }
+            }
+
+            if f(key, value).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every key-value pair in key order, until `f` returns `false` or the
+    /// entries are exhausted. The values are read one at a time as `f` gets to them, an
+    /// error reading one stops the fold.
+    pub fn fold<F: FnMut(Bytes, Bytes) -> bool>(&self, f: F) -> Result<()> {
+        self.fold_with(IteratorOptions::default(), f)
+    }
+
+    /// Like [Engine::fold], restricted to the keys starting with `prefix`
+    pub fn fold_prefix<P, F>(&self, prefix: P, f: F) -> Result<()>
+    where
+        P: Into<Vec<u8>>,
+        F: FnMut(Bytes, Bytes) -> bool,
+    {
+        self.fold_with(IteratorOptions::new().prefix(prefix), f)
+    }
+
+    fn fold_with<F: FnMut(Bytes, Bytes) -> bool>(
+        &self,
+        opts: IteratorOptions,
+        mut f: F,
+    ) -> Result<()> {
+        let mut iter = self.iter(opts)?;
+        while let Some(entry) = iter.next_entry() {
+            let (key, value) = entry?.into_parts();
+            if !f(key, value) {
+                break;
+            }
+        }
+        Ok(())
     }
 }
 
-impl EngineIterator<'_> {
+impl<'a> EngineIterator<'a> {
     pub fn rewind(&mut self) {
-        self.index_iterator.rewind();
+        self.peeked = None;
+        self.cursor.index_iterator.rewind();
     }
 
     pub fn seek(&mut self, key: Vec<u8>) {
-        self.index_iterator.seek(key);
+        self.peeked = None;
+        self.cursor.index_iterator.seek(key);
     }
 
-    pub fn next(&mut self) -> Option<Entry> {
-        if let Some((key, pos)) = self.index_iterator.next() {
-            let value = self.engine.at(pos).unwrap();
-            return Some(Entry {
-                key: key.to_vec().into(),
-                value,
-            });
+    /// Positions the iterator at the greatest key less than or equal to `key`,
+    /// so that the next entry yielded is the closest one at or before `key`.
+    ///
+    /// For a reverse iterator the meaning is mirrored: it is positioned at the
+    /// smallest key greater than or equal to `key`. If no such key exists within
+    /// the range of the iterator, the iterator is exhausted.
+    pub fn seek_for_prev(&mut self, key: Vec<u8>) {
+        self.peeked = None;
+        self.cursor.index_iterator.seek_for_prev(key);
+    }
+
+    /// Positions the iterator at the first entry in iteration order,
+    /// which is the entry with the largest key if the iterator is reversed.
+    pub fn seek_to_first(&mut self) {
+        self.peeked = None;
+        self.cursor.index_iterator.seek_to_first();
+    }
+
+    /// Positions the iterator at the last entry in iteration order,
+    /// which is the entry with the smallest key if the iterator is reversed.
+    pub fn seek_to_last(&mut self) {
+        self.peeked = None;
+        self.cursor.index_iterator.seek_to_last();
+    }
+
+    /// Consumes the iterator, yielding only the keys.
+    ///
+    /// The keys come straight from the index, no datafile is read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the iterator has a value filter, evaluating it reads the values, which may
+    /// fail. [EngineIterator::try_keys] yields the failures.
+    pub fn keys(mut self) -> impl Iterator<Item = Bytes> + 'a {
+        assert!(
+            self.cursor.value_filter.is_none(),
+            "`keys` can not evaluate a `value_filter`, use `try_keys`"
+        );
+        self.cursor.keys_only = true;
+        self.map(|entry| entry.unwrap().key)
+    }
+
+    /// Consumes the iterator, yielding only the keys. No datafile is read unless a value
+    /// filter has to be evaluated, a value that fails to be read yields an error item.
+    pub fn try_keys(mut self) -> impl Iterator<Item = Result<Bytes>> + 'a {
+        self.cursor.keys_only = self.cursor.value_filter.is_none();
+        self.map(|entry| entry.map(|x| x.key))
+    }
+
+    /// Consumes the iterator, yielding only the values.
+    pub fn values(self) -> impl Iterator<Item = Result<Bytes>> + 'a {
+        self.map(|entry| entry.map(|x| x.value))
+    }
+
+    /// Returns a reference to the next entry without advancing the iterator,
+    /// the entry is yielded by the following call to `next`.
+    ///
+    /// Repositioning the iterator, e.g. with [EngineIterator::seek], discards the peeked entry.
+    pub fn peek(&mut self) -> Option<&Result<Entry>> {
+        if self.peeked.is_none() {
+            self.peeked = self.cursor.next_entry(self.engine);
+        }
+        self.peeked.as_ref().map(|(_, entry)| entry)
+    }
+
+    /// Consumes the remaining entries, returning how many there are.
+    ///
+    /// Unlike [Iterator::count], only the index is walked, no value is read
+    /// unless a value filter has to be evaluated.
+    pub fn count_keys(&mut self) -> usize {
+        let mut count = 0;
+        self.drain(|_| count += 1);
+        count
+    }
+
+    /// Consumes the remaining entries, returning the size their records take up
+    /// in the datafiles. Like [EngineIterator::count_keys], no value is read unless
+    /// a value filter has to be evaluated.
+    pub fn approx_bytes(&mut self) -> u64 {
+        let mut bytes = 0;
+        self.drain(|pos| bytes += pos.size as u64);
+        bytes
+    }
+
+    /// Consumes the remaining entries, calling `f` with the position of each of them
+    fn drain(&mut self, mut f: impl FnMut(&LogRecordPos)) {
+        if let Some((pos, _)) = self.peeked.take() {
+            f(&pos);
+        }
+        match self.cursor.value_filter {
+            None => {
+                while let Some((_, pos)) = self.cursor.index_iterator.next() {
+                    f(pos);
+                }
+            }
+            Some(_) => {
+                while let Some((pos, _)) = self.cursor.next_entry(self.engine) {
+                    f(&pos);
+                }
+            }
+        }
+    }
+
+    /// Returns the peeked entry if any, the next entry of the cursor otherwise.
+    pub(crate) fn next_entry(&mut self) -> Option<Result<Entry>> {
+        self.peeked
+            .take()
+            .or_else(|| self.cursor.next_entry(self.engine))
+            .map(|(_, entry)| entry)
+    }
+}
+
+/// Iterator owning a handle to its engine, see [Engine::iter_owned]
+pub struct OwnedEngineIterator {
+    cursor: Cursor,
+    engine: Arc<Engine>,
+}
+
+impl Iterator for OwnedEngineIterator {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next_entry(&self.engine).map(|(_, entry)| entry)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl EngineIterator<'_> {
+    /// Writes up to `limit` entries to `writer` as a JSON array, returning the number of
+    /// entries written. See [Entry] for how keys and values are encoded.
+    pub fn write_json<W: std::io::Write>(mut self, writer: W, limit: usize) -> Result<usize> {
+        use serde::ser::{SerializeSeq, Serializer};
+
+        let mut serializer = serde_json::Serializer::new(writer);
+        let mut seq = serializer
+            .serialize_seq(None)
+            .change_context(Errors::FailToSerialize)?;
+
+        let mut written = 0;
+        while written < limit {
+            match self.next_entry() {
+                None => break,
+                Some(entry) => seq
+                    .serialize_element(&entry?)
+                    .change_context(Errors::FailToSerialize)?,
+            }
+            written += 1;
+        }
+
+        seq.end().change_context(Errors::FailToSerialize)?;
+        Ok(written)
+    }
+
+    /// Collects up to `limit` entries into a JSON array, see [EngineIterator::write_json]
+    pub fn collect_json(self, limit: usize) -> Result<String> {
+        let mut buf = Vec::new();
+        self.write_json(&mut buf, limit)?;
+        // serde_json never produces invalid UTF-8
+        Ok(String::from_utf8(buf).unwrap())
+    }
+}
+
+/// (De)serializes bytes tagged with their encoding, see [Entry]
+#[cfg(feature = "serde")]
+mod encoded {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    #[serde(tag = "encoding", content = "data", rename_all = "lowercase")]
+    enum EncodedRef<'a> {
+        Utf8(&'a str),
+        Base64(String),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "encoding", content = "data", rename_all = "lowercase")]
+    enum Encoded {
+        Utf8(String),
+        Base64(String),
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => EncodedRef::Utf8(s),
+            Err(_) => EncodedRef::Base64(STANDARD.encode(bytes)),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Utf8(s) => Ok(s.into()),
+            Encoded::Base64(s) => STANDARD
+                .decode(s)
+                .map(Bytes::from)
+                .map_err(serde::de::Error::custom),
         }
-        None
     }
 }
 
+/// Reading a datafile fails with an error item instead of ending the iteration, e.g. a
+/// corrupted record or a datafile gone missing.
 impl<'a> std::iter::Iterator for EngineIterator<'a> {
-    type Item = Entry;
+    type Item = Result<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next()
+        self.next_entry()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType};
     use crate::engine;
-    use crate::iterator::Entry;
-    use crate::options::IteratorOptions;
+    use crate::engine::Engine;
+    use crate::errors::{ErrorKey, Errors, Result};
+    use crate::iterator::{EngineIterator, Entry};
+    use crate::mock::alloc::count_allocations;
+    use crate::mock::engine_wrapper::EngineWrapper;
+    use crate::options::{IteratorOptions, OptionsBuilder};
     use bytes::Bytes;
+    use std::ops::{Bound, ControlFlow};
+    use std::sync::Arc;
 
     macro_rules! entry {
         ($key:expr, $val:expr) => {{
-            $crate::iterator::Entry {
-                key: ::bytes::Bytes::from($key),
-                value: ::bytes::Bytes::from($val),
-            }
+            $crate::iterator::Entry::new($key, $val)
         }};
     }
 
     #[test]
     fn rewind() {
         let engine = engine!(["Hello", "World"], ["World", "Hello"]);
-        let mut iter = engine.iter(IteratorOptions::default());
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
         for _ in 0..2 {
             let _ = iter.next();
         }
         iter.rewind();
-        assert_eq!(iter.next(), Some(entry!["Hello", "World"]));
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["Hello", "World"])
+        );
     }
 
     #[test]
     fn std_iter() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let iterator = engine.iter(IteratorOptions::default());
+        let iterator = engine.iter(IteratorOptions::default()).unwrap();
         assert_eq!(
-            iterator.into_iter().collect::<Vec<Entry>>(),
+            iterator
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<Entry>>(),
             vec![
                 entry!["a", "val-a"],
                 entry!["b", "val-b"],
@@ -101,65 +655,964 @@ mod tests {
     #[test]
     fn iter() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let mut iter = engine.iter(IteratorOptions::default());
-        assert_eq!(iter.next(), Some(entry!["a", "val-a"]));
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
-        assert_eq!(iter.next(), Some(entry!["c", "val-c"]));
-        assert_eq!(iter.next(), None);
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.next().transpose().unwrap(), None);
     }
 
     #[test]
     fn reverse_iter() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let mut iter = engine.iter(IteratorOptions {
-            filter: Box::new(|_| true),
-            reverse: true,
-        });
-        assert_eq!(iter.next(), Some(entry!["c", "val-c"]));
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
-        assert_eq!(iter.next(), Some(entry!["a", "val-a"]));
+        let mut iter = engine.iter(IteratorOptions::new().reverse(true)).unwrap();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
     }
 
     #[test]
     fn reverse_rewind() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let mut iter = engine.iter(IteratorOptions {
-            filter: Box::new(|_| true),
-            reverse: true,
-        });
-        assert_eq!(iter.next(), Some(entry!["c", "val-c"]));
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
+        let mut iter = engine.iter(IteratorOptions::new().reverse(true)).unwrap();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
         iter.rewind();
-        assert_eq!(iter.next(), Some(entry!["c", "val-c"]));
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
-        assert_eq!(iter.next(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
     }
 
     #[test]
     fn seek() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let mut iter = engine.iter(IteratorOptions::default());
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
         iter.seek("b".into());
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+    }
+
+    #[test]
+    fn peek() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["a", "val-a"])
+        );
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["a", "val-a"])
+        );
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["c", "val-c"])
+        );
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.peek().map(|entry| entry.as_ref().unwrap()), None);
+        assert_eq!(iter.next().transpose().unwrap(), None);
+    }
+
+    #[test]
+    fn peek_then_seek() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let mut iter = engine.iter(IteratorOptions::new().reverse(true)).unwrap();
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["c", "val-c"])
+        );
+        iter.seek("b".into());
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["b", "val-b"])
+        );
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["a", "val-a"])
+        );
+        iter.rewind();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+
+        assert_eq!(
+            iter.peek().map(|entry| entry.as_ref().unwrap()),
+            Some(&entry!["b", "val-b"])
+        );
+        iter.seek_to_last();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.peek().map(|entry| entry.as_ref().unwrap()), None);
+
+        // peeking at the end does not prevent repositioning
+        iter.seek_for_prev("b".into());
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+    }
+
+    #[test]
+    fn owned_iter_on_other_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = OptionsBuilder::default()
+            .dir_path(dir.path().to_path_buf())
+            .build()
+            .unwrap();
+        let engine = Engine::new(opts).unwrap();
+        for (key, value) in [("a", "val-a"), ("b", "val-b"), ("c", "val-c")] {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+
+        let engine = Arc::new(engine);
+        let iter = engine
+            .iter_owned(IteratorOptions::new().reverse(true))
+            .unwrap();
+        let entries = std::thread::spawn(move || iter.collect::<Result<Vec<Entry>>>())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                entry!["c", "val-c"],
+                entry!["b", "val-b"],
+                entry!["a", "val-a"]
+            ]
+        );
     }
 
     #[test]
     fn reverse_seek() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
-        let mut iter = engine.iter(IteratorOptions {
-            filter: Box::new(|_| true),
-            reverse: true,
-        });
+        let mut iter = engine.iter(IteratorOptions::new().reverse(true)).unwrap();
         iter.seek("b".into());
-        assert_eq!(iter.next(), Some(entry!["b", "val-b"]));
-        assert_eq!(iter.next(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+    }
+
+    #[test]
+    fn entry_accessors() {
+        let engine = engine!(["a", "val-a"]);
+        let entry = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.key(), "a");
+        assert_eq!(entry.value(), "val-a");
+        assert_eq!(entry.clone().into_parts(), ("a".into(), "val-a".into()));
+    }
+
+    #[test]
+    fn prefix_iter() {
+        let engine = engine!(["aa", "val-aa"], ["ab", "val-ab"], ["b", "val-b"]);
+        let iter = engine.iter(IteratorOptions::new().prefix("a")).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["aa", "val-aa"], entry!["ab", "val-ab"]]
+        );
+
+        let iter = engine
+            .iter(IteratorOptions::new().reverse(true).prefix("a"))
+            .unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["ab", "val-ab"], entry!["aa", "val-aa"]]
+        );
+    }
+
+    #[test]
+    fn range() {
+        use Bound::{Excluded, Included, Unbounded};
+        let engine = engine!(["a", "1"], ["b", "2"], ["c", "3"], ["d", "4"]);
+        let keys = |range: (Bound<&str>, Bound<&str>), reverse: bool| {
+            let range = (
+                range.0.map(|key| key.as_bytes().to_vec()),
+                range.1.map(|key| key.as_bytes().to_vec()),
+            );
+            let iter = engine.range(range, reverse).unwrap();
+            iter.keys().collect::<Vec<Bytes>>()
+        };
+
+        assert_eq!(keys((Included("b"), Excluded("d")), false), vec!["b", "c"]);
+        assert_eq!(keys((Included("b"), Excluded("d")), true), vec!["c", "b"]);
+        assert_eq!(keys((Excluded("b"), Included("d")), false), vec!["c", "d"]);
+        assert_eq!(keys((Excluded("b"), Included("d")), true), vec!["d", "c"]);
+        assert_eq!(keys((Unbounded, Excluded("c")), true), vec!["b", "a"]);
+        assert_eq!(keys((Excluded("b"), Unbounded), false), vec!["c", "d"]);
+        assert_eq!(keys((Unbounded, Unbounded), true), vec!["d", "c", "b", "a"]);
+        // ranges matching no key
+        assert!(keys((Excluded("a"), Excluded("b")), false).is_empty());
+        assert!(keys((Included("bb"), Included("bz")), true).is_empty());
+        assert!(keys((Included("d"), Included("a")), false).is_empty());
+        assert!(keys((Excluded("e"), Unbounded), true).is_empty());
+
+        // the standard ranges
+        let iter = engine.range(b"b".to_vec()..=b"c".to_vec(), false).unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["b", "2"], entry!["c", "3"]]
+        );
+        let iter = engine.range(..b"b".to_vec(), true).unwrap();
+        assert_eq!(iter.keys().collect::<Vec<Bytes>>(), vec!["a"]);
+
+        // seeking stays in the range
+        let mut iter = engine.range(b"b".to_vec()..b"d".to_vec(), false).unwrap();
+        iter.seek("a".into());
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "2"]));
+        iter.seek("cc".into());
+        assert_eq!(iter.next().transpose().unwrap(), None);
+    }
+
+    #[test]
+    fn prefix_iter_edges() {
+        let engine = engine!(
+            ["a", "val-a"],
+            ["ab", "val-ab"],
+            ["abc", "val-abc"],
+            ["ac", "val-ac"],
+            [b"b\xff".to_vec(), "val-bff"],
+            [b"b\xff\xff".to_vec(), "val-bffff"],
+            ["c", "val-c"]
+        );
+        let keys = |iter: EngineIterator| iter.keys().collect::<Vec<Bytes>>();
+
+        assert_eq!(keys(engine.prefix_iter("x").unwrap()), Vec::<Bytes>::new());
+        assert_eq!(
+            keys(engine.prefix_iter("abd").unwrap()),
+            Vec::<Bytes>::new()
+        );
+        // the prefix itself is a key
+        assert_eq!(
+            keys(engine.prefix_iter("ab").unwrap()),
+            vec![Bytes::from("ab"), Bytes::from("abc")]
+        );
+        // a prefix without a successor of the same length
+        assert_eq!(
+            keys(engine.prefix_iter(b"b\xff".to_vec()).unwrap()),
+            vec![Bytes::from(&b"b\xff"[..]), Bytes::from(&b"b\xff\xff"[..])]
+        );
+
+        // seeking never leaves the prefix range
+        let mut iter = engine.prefix_iter("a").unwrap();
+        iter.seek("abb".into());
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["abc", "val-abc"])
+        );
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["ac", "val-ac"])
+        );
+        assert_eq!(iter.next().transpose().unwrap(), None);
+        iter.seek("0".into());
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        iter.seek("b".into());
+        assert_eq!(iter.next().transpose().unwrap(), None);
+
+        let mut iter = engine
+            .iter(IteratorOptions::new().reverse(true).prefix("a"))
+            .unwrap();
+        iter.seek("abb".into());
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["ab", "val-ab"])
+        );
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), None);
+        iter.seek("z".into());
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["ac", "val-ac"])
+        );
+    }
+
+    #[test]
+    fn seek_for_prev() {
+        let engine = engine!(["10", "val-10"], ["20", "val-20"], ["30", "val-30"]);
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        iter.seek_for_prev("25".into());
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["20", "val-20"])
+        );
+        iter.seek_for_prev("05".into());
+        assert_eq!(iter.next().transpose().unwrap(), None);
+    }
+
+    #[test]
+    fn seek_for_prev_within_bounds() {
+        let engine = engine!(["10", "val-10"], ["20", "val-20"], ["30", "val-30"]);
+        let mut iter = engine
+            .iter(IteratorOptions::new().upper_bound(Bound::Excluded("30".into())))
+            .unwrap();
+        iter.seek_for_prev("35".into());
+        assert_eq!(
+            iter.next().transpose().unwrap(),
+            Some(entry!["20", "val-20"])
+        );
+
+        let mut iter = engine.iter(IteratorOptions::new().prefix("2")).unwrap();
+        iter.seek_for_prev("15".into());
+        assert_eq!(iter.next().transpose().unwrap(), None);
+    }
+
+    #[test]
+    fn seek_to_first_and_last() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        iter.seek_to_last();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+        assert_eq!(iter.next().transpose().unwrap(), None);
+        iter.seek_to_first();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["b", "val-b"]));
+
+        let mut iter = engine.iter(IteratorOptions::new().reverse(true)).unwrap();
+        iter.seek("b".into());
+        iter.seek_to_last();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        iter.seek_to_first();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["c", "val-c"]));
+    }
+
+    #[test]
+    fn keys_without_reading_datafile() {
+        let (engine, stats) = EngineWrapper::counting();
+        for (key, value) in [("a", "val-a"), ("b", "val-b"), ("c", "val-c")] {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+        let reads = stats.reads();
+
+        let keys = engine.iter(IteratorOptions::default()).unwrap().keys();
+        assert_eq!(
+            keys.collect::<Vec<Bytes>>(),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+        assert_eq!(stats.reads(), reads);
+
+        // whereas the values have to be read from the datafile
+        let _ = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .values()
+            .count();
+        assert!(stats.reads() > reads);
+    }
+
+    #[test]
+    fn engine_keys_and_values() {
+        let engine = engine!(["b", "val-b"], ["a", "val-a"], ["c", "val-c"]);
+        // a value that fails its CRC, only reading it tells
+        let pos = engine.index.get(b"b").unwrap();
+        let path = engine
+            .path()
+            .join(crate::data::data_file::datafile_name(pos.file_id));
+        let mut content = std::fs::read(&path).unwrap();
+        content[pos.offset as usize + pos.size as usize - 1] ^= 0x01;
+        std::fs::write(&path, content).unwrap();
+
+        let keys: Vec<Bytes> = engine.keys(IteratorOptions::default()).unwrap().collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        let keys: Vec<Bytes> = engine
+            .keys(IteratorOptions::new().reverse(true))
+            .unwrap()
+            .collect();
+        assert_eq!(keys, vec!["c", "b", "a"]);
+
+        let values: Vec<_> = engine
+            .values(IteratorOptions::new().reverse(true))
+            .unwrap()
+            .collect();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].as_ref().unwrap(), "val-c");
+        assert_eq!(
+            values[1].as_ref().unwrap_err().current_context(),
+            &Errors::DatafileCorrupted
+        );
+        assert_eq!(values[2].as_ref().unwrap(), "val-a");
+
+        // the value filter reads the values, the corrupted one is an error item
+        let opts = || IteratorOptions::new().value_filter(|_| true);
+        let keys: Vec<_> = engine.try_keys(opts()).unwrap().collect();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0].as_ref().unwrap(), "a");
+        assert_eq!(
+            keys[1].as_ref().unwrap_err().current_context(),
+            &Errors::DatafileCorrupted
+        );
+        assert_eq!(keys[2].as_ref().unwrap(), "c");
+        let report = engine.keys(opts()).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::InvalidIteratorOptions);
+    }
+
+    #[test]
+    #[should_panic(expected = "use `try_keys`")]
+    fn keys_with_value_filter() {
+        let engine = engine!(["a", "val-a"]);
+        let opts = IteratorOptions::new().value_filter(|_| true);
+        let _ = engine.iter(opts).unwrap().keys();
+    }
+
+    #[test]
+    fn truncated_datafile() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let b = engine.index.get(b"b").unwrap();
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+
+        // cut the datafile in the middle of `b`, underneath the iterator
+        let path = engine
+            .path()
+            .join(crate::data::data_file::datafile_name(b.file_id));
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(b.offset + 1).unwrap();
+
+        let report = iter.next().unwrap().unwrap_err();
+        assert!(report.current_context().is_corruption(), "{:?}", report);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn report_tombstones() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"]);
+        let offset = engine.files.read().active.offset();
+        engine.delete("b".into()).unwrap();
+        let size = engine.files.read().active.offset() - offset;
+        // an index out of sync with the datafiles, pointing at the tombstone of `b`
+        let tombstone = LogRecordPos {
+            file_id: engine.files.read().active.id(),
+            offset,
+            size: size as u32,
+        };
+        engine.index.put(Bytes::from_static(b"b"), tombstone);
+
+        let iter = engine.iter(IteratorOptions::default()).unwrap();
+        let entries: Vec<Entry> = iter.map(Result::unwrap).collect();
+        assert_eq!(entries, vec![entry!["a", "val-a"]]);
+
+        let mut iter = engine
+            .iter(IteratorOptions::new().report_tombstones(true))
+            .unwrap();
+        assert_eq!(iter.next().transpose().unwrap(), Some(entry!["a", "val-a"]));
+        let report = iter.next().unwrap().unwrap_err();
+        assert_eq!(report.current_context(), &Errors::KeyNotFound);
+        assert_eq!(
+            report.downcast_ref::<ErrorKey>(),
+            Some(&ErrorKey::new(b"b"))
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn keys_only() {
+        let (engine, stats) = EngineWrapper::counting();
+        for (key, value) in [("a", "val-a"), ("b", "val-b"), ("c", "val-c")] {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+        let reads = stats.reads();
+
+        let iter = engine
+            .iter(IteratorOptions::new().reverse(true).keys_only(true))
+            .unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["c", ""], entry!["b", ""], entry!["a", ""]]
+        );
+        assert_eq!(stats.reads(), reads);
+    }
+
+    #[test]
+    fn count_keys() {
+        let (engine, stats) = EngineWrapper::counting();
+        for (key, value) in [("aa", "v1"), ("ab", "v2"), ("ac", "v3"), ("b", "v4")] {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+        let reads = stats.reads();
+
+        let mut iter = engine
+            .iter(
+                IteratorOptions::new()
+                    .prefix("a")
+                    .filter(|key| key != b"ab"),
+            )
+            .unwrap();
+        assert_eq!(iter.count_keys(), 2);
+        assert_eq!(iter.next().transpose().unwrap(), None);
+        assert_eq!(stats.reads(), reads);
+
+        // the peeked entry is counted as well
+        let mut iter = engine.iter(IteratorOptions::default()).unwrap();
+        iter.next();
+        iter.peek();
+        assert_eq!(iter.count_keys(), 3);
+
+        // values have to be read to evaluate the value filter
+        let mut iter = engine
+            .iter(IteratorOptions::new().value_filter(|value| value != b"v2"))
+            .unwrap();
+        assert_eq!(iter.count_keys(), 3);
+    }
+
+    #[test]
+    fn approx_bytes() {
+        let (engine, stats) = EngineWrapper::counting();
+        let records = [("a", "val-a"), ("b", "longer-val-b"), ("c", "c")];
+        for (key, value) in records {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+        let size = |key: &str, value: &str| {
+            LogRecord {
+                key: key.into(),
+                value: value.into(),
+                record_type: LogRecordType::Normal,
+                seq: None,
+            }
+            .size()
+        };
+        let reads = stats.reads();
+
+        let mut iter = engine
+            .iter(IteratorOptions::new().lower_bound(Bound::Included("b".into())))
+            .unwrap();
+        assert_eq!(
+            iter.approx_bytes(),
+            size("b", "longer-val-b") + size("c", "c")
+        );
+        assert_eq!(stats.reads(), reads);
+    }
+
+    #[test]
+    fn scan_shares_keys() {
+        let engine = EngineWrapper::default();
+        for i in 0..10_000 {
+            let key = format!("key-{:05}", i);
+            engine.put(key.into(), "val".into()).unwrap();
+        }
+        let options = || IteratorOptions::new().keys_only(true);
+        // the first clone of a key may still promote its buffer to a shared one
+        assert_eq!(engine.iter(options()).unwrap().count(), 10_000);
+
+        let (count, allocations) = count_allocations(|| engine.iter(options()).unwrap().count());
+        assert_eq!(count, 10_000);
+        // only the snapshot itself is allocated, keys are never copied
+        assert!(allocations < 100, "{} allocations", allocations);
+    }
+
+    #[test]
+    fn scan_with_visits_like_iter() {
+        let engine = engine!(
+            ["a", "val-a"],
+            ["b", "val-b"],
+            ["c", "val-c"],
+            ["d", "val-d"]
+        );
+        for reverse in [false, true] {
+            let options = || {
+                IteratorOptions::new()
+                    .reverse(reverse)
+                    .lower_bound(Bound::Included("b".into()))
+            };
+            let mut visited = Vec::new();
+            engine
+                .scan_with(options(), |key, value| {
+                    visited.push(entry![key.to_vec(), value.to_vec()]);
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            let expected = engine
+                .iter(options())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<Entry>>();
+            assert_eq!(visited, expected);
+        }
+    }
+
+    #[test]
+    fn scan_with_break() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let mut keys = Vec::new();
+        engine
+            .scan_with(IteratorOptions::default(), |key, _| {
+                keys.push(key.to_vec());
+                match key == b"b" {
+                    true => ControlFlow::Break(()),
+                    false => ControlFlow::Continue(()),
+                }
+            })
+            .unwrap();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn scan_with_read_failure() {
+        let (engine, faults) = EngineWrapper::faulty();
+        engine.put("a".into(), "val-a".into()).unwrap();
+        faults.fail_reads(true);
+
+        let mut visited = 0;
+        let report = engine
+            .scan_with(IteratorOptions::default(), |_, _| {
+                visited += 1;
+                ControlFlow::Continue(())
+            })
+            .unwrap_err();
+        assert_eq!(report.current_context(), &Errors::FailToReadFromFile);
+        assert_eq!(visited, 0);
+
+        // nothing is read from the datafiles with `keys_only`
+        engine
+            .scan_with(IteratorOptions::new().keys_only(true), |_, value| {
+                assert!(value.is_empty());
+                visited += 1;
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn value_filter() {
+        let engine = engine!(["a", "v1"], ["b", "v3"], ["c", "v4"], ["d", "v5"]);
+        let iter = engine
+            .iter(
+                IteratorOptions::new()
+                    .filter(|key| key != b"c")
+                    .value_filter(|value| value >= b"v3".as_slice()),
+            )
+            .unwrap();
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["b", "v3"], entry!["d", "v5"]]
+        );
+    }
+
+    #[test]
+    fn value_filter_with_keys_and_values() {
+        let engine = engine!(["a", "v1"], ["b", "v3"], ["c", "v4"]);
+        let opts = || {
+            IteratorOptions::new()
+                .reverse(true)
+                .value_filter(|value| value != b"v3")
+        };
+        let keys = engine.iter(opts()).unwrap().try_keys();
+        assert_eq!(
+            keys.collect::<Result<Vec<Bytes>>>().unwrap(),
+            vec![Bytes::from("c"), Bytes::from("a")]
+        );
+        let values = engine.iter(opts()).unwrap().values();
+        assert_eq!(
+            values.collect::<Result<Vec<Bytes>>>().unwrap(),
+            vec![Bytes::from("v4"), Bytes::from("v1")]
+        );
+    }
+
+    #[test]
+    fn value_filter_in_pages() {
+        let engine = engine!(
+            ["a", "v1"],
+            ["b", "v3"],
+            ["c", "v4"],
+            ["d", "v1"],
+            ["e", "v5"]
+        );
+        let opts = || IteratorOptions::new().value_filter(|value| value != b"v1");
+        let (page, token) = engine.scan_page(None, 2, opts()).unwrap();
+        assert_eq!(page, vec![entry!["b", "v3"], entry!["c", "v4"]]);
+        assert_eq!(token, Some(Bytes::from("c")));
+        let (page, token) = engine.scan_page(token, 2, opts()).unwrap();
+        assert_eq!(page, vec![entry!["e", "v5"]]);
+        assert_eq!(token, None);
+
+        // the trailing candidate is rejected by the filter, so there is no next page
+        let engine = engine!(["a", "v3"], ["b", "v1"]);
+        let (page, token) = engine.scan_page(None, 1, opts()).unwrap();
+        assert_eq!(page, vec![entry!["a", "v3"]]);
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn keys_only_with_value_filter() {
+        let engine = engine!(["a", "val-a"]);
+        let report = engine
+            .iter(
+                IteratorOptions::new()
+                    .keys_only(true)
+                    .value_filter(|_| true),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            report.current_context(),
+            &crate::errors::Errors::InvalidIteratorOptions
+        );
+        assert!(format!("{:?}", report).contains("`keys_only` can not be combined"));
+    }
+
+    #[test]
+    fn values() {
+        let engine = engine!(["aa", "val-aa"], ["ab", "val-ab"], ["b", "val-b"]);
+        let values = engine
+            .iter(IteratorOptions::new().reverse(true).prefix("a"))
+            .unwrap()
+            .values();
+        assert_eq!(
+            values.collect::<Result<Vec<Bytes>>>().unwrap(),
+            vec![Bytes::from("val-ab"), Bytes::from("val-aa")]
+        );
+    }
+
+    #[test]
+    fn scan_pages() {
+        let engine = engine!();
+        for i in 0..1000 {
+            engine
+                .put(format!("{:04}", i).into(), format!("val-{:04}", i).into())
+                .unwrap();
+        }
+        let expected = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        let mut pages = Vec::new();
+        let mut token = None;
+        loop {
+            let (page, next) = engine
+                .scan_page(token, 7, IteratorOptions::default())
+                .unwrap();
+            assert!(page.len() <= 7);
+            pages.extend(page);
+            match next {
+                None => break,
+                Some(next) => token = Some(next),
+            }
+        }
+        assert_eq!(pages, expected);
+    }
+
+    #[test]
+    fn scan_pages_in_both_orders() {
+        let engine = engine!();
+        for i in 0..1000 {
+            engine
+                .put(format!("{:04}", i).into(), format!("val-{:04}", i).into())
+                .unwrap();
+        }
+        for reverse in [false, true] {
+            let opts = || IteratorOptions::new().reverse(reverse);
+            let expected = engine
+                .iter(opts())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+
+            let mut pages = Vec::new();
+            let mut token = None;
+            loop {
+                let (page, next) = engine.scan_page(token, 37, opts()).unwrap();
+                assert!(page.len() == 37 || next.is_none());
+                pages.extend(page);
+                match next {
+                    None => break,
+                    Some(next) => token = Some(next),
+                }
+            }
+            // no key is missing nor repeated
+            assert_eq!(pages, expected);
+
+            // a smaller limit in the options bounds the page
+            let (page, token) = engine.scan_page(None, 37, opts().limit(5)).unwrap();
+            assert_eq!(page, expected[..5]);
+            assert_eq!(token, Some(expected[4].key().clone()));
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let engine = engine!(["a", "1"], ["b", "2"], ["c", "3"], ["d", "4"]);
+        let iter = engine.iter(IteratorOptions::new().limit(3)).unwrap();
+        assert_eq!(iter.keys().collect::<Vec<Bytes>>(), vec!["a", "b", "c"]);
+
+        let mut iter = engine
+            .iter(IteratorOptions::new().reverse(true).limit(2))
+            .unwrap();
+        assert_eq!(iter.count_keys(), 2);
+        iter.seek("b".into());
+        assert_eq!(
+            iter.map(Result::unwrap).collect::<Vec<Entry>>(),
+            vec![entry!["b", "2"], entry!["a", "1"]]
+        );
+
+        let report = engine
+            .iter(IteratorOptions::new().limit(2).value_filter(|_| true))
+            .err()
+            .unwrap();
+        assert_eq!(report.current_context(), &Errors::InvalidIteratorOptions);
+    }
+
+    #[test]
+    fn fold() {
+        let engine = engine!();
+        let mut count = 0;
+        engine
+            .fold(|_, _| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("ba", "3"), ("bb", "4"), ("c", "5")] {
+            engine.put(key.into(), value.into()).unwrap();
+        }
+        let mut count = 0;
+        engine
+            .fold(|_, _| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 5);
+
+        // stops after the third entry
+        let mut visited = Vec::new();
+        engine
+            .fold(|key, value| {
+                visited.push(Entry::new(key, value));
+                visited.len() < 3
+            })
+            .unwrap();
+        assert_eq!(
+            visited,
+            vec![entry!["a", "1"], entry!["b", "2"], entry!["ba", "3"]]
+        );
+
+        let mut keys = Vec::new();
+        engine
+            .fold_prefix("b", |key, _| {
+                keys.push(key);
+                true
+            })
+            .unwrap();
+        assert_eq!(keys, vec!["b", "ba", "bb"]);
+    }
+
+    #[test]
+    fn fold_read_error() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let pos = engine.index.get(b"b").unwrap();
+        let path = engine
+            .path()
+            .join(crate::data::data_file::datafile_name(pos.file_id));
+        let mut content = std::fs::read(&path).unwrap();
+        content[pos.offset as usize + pos.size as usize - 1] ^= 0x01;
+        std::fs::write(&path, content).unwrap();
+
+        let mut keys = Vec::new();
+        let report = engine
+            .fold(|key, _| {
+                keys.push(key);
+                true
+            })
+            .unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileCorrupted);
+        assert_eq!(keys, vec!["a"]);
+    }
+
+    #[test]
+    fn scan_pages_reverse_with_prefix() {
+        let engine = engine!(
+            ["a", "val-a"],
+            ["ba", "val-ba"],
+            ["bb", "val-bb"],
+            ["bc", "val-bc"]
+        );
+        let opts = || IteratorOptions::new().reverse(true).prefix("b");
+
+        let (page, token) = engine.scan_page(None, 2, opts()).unwrap();
+        assert_eq!(page, vec![entry!["bc", "val-bc"], entry!["bb", "val-bb"]]);
+        assert_eq!(token, Some(Bytes::from("bb")));
+
+        let (page, token) = engine.scan_page(token, 2, opts()).unwrap();
+        assert_eq!(page, vec![entry!["ba", "val-ba"]]);
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn scan_page_exact_fit() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"]);
+        let (page, token) = engine
+            .scan_page(None, 2, IteratorOptions::default())
+            .unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(token, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let engine = engine!(["Hello", "World"]);
+        engine
+            .put(Bytes::from_static(b"\x00\xff"), Bytes::from_static(b"\xfe"))
+            .unwrap();
+
+        let json = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .collect_json(usize::MAX)
+            .unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"[{"key":{"encoding":"base64","data":"AP8="},"value":{"encoding":"base64","data":"/g=="}},"#,
+                r#"{"key":{"encoding":"utf8","data":"Hello"},"value":{"encoding":"utf8","data":"World"}}]"#
+            )
+        );
+
+        let entries: Vec<Entry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entries,
+            engine
+                .iter(IteratorOptions::default())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_limit() {
+        let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
+        let mut buf = Vec::new();
+        let written = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .write_json(&mut buf, 2)
+            .unwrap();
+        assert_eq!(written, 2);
+        let entries: Vec<Entry> = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(entries, vec![entry!["a", "val-a"], entry!["b", "val-b"]]);
+
+        let json = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .collect_json(0)
+            .unwrap();
+        assert_eq!(json, "[]");
     }
 
     #[test]
     fn some_keys() {
         let engine = engine!(["a", "val-a"], ["b", "val-b"], ["c", "val-c"]);
         assert_eq!(
-            engine.keys().unwrap(),
+            engine
+                .keys(IteratorOptions::default())
+                .unwrap()
+                .collect::<Vec<_>>(),
             vec!["a", "b", "c"]
                 .into_iter()
                 .map(bytes::Bytes::from)
@@ -1,10 +1,25 @@
-mod batch;
+pub mod backup;
+pub mod batch;
+pub mod clock;
 pub mod data;
+pub mod dump;
 pub mod engine;
 pub mod errors;
 pub mod fio;
+pub mod import;
 pub mod index;
-mod iterator;
+pub mod iterator;
+pub mod map;
+pub mod merge;
+#[cfg(feature = "import")]
+pub mod migrate;
+#[cfg(test)]
 mod mock;
 pub mod options;
+#[cfg(feature = "http")]
+pub mod serve;
+#[cfg(feature = "resp-server")]
+pub mod server;
+#[cfg(feature = "serde")]
+pub mod typed;
 mod utils;
@@ -0,0 +1,338 @@
+//! A facade over the engine shaped like the standard maps.
+//!
+//! Lookups return `Option`s like [Engine::get], an error is left for a read that failed.
+//! The empty key is never present, looking it up finds nothing while writing it fails
+//! with [Errors::EmptyKey](crate::errors::Errors::EmptyKey).
+
+use crate::engine::Engine;
+use crate::errors::Result;
+use crate::options::IteratorOptions;
+use bytes::Bytes;
+
+/// A map-like view of an [Engine], created by [Engine::as_map]. It borrows the engine
+/// mutably, no other write goes between looking up an [Entry] and writing it.
+pub struct MapView<'a> {
+    engine: &'a mut Engine,
+}
+
+impl Engine {
+    pub fn as_map(&mut self) -> MapView<'_> {
+        MapView { engine: self }
+    }
+}
+
+impl<'a> MapView<'a> {
+    pub fn get<K: Into<Bytes>>(&self, key: K) -> Result<Option<Bytes>> {
+        lookup(self.engine, key.into())
+    }
+
+    pub fn contains_key<K: Into<Bytes>>(&self, key: K) -> bool {
+        self.engine.index.get(&key.into()).is_some()
+    }
+
+    /// Sets the value of `key`, returns the value it replaced if any
+    pub fn insert<K: Into<Bytes>, V: Into<Bytes>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<Option<Bytes>> {
+        let key = key.into();
+        let old = lookup(self.engine, key.clone())?;
+        self.engine.put(key, value.into())?;
+        Ok(old)
+    }
+
+    /// Deletes `key`, returns its value if it was present
+    pub fn remove<K: Into<Bytes>>(&mut self, key: K) -> Result<Option<Bytes>> {
+        let key = key.into();
+        let old = lookup(self.engine, key.clone())?;
+        if old.is_some() {
+            self.engine.delete(key)?;
+        }
+        Ok(old)
+    }
+
+    pub fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engine.is_empty()
+    }
+
+    /// Iterates over the key-value pairs in key order, a value that fails to be read
+    /// yields an error
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(Bytes, Bytes)>> + '_> {
+        let iter = self.engine.iter(IteratorOptions::default())?;
+        Ok(iter.map(|entry| entry.map(|entry| entry.into_parts())))
+    }
+
+    /// The entry of `key` for in-place manipulation, reading its value if present.
+    ///
+    /// ```
+    /// use ailurus_kv::engine::Engine;
+    ///
+    /// let mut engine = Engine::open_temporary().unwrap();
+    /// let mut map = engine.as_map();
+    /// for word in ["a", "b", "a"] {
+    ///     map.entry(word)
+    ///         .unwrap()
+    ///         .and_modify(|count| *count = format!("{}!", count.escape_ascii()).into())
+    ///         .unwrap()
+    ///         .or_insert("seen")
+    ///         .unwrap();
+    /// }
+    /// assert_eq!(map.get("a").unwrap().unwrap(), "seen!");
+    /// assert_eq!(map.get("b").unwrap().unwrap(), "seen");
+    /// ```
+    pub fn entry<K: Into<Bytes>>(&mut self, key: K) -> Result<Entry<'_>> {
+        let key = key.into();
+        Ok(match lookup(self.engine, key.clone())? {
+            Some(value) => Entry::Occupied(OccupiedEntry {
+                engine: self.engine,
+                key,
+                value,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                engine: self.engine,
+                key,
+            }),
+        })
+    }
+}
+
+fn lookup(engine: &Engine, key: Bytes) -> Result<Option<Bytes>> {
+    if key.is_empty() {
+        return Ok(None);
+    }
+    engine.get(key)
+}
+
+/// The entry of a key in a [MapView], created by [MapView::entry]
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+impl<'a> Entry<'a> {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value, inserting `default` if the key is vacant
+    pub fn or_insert<V: Into<Bytes>>(self, default: V) -> Result<Bytes> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value, inserting the result of `default` if the key is vacant
+    pub fn or_insert_with<V: Into<Bytes>, F: FnOnce() -> V>(self, default: F) -> Result<Bytes> {
+        self.or_insert_with_key(|_| default())
+    }
+
+    /// Like [Entry::or_insert_with], `default` is given the key
+    pub fn or_insert_with_key<V: Into<Bytes>, F: FnOnce(&Bytes) -> V>(
+        self,
+        default: F,
+    ) -> Result<Bytes> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.value),
+            Entry::Vacant(entry) => {
+                let value = default(&entry.key);
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Returns the value, inserting an empty one if the key is vacant
+    pub fn or_default(self) -> Result<Bytes> {
+        self.or_insert(Bytes::new())
+    }
+
+    /// Updates the value with `f` if the key is occupied, the new value is written at once
+    pub fn and_modify<F: FnOnce(&mut Bytes)>(self, f: F) -> Result<Self> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.value.clone();
+                f(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+}
+
+pub struct OccupiedEntry<'a> {
+    engine: &'a mut Engine,
+    key: Bytes,
+    value: Bytes,
+}
+
+impl<'a> OccupiedEntry<'a> {
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    pub fn get(&self) -> &Bytes {
+        &self.value
+    }
+
+    pub fn into_value(self) -> Bytes {
+        self.value
+    }
+
+    /// Sets the value, returns the previous one
+    pub fn insert<V: Into<Bytes>>(&mut self, value: V) -> Result<Bytes> {
+        let value = value.into();
+        self.engine.put(self.key.clone(), value.clone())?;
+        Ok(std::mem::replace(&mut self.value, value))
+    }
+
+    /// Deletes the key, returns its value
+    pub fn remove(self) -> Result<Bytes> {
+        self.engine.delete(self.key)?;
+        Ok(self.value)
+    }
+}
+
+pub struct VacantEntry<'a> {
+    engine: &'a mut Engine,
+    key: Bytes,
+}
+
+impl<'a> VacantEntry<'a> {
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    pub fn into_key(self) -> Bytes {
+        self.key
+    }
+
+    /// Sets the value of the key, returns it
+    pub fn insert<V: Into<Bytes>>(self, value: V) -> Result<Bytes> {
+        let value = value.into();
+        self.engine.put(self.key, value.clone())?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine;
+    use crate::errors::Errors;
+    use crate::map::Entry;
+    use bytes::Bytes;
+
+    #[test]
+    fn insert_remove() {
+        let mut engine = engine!(["a", "1"]);
+        let mut map = engine.as_map();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.insert("a", "2").unwrap(), Some("1".into()));
+        assert_eq!(map.insert("b", "3").unwrap(), None);
+        assert_eq!(map.get("a").unwrap(), Some("2".into()));
+        assert_eq!(map.get("c").unwrap(), None);
+        assert!(map.contains_key("b"));
+        assert!(!map.contains_key("c"));
+
+        assert_eq!(map.remove("a").unwrap(), Some("2".into()));
+        assert_eq!(map.remove("a").unwrap(), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![(Bytes::from("b"), Bytes::from("3"))]
+        );
+
+        assert_eq!(map.remove("b").unwrap(), Some("3".into()));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn empty_key() {
+        let mut engine = engine!();
+        let mut map = engine.as_map();
+        assert_eq!(map.get("").unwrap(), None);
+        assert!(!map.contains_key(""));
+        assert_eq!(map.remove("").unwrap(), None);
+        assert_eq!(
+            map.insert("", "value").unwrap_err().current_context(),
+            &Errors::EmptyKey
+        );
+        let entry = map.entry("").unwrap();
+        assert!(matches!(entry, Entry::Vacant(_)));
+        assert_eq!(
+            entry.or_insert("value").unwrap_err().current_context(),
+            &Errors::EmptyKey
+        );
+    }
+
+    #[test]
+    fn entry() {
+        let mut engine = engine!(["a", "1"]);
+        let mut map = engine.as_map();
+
+        let mut called = false;
+        let value = map
+            .entry("a")
+            .unwrap()
+            .or_insert_with(|| {
+                called = true;
+                "unused"
+            })
+            .unwrap();
+        assert_eq!((value, called), (Bytes::from("1"), false));
+        let value = map
+            .entry("b")
+            .unwrap()
+            .or_insert_with_key(|key| [key.as_ref(), b"-default"].concat())
+            .unwrap();
+        assert_eq!(value, "b-default");
+        assert_eq!(map.entry("c").unwrap().or_default().unwrap(), "");
+        assert_eq!(map.get("c").unwrap(), Some(Bytes::new()));
+
+        let increment = |value: &mut Bytes| {
+            let n: u32 = std::str::from_utf8(value).unwrap().parse().unwrap();
+            *value = (n + 1).to_string().into();
+        };
+        for _ in 0..3 {
+            map.entry("n")
+                .unwrap()
+                .and_modify(increment)
+                .unwrap()
+                .or_insert("0")
+                .unwrap();
+        }
+        assert_eq!(map.get("n").unwrap(), Some("2".into()));
+    }
+
+    #[test]
+    fn occupied_and_vacant() {
+        let mut engine = engine!(["a", "1"]);
+        let mut map = engine.as_map();
+        match map.entry("a").unwrap() {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), "a");
+                assert_eq!(entry.get(), "1");
+                assert_eq!(entry.insert("2").unwrap(), "1");
+                assert_eq!(entry.get(), "2");
+            }
+            Entry::Vacant(_) => panic!("`a` is present"),
+        }
+        match map.entry("a").unwrap() {
+            Entry::Occupied(entry) => assert_eq!(entry.remove().unwrap(), "2"),
+            Entry::Vacant(_) => panic!("`a` is present"),
+        }
+        match map.entry("a").unwrap() {
+            Entry::Vacant(entry) => {
+                assert_eq!(entry.key(), "a");
+                assert_eq!(entry.insert("3").unwrap(), "3");
+            }
+            Entry::Occupied(_) => panic!("`a` was removed"),
+        }
+        assert_eq!(map.get("a").unwrap(), Some("3".into()));
+    }
+}
@@ -0,0 +1,2092 @@
+use crate::clock::{self, SharedClock};
+use crate::data::data_file::{datafile_name, DataFile};
+use crate::data::hint_file::{self, hint_name, HintEntry};
+use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType, ReadLogRecord};
+use crate::engine::{Datafiles, Engine};
+use crate::errors::{Errors, RecordLocation, Result};
+use crate::fio;
+use crate::index::Indexer;
+use crate::options::{IteratorOptions, RuntimeOptions, SyncPolicy};
+use bytes::Bytes;
+use error_stack::{Report, ResultExt};
+use log::{error, info};
+use parking_lot::{Mutex, RwLock};
+use std::collections::hash_map;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Directory of the database the datafiles written by a merge are staged in,
+/// until the merge installs them next to the others
+pub const MERGE_DIR: &str = "merge-tmp";
+
+/// File of the database listing the merged datafiles an iterator kept from being removed,
+/// they are removed when the database is opened if still there
+pub const RETIRED_FILE: &str = "merge-retired";
+
+/// Merges kept by [Engine::merge_history]
+pub const MERGE_HISTORY_LEN: usize = 16;
+
+/// What a merge did
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeStats {
+    /// Datafiles merged, they are removed once merged
+    pub files_in: usize,
+    /// Datafiles written by the merge
+    pub files_out: usize,
+    /// Live records and needed tombstones copied
+    pub records_copied: u64,
+    /// Overwritten records and tombstones left behind
+    pub records_dropped: u64,
+    /// Size of the datafiles written
+    pub bytes_written: u64,
+    /// Size of the merged datafiles minus the size of the datafiles written
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+}
+
+/// Progress of a merge, reported to [MergeHandle::with_progress]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeProgress {
+    /// Live records copied so far
+    pub records_done: u64,
+    /// Live records of the merged datafiles
+    pub records_total: u64,
+}
+
+type MergeProgressFn = Arc<dyn Fn(MergeProgress) + Send + Sync>;
+
+/// Observes and cancels a running merge, the handle can be cloned and
+/// [cancelled](MergeHandle::cancel) from another thread.
+///
+/// A cancelled merge fails with [Errors::MergeCancelled] and leaves the
+/// database as it was before the merge.
+#[derive(Clone, Default)]
+pub struct MergeHandle {
+    cancelled: Arc<AtomicBool>,
+    progress: Option<MergeProgressFn>,
+}
+
+impl MergeHandle {
+    pub fn new() -> Self {
+        MergeHandle::default()
+    }
+
+    /// Calls `progress` after each live record copied by the merge. The clones of the
+    /// handle made before this call do not call it.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(MergeProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Stops the merge before its next record
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// The last merge of an engine, see [Engine::last_merge_info]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeInfo {
+    /// When the merge completed
+    pub finished_at: SystemTime,
+    /// Whether the merge was run automatically, by a write sealing the active datafile or
+    /// by the [merge_schedule](crate::options::Options::merge_schedule)
+    pub background: bool,
+    pub stats: MergeStats,
+}
+
+/// Counters of the writes since the engine was opened, see [Engine::metrics]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    /// Bytes appended by puts, deletes and write batches
+    pub user_bytes_written: u64,
+    /// Bytes written by the merges
+    pub merge_bytes_written: u64,
+    /// Merges that merged any datafile
+    pub merges: u64,
+    /// Bytes reclaimed by the merges
+    pub bytes_reclaimed: u64,
+}
+
+impl Metrics {
+    /// Bytes written to disk per byte written by the user, `1.0` before any write
+    pub fn write_amplification(&self) -> f64 {
+        match self.user_bytes_written {
+            0 => 1.0,
+            user => (user + self.merge_bytes_written) as f64 / user as f64,
+        }
+    }
+}
+
+/// A sealed datafile as seen by [Engine::compaction_candidates]
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileInfo {
+    pub id: u32,
+    /// Bytes of the datafile, `live_bytes + dead_bytes`
+    pub size: u64,
+    /// Bytes of the records the index points at, and of the tombstones still needed
+    pub live_bytes: u64,
+    /// Bytes a merge of the datafile would reclaim
+    pub dead_bytes: u64,
+    /// Records the index points at
+    pub live_records: u64,
+    /// When the datafile was created, `None` if the filesystem does not tell
+    pub created_at: Option<SystemTime>,
+}
+
+/// A record copied by a merge, indexed once the merge completes
+struct CopiedRecord {
+    key: Vec<u8>,
+    record_type: LogRecordType,
+    value_len: usize,
+    /// where the record was copied from
+    original: LogRecordPos,
+    pos: LogRecordPos,
+}
+
+/// Datafiles written by a merge, numbered after the active datafile
+struct MergeOutput {
+    datafiles: Vec<DataFile>,
+    copies: Vec<CopiedRecord>,
+    /// ids set aside for the datafiles, the active datafile is numbered after them
+    ids: Range<u32>,
+}
+
+/// A datafile being merged, read through a handle of its own
+struct MergeSource {
+    datafile: DataFile,
+    /// whether a datafile older than this one and not merged may hold a record of the
+    /// key of a tombstone, dropping the tombstone would resurrect the record
+    resurrects: bool,
+}
+
+/// Paces a merge to [RuntimeOptions::merge_bytes_per_sec], pausing for as long as reading
+/// the records read so far should take at that rate. The time spent reading them is not
+/// taken off, so the merge runs a bit below the rate.
+struct Throttle<'a> {
+    runtime: &'a RwLock<RuntimeOptions>,
+    /// bytes read since the last pause
+    owed: u64,
+}
+
+impl Throttle<'_> {
+    /// Shorter pauses would mostly measure the granularity of the sleeps
+    const MIN_PAUSE: Duration = Duration::from_millis(10);
+
+    /// Pauses once a pause of [Throttle::MIN_PAUSE] is owed, the pause ends early if the
+    /// merge is cancelled
+    fn read(&mut self, bytes: u64, handle: Option<&MergeHandle>) {
+        let Some(rate) = self.runtime.read().merge_bytes_per_sec else {
+            self.owed = 0;
+            return;
+        };
+        self.owed += bytes;
+        let mut pause = Duration::from_secs_f64(self.owed as f64 / rate as f64);
+        if pause < Self::MIN_PAUSE {
+            return;
+        }
+        self.owed = 0;
+        while !pause.is_zero() && !handle.is_some_and(MergeHandle::is_cancelled) {
+            let step = pause.min(Self::MIN_PAUSE);
+            std::thread::sleep(step);
+            pause -= step;
+        }
+    }
+}
+
+impl Engine {
+    /// Merges all the datafiles, see [Engine::merge_files]
+    pub fn merge(&mut self, handle: Option<&MergeHandle>) -> Result<MergeStats> {
+        self.merger.merge(handle, false)
+    }
+
+    /// Merges the given datafiles: their live records are copied to fresh datafiles,
+    /// then the merged datafiles are removed. The other datafiles are left untouched.
+    ///
+    /// A record is live when the index points at it, whatever the other records of the
+    /// merged datafiles. A tombstone is copied as long as a datafile older than its own
+    /// and not merged may hold a record of the key, dropping it would resurrect the record.
+    ///
+    /// The active datafile is sealed first, it is merged too when among `file_ids`. The
+    /// copies are written to [MERGE_DIR] and numbered after it, each along with a
+    /// [hint file](crate::data::hint_file) the index is rebuilt from. Once synced, they are
+    /// moved next to the other datafiles. A crash before the merged datafiles are removed
+    /// leaves the copies next to them, replaying both yields the same records.
+    ///
+    /// The datafiles are only held while the merge starts and once it installs the copies,
+    /// the engine is read and written meanwhile, at the pace of
+    /// [merge_bytes_per_sec](crate::options::Options::merge_bytes_per_sec) if set. Enough
+    /// ids for the copies are set aside when the active datafile is sealed, the writes
+    /// made during the merge land in later datafiles. A record overwritten or deleted
+    /// during the merge is not indexed at its copy.
+    ///
+    /// Fails with [Errors::DatafileNotFound] if an id is not a datafile of the engine.
+    pub fn merge_files(&mut self, file_ids: &[u32]) -> Result<MergeStats> {
+        let _running = self.merger.state.running.lock();
+        self.merger.merge_with(file_ids, None, false)
+    }
+
+    /// Merges up to `max_files` sealed datafiles, picking those with the largest share
+    /// of reclaimable bytes. A datafile without any is never picked.
+    pub fn merge_partial(&mut self, max_files: usize) -> Result<MergeStats> {
+        let _running = self.merger.state.running.lock();
+        let selected: Vec<u32> = {
+            let files = self.files.read();
+            let mut candidates: Vec<(u32, f64)> = files
+                .idle
+                .values()
+                .filter_map(|datafile| {
+                    let dead = files.dead_bytes.get(&datafile.id()).copied()?;
+                    (dead > 0).then(|| (datafile.id(), dead as f64 / datafile.offset() as f64))
+                })
+                .collect();
+            // most fragmented first, then oldest first
+            candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            candidates
+                .into_iter()
+                .take(max_files)
+                .map(|(id, _)| id)
+                .collect()
+        };
+        self.merger.merge_with(&selected, None, false)
+    }
+
+    /// Describes the sealed datafiles, oldest first, for a policy of its own to pick those
+    /// to merge with [Engine::merge_files].
+    ///
+    /// ```
+    /// use ailurus_kv::engine::Engine;
+    ///
+    /// let mut engine = Engine::open_temporary().unwrap();
+    /// engine.put("key".into(), "value".into()).unwrap();
+    ///
+    /// // compact the two worst datafiles
+    /// let mut candidates = engine.compaction_candidates();
+    /// candidates.sort_by_key(|file| std::cmp::Reverse(file.dead_bytes));
+    /// let worst: Vec<u32> = candidates.iter().take(2).map(|file| file.id).collect();
+    /// engine.merge_files(&worst).unwrap();
+    /// ```
+    pub fn compaction_candidates(&self) -> Vec<FileInfo> {
+        let files = self.files.read();
+        let mut candidates: Vec<FileInfo> = files
+            .idle
+            .values()
+            .map(|datafile| {
+                let id = datafile.id();
+                let size = datafile.offset();
+                let dead_bytes = files.dead_bytes.get(&id).copied().unwrap_or_default();
+                let path = self.options.dir_path.join(datafile_name(id));
+                FileInfo {
+                    id,
+                    size,
+                    live_bytes: size.saturating_sub(dead_bytes),
+                    dead_bytes,
+                    live_records: files.live_records.get(&id).copied().unwrap_or_default(),
+                    created_at: fs::metadata(path)
+                        .and_then(|metadata| metadata.created())
+                        .ok(),
+                }
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|file| file.id);
+        candidates
+    }
+
+    /// The last merge that merged any datafile since the engine was opened,
+    /// be it run by hand or by the [merge_schedule](crate::options::Options::merge_schedule)
+    pub fn last_merge_info(&self) -> Option<MergeInfo> {
+        self.merger.state.log.lock().history.back().copied()
+    }
+
+    /// The last [MERGE_HISTORY_LEN] merges that merged any datafile, oldest first
+    pub fn merge_history(&self) -> Vec<MergeInfo> {
+        self.merger
+            .state
+            .log
+            .lock()
+            .history
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Runs the merge due once a write sealed the active datafile, see [Engine::merge_due].
+    /// The write went through whatever the merge does, a failed merge is only logged.
+    pub(crate) fn merge_if_due(&self) {
+        match self.merger.merge_if_due() {
+            Ok(Some(stats)) => info!("Automatic merge done: {:?}", stats),
+            Ok(None) => {}
+            Err(e) => error!("Automatic merge failed: {:?}", e),
+        }
+    }
+
+    /// Counters of the writes since the engine was opened, nothing is persisted
+    pub fn metrics(&self) -> Metrics {
+        let user_bytes_written = self.files.read().bytes_written;
+        let log = self.merger.state.log.lock();
+        Metrics {
+            user_bytes_written,
+            merge_bytes_written: log.bytes_written,
+            merges: log.merges,
+            bytes_reclaimed: log.bytes_reclaimed,
+        }
+    }
+
+    /// Drops the tombstones of the sealed datafiles that no longer hide any record: their
+    /// key has been written again since, or no record of their key precedes them.
+    /// A tombstone following a record of its key is kept, even if a merge could tell
+    /// it is the last one. The datafiles holding droppable tombstones are rewritten in
+    /// place without them, the others are left untouched.
+    ///
+    /// Returns the bytes reclaimed.
+    pub fn purge_tombstones(&mut self) -> Result<u64> {
+        let _running = self.merger.state.running.lock();
+        let mut files = self.files.write();
+        files.check_writable()?;
+        let mut ids: Vec<u32> = files.idle.keys().copied().collect();
+        ids.sort_unstable();
+
+        // keys with a record before the tombstones being checked
+        let mut written = HashSet::<Vec<u8>>::new();
+        let mut reclaimed = 0;
+        for id in ids {
+            let mut kept = Vec::new();
+            let mut dropped = 0;
+            let datafile = &files.idle[&id];
+            let mut offset = 0;
+            while let Some(ReadLogRecord { record, size }) = datafile.read(offset)? {
+                let pos = LogRecordPos {
+                    file_id: id,
+                    offset,
+                    size: size as u32,
+                };
+                offset += pos.size as u64;
+                match record.record_type {
+                    LogRecordType::Normal => {
+                        written.insert(record.key.clone());
+                    }
+                    LogRecordType::Deleted => {
+                        let live = self.index.get(&record.key).is_some();
+                        if live || !written.contains(&record.key) {
+                            dropped += pos.size as u64;
+                            continue;
+                        }
+                    }
+                    LogRecordType::TxnFinished => {}
+                }
+                kept.push((record, pos));
+            }
+            if dropped == 0 {
+                continue;
+            }
+            self.rewrite(&mut files, id, kept)?;
+            if let Some(dead) = files.dead_bytes.get_mut(&id) {
+                *dead = dead.saturating_sub(dropped);
+            }
+            reclaimed += dropped;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Atomically replaces the sealed datafile `id` by the `records` it should hold,
+    /// given with their current position, and moves the index along
+    fn rewrite(
+        &self,
+        files: &mut Datafiles,
+        id: u32,
+        records: Vec<(LogRecord, LogRecordPos)>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut moves = Vec::new();
+        for (record, pos) in records {
+            let encoded = record.encode();
+            let moved = LogRecordPos {
+                file_id: id,
+                offset: buf.len() as u64,
+                size: encoded.len() as u32,
+            };
+            buf.extend_from_slice(&encoded);
+            if self.index.get(&record.key) == Some(pos) {
+                moves.push((record.key, moved));
+            }
+        }
+
+        hint_file::remove(&self.options.dir_path, id)?;
+        let path = self.options.dir_path.join(datafile_name(id));
+        fio::atomic_create(&path, &buf)?;
+        let datafile = DataFile::with_io_manager(&self.options.dir_path, id, &self.io_manager)?;
+        files.idle.insert(id, datafile);
+        for (key, pos) in moves {
+            self.index.put(Bytes::from(key), pos);
+        }
+        Ok(())
+    }
+
+    /// Removes the sealed datafiles without any live record, returns their ids.
+    ///
+    /// The datafiles are checked against the index before being removed. Like a merge,
+    /// a datafile holding a tombstone is kept as long as an older datafile may hold a
+    /// record of the key, the datafiles are thus removed oldest first.
+    pub fn gc_dead_files(&mut self) -> Result<Vec<u32>> {
+        let _running = self.merger.state.running.lock();
+        let mut files = self.files.write();
+        files.check_writable()?;
+        let mut candidates: Vec<u32> = files
+            .idle
+            .keys()
+            .filter(|id| files.live_records.get(id).is_none_or(|live| *live == 0))
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+        // the records superseding those of the candidates must not be lost with them
+        files.sync_active()?;
+
+        let mut removed = Vec::new();
+        for id in candidates {
+            let resurrects = files.idle.keys().any(|other| *other < id);
+            if !self.is_dead(&files.idle[&id], resurrects)? {
+                continue;
+            }
+            let datafile = files.idle.remove(&id).unwrap();
+            files.remove_merged(&self.options.dir_path, datafile)?;
+            removed.push(id);
+        }
+        Ok(removed)
+    }
+
+    /// Whether no record of `datafile` is needed anymore, `resurrects` tells whether
+    /// dropping a tombstone may bring an older record back
+    fn is_dead(&self, datafile: &DataFile, resurrects: bool) -> Result<bool> {
+        let mut offset = 0;
+        while let Some(ReadLogRecord { record, size }) = datafile.read(offset)? {
+            let pos = LogRecordPos {
+                file_id: datafile.id(),
+                offset,
+                size: size as u32,
+            };
+            offset += pos.size as u64;
+            let live = self.index.get(&record.key);
+            let needed = match record.record_type {
+                LogRecordType::Normal => live == Some(pos),
+                LogRecordType::Deleted => live.is_none() && resurrects,
+                // the records of its batch are all in the same datafile
+                LogRecordType::TxnFinished => false,
+            };
+            if needed {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// What a merge needs from the engine, shared with the background merge thread
+#[derive(Clone)]
+pub(crate) struct Merger {
+    pub(crate) dir_path: PathBuf,
+    pub(crate) data_file_size: u64,
+    /// see [Options::verify_after_merge](crate::options::Options::verify_after_merge)
+    pub(crate) verify: bool,
+    pub(crate) io_manager: fio::IOManagerFactory,
+    pub(crate) runtime: Arc<RwLock<RuntimeOptions>>,
+    pub(crate) files: Arc<RwLock<Datafiles>>,
+    pub(crate) index: Arc<dyn Indexer>,
+    pub(crate) clock: SharedClock,
+    /// whether automatic merges are enabled, see [Engine::set_auto_merge]
+    pub(crate) enabled: Arc<AtomicBool>,
+    pub(crate) state: Arc<MergeState>,
+}
+
+#[derive(Default)]
+pub(crate) struct MergeState {
+    /// held for the whole duration of a merge, no two merges ever run at once
+    pub(crate) running: Mutex<()>,
+    pub(crate) log: Mutex<MergeLog>,
+}
+
+/// The merges done since the engine was opened
+#[derive(Default)]
+pub(crate) struct MergeLog {
+    /// the last [MERGE_HISTORY_LEN] merges
+    pub(crate) history: VecDeque<MergeInfo>,
+    pub(crate) merges: u64,
+    pub(crate) bytes_written: u64,
+    pub(crate) bytes_reclaimed: u64,
+}
+
+impl MergeLog {
+    fn record(&mut self, info: MergeInfo) {
+        if self.history.len() == MERGE_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(info);
+        self.merges += 1;
+        self.bytes_written += info.stats.bytes_written;
+        self.bytes_reclaimed += info.stats.bytes_reclaimed;
+    }
+}
+
+impl Merger {
+    /// See [Engine::merge_due]
+    pub(crate) fn due(&self) -> bool {
+        let runtime = *self.runtime.read();
+        let ratio = runtime.merge_ratio;
+        if !self.enabled.load(Ordering::Relaxed) || ratio == 0.0 {
+            return false;
+        }
+        let files = self.files.read();
+        let reclaimable = files.reclaimable_bytes();
+        reclaimable >= runtime.merge_min_bytes
+            && reclaimable as f64 >= files.total_bytes() as f64 * ratio as f64
+    }
+
+    /// Merges all the datafiles
+    fn merge(&self, handle: Option<&MergeHandle>, background: bool) -> Result<MergeStats> {
+        let _running = self.state.running.lock();
+        self.merge_with(&self.file_ids(), handle, background)
+    }
+
+    /// Merges all the datafiles if a merge is due, unless another merge is running
+    fn merge_if_due(&self) -> Result<Option<MergeStats>> {
+        if !self.due() {
+            return Ok(None);
+        }
+        let Some(_running) = self.state.running.try_lock() else {
+            return Ok(None);
+        };
+        self.merge_with(&self.file_ids(), None, true).map(Some)
+    }
+
+    /// The ids of the sealed datafiles and of the active one
+    fn file_ids(&self) -> Vec<u32> {
+        let files = self.files.read();
+        files
+            .idle
+            .keys()
+            .copied()
+            .chain([files.active.id()])
+            .collect()
+    }
+
+    /// Merges the datafiles `file_ids`, the caller holds [MergeState::running]
+    fn merge_with(
+        &self,
+        file_ids: &[u32],
+        handle: Option<&MergeHandle>,
+        background: bool,
+    ) -> Result<MergeStats> {
+        let started = self.clock.now_millis();
+        let Some((sources, mut output, sealed)) = self.start(file_ids)? else {
+            return Ok(MergeStats::default());
+        };
+        let mut stats = MergeStats {
+            files_in: sources.len(),
+            ..MergeStats::default()
+        };
+        // only the merges of the scheduler are paced, an inline merge runs on the thread
+        // of the write that made it due
+        let throttled = background && handle.is_some();
+        // the datafiles are left to the engine while the records are copied
+        let copied = self
+            .copy_live(&sources, handle, throttled, &mut output, &mut stats)
+            .and_then(|_| self.install(&mut output))
+            .and_then(|_| match self.verify {
+                true => self.verify(&output),
+                false => Ok(()),
+            });
+        if let Err(e) = copied {
+            let active = output.ids.end;
+            self.discard(output);
+            if let Err(e) = self.unseal(&mut self.files.write(), sealed, active) {
+                error!("Fail to restore the active datafile: {:?}", e);
+            }
+            return Err(e);
+        }
+
+        // from now on the merged datafiles are garbage
+        let mut files = self.files.write();
+        for copy in output.copies {
+            let live = self.index.get(&copy.key);
+            let current = match copy.record_type {
+                LogRecordType::Normal => live == Some(copy.original),
+                _ => live.is_none(),
+            };
+            match current {
+                true => files.update_index(
+                    &*self.index,
+                    Bytes::from(copy.key),
+                    copy.record_type,
+                    copy.pos,
+                )?,
+                // overwritten or deleted while merging, the copy is as dead as its original
+                false => files.add_dead(&copy.pos),
+            }
+        }
+        stats.files_out = output.datafiles.len();
+        let bytes_out: u64 = output.datafiles.iter().map(DataFile::offset).sum();
+        for datafile in output.datafiles {
+            files.idle.insert(datafile.id(), datafile);
+        }
+
+        let dir_path = &self.dir_path;
+        let mut bytes_in = 0;
+        for source in sources {
+            let id = source.datafile.id();
+            drop(source);
+            let datafile = files.idle.remove(&id).unwrap();
+            bytes_in += datafile.offset();
+            files.remove_merged(dir_path, datafile)?;
+        }
+        stats.bytes_written = bytes_out;
+        stats.bytes_reclaimed = bytes_in.saturating_sub(bytes_out);
+        stats.duration = clock::elapsed(&*self.clock, started);
+        self.state.log.lock().record(MergeInfo {
+            finished_at: clock::now(&*self.clock),
+            background,
+            stats,
+        });
+        Ok(stats)
+    }
+
+    /// Seals the active datafile, numbering the next one after the ids set aside for the
+    /// copies, and opens the datafiles `file_ids` to merge. Returns them along with the id
+    /// of the datafile sealed, `None` if there is nothing to merge.
+    #[allow(clippy::type_complexity)]
+    fn start(&self, file_ids: &[u32]) -> Result<Option<(Vec<MergeSource>, MergeOutput, u32)>> {
+        let mut files = self.files.write();
+        files.check_writable()?;
+        let mut selected = file_ids.to_vec();
+        selected.sort_unstable();
+        selected.dedup();
+        if let Some(id) = selected
+            .iter()
+            .find(|id| **id != files.active.id() && !files.idle.contains_key(id))
+        {
+            return Err(Report::new(Errors::DatafileNotFound))
+                .attach_printable_lazy(|| format!("Datafile {} cannot be merged", id));
+        }
+        // an empty active datafile has nothing to merge
+        if files.active.offset() == 0 {
+            selected.retain(|id| *id != files.active.id());
+        }
+        if selected.is_empty() {
+            return Ok(None);
+        }
+
+        let staging = self.dir_path.join(MERGE_DIR);
+        fs::create_dir_all(&staging)
+            .change_context(Errors::CreateDbDirFail)
+            .attach_printable_lazy(|| format!("Fail to create {:?}", staging))?;
+        // a copy starts a datafile only if it does not fit in the previous one, any two
+        // datafiles in a row thus hold more than a datafile worth of bytes
+        let bytes: u64 = selected
+            .iter()
+            .map(|id| match *id == files.active.id() {
+                true => files.active.offset(),
+                false => files.idle[id].offset(),
+            })
+            .sum();
+        let reserved = 2 * bytes.div_ceil(self.data_file_size) + 1;
+        let first = files.active.id() + 1;
+        let ids = u32::try_from(reserved)
+            .ok()
+            .and_then(|reserved| first.checked_add(reserved))
+            .map(|end| first..end)
+            .ok_or_else(|| Report::new(Errors::InternalError))
+            .attach_printable("No datafile id left for the merge")?;
+        // the writes made while merging are replayed after the copies
+        let sealed = files.active.id();
+        self.seal_active(&mut files, ids.end)?;
+
+        let mut sources = Vec::with_capacity(selected.len());
+        for id in &selected {
+            sources.push(MergeSource {
+                datafile: DataFile::with_io_manager(&self.dir_path, *id, &self.io_manager)?,
+                resurrects: files
+                    .idle
+                    .keys()
+                    .any(|other| other < id && !selected.contains(other)),
+            });
+        }
+        let output = MergeOutput {
+            datafiles: Vec::new(),
+            copies: Vec::new(),
+            ids,
+        };
+        Ok(Some((sources, output, sealed)))
+    }
+
+    /// Undoes the sealing of datafile `sealed` by a merge that failed, unless the datafile
+    /// `active` replacing it was written to since
+    fn unseal(&self, files: &mut Datafiles, sealed: u32, active: u32) -> Result<()> {
+        if files.active.id() != active || files.active.offset() > 0 {
+            return Ok(());
+        }
+        // an empty datafile is removed when sealed
+        let restored = match files.idle.remove(&sealed) {
+            Some(datafile) => datafile,
+            None => DataFile::with_io_manager(&self.dir_path, sealed, &self.io_manager)?,
+        };
+        drop(std::mem::replace(&mut files.active, restored));
+        let path = self.dir_path.join(datafile_name(active));
+        fs::remove_file(&path)
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to remove empty datafile {:?}", path))
+    }
+
+    /// Writes the hint files of the synced datafiles of `output`, then moves both from the
+    /// staging directory to the directory of the database, each datafile before its hint
+    /// file. A crash in between leaves copies of live records next to the originals,
+    /// replaying both yields the same records.
+    fn install(&self, output: &mut MergeOutput) -> Result<()> {
+        let dir_path = &self.dir_path;
+        let staging = dir_path.join(MERGE_DIR);
+        for datafile in &output.datafiles {
+            let entries: Vec<HintEntry> = output
+                .copies
+                .iter()
+                .filter(|copy| copy.pos.file_id == datafile.id())
+                .map(|copy| HintEntry {
+                    key: copy.key.clone(),
+                    record_type: copy.record_type,
+                    pos: copy.pos,
+                })
+                .collect();
+            hint_file::write(&staging, datafile.id(), &entries)?;
+        }
+        fio::sync_dir(&staging)?;
+        for datafile in &output.datafiles {
+            for name in [datafile_name(datafile.id()), hint_name(datafile.id())] {
+                fs::rename(staging.join(&name), dir_path.join(&name))
+                    .change_context(Errors::CreateDbFileFail)
+                    .attach_printable_lazy(|| format!("Fail to install merged file {}", name))?;
+            }
+        }
+        fio::sync_dir(dir_path)?;
+        for datafile in output.datafiles.iter_mut() {
+            *datafile = DataFile::with_io_manager(dir_path, datafile.id(), &self.io_manager)?;
+        }
+        fs::remove_dir(&staging)
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to remove {:?}", staging))
+    }
+
+    /// Copies the live records of the `sources` to the datafiles of `output` and syncs
+    /// them, the index is left untouched. The copy is paced by a [Throttle] if `throttled`.
+    fn copy_live(
+        &self,
+        sources: &[MergeSource],
+        handle: Option<&MergeHandle>,
+        throttled: bool,
+        output: &mut MergeOutput,
+        stats: &mut MergeStats,
+    ) -> Result<()> {
+        let merged: HashSet<u32> = sources.iter().map(|source| source.datafile.id()).collect();
+        let mut progress = MergeProgress::default();
+        let mut iter = self.index.iterator(IteratorOptions::default());
+        while let Some((_, pos)) = iter.next() {
+            progress.records_total += merged.contains(&pos.file_id) as u64;
+        }
+        drop(iter);
+
+        let mut throttle = throttled.then(|| Throttle {
+            runtime: &self.runtime,
+            owed: 0,
+        });
+        for MergeSource {
+            datafile,
+            resurrects,
+        } in sources
+        {
+            let mut offset = 0;
+            while let Some(ReadLogRecord { mut record, size }) = datafile.read(offset)? {
+                if handle.is_some_and(MergeHandle::is_cancelled) {
+                    return Err(Report::new(Errors::MergeCancelled));
+                }
+                let pos = LogRecordPos {
+                    file_id: datafile.id(),
+                    offset,
+                    size: size as u32,
+                };
+                offset += pos.size as u64;
+                if let Some(throttle) = throttle.as_mut() {
+                    throttle.read(size, handle);
+                }
+
+                let live = self.index.get(&record.key);
+                let keep = match record.record_type {
+                    LogRecordType::Normal => live == Some(pos),
+                    LogRecordType::Deleted => live.is_none() && *resurrects,
+                    // the copies are written outside of any batch
+                    LogRecordType::TxnFinished => false,
+                };
+                if !keep {
+                    stats.records_dropped += 1;
+                    continue;
+                }
+
+                record.seq = None;
+                let encoded = record.encode();
+                let full = output.datafiles.last().is_none_or(|datafile| {
+                    datafile.offset() + encoded.len() as u64 > self.data_file_size
+                });
+                if full {
+                    let id = output
+                        .datafiles
+                        .last()
+                        .map_or(output.ids.start, |datafile| datafile.id() + 1);
+                    if !output.ids.contains(&id) {
+                        return Err(Report::new(Errors::InternalError))
+                            .attach_printable("The merge ran out of the datafile ids set aside");
+                    }
+                    output.datafiles.push(DataFile::with_io_manager(
+                        self.dir_path.join(MERGE_DIR),
+                        id,
+                        &self.io_manager,
+                    )?);
+                }
+                let datafile = output.datafiles.last_mut().unwrap();
+                let offset = datafile.offset();
+                datafile.write(&encoded)?;
+                output.copies.push(CopiedRecord {
+                    key: record.key,
+                    record_type: record.record_type,
+                    value_len: record.value.len(),
+                    original: pos,
+                    pos: LogRecordPos {
+                        file_id: datafile.id(),
+                        offset,
+                        size: encoded.len() as u32,
+                    },
+                });
+                stats.records_copied += 1;
+
+                if record.record_type == LogRecordType::Normal {
+                    progress.records_done += 1;
+                    if let Some(callback) = handle.and_then(|handle| handle.progress.as_ref()) {
+                        callback(progress);
+                    }
+                }
+            }
+        }
+
+        // the copies must not be lost once the originals are removed
+        for datafile in &output.datafiles {
+            datafile.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Reads every copy back from the installed datafiles of `output`, fails with
+    /// [Errors::MergeVerificationFailed] on the first one differing from its original
+    fn verify(&self, output: &MergeOutput) -> Result<()> {
+        for copy in &output.copies {
+            let datafile = output
+                .datafiles
+                .iter()
+                .find(|datafile| datafile.id() == copy.pos.file_id)
+                .unwrap();
+            let record = datafile
+                .read(copy.pos.offset)
+                .change_context(Errors::MergeVerificationFailed)?;
+            let intact = record.is_some_and(|ReadLogRecord { record, size }| {
+                record.key == copy.key
+                    && record.record_type == copy.record_type
+                    && record.value.len() == copy.value_len
+                    && size == copy.pos.size as u64
+            });
+            if !intact {
+                return Err(Report::new(Errors::MergeVerificationFailed))
+                    .attach_printable(RecordLocation {
+                        file_id: copy.pos.file_id,
+                        offset: copy.pos.offset,
+                    })
+                    .attach_printable("The copy does not match the record copied");
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the datafiles of an unfinished merge, be they staged or already installed
+    fn discard(&self, output: MergeOutput) {
+        let dir_path = &self.dir_path;
+        for datafile in output.datafiles {
+            if let Err(e) = hint_file::remove(dir_path, datafile.id()) {
+                error!("Fail to remove unfinished merge output: {:?}", e);
+            }
+            let installed = dir_path.join(datafile_name(datafile.id()));
+            drop(datafile);
+            if installed.is_file() {
+                if let Err(e) = fs::remove_file(&installed) {
+                    error!(
+                        "Fail to remove unfinished merge output {:?}: {}",
+                        installed, e
+                    );
+                }
+            }
+        }
+        let staging = dir_path.join(MERGE_DIR);
+        if let Err(e) = fs::remove_dir_all(&staging) {
+            error!(
+                "Fail to remove unfinished merge output {:?}: {}",
+                staging, e
+            );
+        }
+    }
+
+    /// Replaces the active datafile with a fresh one numbered `id`, the replaced
+    /// datafile is sealed or removed if empty
+    fn seal_active(&self, files: &mut Datafiles, id: u32) -> Result<()> {
+        if self.runtime.read().sync_policy != SyncPolicy::Never {
+            files.sync_active()?;
+        }
+        let fresh = DataFile::with_io_manager(&self.dir_path, id, &self.io_manager)?;
+        let sealed = std::mem::replace(&mut files.active, fresh);
+        match sealed.offset() {
+            0 => {
+                let path = self.dir_path.join(datafile_name(sealed.id()));
+                files.dead_bytes.remove(&sealed.id());
+                files.live_records.remove(&sealed.id());
+                drop(sealed);
+                fs::remove_file(&path)
+                    .change_context(Errors::InternalError)
+                    .attach_printable_lazy(|| {
+                        format!("Fail to remove empty datafile {:?}", path)
+                    })?;
+            }
+            _ => {
+                files.idle.insert(sealed.id(), sealed);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The datafiles an iterator may read: a merge retires them instead of removing them,
+/// they are removed once the last iterator pinning them is dropped
+pub(crate) struct DatafilePins {
+    files: Arc<RwLock<Datafiles>>,
+    dir_path: PathBuf,
+    ids: Vec<u32>,
+}
+
+impl Engine {
+    /// Pins the datafiles of the engine and calls `f` before any of them can be merged,
+    /// e.g. to take a snapshot of the index pointing into them
+    pub(crate) fn pinned<T>(&self, f: impl FnOnce() -> T) -> (DatafilePins, T) {
+        let files = self.files.read();
+        let ids: Vec<u32> = files
+            .idle
+            .keys()
+            .copied()
+            .chain([files.active.id()])
+            .collect();
+        let mut pins = files.pins.lock();
+        for id in &ids {
+            *pins.entry(*id).or_default() += 1;
+        }
+        drop(pins);
+        let pinned = f();
+        drop(files);
+
+        let pins = DatafilePins {
+            files: self.files.clone(),
+            dir_path: self.options.dir_path.clone(),
+            ids,
+        };
+        (pins, pinned)
+    }
+}
+
+impl Drop for DatafilePins {
+    fn drop(&mut self) {
+        let mut guard = self.files.write();
+        let files = &mut *guard;
+        let pins = files.pins.get_mut();
+        for id in &self.ids {
+            if let hash_map::Entry::Occupied(mut pin) = pins.entry(*id) {
+                *pin.get_mut() -= 1;
+                if *pin.get() == 0 {
+                    pin.remove();
+                }
+            }
+        }
+        let unpinned: Vec<u32> = files
+            .retired
+            .keys()
+            .filter(|id| !pins.contains_key(id))
+            .copied()
+            .collect();
+        if unpinned.is_empty() {
+            return;
+        }
+        for id in unpinned {
+            drop(files.retired.remove(&id));
+            if let Err(e) = hint_file::remove(&self.dir_path, id) {
+                error!(
+                    "Fail to remove the hint file of retired datafile {}: {:?}",
+                    id, e
+                );
+            }
+            let path = self.dir_path.join(datafile_name(id));
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Fail to remove retired datafile {:?}: {}", path, e);
+            }
+        }
+        if let Err(e) = write_retired(&self.dir_path, files.retired.keys().copied()) {
+            error!("Fail to update the retired datafiles: {:?}", e);
+        }
+    }
+}
+
+impl Datafiles {
+    /// Removes the merged `datafile`, which is retired instead while an iterator pins it
+    fn remove_merged(&mut self, dir_path: &Path, datafile: DataFile) -> Result<()> {
+        let id = datafile.id();
+        self.dead_bytes.remove(&id);
+        self.live_records.remove(&id);
+        if self.pins.get_mut().contains_key(&id) {
+            self.retired.insert(id, datafile);
+            return write_retired(dir_path, self.retired.keys().copied());
+        }
+        drop(datafile);
+        hint_file::remove(dir_path, id)?;
+        let path = dir_path.join(datafile_name(id));
+        fs::remove_file(&path)
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to remove merged datafile {:?}", path))
+    }
+}
+
+/// Lists the retired datafiles `ids` in [RETIRED_FILE], removed once none is left
+fn write_retired(dir_path: &Path, ids: impl Iterator<Item = u32>) -> Result<()> {
+    let path = dir_path.join(RETIRED_FILE);
+    let listed: String = ids.map(|id| format!("{}\n", id)).collect();
+    match listed.is_empty() {
+        true => fs::remove_file(&path)
+            .change_context(Errors::InternalError)
+            .attach_printable_lazy(|| format!("Fail to remove {:?}", path)),
+        false => fio::atomic_create(&path, listed.as_bytes()),
+    }
+}
+
+/// Removes the datafiles listed in [RETIRED_FILE], left behind by an engine that did not
+/// outlive the iterators pinning them. Their live records are in the datafiles of the merge.
+pub(crate) fn remove_retired(dir_path: &Path) -> Result<()> {
+    let path = dir_path.join(RETIRED_FILE);
+    let listed = match fs::read_to_string(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        listed => listed
+            .change_context(Errors::FailToReadFromFile)
+            .attach_printable_lazy(|| format!("Fail to read {:?}", path))?,
+    };
+    for line in listed.lines() {
+        let id = line
+            .parse::<u32>()
+            .change_context(Errors::DatafileCorrupted)
+            .attach_printable_lazy(|| format!("Invalid retired datafile id: {:?}", line))?;
+        hint_file::remove(dir_path, id)?;
+        let datafile = dir_path.join(datafile_name(id));
+        match fs::remove_file(&datafile) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            removed => removed
+                .change_context(Errors::InternalError)
+                .attach_printable_lazy(|| format!("Fail to remove retired {:?}", datafile))?,
+        }
+    }
+    fio::sync_dir(dir_path)?;
+    fs::remove_file(&path)
+        .change_context(Errors::InternalError)
+        .attach_printable_lazy(|| format!("Fail to remove {:?}", path))
+}
+
+/// The thread running the merges of [merge_schedule](crate::options::Options::merge_schedule),
+/// stopped and joined when dropped
+pub(crate) struct MergeScheduler {
+    stop: Option<mpsc::Sender<()>>,
+    handle: MergeHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MergeScheduler {
+    /// Wakes up every `interval` on the clock of `merger` to merge all the datafiles if
+    /// a merge is due, a merge thus runs at most once per `interval`
+    pub(crate) fn spawn(merger: Merger, interval: Duration) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = MergeHandle::new();
+        let cancelled = handle.clone();
+        let interval_ms = interval.as_millis() as u64;
+        let thread = std::thread::Builder::new()
+            .name("ailurus-kv-merge".to_string())
+            .spawn(move || {
+                let mut next = merger.clock.now_millis() + interval_ms;
+                loop {
+                    // a clock other than the system one is read again at least every interval
+                    let left = next.saturating_sub(merger.clock.now_millis());
+                    match stopped.recv_timeout(Duration::from_millis(left).min(interval)) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => return,
+                    }
+                    let now = merger.clock.now_millis();
+                    if now < next {
+                        continue;
+                    }
+                    next = now + interval_ms;
+                    if !merger.due() {
+                        continue;
+                    }
+                    match merger.merge(Some(&cancelled), true) {
+                        Ok(stats) => info!("Background merge done: {:?}", stats),
+                        Err(_) if cancelled.is_cancelled() => return,
+                        Err(e) => error!("Background merge failed: {:?}", e),
+                    }
+                }
+            })
+            .change_context(Errors::InternalError)
+            .attach_printable("Fail to spawn the merge thread")?;
+        Ok(MergeScheduler {
+            stop: Some(stop),
+            handle,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        self.handle.cancel();
+        // disconnecting wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The merge thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::data::data_file::{datafile_name, DATAFILE_SUFFIX};
+    use crate::data::hint_file::hint_name;
+    use crate::engine::LOCK_FILE;
+    use crate::errors::{CorruptionInfo, CorruptionReason, Errors};
+    use crate::iterator::Entry;
+    use crate::merge::{
+        FileInfo, MergeHandle, MergeStats, MERGE_DIR, MERGE_HISTORY_LEN, RETIRED_FILE,
+    };
+    use crate::mock::engine_wrapper::{self, EngineWrapper};
+    use crate::options::IteratorOptions;
+    use parking_lot::Mutex;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    /// An engine whose datafiles hold 10 records of a 4 bytes key and a 5 bytes value,
+    /// merged only when told to
+    fn small_files() -> EngineWrapper {
+        EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(0.0)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn put(db: &EngineWrapper, key: &str, value: &str) {
+        db.put(key.to_string().into(), value.to_string().into())
+            .unwrap();
+    }
+
+    /// Datafile 0 half overwritten by datafile 1, which is fully live
+    fn fragmented() -> EngineWrapper {
+        let db = small_files();
+        fragment(&db);
+        db
+    }
+
+    fn fragment(db: &EngineWrapper) {
+        for i in 0..10 {
+            put(db, &format!("k{:03}", i), "val-0");
+        }
+        for i in 0..5 {
+            put(db, &format!("k{:03}", i), "val-1");
+        }
+        for i in 10..16 {
+            put(db, &format!("k{:03}", i), "val-1");
+        }
+    }
+
+    /// Like [small_files], a merge is due once [fragment]ed and checked every `interval`
+    /// on `clock`
+    fn scheduled(interval: Duration, clock: Arc<MockClock>) -> EngineWrapper {
+        EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(0.2)
+                .merge_schedule(Some(interval))
+                .clock(clock)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    /// Polls `f` until it returns something, failing after 10 seconds
+    fn wait_for<T>(f: impl Fn() -> Option<T>) -> T {
+        let started = Instant::now();
+        loop {
+            if let Some(found) = f() {
+                return found;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+    }
+
+    fn assert_fragmented(db: &EngineWrapper) {
+        for i in 0..16 {
+            let expected = match i {
+                5..10 => "val-0",
+                _ => "val-1",
+            };
+            assert_eq!(
+                db.get(format!("k{:03}", i).into()).unwrap().unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn merge_files() {
+        let mut db = fragmented();
+        let untouched = fs::read(db.path().join(datafile_name(1))).unwrap();
+
+        let stats = db.merge_files(&[0]).unwrap();
+        assert_eq!((stats.files_in, stats.files_out), (1, 1));
+        assert_eq!((stats.records_copied, stats.records_dropped), (5, 5));
+        assert_eq!(stats.bytes_reclaimed, 5 * 16);
+        assert!(!db.path().join(datafile_name(0)).exists());
+        let path = db.path().join(datafile_name(1));
+        assert_eq!(fs::read(path).unwrap(), untouched);
+        assert_fragmented(&db);
+        assert!(!db.merge_due());
+
+        let db = db.reopen();
+        assert_fragmented(&db);
+    }
+
+    #[test]
+    fn merge_files_unknown_id() {
+        let mut db = fragmented();
+        let report = db.merge_files(&[0, 42]).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DatafileNotFound);
+        assert!(db.path().join(datafile_name(0)).exists());
+        assert_eq!(db.merge_files(&[]).unwrap(), MergeStats::default());
+    }
+
+    #[test]
+    fn merge_active_file() {
+        let mut db = fragmented();
+        let stats = db.merge_files(&[0, 1, 2]).unwrap();
+        assert_eq!((stats.records_copied, stats.records_dropped), (16, 5));
+        for id in 0..3 {
+            assert!(!db.path().join(datafile_name(id)).exists());
+        }
+        assert_fragmented(&db);
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn merge_keeps_needed_tombstones() {
+        let mut db = small_files();
+        put(&db, "gone", "val-0");
+        for i in 0..9 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        // tombstone in datafile 1 while the record lives on in datafile 0
+        db.delete("gone".into()).unwrap();
+        for i in 0..10 {
+            put(&db, &format!("g{:03}", i), "val-1");
+        }
+
+        let stats = db.merge_files(&[1]).unwrap();
+        assert_eq!((stats.records_copied, stats.records_dropped), (10, 0));
+        let mut db = db.reopen();
+        assert_eq!(db.get("gone".into()).unwrap(), None);
+
+        // once the record is merged away, so is the tombstone
+        let stats = db.merge_files(&[0]).unwrap();
+        assert_eq!((stats.records_copied, stats.records_dropped), (9, 1));
+        let sealed: Vec<u32> = db.files.read().idle.keys().copied().collect();
+        let stats = db.merge_files(&sealed).unwrap();
+        assert_eq!(stats.records_dropped, 1);
+        let db = db.reopen();
+        assert!(db.get("gone".into()).unwrap().is_none());
+        assert_eq!(db.get("g009".into()).unwrap().unwrap(), "val-1");
+    }
+
+    /// Contents of the datafiles in the directory of `db`, by name
+    fn snapshot(db: &EngineWrapper) -> BTreeMap<String, Vec<u8>> {
+        fs::read_dir(db.path())
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| {
+                let name = entry.file_name().into_string().unwrap();
+                (name, fs::read(entry.path()).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge() {
+        let mut db = fragmented();
+        db.delete("k015".into()).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let handle = MergeHandle::new().with_progress(move |progress| sink.lock().push(progress));
+
+        assert_eq!(db.last_merge_info(), None);
+        let stats = db.merge(Some(&handle)).unwrap();
+        let info = db.last_merge_info().unwrap();
+        assert_eq!((info.stats, info.background), (stats, false));
+        assert_eq!((stats.files_in, stats.files_out), (3, 2));
+        // the datafiles end up with the 15 live records and a fresh active datafile
+        assert_eq!((stats.records_copied, stats.records_dropped), (15, 7));
+        let names: Vec<_> = snapshot(&db).into_keys().collect();
+        assert_eq!(
+            names,
+            [
+                datafile_name(3),
+                hint_name(3),
+                datafile_name(4),
+                hint_name(4),
+                // after the ids set aside for 348 bytes of datafiles of 160 bytes
+                datafile_name(10),
+                LOCK_FILE.to_string(),
+            ]
+        );
+        assert_eq!(db.files.read().total_bytes(), 15 * 16);
+
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 15);
+        assert!(reports
+            .iter()
+            .enumerate()
+            .all(|(i, progress)| progress.records_done == i as u64 + 1
+                && progress.records_total == 15));
+
+        let db = db.reopen();
+        assert!(db.get("k015".into()).unwrap().is_none());
+        assert_eq!(db.get("k014".into()).unwrap().unwrap(), "val-1");
+        assert_eq!(db.get("k005".into()).unwrap().unwrap(), "val-0");
+    }
+
+    #[test]
+    fn merge_reclaims_space() {
+        let mut db = small_files();
+        for i in 0..200 {
+            put(&db, &format!("k{:03}", i), "val-0");
+        }
+        for i in (0..200).step_by(2) {
+            put(&db, &format!("k{:03}", i), "val-1");
+        }
+        for i in (1..200).step_by(4) {
+            db.delete(format!("k{:03}", i).into()).unwrap();
+        }
+        let size = |db: &EngineWrapper| snapshot(db).values().map(Vec::len).sum::<usize>();
+        let before = size(&db);
+
+        let stats = db.merge(None).unwrap();
+        assert_eq!(stats.records_copied, 150);
+        // the overwritten values and the tombstones are gone
+        assert_eq!(db.files.read().total_bytes(), 150 * 16);
+        assert!(size(&db) < before);
+
+        let db = db.reopen();
+        for i in 0..200 {
+            let got = db.get(format!("k{:03}", i).into()).unwrap();
+            match i % 4 {
+                1 => assert_eq!(got, None),
+                3 => assert_eq!(got.unwrap(), "val-0"),
+                _ => assert_eq!(got.unwrap(), "val-1"),
+            }
+        }
+    }
+
+    #[test]
+    fn stat() {
+        // the datafiles on disk, and their size
+        let on_disk = |db: &EngineWrapper| {
+            let datafiles: Vec<_> = snapshot(db)
+                .into_iter()
+                .filter(|(name, _)| name.ends_with(DATAFILE_SUFFIX))
+                .map(|(_, content)| content.len() as u64)
+                .collect();
+            (datafiles.len(), datafiles.iter().sum::<u64>())
+        };
+        let check = |db: &EngineWrapper, keys: usize, reclaimable_bytes: u64| {
+            let stat = db.stat().unwrap();
+            assert_eq!(stat.keys, keys);
+            assert_eq!((stat.datafiles, stat.disk_bytes), on_disk(db));
+            assert_eq!(stat.reclaimable_bytes, reclaimable_bytes);
+        };
+
+        let mut db = small_files();
+        check(&db, 0, 0);
+        for i in 0..30 {
+            put(&db, &format!("k{:03}", i), "val-0");
+        }
+        check(&db, 30, 0);
+        assert_eq!(db.stat().unwrap().disk_bytes, 30 * 16);
+
+        for i in 0..10 {
+            put(&db, &format!("k{:03}", i), "val-1");
+        }
+        check(&db, 30, 10 * 16);
+        for i in 10..15 {
+            db.delete(format!("k{:03}", i).into()).unwrap();
+        }
+        let tombstones = 5 * 11;
+        check(&db, 25, 15 * 16 + tombstones);
+
+        db.merge(None).unwrap();
+        check(&db, 25, 0);
+        assert_eq!(db.stat().unwrap().disk_bytes, 25 * 16);
+
+        let db = db.reopen();
+        check(&db, 25, 0);
+    }
+
+    #[test]
+    fn hint_files() {
+        let opts = engine_wrapper::options()
+            .data_file_size(10 * 16)
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        let (mut db, stats) = EngineWrapper::counting_with(opts);
+        fragment(&db);
+        db.merge(None).unwrap();
+        let positions = |db: &EngineWrapper| -> Vec<_> {
+            let mut iter = db.index.iterator(IteratorOptions::default());
+            std::iter::from_fn(|| iter.next().map(|(key, pos)| (key.clone(), *pos))).collect()
+        };
+        let merged = positions(&db);
+
+        let hints: Vec<_> = [3, 4]
+            .into_iter()
+            .map(|id| (id, fs::read(db.path().join(hint_name(id))).unwrap()))
+            .collect();
+        for (id, _) in &hints {
+            fs::remove_file(db.path().join(hint_name(*id))).unwrap();
+        }
+        let reads = stats.reads();
+        let db = db.reopen();
+        assert!(stats.reads() > reads);
+        assert_eq!(positions(&db), merged);
+        assert_fragmented(&db);
+
+        // the merged datafiles are not read at all with their hint files
+        for (id, hint) in &hints {
+            fs::write(db.path().join(hint_name(*id)), hint).unwrap();
+        }
+        let reads = stats.reads();
+        let db = db.reopen();
+        assert_eq!(stats.reads(), reads);
+        assert_eq!(positions(&db), merged);
+        assert_fragmented(&db);
+    }
+
+    #[test]
+    fn hint_file_removed_with_datafile() {
+        let mut db = fragmented();
+        db.merge(None).unwrap();
+        put(&db, "k000", "val-2");
+        db.merge_files(&[3]).unwrap();
+        assert!(!db.path().join(hint_name(3)).exists());
+        assert!(db.path().join(hint_name(4)).exists());
+
+        // left behind by a crash removing datafile 4
+        fs::remove_file(db.path().join(datafile_name(4))).unwrap();
+        let db = db.reopen();
+        assert!(!db.path().join(hint_name(4)).exists());
+    }
+
+    #[test]
+    fn merge_on_write() {
+        let datafiles = |db: &EngineWrapper| {
+            snapshot(db)
+                .into_keys()
+                .filter(|name| name.ends_with(DATAFILE_SUFFIX))
+                .count()
+        };
+        // the datafiles after each write
+        let overwrite = |db: &mut EngineWrapper| {
+            let mut counts = Vec::new();
+            for round in 0..20 {
+                for i in 0..5 {
+                    put(db, &format!("k{:03}", i), &format!("val-{}", round % 10));
+                    counts.push(datafiles(db));
+                }
+            }
+            counts
+        };
+        let opts = |ratio| {
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(ratio)
+                .build()
+                .unwrap()
+        };
+
+        let mut db = EngineWrapper::new(opts(0.5));
+        // never more than the datafile written by the last merge and the active one
+        let counts = overwrite(&mut db);
+        assert_eq!(counts.iter().max(), Some(&2));
+        assert!(db.metrics().merges > 1);
+        assert!(db.last_merge_info().unwrap().background);
+        let db = db.reopen();
+        for i in 0..5 {
+            assert_eq!(
+                db.get(format!("k{:03}", i).into()).unwrap().unwrap(),
+                "val-9"
+            );
+        }
+
+        // 100 records of 16 bytes fill 10 datafiles
+        let mut db = EngineWrapper::new(opts(0.0));
+        overwrite(&mut db);
+        assert_eq!(datafiles(&db), 10);
+        assert_eq!(db.last_merge_info(), None);
+
+        let mut db = EngineWrapper::new(opts(0.5));
+        db.set_auto_merge(false);
+        overwrite(&mut db);
+        assert_eq!(datafiles(&db), 10);
+        assert_eq!(db.last_merge_info(), None);
+    }
+
+    #[test]
+    fn merge_on_write_is_not_throttled() {
+        let db = EngineWrapper::new(
+            engine_wrapper::options()
+                .data_file_size(10 * 16)
+                .danger_small_files(true)
+                .merge_ratio(0.5)
+                // a throttled merge of a single datafile would take 10 seconds
+                .merge_bytes_per_sec(Some(16))
+                .build()
+                .unwrap(),
+        );
+        let started = Instant::now();
+        for round in 0..20 {
+            for i in 0..5 {
+                put(&db, &format!("k{:03}", i), &format!("val-{}", round % 10));
+            }
+        }
+        assert!(db.metrics().merges > 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn merge_cancelled() {
+        let mut db = fragmented();
+        let before = snapshot(&db);
+        let handle = MergeHandle::new();
+        let canceller = handle.clone();
+        let handle = handle.with_progress(move |progress| {
+            if progress.records_done * 2 >= progress.records_total {
+                canceller.cancel();
+            }
+        });
+
+        let report = db.merge(Some(&handle)).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::MergeCancelled);
+        assert!(handle.is_cancelled());
+        assert_eq!(snapshot(&db), before);
+        assert_fragmented(&db);
+
+        let mut db = db.reopen();
+        assert_eq!(snapshot(&db), before);
+        assert_fragmented(&db);
+        assert_eq!(db.merge(None).unwrap().files_in, 3);
+        assert_fragmented(&db);
+    }
+
+    #[test]
+    fn throttled_merge_yields_to_reads() {
+        let db = fragmented();
+        // about a second for the 336 bytes of the datafiles
+        db.update_options(|opts| opts.merge_bytes_per_sec = Some(336))
+            .unwrap();
+        let started = Arc::new(AtomicBool::new(false));
+        let copying = started.clone();
+        let handle =
+            MergeHandle::new().with_progress(move |_| copying.store(true, Ordering::Relaxed));
+        let merger = db.merger.clone();
+        let merge = std::thread::spawn(move || merger.merge(Some(&handle), true));
+
+        wait_for(|| started.load(Ordering::Relaxed).then_some(()));
+        for _ in 0..100 {
+            assert_fragmented(&db);
+        }
+        put(&db, "k100", "val-2");
+        assert_eq!(db.get("k100".into()).unwrap().unwrap(), "val-2");
+        assert!(!merge.is_finished());
+
+        let stats = merge.join().unwrap().unwrap();
+        assert_eq!(stats.records_copied, 16);
+        assert!(stats.duration >= Duration::from_millis(500));
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn merge_keeps_concurrent_writes() {
+        let db = fragmented();
+        // the merge waits after copying its first record, `k005`, until the writes are done
+        let barrier = Arc::new(Barrier::new(2));
+        let paused = barrier.clone();
+        let handle = MergeHandle::new().with_progress(move |progress| {
+            if progress.records_done == 1 {
+                paused.wait();
+                paused.wait();
+            }
+        });
+        let merger = db.merger.clone();
+        let merge = std::thread::spawn(move || merger.merge(Some(&handle), false));
+
+        barrier.wait();
+        // copied already, and yet to be copied
+        put(&db, "k005", "val-2");
+        put(&db, "k006", "val-2");
+        db.delete("k010".into()).unwrap();
+        barrier.wait();
+        let stats = merge.join().unwrap().unwrap();
+        // `k006` and `k010` are read once written again, they are dropped
+        assert_eq!((stats.records_copied, stats.records_dropped), (14, 7));
+
+        let check = |db: &EngineWrapper| {
+            for i in 0..16 {
+                let expected = match i {
+                    5 | 6 => Some("val-2"),
+                    7..10 => Some("val-0"),
+                    10 => None,
+                    _ => Some("val-1"),
+                };
+                let value = db.get(format!("k{:03}", i).into()).unwrap();
+                assert_eq!(value.as_deref(), expected.map(str::as_bytes), "k{:03}", i);
+            }
+        };
+        check(&db);
+        // the stale copy of `k005` is left for the next merge to reclaim
+        let candidates = db.compaction_candidates();
+        assert_eq!(candidates[0].dead_bytes, 16);
+        let db = db.reopen();
+        check(&db);
+        assert_eq!(db.compaction_candidates(), candidates);
+    }
+
+    #[test]
+    fn purge_tombstones() {
+        let mut db = small_files();
+        let del = |db: &mut EngineWrapper, key: &str| db.delete(key.to_string().into()).unwrap();
+        put(&db, "kaaa", "val-0");
+        put(&db, "kbbb", "val-0");
+        put(&db, "kddd", "val-0");
+        for i in 0..10 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        // would resurrect `kaaa` and `kddd`
+        del(&mut db, "kaaa");
+        del(&mut db, "kddd");
+        // `kbbb` is written again
+        del(&mut db, "kbbb");
+        put(&db, "kbbb", "val-1");
+        // `keee` written and deleted within the same datafile
+        put(&db, "keee", "val-0");
+        del(&mut db, "keee");
+        for i in 10..22 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        let sizes =
+            |db: &EngineWrapper| -> u64 { snapshot(db).values().map(|x| x.len() as u64).sum() };
+        let before = sizes(&db);
+
+        // only the tombstone of `kbbb` hides nothing anymore
+        assert_eq!(db.purge_tombstones().unwrap(), 11);
+        assert_eq!(sizes(&db), before - 11);
+        assert_eq!(db.purge_tombstones().unwrap(), 0);
+
+        let check = |db: &EngineWrapper| {
+            for key in ["kaaa", "kddd", "keee"] {
+                assert!(
+                    db.get(key.into()).unwrap().is_none(),
+                    "{} is resurrected",
+                    key
+                );
+            }
+            assert_eq!(db.get("kbbb".into()).unwrap().unwrap(), "val-1");
+            for i in 0..22 {
+                assert_eq!(
+                    db.get(format!("f{:03}", i).into()).unwrap().unwrap(),
+                    "val-0"
+                );
+            }
+        };
+        check(&db);
+        let mut db = db.reopen();
+        check(&db);
+
+        // once the deleted records are merged away, their tombstones can go as well,
+        // unlike the one following its record in the same datafile
+        let stats = db.merge_files(&[0]).unwrap();
+        assert_eq!(stats.records_dropped, 3);
+        assert_eq!(db.purge_tombstones().unwrap(), 2 * 11);
+        check(&db);
+        check(&db.reopen());
+    }
+
+    #[test]
+    fn gc_dead_files() {
+        let mut db = small_files();
+        for round in 0..2 {
+            for i in 0..10 {
+                put(&db, &format!("k{:03}", i), &format!("val-{}", round));
+            }
+        }
+        for i in 0..5 {
+            put(&db, &format!("k{:03}", i), "val-2");
+        }
+        for i in 0..6 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        let check = |db: &EngineWrapper| {
+            for i in 0..10 {
+                let expected = if i < 5 { "val-2" } else { "val-1" };
+                assert_eq!(
+                    db.get(format!("k{:03}", i).into()).unwrap().unwrap(),
+                    expected
+                );
+            }
+        };
+
+        // datafile 0 is fully overwritten, 1 and 2 are partially live
+        assert_eq!(db.gc_dead_files().unwrap(), [0]);
+        assert!(!db.path().join(datafile_name(0)).exists());
+        assert!(db.path().join(datafile_name(1)).exists());
+        assert!(db.path().join(datafile_name(2)).exists());
+        assert!(db.gc_dead_files().unwrap().is_empty());
+        check(&db);
+        let mut db = db.reopen();
+        check(&db);
+
+        for i in 5..10 {
+            put(&db, &format!("k{:03}", i), "val-3");
+        }
+        for i in 0..5 {
+            db.delete(format!("k{:03}", i).into()).unwrap();
+        }
+        for i in 6..20 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        assert_eq!(db.gc_dead_files().unwrap(), [1]);
+    }
+
+    #[test]
+    fn gc_keeps_tombstones() {
+        let mut db = small_files();
+        put(&db, "gone", "val-0");
+        for i in 0..9 {
+            put(&db, &format!("f{:03}", i), "val-0");
+        }
+        // datafile 1 holds nothing live but the tombstone of a record of datafile 0
+        db.delete("gone".into()).unwrap();
+        for round in 0..2 {
+            for i in 0..9 {
+                put(&db, &format!("g{:03}", i), &format!("val-{}", round));
+            }
+        }
+        put(&db, "h000", "val-0");
+        put(&db, "h001", "val-0");
+        assert_eq!(db.files.read().live_records.get(&1), Some(&0));
+
+        assert!(db.gc_dead_files().unwrap().is_empty());
+        let db = db.reopen();
+        assert!(db.get("gone".into()).unwrap().is_none());
+    }
+
+    /// Opens a database in a fresh directory, made of `files` and of the files
+    /// left by a merge in its staging directory
+    fn crashed(
+        files: &BTreeMap<String, Vec<u8>>,
+        staged: &BTreeMap<String, Vec<u8>>,
+    ) -> EngineWrapper {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(MERGE_DIR)).unwrap();
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        for (name, content) in staged {
+            fs::write(dir.path().join(MERGE_DIR).join(name), content).unwrap();
+        }
+        let opts = small_files().options.clone();
+        let db = EngineWrapper::in_dir(dir, opts, crate::fio::default_io_manager());
+        assert!(!db.path().join(MERGE_DIR).exists());
+        db
+    }
+
+    /// The datafiles of `db` before and after a complete merge
+    fn merge_states() -> (BTreeMap<String, Vec<u8>>, BTreeMap<String, Vec<u8>>) {
+        let mut db = fragmented();
+        let before = snapshot(&db);
+        db.merge(None).unwrap();
+        assert!(!db.path().join(MERGE_DIR).exists());
+        (before, snapshot(&db))
+    }
+
+    #[test]
+    fn crashed_before_install() {
+        let (before, after) = merge_states();
+        // the first output was fully written, the second one only partially
+        let mut staged = after.clone();
+        staged.retain(|name, _| [datafile_name(3), datafile_name(4)].contains(name));
+        staged.get_mut(&datafile_name(4)).unwrap().truncate(7);
+
+        let mut db = crashed(&before, &staged);
+        assert_eq!(snapshot(&db), before);
+        assert_fragmented(&db);
+        db.merge(None).unwrap();
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn crashed_mid_install() {
+        let (before, after) = merge_states();
+        // the first output has been moved next to the merged datafiles
+        let mut files = before.clone();
+        let first = datafile_name(3);
+        files.insert(first.clone(), after[&first].clone());
+        let mut staged = after.clone();
+        staged.retain(|name, _| *name == datafile_name(4));
+
+        let mut db = crashed(&files, &staged);
+        assert_eq!(snapshot(&db), files);
+        assert_fragmented(&db);
+        let stats = db.merge(None).unwrap();
+        assert_eq!(stats.records_copied, 16);
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn compaction_candidates() {
+        let mut db = fragmented();
+        let candidates = db.compaction_candidates();
+        let stripped: Vec<FileInfo> = candidates
+            .iter()
+            .map(|file| FileInfo {
+                created_at: None,
+                ..file.clone()
+            })
+            .collect();
+        let info = |id, dead_bytes, live_records| FileInfo {
+            id,
+            size: 10 * 16,
+            live_bytes: 10 * 16 - dead_bytes,
+            dead_bytes,
+            live_records,
+            created_at: None,
+        };
+        assert_eq!(stripped, [info(0, 5 * 16, 5), info(1, 0, 10)]);
+        assert!(candidates.iter().all(|file| file.created_at.is_some()));
+        let files = db.files.read();
+        let dead: u64 = candidates.iter().map(|file| file.dead_bytes).sum();
+        assert_eq!(dead, files.reclaimable_bytes());
+        drop(files);
+
+        db.merge_files(&[0]).unwrap();
+        let ids: Vec<u32> = db.compaction_candidates().iter().map(|f| f.id).collect();
+        // the active datafile got sealed by the merge
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut db = small_files();
+        assert_eq!(db.metrics().write_amplification(), 1.0);
+        let mut last = db.metrics();
+        for round in 0..MERGE_HISTORY_LEN + 2 {
+            for i in 0..4 {
+                put(&db, &format!("k{:03}", i), &format!("val-{}", round % 10));
+            }
+            let stats = db.merge(None).unwrap();
+            let metrics = db.metrics();
+            assert_eq!(metrics.merges, round as u64 + 1);
+            assert_eq!(metrics.user_bytes_written, last.user_bytes_written + 4 * 16);
+            assert_eq!(
+                metrics.merge_bytes_written,
+                last.merge_bytes_written + stats.bytes_written
+            );
+            assert!(metrics.bytes_reclaimed >= last.bytes_reclaimed);
+            assert_eq!(db.last_merge_info().unwrap().stats, stats);
+            last = metrics;
+        }
+        // the 4 live records are rewritten by every merge
+        assert_eq!(last.write_amplification(), 2.0);
+        assert_eq!(
+            last.bytes_reclaimed,
+            (MERGE_HISTORY_LEN as u64 + 1) * 4 * 16
+        );
+
+        let history = db.merge_history();
+        assert_eq!(history.len(), MERGE_HISTORY_LEN);
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].finished_at <= pair[1].finished_at));
+        assert_eq!(history.last(), db.last_merge_info().as_ref());
+    }
+
+    #[test]
+    fn merge_partial() {
+        let mut db = fragmented();
+        // datafile 1 has nothing to reclaim
+        let stats = db.merge_partial(5).unwrap();
+        assert_eq!(stats.files_in, 1);
+        assert!(!db.path().join(datafile_name(0)).exists());
+        assert!(db.path().join(datafile_name(1)).exists());
+        assert_eq!(db.merge_partial(5).unwrap(), MergeStats::default());
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn scheduled_merge() {
+        let clock = MockClock::new(0);
+        // the thread wakes up every 10 milliseconds, it merges once the clock tells to
+        let db = scheduled(Duration::from_millis(10), clock.clone());
+        // no background merge while another one is running
+        let state = db.merger.state.clone();
+        let running = state.running.lock();
+        fragment(&db);
+        assert!(db.merge_due());
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(db.last_merge_info(), None);
+        drop(running);
+
+        let info = wait_for(|| db.last_merge_info());
+        assert!(info.background);
+        assert_eq!(info.finished_at, UNIX_EPOCH + Duration::from_millis(10));
+        assert_eq!(info.stats.duration, Duration::ZERO);
+        assert_eq!(info.stats.records_copied, 16);
+        assert!(!db.merge_due());
+        assert_fragmented(&db);
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn scheduled_merge_stops_on_drop() {
+        let db = scheduled(Duration::from_secs(3600), MockClock::new(0));
+        fragment(&db);
+        let started = Instant::now();
+        drop(db);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn verify_after_merge() {
+        let opts = engine_wrapper::options()
+            .data_file_size(10 * 16)
+            .danger_small_files(true)
+            .verify_after_merge(true)
+            .build()
+            .unwrap();
+        let (mut db, faults) = EngineWrapper::faulty_with(opts);
+        fragment(&db);
+        let before = snapshot(&db);
+
+        // the third record copied is damaged on its way to disk
+        faults.corrupt_write(3);
+        let report = db.merge(None).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::MergeVerificationFailed);
+        let info = report.downcast_ref::<CorruptionInfo>().unwrap();
+        assert_eq!((info.file_id, info.offset), (3, 2 * 16));
+        assert_eq!(info.reason, CorruptionReason::CrcMismatch);
+        assert_eq!(snapshot(&db), before);
+        assert!(!db.path().join(MERGE_DIR).exists());
+        assert_eq!(db.last_merge_info(), None);
+        assert_fragmented(&db);
+
+        assert_eq!(db.merge(None).unwrap().records_copied, 16);
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn merge_retires_pinned_files() {
+        let db = fragmented();
+        let mut iter = db.iter(IteratorOptions::default()).unwrap();
+        let first = iter.next().unwrap().unwrap();
+        // like a background merge, running along the iterator
+        db.merger.merge(None, true).unwrap();
+        for id in 0..3 {
+            assert!(db.path().join(datafile_name(id)).exists());
+        }
+        assert_eq!(db.files.read().retired.len(), 3);
+
+        let entries: Vec<Entry> = [first]
+            .into_iter()
+            .chain(iter.map(Result::unwrap))
+            .collect();
+        assert_eq!(entries.len(), 16);
+        assert_eq!(entries[0], Entry::new("k000", "val-1"));
+        assert_eq!(entries[5], Entry::new("k005", "val-0"));
+        // the iterator is dropped once exhausted
+        for id in 0..3 {
+            assert!(!db.path().join(datafile_name(id)).exists());
+        }
+        assert!(!db.path().join(RETIRED_FILE).exists());
+        assert!(db.files.read().retired.is_empty());
+        assert_fragmented(&db.reopen());
+    }
+
+    #[test]
+    fn retired_files_removed_on_open() {
+        let (before, after) = merge_states();
+        // the merged datafiles outlived the engine, still pinned by an iterator
+        let mut files = before.clone();
+        files.extend(after.clone());
+        files.insert(RETIRED_FILE.to_string(), b"0\n1\n2\n".to_vec());
+
+        let db = crashed(&files, &BTreeMap::new());
+        assert_eq!(snapshot(&db), after);
+        assert_fragmented(&db);
+    }
+}
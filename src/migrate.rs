@@ -0,0 +1,138 @@
+//! Migration from other key-value stores into a fresh store.
+//!
+//! While a migration runs, the target directory holds a marker file: a migration that
+//! fails leaves it behind, and [Engine::new] refuses to open the store until the directory
+//! is removed. A store opened fine thus never holds a partial migration.
+
+use crate::batch::SyncOverride;
+use crate::engine::{mark_complete, mark_incomplete, Engine};
+use crate::errors::{Errors, Result};
+use crate::fio;
+use crate::import::{ImportStats, MalformedLine};
+use crate::options::{ImportOptions, Options, WriteBatchOptions};
+use bytes::Bytes;
+
+/// Writes the key-value `pairs` into a fresh store at `target_opts.dir_path`, in the order
+/// they come: sources iterating in key order, as most embedded stores do, produce
+/// datafiles in key order too.
+///
+/// A failing source item aborts the migration. Pairs the store rejects, e.g. of an empty
+/// key, are counted in [ImportStats::malformed] with their position in `pairs`.
+pub fn from_iter<I>(pairs: I, target_opts: Options) -> Result<ImportStats>
+where
+    I: IntoIterator<Item = Result<(Bytes, Bytes)>>,
+{
+    let mut opts = target_opts;
+    opts.create_if_missing = true;
+    opts.error_if_exists = true;
+    let marker = mark_incomplete(&opts.dir_path)?;
+
+    let engine = Engine::with_io_manager(opts, fio::default_io_manager())?;
+    let defaults = ImportOptions::default();
+    let mut batch = engine.write_batch(WriteBatchOptions {
+        batch_size: defaults.batch_size,
+        sync_on_commit: false,
+    });
+    let mut stats = ImportStats::default();
+    for pair in pairs {
+        let (key, value) = pair?;
+        stats.lines += 1;
+        if !batch.is_staged(&key) && batch.staged_len() >= defaults.batch_size as usize {
+            batch.commit_with(SyncOverride::Skip)?;
+        }
+        let reason = match batch.put(key, value) {
+            Ok(()) => {
+                stats.imported += 1;
+                continue;
+            }
+            Err(report) => match report.current_context() {
+                Errors::EmptyKey => "empty key",
+                Errors::KeyTooLarge => "key too large",
+                Errors::ValueTooLarge => "value too large",
+                _ => return Err(report),
+            },
+        };
+        stats.malformed += 1;
+        if stats.malformed_lines.len() < defaults.max_malformed_kept {
+            stats.malformed_lines.push(MalformedLine {
+                line: stats.lines,
+                reason,
+            });
+        }
+    }
+    batch.commit_with(SyncOverride::Skip)?;
+    engine.sync()?;
+    drop(engine);
+
+    mark_complete(&marker)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, INCOMPLETE_FILE};
+    use crate::errors::{Errors, Result};
+    use crate::migrate::from_iter;
+    use crate::options::{IteratorOptions, Options, OptionsBuilder};
+    use bytes::Bytes;
+    use error_stack::Report;
+    use std::path::Path;
+
+    fn options(dir: &Path) -> Options {
+        OptionsBuilder::default()
+            .dir_path(dir.join("target"))
+            .build()
+            .unwrap()
+    }
+
+    fn pairs(n: u32) -> impl Iterator<Item = Result<(Bytes, Bytes)>> {
+        (0..n).map(|i| {
+            Ok((
+                format!("key-{:05}", i).into(),
+                format!("value-{}", i).into(),
+            ))
+        })
+    }
+
+    #[test]
+    fn migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        // more pairs than fit in a single batch
+        let source = pairs(70_000).chain([Ok((Bytes::new(), "no key".into()))]);
+        let stats = from_iter(source, options(dir.path())).unwrap();
+        assert_eq!(
+            (stats.lines, stats.imported, stats.malformed),
+            (70_001, 70_000, 1)
+        );
+        assert_eq!(stats.malformed_lines[0].line, 70_001);
+
+        let engine = Engine::new(options(dir.path())).unwrap();
+        let keys: Vec<_> = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .keys()
+            .collect();
+        assert_eq!(keys.len(), 70_000);
+        assert_eq!(engine.get("key-00042".into()).unwrap().unwrap(), "value-42");
+        drop(engine);
+
+        let report = from_iter(pairs(1), options(dir.path())).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::DbAlreadyExists);
+        assert!(Engine::new(options(dir.path())).is_ok());
+    }
+
+    #[test]
+    fn failed_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = pairs(10).chain([Err(Report::new(Errors::FailToReadFromFile))]);
+        let report = from_iter(source, options(dir.path())).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::FailToReadFromFile);
+
+        let target = dir.path().join("target");
+        assert!(target.join(INCOMPLETE_FILE).exists());
+        let report = Engine::new(options(dir.path())).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::IncompleteDb);
+        let report = from_iter(pairs(1), options(dir.path())).unwrap_err();
+        assert_eq!(report.current_context(), &Errors::IncompleteDb);
+    }
+}
@@ -0,0 +1,64 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// The system allocator, counting the allocations made by threads inside [count_allocations]
+/// along with the bytes allocated inside [count_allocated_bytes]
+pub struct CountingAlloc;
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record(size: usize) {
+    // `try_with` since the allocator can still be called while thread locals are torn down
+    if COUNTING.try_with(|x| x.get()).unwrap_or(false) {
+        let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+        let _ = BYTES.try_with(|x| x.set(x.get() + size));
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Runs `f`, returning its result and the number of allocations it made on the current thread,
+/// tests running concurrently on other threads are not counted.
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    ALLOCATIONS.with(|x| x.set(0));
+    COUNTING.with(|x| x.set(true));
+    let result = f();
+    COUNTING.with(|x| x.set(false));
+    (result, ALLOCATIONS.with(|x| x.get()))
+}
+
+/// Like [count_allocations], returning the number of bytes allocated instead, a
+/// reallocation counts the bytes it grows by
+pub fn count_allocated_bytes<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    BYTES.with(|x| x.set(0));
+    COUNTING.with(|x| x.set(true));
+    let result = f();
+    COUNTING.with(|x| x.set(false));
+    (result, BYTES.with(|x| x.get()))
+}
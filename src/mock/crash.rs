@@ -0,0 +1,266 @@
+//! Crash-consistency harness: a random workload runs on [CrashyIO], then its writes are
+//! replayed up to a crash point into a fresh directory, which is reopened and checked
+//! against a model of the workload.
+//!
+//! A crash keeps a prefix of the writes, i.e. they reach the disk in the order they were
+//! issued, and it may cut them at any byte written after the last sync. The invariants are
+//! that every operation acknowledged before that sync is present, and that the operations
+//! are applied one at a time, a batch as a whole.
+
+use crate::data::data_file::datafile_name;
+use crate::engine::Engine;
+use crate::errors::{CorruptionInfo, Errors};
+use crate::mock::io_wrapper::{CrashLog, CrashyIO};
+use crate::options::{IteratorOptions, Options, OptionsBuilder, SyncPolicy, WriteBatchOptions};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+type State = BTreeMap<Bytes, Bytes>;
+
+/// A single put or delete, or a whole batch
+struct Unit {
+    /// The records in the order they are written, `None` deletes the key
+    writes: Vec<(Bytes, Option<Bytes>)>,
+    batch: bool,
+    /// Bytes written once the unit was acknowledged
+    end: u64,
+    /// Bytes synced once the unit was acknowledged
+    synced: u64,
+}
+
+fn apply(state: &mut State, writes: &[(Bytes, Option<Bytes>)]) {
+    for (key, value) in writes {
+        match value {
+            Some(value) => state.insert(key.clone(), value.clone()),
+            None => state.remove(key),
+        };
+    }
+}
+
+pub(crate) struct Workload {
+    seed: u64,
+    opts: Options,
+    units: Vec<Unit>,
+    log: Arc<CrashLog>,
+    _dir: TempDir,
+}
+
+impl Workload {
+    /// Runs `len` random puts, deletes, batches and syncs drawn from `seed` on a fresh
+    /// engine whose small datafiles rotate often
+    pub(crate) fn run(seed: u64, len: usize) -> Workload {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let dir = tempfile::tempdir().unwrap();
+        let policies = [
+            SyncPolicy::Always,
+            SyncPolicy::OnRotation,
+            SyncPolicy::Bytes(2 * 1024),
+            SyncPolicy::Never,
+        ];
+        let opts = OptionsBuilder::default()
+            .dir_path(dir.path().to_path_buf())
+            .data_file_size(8 * 1024)
+            .danger_small_files(true)
+            .sync_policy(policies[rng.usize(..policies.len())])
+            // the log records the writes to the datafiles, not those of a merge
+            .merge_ratio(0.0)
+            .build()
+            .unwrap();
+        let log = Arc::new(CrashLog::default());
+        let engine = Engine::with_io_manager(opts.clone(), CrashyIO::factory(log.clone())).unwrap();
+
+        let mut state = State::new();
+        let mut units = Vec::with_capacity(len);
+        let key = |rng: &mut fastrand::Rng| Bytes::from(format!("key-{:02}", rng.u8(..32)));
+        let value = |rng: &mut fastrand::Rng| {
+            let len = rng.usize(..512);
+            Bytes::from(
+                std::iter::repeat_with(|| rng.u8(..))
+                    .take(len)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        for _ in 0..len {
+            let (writes, batch) = match rng.u8(..10) {
+                0..=4 => {
+                    let (key, value) = (key(&mut rng), value(&mut rng));
+                    engine.put(key.clone(), value.clone()).unwrap();
+                    (vec![(key, Some(value))], false)
+                }
+                5 | 6 => {
+                    let key = key(&mut rng);
+                    match engine.delete(key.clone()) {
+                        Ok(()) => (vec![(key, None)], false),
+                        Err(report) if report.current_context() == &Errors::KeyNotFound => continue,
+                        Err(report) => panic!("{:?}", report),
+                    }
+                }
+                7 | 8 => {
+                    let mut batch = engine.write_batch(WriteBatchOptions {
+                        sync_on_commit: rng.bool(),
+                        ..Default::default()
+                    });
+                    // staged as the batch stages them, written in key order
+                    let mut staged = BTreeMap::new();
+                    for _ in 0..rng.usize(1..8) {
+                        let key = key(&mut rng);
+                        match rng.bool() {
+                            true => {
+                                let value = value(&mut rng);
+                                batch.put(key.clone(), value.clone()).unwrap();
+                                staged.insert(key, Some(value));
+                            }
+                            false if state.contains_key(&key) => {
+                                batch.delete(key.clone()).unwrap();
+                                staged.insert(key, None);
+                            }
+                            false if staged.remove(&key).is_some() => {
+                                batch.delete(key).unwrap();
+                            }
+                            false => {}
+                        }
+                    }
+                    batch.commit().unwrap();
+                    (staged.into_iter().collect(), true)
+                }
+                _ => {
+                    engine.sync().unwrap();
+                    continue;
+                }
+            };
+            apply(&mut state, &writes);
+            units.push(Unit {
+                writes,
+                batch,
+                end: log.written(),
+                synced: log.synced(),
+            });
+        }
+        drop(engine);
+
+        Workload {
+            seed,
+            opts,
+            units,
+            log,
+            _dir: dir,
+        }
+    }
+
+    /// Crashes at random points of the workload, as many as `crashes`, each checked
+    /// by [Workload::check]
+    pub(crate) fn crash_randomly(&self, crashes: usize) {
+        let mut rng = fastrand::Rng::with_seed(self.seed);
+        for _ in 0..crashes {
+            // the crash strikes after the `i`th unit is acknowledged and before the next one is
+            let i = rng.usize(..self.units.len());
+            let next = match self.units.get(i + 1) {
+                Some(unit) => unit.end,
+                None => self.log.written(),
+            };
+            self.check(rng.u64(self.units[i].synced..=next));
+        }
+    }
+
+    /// Crashes around the end of each write: right at it, one byte short of it, and one
+    /// byte into the next write, each checked by [Workload::check]
+    pub(crate) fn crash_at_boundaries(&self) {
+        let written = self.log.written();
+        for end in self.log.boundaries() {
+            for bytes in [end.saturating_sub(1), end, (end + 1).min(written)] {
+                self.check(bytes);
+            }
+        }
+    }
+
+    /// Reopens the store as a crash after `bytes` bytes written leaves it, and checks it
+    /// against the model
+    fn check(&self, bytes: u64) {
+        let mut state = State::new();
+        let done = self.units.iter().take_while(|unit| unit.end <= bytes);
+        for unit in done.clone() {
+            apply(&mut state, &unit.writes);
+        }
+        let done = done.count();
+
+        // the unit the crash cuts, be it a batch, is not applied at all
+        let recovered = self.reopen(bytes);
+        assert!(
+            recovered == state,
+            "seed {}, crash after {} bytes: the store does not hold the {} units written by then",
+            self.seed,
+            bytes,
+            done
+        );
+    }
+
+    fn reopen(&self, bytes: u64) -> State {
+        let dir = tempfile::tempdir().unwrap();
+        self.log.crash(bytes, dir.path()).unwrap();
+        let opts = Options {
+            dir_path: dir.path().to_path_buf(),
+            ..self.opts.clone()
+        };
+        let engine = match Engine::new(opts.clone()) {
+            Ok(engine) => engine,
+            // the engine does not repair a torn write, truncate the datafile as it tells
+            Err(report) => {
+                let info = *report.downcast_ref::<CorruptionInfo>().unwrap_or_else(|| {
+                    panic!("seed {}, crash after {}: {:?}", self.seed, bytes, report)
+                });
+                assert!(
+                    info.recoverable,
+                    "seed {}, crash after {} bytes: {}",
+                    self.seed, bytes, info
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(dir.path().join(datafile_name(info.file_id)))
+                    .and_then(|file| file.set_len(info.offset))
+                    .unwrap();
+                Engine::new(opts).unwrap()
+            }
+        };
+        let recovered = engine
+            .iter(IteratorOptions::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().into_parts())
+            .collect();
+        recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::crash::Workload;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn fixed_seeds() {
+        for seed in [0, 1, 7, 42, 1234, 0xdead_beef] {
+            Workload::run(seed, 200).crash_randomly(20);
+        }
+    }
+
+    #[test]
+    fn write_boundaries() {
+        let workload = Workload::run(3, 60);
+        assert!(workload.units.iter().any(|unit| unit.batch));
+        workload.crash_at_boundaries();
+    }
+
+    #[test]
+    fn random_seeds() {
+        // the seed is in the message of a failure, add it to `fixed_seeds` once fixed
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        for seed in seed..seed + 5 {
+            Workload::run(seed, 300).crash_randomly(20);
+        }
+    }
+}
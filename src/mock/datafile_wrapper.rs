@@ -1,79 +1,31 @@
-use crate::data::data_file::{DataFile, DATAFILE_SUFFIX};
-use lazy_static::lazy_static;
-use std::fs;
-use std::fs::{remove_file, OpenOptions};
+use crate::data::data_file::DataFile;
 use std::ops::{Deref, DerefMut};
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-
-lazy_static! {
-    static ref DATAFILEDISTRIBUTOR: DataFileDistributor = DataFileDistributor::new();
-}
-
-pub struct DataFileDistributor {
-    count: Mutex<u32>,
-}
-
-impl DataFileDistributor {
-    pub(crate) fn new() -> Self {
-        DataFileDistributor {
-            count: Mutex::new(0),
-        }
-    }
-
-    pub(crate) fn id(&self) -> u32 {
-        let mut guard = self.count.lock().unwrap();
-        let id = *guard;
-        *guard += 1;
-        id
-    }
-}
+use std::path::PathBuf;
+use tempfile::TempDir;
 
+/// A datafile living in a fresh temporary directory, removed when the wrapper is dropped
 pub struct DataFileWrapper {
     datafile: DataFile,
     path: PathBuf,
+    // dropped after the datafile is closed
+    _dir: TempDir,
 }
 
 impl DataFileWrapper {
-    pub(crate) fn new<P: AsRef<Path>>(path: P, id: u32) -> Self {
-        let path = path.as_ref().to_path_buf();
-
-        let path = match path.is_dir() {
-            true => {
-                let datafile = std::format!("{:09}{}", id, DATAFILE_SUFFIX);
-                path.join(datafile)
-            }
-            false => {
-                // TODO: deal with case that path is file not a path
-                fs::create_dir_all(&path).unwrap();
-                let datafile = std::format!("{:09}{}", id, DATAFILE_SUFFIX);
-                path.join(datafile)
-            }
-        };
-
-        let _ = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(&path)
-            .unwrap()
-            .sync_all();
-
-        let datafile = DataFile::new(path.parent().unwrap(), id).unwrap();
-
-        DataFileWrapper { datafile, path }
+    pub(crate) fn new(id: u32) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let datafile = DataFile::new(dir.path(), id).unwrap();
+        DataFileWrapper {
+            datafile,
+            path: dir.path().join(crate::data::data_file::datafile_name(id)),
+            _dir: dir,
+        }
     }
 }
 
 impl Default for DataFileWrapper {
     fn default() -> Self {
-        DataFileWrapper::new(Path::new("tmp"), DATAFILEDISTRIBUTOR.id())
-    }
-}
-
-impl Drop for DataFileWrapper {
-    fn drop(&mut self) {
-        remove_file(&self.path).unwrap();
+        DataFileWrapper::new(0)
     }
 }
 
@@ -1,101 +1,124 @@
 use crate::engine::Engine;
-use crate::options::IndexType;
-use lazy_static::lazy_static;
-use std::fs;
+use crate::mock::io_wrapper::{CountingIO, Faults, FaultyIO, IOStats};
+use crate::options::{IndexType, OptionsBuilder};
 use std::ops::{Deref, DerefMut};
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-
-const PREFIX: &str = "tmp/engine";
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
 
 #[macro_export]
 macro_rules! engine {
     ($([$key:expr, $value:expr]),* $(,)?) => {{
-        #[allow(unused_mut)]
-        let mut db = $crate::mock::engine_wrapper::EngineWrapper::default();
+        let db = $crate::mock::engine_wrapper::EngineWrapper::default();
         $(db.put($key.into(),$value.into()).unwrap();)*
         db
     }};
 }
 
-lazy_static! {
-    pub static ref ENGINEDISTRIBUTOR: EngineDistributor = EngineDistributor::new();
-}
-pub struct Inner {
-    id: u32,
-    count: u32,
+/// The builder of the options of an [EngineWrapper], its `dir_path` is ignored since the
+/// wrapper opens the engine in a directory of its own
+pub(crate) fn options() -> OptionsBuilder {
+    let mut builder = OptionsBuilder::default();
+    builder.dir_path(std::env::temp_dir());
+    builder
 }
 
-pub struct EngineDistributor {
-    inner: Mutex<Inner>,
+/// An engine living in a fresh temporary directory, removed along with the datafiles when
+/// the wrapper is dropped, even by a panicking test
+pub struct EngineWrapper {
+    engine: Engine,
+    // dropped after the engine has closed its datafiles
+    dir: TempDir,
 }
 
-impl EngineDistributor {
-    pub fn new() -> Self {
-        EngineDistributor {
-            inner: Mutex::new(Inner { id: 0, count: 0 }),
-        }
+impl EngineWrapper {
+    pub(crate) fn new(opts: crate::options::Options) -> EngineWrapper {
+        EngineWrapper::with_io_manager(opts, crate::fio::default_io_manager())
     }
 
-    pub fn path(&self) -> PathBuf {
-        let mut path = PathBuf::from(PREFIX);
-        let mut inner = self.inner.lock().unwrap();
-        path.push(inner.id.to_string());
-        inner.id += 1;
-        inner.count += 1;
-        drop(inner);
-        path
+    pub(crate) fn with_io_manager(
+        opts: crate::options::Options,
+        io_manager: crate::fio::IOManagerFactory,
+    ) -> EngineWrapper {
+        EngineWrapper::in_dir(tempfile::tempdir().unwrap(), opts, io_manager)
     }
 
-    pub fn drop(&self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.count -= 1;
-        if inner.count == 0 {
-            fs::remove_dir(PREFIX).unwrap();
+    /// Opens the engine in `dir`, which may already hold files
+    pub(crate) fn in_dir(
+        dir: TempDir,
+        opts: crate::options::Options,
+        io_manager: crate::fio::IOManagerFactory,
+    ) -> EngineWrapper {
+        let opts = crate::options::Options {
+            dir_path: dir.path().to_path_buf(),
+            ..opts
+        };
+        EngineWrapper {
+            engine: Engine::with_io_manager(opts, io_manager).unwrap(),
+            dir,
         }
-        drop(inner);
     }
-}
 
-pub struct EngineWrapper {
-    engine: Engine,
-    path: PathBuf,
-}
+    /// Closes the engine, then opens it again on the same datafiles
+    #[allow(dead_code)]
+    pub(crate) fn reopen(self) -> EngineWrapper {
+        let EngineWrapper { engine, dir } = self;
+        let opts = engine.options.clone();
+        let io_manager = engine.io_manager.clone();
+        drop(engine);
+        EngineWrapper::in_dir(dir, opts, io_manager)
+    }
 
-impl EngineWrapper {
-    pub(crate) fn new(opts: crate::options::Options) -> EngineWrapper {
-        // create dir if not exist
-        if !opts.dir_path.is_dir() {
-            fs::create_dir_all(&opts.dir_path).unwrap()
-        }
+    /// Returns an engine whose io calls are all counted by the returned [IOStats]
+    #[allow(dead_code)]
+    pub(crate) fn counting() -> (EngineWrapper, Arc<IOStats>) {
+        let opts = options()
+            .data_file_size(8 * 1024) // 8 KB, easy to test
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        EngineWrapper::counting_with(opts)
+    }
 
-        EngineWrapper {
-            path: opts.dir_path.to_owned(),
-            engine: Engine::new(opts).unwrap(),
-        }
+    /// Like [EngineWrapper::counting], with the given options instead of the default ones
+    #[allow(dead_code)]
+    pub(crate) fn counting_with(opts: crate::options::Options) -> (EngineWrapper, Arc<IOStats>) {
+        let stats = Arc::new(IOStats::default());
+        let engine = EngineWrapper::with_io_manager(opts, CountingIO::factory(stats.clone()));
+        (engine, stats)
+    }
+
+    /// Returns an engine whose io calls fail as selected by the returned [Faults]
+    #[allow(dead_code)]
+    pub(crate) fn faulty() -> (EngineWrapper, Arc<Faults>) {
+        let opts = options()
+            .data_file_size(8 * 1024) // 8 KB, easy to test
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        EngineWrapper::faulty_with(opts)
     }
 
+    /// Like [EngineWrapper::faulty], with the given options instead of the default ones
     #[allow(dead_code)]
-    pub(crate) fn reopen(mut self) -> EngineWrapper {
-        // FIXME: The old engine is not dropped when the reopened engine is opened
-        // so the `drop` method of the old engine may not be applied timely
-        let engine = Engine::new(self.options.clone()).unwrap();
-        let _ = std::mem::replace(&mut self.engine, engine);
-        self
+    pub(crate) fn faulty_with(opts: crate::options::Options) -> (EngineWrapper, Arc<Faults>) {
+        let faults = Arc::new(Faults::default());
+        let engine = EngineWrapper::with_io_manager(opts, FaultyIO::factory(faults.clone()));
+        (engine, faults)
     }
 
     #[allow(dead_code)]
     pub(crate) fn path(&self) -> &Path {
-        &self.path
+        self.dir.path()
     }
 }
 
 impl Default for EngineWrapper {
     fn default() -> Self {
-        let opts = crate::options::OptionsBuilder::default()
-            .dir_path(ENGINEDISTRIBUTOR.path())
+        let opts = options()
             .data_file_size(8 * 1024) // 8 KB, easy to test
-            .sync_writes(true)
+            .danger_small_files(true)
+            .sync_policy(crate::options::SyncPolicy::Always)
             .index_type(IndexType::BTree)
             .build()
             .unwrap();
@@ -117,45 +140,47 @@ impl DerefMut for EngineWrapper {
     }
 }
 
-impl Drop for EngineWrapper {
-    fn drop(&mut self) {
-        for entry in fs::read_dir(&self.path).unwrap().flatten() {
-            fs::remove_file(entry.path()).unwrap()
-        }
-        fs::remove_dir(&self.path).unwrap();
-        ENGINEDISTRIBUTOR.drop();
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use crate::mock::engine_wrapper::{EngineWrapper, ENGINEDISTRIBUTOR};
+    use crate::mock::engine_wrapper::EngineWrapper;
     use std::collections::HashSet;
-    use std::path::PathBuf;
     use std::sync::{Arc, Mutex};
     use std::thread::spawn;
 
     #[test]
     fn distribute_one_engine() {
         let engine = EngineWrapper::default();
-        let path = engine.path.clone();
+        let path = engine.path().to_path_buf();
         assert!(path.is_dir());
         drop(engine);
         assert!(!path.is_dir());
     }
 
+    #[test]
+    fn removed_on_panic() {
+        let path = Arc::new(Mutex::new(None));
+        let leaked = path.clone();
+        let result = std::panic::catch_unwind(move || {
+            let engine = EngineWrapper::default();
+            engine.put("a".into(), "b".into()).unwrap();
+            *leaked.lock().unwrap() = Some(engine.path().to_path_buf());
+            panic!("the test fails");
+        });
+        assert!(result.is_err());
+        assert!(!path.lock().unwrap().take().unwrap().exists());
+    }
+
     #[test]
     fn path_never_collision() {
-        let memo = Arc::new(Mutex::new(HashSet::<PathBuf>::new()));
+        let memo = Arc::new(Mutex::new(HashSet::new()));
         let mut handlers = Vec::new();
 
         for _ in 1..100 {
             let memo = memo.clone();
             let handler = spawn(move || {
-                let generated = ENGINEDISTRIBUTOR.path();
+                let engine = EngineWrapper::default();
                 let mut guard = memo.lock().unwrap();
-                assert!(!guard.contains(&generated));
-                guard.insert(generated);
+                assert!(guard.insert(engine.path().to_path_buf()));
                 drop(guard);
             });
             handlers.push(handler);
@@ -164,4 +189,14 @@ mod tests {
             handler.join().unwrap();
         }
     }
+
+    #[test]
+    fn reopen() {
+        let engine = engine!(["a", "1"]);
+        engine.put("b".into(), "2".into()).unwrap();
+        let path = engine.path().to_path_buf();
+        let engine = engine.reopen();
+        assert_eq!(engine.path(), path);
+        assert_eq!(engine.get("b".into()).unwrap().unwrap(), "2");
+    }
 }
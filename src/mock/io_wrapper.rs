@@ -0,0 +1,294 @@
+use crate::errors::{Errors, Result};
+use crate::fio::{io_manager, IOManager, IOManagerFactory};
+use error_stack::{Report, ResultExt};
+use parking_lot::Mutex;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Number of calls observed by [CountingIO]
+#[derive(Default)]
+pub struct IOStats {
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    syncs: AtomicUsize,
+}
+
+impl IOStats {
+    pub(crate) fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::SeqCst)
+    }
+}
+
+/// An [IOManager] counting the calls made to the underlying file io
+pub struct CountingIO {
+    inner: Box<dyn IOManager>,
+    stats: Arc<IOStats>,
+}
+
+impl CountingIO {
+    /// Returns a factory whose io managers all report to `stats`
+    pub(crate) fn factory(stats: Arc<IOStats>) -> IOManagerFactory {
+        Arc::new(move |path| {
+            Ok(Box::new(CountingIO {
+                inner: Box::new(io_manager(path)?),
+                stats: stats.clone(),
+            }))
+        })
+    }
+}
+
+impl IOManager for CountingIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.stats.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read(buf, offset)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stats.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.write(buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.stats.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync()
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+}
+
+/// Faults injected by [FaultyIO], all disabled by default
+#[derive(Default)]
+pub struct Faults {
+    fail_reads: AtomicBool,
+    /// writes left before the corrupted one, `0` if none is
+    corrupt_write: AtomicUsize,
+    /// writes left before the failing one, `0` if none is
+    fail_write: AtomicUsize,
+}
+
+impl Faults {
+    pub(crate) fn fail_reads(&self, fail: bool) {
+        self.fail_reads.store(fail, Ordering::SeqCst)
+    }
+
+    /// Flips a bit of the last byte written by the `nth` write from now, counting from 1
+    #[allow(dead_code)]
+    pub(crate) fn corrupt_write(&self, nth: usize) {
+        self.corrupt_write.store(nth, Ordering::SeqCst)
+    }
+
+    /// Fails the `nth` write from now without writing anything, counting from 1
+    #[allow(dead_code)]
+    pub(crate) fn fail_write(&self, nth: usize) {
+        self.fail_write.store(nth, Ordering::SeqCst)
+    }
+}
+
+/// An [IOManager] failing the calls selected by its [Faults]
+pub struct FaultyIO {
+    inner: Box<dyn IOManager>,
+    faults: Arc<Faults>,
+}
+
+impl FaultyIO {
+    /// Returns a factory whose io managers are all controlled by `faults`
+    pub(crate) fn factory(faults: Arc<Faults>) -> IOManagerFactory {
+        Arc::new(move |path| {
+            Ok(Box::new(FaultyIO {
+                inner: Box::new(io_manager(path)?),
+                faults: faults.clone(),
+            }))
+        })
+    }
+}
+
+impl IOManager for FaultyIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        if self.faults.fail_reads.load(Ordering::SeqCst) {
+            return Err(Report::new(Errors::FailToReadFromFile))
+                .attach_printable("read failure injected by `FaultyIO`");
+        }
+        self.inner.read(buf, offset)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let fail =
+            self.faults
+                .fail_write
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                });
+        if fail == Ok(1) {
+            return Err(Report::new(Errors::FailToWriteToFile))
+                .attach_printable("write failure injected by `FaultyIO`");
+        }
+        let left =
+            self.faults
+                .corrupt_write
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                });
+        if left == Ok(1) && !buf.is_empty() {
+            let mut corrupted = buf.to_vec();
+            *corrupted.last_mut().unwrap() ^= 0x01;
+            return self.inner.write(&corrupted);
+        }
+        self.inner.write(buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+}
+
+/// A call observed by [CrashyIO]
+enum IOEvent {
+    /// The datafile of the given name was opened, it exists from then on
+    Open(OsString),
+    Write(OsString, Vec<u8>),
+    Sync,
+}
+
+/// The calls recorded by [CrashyIO], in the order they were made
+#[derive(Default)]
+pub struct CrashLog {
+    events: Mutex<Vec<IOEvent>>,
+}
+
+impl CrashLog {
+    /// Bytes written so far
+    pub(crate) fn written(&self) -> u64 {
+        self.events
+            .lock()
+            .iter()
+            .map(|event| match event {
+                IOEvent::Write(_, buf) => buf.len() as u64,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Bytes written by the end of each write
+    pub(crate) fn boundaries(&self) -> Vec<u64> {
+        let mut written = 0;
+        let mut boundaries = Vec::new();
+        for event in self.events.lock().iter() {
+            if let IOEvent::Write(_, buf) = event {
+                written += buf.len() as u64;
+                boundaries.push(written);
+            }
+        }
+        boundaries
+    }
+
+    /// Bytes written before the last sync, no crash can lose them
+    pub(crate) fn synced(&self) -> u64 {
+        let events = self.events.lock();
+        let last_sync = events
+            .iter()
+            .rposition(|event| matches!(event, IOEvent::Sync))
+            .unwrap_or_default();
+        events[..last_sync]
+            .iter()
+            .map(|event| match event {
+                IOEvent::Write(_, buf) => buf.len() as u64,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Replays the first `bytes` bytes written into the datafiles of `dir`, leaving them as
+    /// a crash at that point would. The write the crash cuts is torn, the later ones are lost.
+    pub(crate) fn crash(&self, bytes: u64, dir: &Path) -> Result<()> {
+        let mut left = bytes;
+        for event in self.events.lock().iter() {
+            let (name, buf) = match event {
+                IOEvent::Open(name) => (name, &[][..]),
+                IOEvent::Write(_, _) if left == 0 => break,
+                IOEvent::Write(name, buf) => {
+                    let len = left.min(buf.len() as u64);
+                    left -= len;
+                    (name, &buf[..len as usize])
+                }
+                IOEvent::Sync => continue,
+            };
+            let path = dir.join(name);
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(buf))
+                .change_context(Errors::FailToWriteToFile)
+                .attach_printable_lazy(|| format!("Fail to replay the writes of {:?}", path))?;
+        }
+        Ok(())
+    }
+}
+
+/// An [IOManager] recording every write and sync to its [CrashLog], so that a crash can be
+/// simulated after the fact. Only the names of the datafiles are recorded, the datafiles of
+/// a merge would be mistaken for the ones they replace.
+pub struct CrashyIO {
+    inner: Box<dyn IOManager>,
+    name: OsString,
+    log: Arc<CrashLog>,
+}
+
+impl CrashyIO {
+    /// Returns a factory whose io managers all record to `log`
+    pub(crate) fn factory(log: Arc<CrashLog>) -> IOManagerFactory {
+        Arc::new(move |path| {
+            let name = path.file_name().unwrap_or_default().to_owned();
+            log.events.lock().push(IOEvent::Open(name.clone()));
+            Ok(Box::new(CrashyIO {
+                inner: Box::new(io_manager(path)?),
+                name,
+                log: log.clone(),
+            }))
+        })
+    }
+}
+
+impl IOManager for CrashyIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.inner.read(buf, offset)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.log
+            .events
+            .lock()
+            .push(IOEvent::Write(self.name.clone(), buf[..written].to_vec()));
+        Ok(written)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()?;
+        self.log.events.lock().push(IOEvent::Sync);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+}
@@ -1,2 +1,9 @@
+pub mod alloc;
+pub mod crash;
 pub mod datafile_wrapper;
 pub mod engine_wrapper;
+pub mod io_wrapper;
+pub mod model;
+#[cfg(feature = "tracing")]
+pub mod spans;
+pub mod stress;
@@ -0,0 +1,324 @@
+//! Model-based testing: random sequences of operations run on an engine with tiny datafiles
+//! and on a [BTreeMap], which must be observably equivalent after every operation. A failing
+//! sequence is shrunk to a minimal one before it is reported.
+
+use crate::engine::Engine;
+use crate::errors::{Errors, Result};
+use crate::options::{IteratorOptions, Options, OptionsBuilder};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Op {
+    /// Puts `len` times `byte` as the value of `key`
+    Put {
+        key: u8,
+        len: u16,
+        byte: u8,
+    },
+    Delete {
+        key: u8,
+    },
+    Get {
+        key: u8,
+    },
+    /// Iterates over the keys starting with the prefix, if any
+    Iterate {
+        prefix: Option<u8>,
+        reverse: bool,
+    },
+    Reopen,
+    Merge,
+}
+
+fn key(key: u8) -> Vec<u8> {
+    format!("key-{:02}", key).into_bytes()
+}
+
+impl Op {
+    /// Draws an operation, on keys among `keys` and values of less than `max_len` bytes
+    fn random(rng: &mut fastrand::Rng, keys: u8, max_len: u16) -> Op {
+        match rng.u8(..20) {
+            0..=8 => Op::Put {
+                key: rng.u8(..keys),
+                len: rng.u16(..max_len),
+                byte: rng.u8(..),
+            },
+            9..=12 => Op::Delete {
+                key: rng.u8(..keys),
+            },
+            13..=15 => Op::Get {
+                key: rng.u8(..keys),
+            },
+            16 | 17 => Op::Iterate {
+                // the prefix `key-0` selects the keys below 10
+                prefix: rng.bool().then(|| rng.u8(..keys.div_ceil(10))),
+                reverse: rng.bool(),
+            },
+            18 => Op::Reopen,
+            _ => Op::Merge,
+        }
+    }
+}
+
+struct Harness {
+    opts: Options,
+    engine: Option<Engine>,
+    model: Model,
+    _dir: TempDir,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = OptionsBuilder::default()
+            .dir_path(dir.path().to_path_buf())
+            .data_file_size(4 * 1024)
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        Harness {
+            engine: Some(Engine::new(opts.clone()).unwrap()),
+            opts,
+            model: Model::new(),
+            _dir: dir,
+        }
+    }
+
+    /// Applies `op` to both the engine and the model, returns how they differ if they do
+    fn apply(&mut self, op: Op) -> Result<Option<String>> {
+        let engine = self.engine.as_mut().unwrap();
+        let differ = |engine: String, model: String| {
+            (engine != model).then(|| format!("engine: {}, model: {}", engine, model))
+        };
+        let diff = match op {
+            Op::Put { key: k, len, byte } => {
+                let value = vec![byte; len as usize];
+                engine.put(key(k).into(), value.clone().into())?;
+                self.model.insert(key(k), value);
+                None
+            }
+            Op::Delete { key: k } => {
+                let deleted = match engine.delete(key(k).into()) {
+                    Ok(()) => true,
+                    Err(report) if report.current_context() == &Errors::KeyNotFound => false,
+                    Err(report) => return Err(report),
+                };
+                let removed = self.model.remove(&key(k)).is_some();
+                differ(
+                    format!("deleted {}", deleted),
+                    format!("deleted {}", removed),
+                )
+            }
+            Op::Get { key: k } => {
+                let value = engine.get(key(k).into())?;
+                differ(
+                    format!("{:?}", value.map(|value| value.len())),
+                    format!("{:?}", self.model.get(&key(k)).map(Vec::len)),
+                )
+            }
+            Op::Iterate { prefix, reverse } => {
+                let prefix = prefix.map(|prefix| format!("key-{}", prefix).into_bytes());
+                let mut opts = IteratorOptions::new().reverse(reverse);
+                if let Some(prefix) = &prefix {
+                    opts = opts.prefix(prefix.clone());
+                }
+                let entries: Vec<_> = engine
+                    .iter(opts)?
+                    .map(|entry| {
+                        let (key, value) = entry?.into_parts();
+                        Ok((key.to_vec(), value.to_vec()))
+                    })
+                    .collect::<Result<_>>()?;
+                let mut expected: Vec<_> = self
+                    .model
+                    .iter()
+                    .filter(|(key, _)| prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix)))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                if reverse {
+                    expected.reverse();
+                }
+                (entries != expected).then(|| "iterated entries differ".to_string())
+            }
+            Op::Reopen => {
+                drop(self.engine.take());
+                self.engine = Some(Engine::new(self.opts.clone())?);
+                None
+            }
+            Op::Merge => {
+                engine.merge(None)?;
+                None
+            }
+        };
+        if diff.is_some() {
+            return Ok(diff);
+        }
+
+        // the whole store, after every operation
+        let engine = self.engine.as_ref().unwrap();
+        let keys: Vec<Vec<u8>> = engine
+            .keys(IteratorOptions::default())?
+            .map(|key| key.to_vec())
+            .collect();
+        if !keys.iter().eq(self.model.keys()) {
+            return Ok(Some("keys differ".to_string()));
+        }
+        for (key, value) in &self.model {
+            if engine.get(Bytes::copy_from_slice(key))?.as_deref() != Some(value.as_slice()) {
+                return Ok(Some(format!("values of {:?} differ", key.escape_ascii())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Runs `ops` on a fresh engine and model, returns the failing step and why it failed
+fn run(ops: &[Op]) -> std::result::Result<(), (usize, String)> {
+    let mut harness = Harness::new();
+    for (step, op) in ops.iter().enumerate() {
+        match harness.apply(*op) {
+            Ok(None) => {}
+            Ok(Some(diff)) => return Err((step, diff)),
+            Err(report) => return Err((step, format!("{:?}", report))),
+        }
+    }
+    Ok(())
+}
+
+/// Removes operations from `ops` as long as they keep failing, halving the chunks removed
+/// down to single operations
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let candidate = [&ops[..start], &ops[end..]].concat();
+            match fails(&candidate) {
+                true => ops = candidate,
+                false => start = end,
+            }
+        }
+        chunk /= 2;
+    }
+    ops
+}
+
+/// Runs `ops`, panics with the shrunk sequence if the engine and the model differ
+pub(crate) fn check(ops: Vec<Op>, seed: Option<u64>) {
+    if run(&ops).is_ok() {
+        return;
+    }
+    let ops = shrink(ops, |ops| run(ops).is_err());
+    let (step, diff) = run(&ops).unwrap_err();
+    panic!(
+        "seed {:?}: step {} of {:?} fails: {}",
+        seed, step, ops, diff
+    );
+}
+
+/// Checks `len` random operations on keys among `keys` drawn from `seed`
+pub(crate) fn check_random(seed: u64, len: usize, keys: u8) {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let ops = std::iter::repeat_with(|| Op::random(&mut rng, keys, 1024))
+        .take(len)
+        .collect();
+    check(ops, Some(seed));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::model::{check, check_random, shrink, Op};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn fixed_seeds() {
+        for seed in [0, 1, 2, 3, 42, 1234] {
+            check_random(seed, 300, 16);
+        }
+    }
+
+    #[test]
+    fn random_seeds() {
+        // the seed is in the message of a failure, pin the shrunk sequence once fixed
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        for seed in seed..seed + 4 {
+            check_random(seed, 300, 16);
+        }
+    }
+
+    /// Fills the datafiles with other keys until the active one rotates at least once
+    fn rotate() -> impl Iterator<Item = Op> {
+        (100..106).map(|key| Op::Put {
+            key,
+            len: 1000,
+            byte: 0,
+        })
+    }
+
+    #[test]
+    fn overwrite_across_rotation() {
+        let put = |byte| Op::Put {
+            key: 0,
+            len: 10,
+            byte,
+        };
+        let ops = [put(1)]
+            .into_iter()
+            .chain(rotate())
+            .chain([put(2), Op::Get { key: 0 }, Op::Reopen, Op::Get { key: 0 }])
+            .collect();
+        check(ops, None);
+    }
+
+    #[test]
+    fn delete_then_reopen() {
+        let put = Op::Put {
+            key: 0,
+            len: 10,
+            byte: 1,
+        };
+        let ops = [put]
+            .into_iter()
+            .chain(rotate())
+            .chain([Op::Delete { key: 0 }, Op::Reopen, Op::Get { key: 0 }])
+            .chain([
+                Op::Merge,
+                Op::Reopen,
+                Op::Get { key: 0 },
+                Op::Delete { key: 0 },
+            ])
+            .collect();
+        check(ops, None);
+    }
+
+    #[test]
+    fn shrink_to_minimal() {
+        let ops: Vec<_> = (0..40)
+            .map(|i| Op::Put {
+                key: i % 4,
+                len: if i == 25 { 1000 } else { 10 },
+                byte: 0,
+            })
+            .chain([Op::Reopen])
+            .collect();
+        // only the put of 1000 bytes is needed to fail
+        let fails = |ops: &[Op]| ops.iter().any(|op| matches!(op, Op::Put { len: 1000, .. }));
+        let shrunk = shrink(ops, fails);
+        assert_eq!(
+            shrunk,
+            vec![Op::Put {
+                key: 1,
+                len: 1000,
+                byte: 0
+            }]
+        );
+    }
+}
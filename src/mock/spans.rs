@@ -0,0 +1,96 @@
+use parking_lot::Mutex;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+/// A span created inside [collect_spans], with the fields it recorded
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpanData {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl SpanData {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Clone, Default)]
+struct Collector {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+    metadata: Arc<Mutex<Vec<&'static Metadata<'static>>>>,
+    entered: Arc<Mutex<Vec<Id>>>,
+}
+
+struct Fields<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.retain(|(name, _)| *name != field.name());
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut data = SpanData {
+            name: span.metadata().name(),
+            fields: Vec::new(),
+        };
+        span.record(&mut Fields(&mut data.fields));
+        self.metadata.lock().push(span.metadata());
+        let mut spans = self.spans.lock();
+        spans.push(data);
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock();
+        let data = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut Fields(&mut data.fields));
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().push(span.clone());
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut entered = self.entered.lock();
+        if let Some(i) = entered.iter().rposition(|id| id == span) {
+            entered.remove(i);
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        match self.entered.lock().last() {
+            Some(id) => {
+                let metadata = self.metadata.lock()[id.into_u64() as usize - 1];
+                Current::new(id.clone(), metadata)
+            }
+            None => Current::none(),
+        }
+    }
+}
+
+/// Runs `f`, returning the spans it created on the current thread in creation order
+pub fn collect_spans<R>(f: impl FnOnce() -> R) -> (R, Vec<SpanData>) {
+    let collector = Collector::default();
+    let result = tracing::subscriber::with_default(collector.clone(), f);
+    let spans = collector.spans.lock().clone();
+    (result, spans)
+}
@@ -0,0 +1,244 @@
+//! Concurrency stress tests: writers and readers hammer a shared engine while it syncs,
+//! rotates and merges in the background, then the store is checked against the writes the
+//! writers logged.
+//!
+//! Each writer logs its own writes, stamped by a global clock read before and after each
+//! write. A write `w` is superseded once another write of the same key starts after `w` is
+//! acknowledged, the final value of a key must thus be the one of a write never superseded.
+
+use crate::engine::Engine;
+use crate::errors::Errors;
+use crate::mock::engine_wrapper::{self, EngineWrapper};
+use crate::options::{IteratorOptions, WriteBatchOptions};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Keys shared by all the writers, each writer also writes keys of its own after them
+const KEYS: u32 = 512;
+
+struct Write {
+    key: u32,
+    /// `None` deletes the key
+    value: Option<Bytes>,
+    /// The clock before the write was issued
+    start: u64,
+    /// The clock once the write was acknowledged
+    end: u64,
+}
+
+fn key(key: u32) -> Bytes {
+    format!("key-{:04}", key).into()
+}
+
+/// Whether `value` is one a writer may have written for `key`
+fn well_formed(key: &[u8], value: &[u8]) -> bool {
+    value.starts_with(key) && value.get(key.len()) == Some(&b'/')
+}
+
+pub(crate) struct Stress {
+    pub(crate) writers: usize,
+    pub(crate) readers: usize,
+    pub(crate) duration: Duration,
+    /// Whether merges run in the background
+    pub(crate) merge: bool,
+}
+
+impl Stress {
+    pub(crate) fn run(&self) {
+        let mut builder = engine_wrapper::options();
+        builder
+            .data_file_size(64 * 1024)
+            .danger_small_files(true)
+            .sync_policy(crate::options::SyncPolicy::Never);
+        if self.merge {
+            builder
+                .merge_ratio(0.3)
+                .merge_schedule(Some(Duration::from_millis(20)));
+        }
+        let db = EngineWrapper::new(builder.build().unwrap());
+        let engine: &Engine = &db;
+
+        let clock = AtomicU64::new(0);
+        let stop = AtomicBool::new(false);
+        let deadline = Instant::now() + self.duration;
+        let writes: Vec<Write> = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..self.writers)
+                .map(|writer| {
+                    let (clock, stop) = (&clock, &stop);
+                    scope.spawn(move || write(engine, writer, clock, stop))
+                })
+                .collect();
+            for _ in 0..self.readers {
+                scope.spawn(|| read(engine, &stop));
+            }
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    engine.sync().unwrap();
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            stop.store(true, Ordering::Relaxed);
+            writers
+                .into_iter()
+                .flat_map(|writer| writer.join().unwrap())
+                .collect()
+        });
+
+        check(&db, &writes);
+        // the datafiles agree with the index
+        check(&db.reopen(), &writes);
+    }
+}
+
+/// Puts and deletes random keys until stopped, directly or through a batch, returns the
+/// writes acknowledged
+fn write(engine: &Engine, writer: usize, clock: &AtomicU64, stop: &AtomicBool) -> Vec<Write> {
+    let mut rng = fastrand::Rng::with_seed(writer as u64);
+    let mut writes = Vec::new();
+    for seq in 0.. {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let k = match rng.bool() {
+            true => rng.u32(..KEYS),
+            false => KEYS * (writer as u32 + 1) + rng.u32(..KEYS),
+        };
+        let value = match rng.u8(..4) {
+            0 => None,
+            _ => {
+                let mut value =
+                    format!("{}/{}/{}/", key(k).escape_ascii(), writer, seq).into_bytes();
+                value.resize(value.len() + rng.usize(..256), b'.');
+                Some(Bytes::from(value))
+            }
+        };
+
+        let start = clock.fetch_add(1, Ordering::SeqCst);
+        let written = match rng.bool() {
+            true => match &value {
+                Some(value) => engine.put(key(k), value.clone()),
+                None => engine.delete(key(k)),
+            },
+            false => {
+                let mut batch = engine.write_batch(WriteBatchOptions {
+                    sync_on_commit: false,
+                    ..Default::default()
+                });
+                match &value {
+                    Some(value) => batch.put(key(k), value.clone()),
+                    None => batch.delete(key(k)),
+                }
+                .and_then(|_| batch.commit().map(|_| ()))
+            }
+        };
+        match written {
+            Ok(()) => {}
+            // the key was absent, nothing is written
+            Err(report) if report.current_context() == &Errors::KeyNotFound => continue,
+            Err(report) => panic!("{:?}", report),
+        }
+        let end = clock.fetch_add(1, Ordering::SeqCst);
+        writes.push(Write {
+            key: k,
+            value,
+            start,
+            end,
+        });
+    }
+    writes
+}
+
+/// Gets random keys and scans the whole store until stopped, checking every value read
+/// belongs to its key
+fn read(engine: &Engine, stop: &AtomicBool) {
+    let mut rng = fastrand::Rng::new();
+    while !stop.load(Ordering::Relaxed) {
+        for _ in 0..64 {
+            let k = key(rng.u32(..KEYS));
+            if let Some(value) = engine.get(k.clone()).unwrap() {
+                assert!(well_formed(&k, &value), "{:?}", value);
+            }
+        }
+
+        let mut previous: Option<Bytes> = None;
+        for entry in engine
+            .iter(IteratorOptions::new().reverse(rng.bool()))
+            .unwrap()
+        {
+            let (key, value) = entry.unwrap().into_parts();
+            assert!(well_formed(&key, &value), "{:?}", value);
+            assert!(
+                previous.as_ref() != Some(&key),
+                "{:?} is scanned twice",
+                key
+            );
+            previous = Some(key);
+        }
+    }
+}
+
+/// Checks that every key holds the value of a write never superseded
+fn check(engine: &Engine, writes: &[Write]) {
+    let mut by_key = HashMap::<u32, Vec<&Write>>::new();
+    for write in writes {
+        by_key.entry(write.key).or_default().push(write);
+    }
+    for k in engine.keys(IteratorOptions::default()).unwrap() {
+        assert!(
+            by_key.keys().any(|written| key(*written) == k),
+            "{:?} was never written",
+            k
+        );
+    }
+
+    for (k, writes) in by_key {
+        let value = engine.get(key(k)).unwrap();
+        let last_start = writes.iter().map(|write| write.start).max().unwrap();
+        let found = writes
+            .iter()
+            .filter(|write| write.end > last_start)
+            .any(|write| write.value == value);
+        assert!(
+            found,
+            "{:?} holds {:?}, not the last of its {} writes",
+            key(k),
+            value.map(|value| value.escape_ascii().to_string()),
+            writes.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::stress::Stress;
+    use std::time::Duration;
+
+    #[test]
+    fn smoke() {
+        Stress {
+            writers: 4,
+            readers: 2,
+            duration: Duration::from_millis(500),
+            merge: true,
+        }
+        .run();
+    }
+
+    #[test]
+    #[ignore = "runs for several seconds, run it with `cargo test -- --ignored`"]
+    fn stress() {
+        for merge in [false, true] {
+            Stress {
+                writers: 8,
+                readers: 4,
+                duration: Duration::from_secs(5),
+                merge,
+            }
+            .run();
+        }
+    }
+}
@@ -1,60 +1,778 @@
+use crate::clock::{SharedClock, SystemClock};
+use crate::data::log_record::max_header_size;
+use crate::dump::LoadMode;
 use crate::errors::{Errors, Result};
 use derive_builder::Builder;
 use error_stack::{Report, ResultExt};
+use std::cmp::Ordering;
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[non_exhaustive]
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum IndexType {
     BTree,
+    /// A skip list behind a single lock, writes block the readers as much as with
+    /// [IndexType::BTree]
     SkipList,
+    /// Fast point lookups, iterating sorts the keys first
+    HashMap,
+}
+
+impl IndexType {
+    /// Names accepted by [IndexType::from_str], in the order of the variants
+    ///
+    /// [IndexType::from_str]: std::str::FromStr::from_str
+    pub const VARIANTS: &'static [&'static str] = &["btree", "skiplist", "hashmap"];
+
+    /// Whether the engine can build an index of this type
+    pub(crate) fn is_supported(&self) -> bool {
+        matches!(
+            self,
+            IndexType::BTree | IndexType::SkipList | IndexType::HashMap
+        )
+    }
+}
+
+impl std::fmt::Display for IndexType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            IndexType::BTree => "btree",
+            IndexType::SkipList => "skiplist",
+            IndexType::HashMap => "hashmap",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for IndexType {
+    type Err = ParseOptionError;
+
+    /// Parses the name printed by [Display](std::fmt::Display), ignoring case
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "btree" => Ok(IndexType::BTree),
+            "skiplist" => Ok(IndexType::SkipList),
+            "hashmap" => Ok(IndexType::HashMap),
+            _ => Err(ParseOptionError {
+                value: s.to_string(),
+                expected: IndexType::VARIANTS,
+            }),
+        }
+    }
+}
+
+/// When the engine syncs the active datafile to disk
+///
+/// The engine always syncs on [Engine::sync], the policy only decides what happens in between.
+///
+/// [Engine::sync]: crate::engine::Engine::sync
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum SyncPolicy {
+    /// Sync after every write
+    Always,
+    /// Sync when a datafile is sealed and when the engine is closed
+    OnRotation,
+    /// Like [SyncPolicy::OnRotation], and sync after a write once the interval
+    /// elapsed since the previous sync
+    Interval(Duration),
+    /// Like [SyncPolicy::OnRotation], and sync after a write once the given
+    /// number of bytes has been written since the previous sync
+    Bytes(u64),
+    /// Never sync after a write, flushing is left to the operating system until the
+    /// engine is closed or dropped
+    Never,
+}
+
+/// Error parsing an option from a string, e.g. an environment variable or a command line flag
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+#[error("invalid value `{value}`, expected one of: {}", expected.join(", "))]
+pub struct ParseOptionError {
+    value: String,
+    expected: &'static [&'static str],
 }
 
+/// With the `config` feature enabled, options can be loaded from a config file,
+/// see [Options::from_file]. The fields left out take the defaults of [OptionsBuilder].
 #[derive(Clone, Builder)]
+#[builder(build_fn(private, name = "build_unchecked"))]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
 pub struct Options {
     /// location of database
     pub dir_path: PathBuf,
     /// Size of data file
-    #[builder(default = "8 * 1024 * 1024")]
+    #[builder(default = "default_data_file_size()")]
+    #[cfg_attr(feature = "config", serde(default = "default_data_file_size"))]
     pub data_file_size: u64,
-    /// Whether to sync in each writes
-    #[builder(default = "false")]
-    pub sync_writes: bool,
+    /// When to sync the written data to disk
+    #[builder(default = "default_sync_policy()")]
+    #[cfg_attr(feature = "config", serde(default = "default_sync_policy"))]
+    pub sync_policy: SyncPolicy,
     /// Indexing Method
-    #[builder(default = "crate::options::IndexType::BTree")]
+    #[builder(default = "default_index_type()")]
+    #[cfg_attr(feature = "config", serde(default = "default_index_type"))]
     pub index_type: IndexType,
+    /// Create the database if `dir_path` holds no datafile, otherwise opening it fails
+    /// with [Errors::DbNotFound]
+    #[builder(default = "default_create_if_missing()")]
+    #[cfg_attr(feature = "config", serde(default = "default_create_if_missing"))]
+    pub create_if_missing: bool,
+    /// Fail with [Errors::DbAlreadyExists] if `dir_path` already holds datafiles
+    #[builder(default = "false")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub error_if_exists: bool,
+    /// Remove the database when the engine is dropped. Only the datafiles created by the
+    /// engine are removed, the directory itself is removed once nothing else is left in it
+    #[builder(default = "false")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub temporary: bool,
+    /// Open an existing database without ever writing to `dir_path`: writes, batch commits
+    /// and merges fail with [Errors::ReadOnly]. The database is shared with the other
+    /// read-only engines only, it cannot be opened along a writing engine.
+    #[builder(default = "false")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub read_only: bool,
+    /// Fraction of the datafile bytes that must be reclaimable before a merge is due,
+    /// `0` disables automatic merges. A write sealing the active datafile merges all the
+    /// datafiles when a merge is due, see [Engine::merge_due].
+    ///
+    /// [Engine::merge_due]: crate::engine::Engine::merge_due
+    #[builder(default = "default_merge_ratio()")]
+    #[cfg_attr(feature = "config", serde(default = "default_merge_ratio"))]
+    pub merge_ratio: f32,
+    /// Reclaimable bytes required, on top of [Options::merge_ratio], before a merge is due
+    #[builder(default = "0")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub merge_min_bytes: u64,
+    /// Run a background thread waking up at this interval to merge all the datafiles
+    /// when a merge is due, see [Engine::merge_due]. It never runs along another merge,
+    /// and a running merge is cancelled when the engine is dropped.
+    ///
+    /// [Engine::merge_due]: crate::engine::Engine::merge_due
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub merge_schedule: Option<Duration>,
+    /// Read back every record copied by a merge before removing the merged datafiles.
+    /// A copy that differs from its original fails the merge with
+    /// [Errors::MergeVerificationFailed], the merged datafiles are then left untouched.
+    #[builder(default = "false")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub verify_after_merge: bool,
+    /// Bytes of datafiles a background merge of the [Options::merge_schedule] reads per
+    /// second at most, unthrottled if `None`. The other merges, among them the ones a write
+    /// runs once [Options::merge_ratio] is reached, are never throttled.
+    /// A merge only holds the datafiles while it starts and once it installs its copies,
+    /// the engine is read and written as usual while it copies the records.
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub merge_bytes_per_sec: Option<u64>,
+    /// Number of keys the database is expected to hold, a hint for the index
+    /// to reserve its capacity when the engine is opened
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub expected_keys: Option<usize>,
+    /// Allow a [data_file_size](Options::data_file_size) below [MIN_DATA_FILE_SIZE],
+    /// rotating datafiles that often is only meant for tests
+    #[builder(default = "false")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub danger_small_files: bool,
+    /// Largest key accepted by a write, unbounded if `None`
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub max_key_size: Option<usize>,
+    /// Largest value accepted by a write, unbounded if `None`
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(default))]
+    pub max_value_size: Option<usize>,
+    /// Called with the progress of the index rebuild while the engine is opened, at most
+    /// once per [open_progress_interval](Options::open_progress_interval) and once when the
+    /// rebuild completes. A panic of the callback is caught and logged, the open goes on
+    /// without calling it again.
+    #[builder(default = "None")]
+    #[cfg_attr(feature = "config", serde(skip))]
+    pub open_progress: Option<OpenProgressFn>,
+    /// Least time between two calls of [open_progress](Options::open_progress)
+    #[builder(default = "default_open_progress_interval()")]
+    #[cfg_attr(feature = "config", serde(default = "default_open_progress_interval"))]
+    pub open_progress_interval: Duration,
+    /// Clock of the engine, read by every feature depending on time
+    #[builder(default = "default_clock()")]
+    #[cfg_attr(feature = "config", serde(skip, default = "default_clock"))]
+    pub clock: SharedClock,
+}
+
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Options")
+            .field("dir_path", &self.dir_path)
+            .field("data_file_size", &self.data_file_size)
+            .field("sync_policy", &self.sync_policy)
+            .field("index_type", &self.index_type)
+            .field("create_if_missing", &self.create_if_missing)
+            .field("error_if_exists", &self.error_if_exists)
+            .field("temporary", &self.temporary)
+            .field("read_only", &self.read_only)
+            .field("merge_ratio", &self.merge_ratio)
+            .field("merge_min_bytes", &self.merge_min_bytes)
+            .field("merge_schedule", &self.merge_schedule)
+            .field("verify_after_merge", &self.verify_after_merge)
+            .field("merge_bytes_per_sec", &self.merge_bytes_per_sec)
+            .field("expected_keys", &self.expected_keys)
+            .field("danger_small_files", &self.danger_small_files)
+            .field("max_key_size", &self.max_key_size)
+            .field("max_value_size", &self.max_value_size)
+            .field(
+                "open_progress",
+                &self.open_progress.as_ref().map(|_| "Fn(OpenProgress)"),
+            )
+            .field("open_progress_interval", &self.open_progress_interval)
+            .field("clock", &"dyn Clock")
+            .finish()
+    }
+}
+
+/// Callback of [Options::open_progress]
+pub type OpenProgressFn = Arc<dyn Fn(OpenProgress) + Send + Sync>;
+
+/// Progress of the index rebuild when an engine is opened, see [Options::open_progress]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpenProgress {
+    /// Datafiles fully replayed
+    pub files_done: usize,
+    /// Datafiles to replay
+    pub files_total: usize,
+    /// Records replayed so far
+    pub records_done: u64,
+    /// Bytes of datafiles replayed so far
+    pub bytes_done: u64,
+    /// Bytes of all the datafiles to replay
+    pub bytes_total: u64,
+}
+
+/// Smallest [data_file_size](Options::data_file_size) accepted, unless
+/// [danger_small_files](Options::danger_small_files) is set
+pub const MIN_DATA_FILE_SIZE: u64 = 1024 * 1024;
+
+fn default_data_file_size() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_index_type() -> IndexType {
+    IndexType::BTree
+}
+
+fn default_sync_policy() -> SyncPolicy {
+    SyncPolicy::OnRotation
+}
+
+fn default_create_if_missing() -> bool {
+    true
+}
+
+fn default_merge_ratio() -> f32 {
+    0.5
+}
+
+fn default_open_progress_interval() -> Duration {
+    Duration::from_millis(250)
+}
+
+fn default_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Preset profiles, the returned options can still be tweaked with struct update syntax
+///
+/// ```
+/// use ailurus_kv::options::{Options, SyncPolicy};
+///
+/// let opts = Options {
+///     data_file_size: 16 * 1024 * 1024,
+///     ..Options::fast("/tmp/ailurus-kv")
+/// };
+/// assert_eq!(opts.sync_policy, SyncPolicy::Bytes(1024 * 1024));
+/// ```
+impl Options {
+    /// Every write is synced before it returns, at the cost of throughput
+    pub fn durable<P: Into<PathBuf>>(dir_path: P) -> Options {
+        Options {
+            data_file_size: 4 * 1024 * 1024,
+            sync_policy: SyncPolicy::Always,
+            ..Options::preset(dir_path)
+        }
+    }
+
+    /// Writes are synced every megabyte, a crash may lose the last unsynced ones
+    pub fn fast<P: Into<PathBuf>>(dir_path: P) -> Options {
+        Options {
+            data_file_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Bytes(1024 * 1024),
+            ..Options::preset(dir_path)
+        }
+    }
+
+    /// For loading a large dataset at once: nothing is synced before the engine is closed
+    /// and automatic merges are disabled, call [Engine::sync] once done loading
+    ///
+    /// [Engine::sync]: crate::engine::Engine::sync
+    pub fn bulk_load<P: Into<PathBuf>>(dir_path: P) -> Options {
+        Options {
+            data_file_size: 256 * 1024 * 1024,
+            sync_policy: SyncPolicy::Never,
+            merge_ratio: 0.0,
+            ..Options::preset(dir_path)
+        }
+    }
+
+    fn preset<P: Into<PathBuf>>(dir_path: P) -> Options {
+        OptionsBuilder::default()
+            .dir_path(dir_path.into())
+            .build_unchecked()
+            .unwrap()
+    }
+}
+
+#[cfg(feature = "config")]
+impl Options {
+    /// Parses options from a TOML document, validated like [OptionsBuilder::build]
+    pub fn from_toml_str(s: &str) -> Result<Options> {
+        let opts: Options = toml::from_str(s)
+            .change_context(Errors::FailToLoadConfig)
+            .attach_printable("Invalid TOML options")?;
+        check_options(&opts)?;
+        Ok(opts)
+    }
+
+    /// Parses options from a JSON document, validated like [OptionsBuilder::build]
+    pub fn from_json_str(s: &str) -> Result<Options> {
+        let opts: Options = serde_json::from_str(s)
+            .change_context(Errors::FailToLoadConfig)
+            .attach_printable("Invalid JSON options")?;
+        check_options(&opts)?;
+        Ok(opts)
+    }
+
+    /// Loads options from a config file, the format is picked from the extension
+    /// of the file, either `.toml` or `.json`
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Options> {
+        let path = path.as_ref();
+        let parse = match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => Options::from_toml_str,
+            Some("json") => Options::from_json_str,
+            _ => {
+                return Err(Report::new(Errors::FailToLoadConfig))
+                    .attach_printable_lazy(|| format!("Unsupported config format of {:?}", path))
+            }
+        };
+
+        let content = std::fs::read_to_string(path)
+            .change_context(Errors::FailToReadFromFile)
+            .attach_printable_lazy(|| format!("Fail to read config {:?}", path))?;
+        parse(&content).attach_printable_lazy(|| format!("Fail to load config {:?}", path))
+    }
+}
+
+impl OptionsBuilder {
+    /// Whether to sync in each writes, maps to [SyncPolicy::Always] or [SyncPolicy::Never]
+    #[deprecated(note = "use `sync_policy` instead")]
+    pub fn sync_writes(&mut self, sync_writes: bool) -> &mut Self {
+        self.sync_policy(match sync_writes {
+            true => SyncPolicy::Always,
+            false => SyncPolicy::Never,
+        })
+    }
+
+    /// Builds the [Options], rejecting the configurations [Engine::new] would reject.
+    ///
+    /// The report of a rejected configuration carries an [InvalidField] naming the offending field.
+    ///
+    /// [Engine::new]: crate::engine::Engine::new
+    pub fn build(&self) -> Result<Options> {
+        let opts = self.build_unchecked().map_err(|e| match e {
+            OptionsBuilderError::UninitializedField(field) => {
+                Report::new(Errors::InvalidOptions).attach_printable(InvalidField(field))
+            }
+            OptionsBuilderError::ValidationError(reason) => {
+                Report::new(Errors::InvalidOptions).attach_printable(reason)
+            }
+        })?;
+        check_options(&opts)?;
+        Ok(opts)
+    }
+}
+
+/// Names the field of [Options] that was rejected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidField(pub &'static str);
+
+impl std::fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid field `{}`", self.0)
+    }
 }
 
 pub(crate) fn check_options(opts: &Options) -> Result<()> {
+    if opts.dir_path.as_os_str().is_empty() {
+        return Err(Report::new(Errors::InvalidDbPath))
+            .attach_printable(InvalidField("dir_path"))
+            .attach_printable("Database path is empty");
+    }
+
     if opts.dir_path.to_str().is_none() {
         return Err(Report::new(Errors::InvalidDbPath))
+            .attach_printable(InvalidField("dir_path"))
             .attach_printable_lazy(|| format!("Invalid database path: {:?}", opts.dir_path));
     }
 
-    if opts.data_file_size == 0 {
-        return Err(Report::new(Errors::DatafileSizeTooSmall));
+    if opts.dir_path.exists() && !opts.dir_path.is_dir() {
+        return Err(Report::new(Errors::DbPathNotDir))
+            .attach_printable(InvalidField("dir_path"))
+            .attach_printable_lazy(|| format!("{:?} is not a directory", opts.dir_path));
+    }
+
+    if opts.data_file_size < MIN_DATA_FILE_SIZE && !opts.danger_small_files {
+        return Err(Report::new(Errors::DatafileSizeTooSmall))
+            .attach_printable(InvalidField("data_file_size"))
+            .attach_printable_lazy(|| {
+                format!(
+                    "Datafile size is {} bytes, at least {} bytes are required",
+                    opts.data_file_size, MIN_DATA_FILE_SIZE
+                )
+            });
+    }
+
+    // a datafile has to hold at least one record
+    if opts.data_file_size < max_header_size() as u64 {
+        return Err(Report::new(Errors::DatafileSizeTooSmall))
+            .attach_printable(InvalidField("data_file_size"))
+            .attach_printable_lazy(|| {
+                format!(
+                    "Datafile size is {} bytes, at least {} bytes are required",
+                    opts.data_file_size,
+                    max_header_size()
+                )
+            });
+    }
+
+    // and the largest record accepted by a write
+    if let (Some(key), Some(value)) = (opts.max_key_size, opts.max_value_size) {
+        let record = (max_header_size() + key + value) as u64;
+        if opts.data_file_size < record {
+            return Err(Report::new(Errors::DatafileSizeTooSmall))
+                .attach_printable(InvalidField("data_file_size"))
+                .attach_printable_lazy(|| {
+                    format!(
+                        "Datafile size is {} bytes, the largest record takes {} bytes",
+                        opts.data_file_size, record
+                    )
+                });
+        }
+    }
+
+    if !opts.index_type.is_supported() {
+        return Err(Report::new(Errors::UnsupportedIndexType))
+            .attach_printable(InvalidField("index_type"))
+            .attach_printable_lazy(|| format!("Index type `{}`", opts.index_type));
+    }
+
+    if opts.merge_schedule == Some(Duration::ZERO) {
+        return Err(Report::new(Errors::InvalidOptions))
+            .attach_printable(InvalidField("merge_schedule"))
+            .attach_printable("Merge schedule is zero, expected a positive interval");
+    }
+
+    if opts.read_only && opts.temporary {
+        return Err(Report::new(Errors::InvalidOptions))
+            .attach_printable(InvalidField("read_only"))
+            .attach_printable("A read-only database cannot be temporary");
+    }
+
+    check_runtime_options(&RuntimeOptions::from(opts))
+}
+
+/// The options that can be changed on a live engine through [Engine::update_options],
+/// they apply to the writes made after the change. The other fields of [Options]
+/// are fixed once the engine is opened.
+///
+/// [Engine::update_options]: crate::engine::Engine::update_options
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RuntimeOptions {
+    /// See [Options::sync_policy]
+    pub sync_policy: SyncPolicy,
+    /// See [Options::merge_ratio]
+    pub merge_ratio: f32,
+    /// See [Options::merge_min_bytes]
+    pub merge_min_bytes: u64,
+    /// See [Options::merge_bytes_per_sec], a running merge is paced by the latest value
+    pub merge_bytes_per_sec: Option<u64>,
+}
+
+impl From<&Options> for RuntimeOptions {
+    fn from(opts: &Options) -> Self {
+        RuntimeOptions {
+            sync_policy: opts.sync_policy,
+            merge_ratio: opts.merge_ratio,
+            merge_min_bytes: opts.merge_min_bytes,
+            merge_bytes_per_sec: opts.merge_bytes_per_sec,
+        }
+    }
+}
+
+pub(crate) fn check_runtime_options(opts: &RuntimeOptions) -> Result<()> {
+    if !(0.0..=1.0).contains(&opts.merge_ratio) {
+        return Err(Report::new(Errors::InvalidOptions))
+            .attach_printable(InvalidField("merge_ratio"))
+            .attach_printable_lazy(|| {
+                format!("Merge ratio is {}, expected within 0..=1", opts.merge_ratio)
+            });
+    }
+    if opts.merge_bytes_per_sec == Some(0) {
+        return Err(Report::new(Errors::InvalidOptions))
+            .attach_printable(InvalidField("merge_bytes_per_sec"))
+            .attach_printable("Merge rate is 0 bytes per second, expected at least 1");
     }
 
     Ok(())
 }
 
+pub type KeyFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+pub type ValueFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Lower and upper bound of the keys visited by an iterator
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+pub(crate) fn check_iterator_options(opts: &IteratorOptions) -> Result<()> {
+    if opts.keys_only && opts.value_filter.is_some() {
+        return Err(Report::new(Errors::InvalidIteratorOptions)).attach_printable(
+            "`keys_only` can not be combined with `value_filter`, values are never read",
+        );
+    }
+    if opts.limit.is_some() && opts.value_filter.is_some() {
+        return Err(Report::new(Errors::InvalidIteratorOptions)).attach_printable(
+            "`limit` can not be combined with `value_filter`, keys are counted before their values are read",
+        );
+    }
+
+    Ok(())
+}
+
+/// Options of an iterator, usually built by chaining the setters after [IteratorOptions::new]
+///
+/// ```
+/// use ailurus_kv::options::IteratorOptions;
+///
+/// let opts = IteratorOptions::new()
+///     .reverse(true)
+///     .prefix("user:")
+///     .filter(|key| !key.ends_with(b":draft"));
+/// ```
+#[derive(Clone)]
 pub struct IteratorOptions {
-    pub filter: Box<dyn FnMut(&Vec<u8>) -> bool>,
+    /// Only visit the keys accepted by the filter, every key is visited if `None`
+    pub filter: Option<KeyFilter>,
+    /// Visit the keys from the largest to the smallest one
     pub reverse: bool,
+    /// Only visit the keys starting with the prefix
+    pub prefix: Option<Vec<u8>>,
+    /// Only visit the keys above the bound, regardless of the iteration order
+    pub lower_bound: Bound<Vec<u8>>,
+    /// Only visit the keys below the bound, regardless of the iteration order
+    pub upper_bound: Bound<Vec<u8>>,
+    /// Yield entries with empty values, without reading the values from datafiles
+    pub keys_only: bool,
+    /// Only yield the entries whose value is accepted by the filter.
+    ///
+    /// Unlike `filter`, every candidate has to be read from the datafiles to evaluate it,
+    /// so narrow the candidates down with the key based options whenever possible.
+    pub value_filter: Option<ValueFilter>,
+    /// Yield an error for a key whose record turns out to be a tombstone, instead of
+    /// skipping it. The index never points at a tombstone unless it went out of sync with
+    /// the datafiles. Tombstones are only noticed when the values are read.
+    pub report_tombstones: bool,
+    /// Stop after visiting that many keys, counted again from every repositioning of the
+    /// iterator, e.g. by a seek. Every key is visited if `None`.
+    pub limit: Option<usize>,
+}
+
+impl IteratorOptions {
+    /// Returns the default options, visiting every key in ascending order
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    pub fn prefix<P: Into<Vec<u8>>>(mut self, prefix: P) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn lower_bound(mut self, bound: Bound<Vec<u8>>) -> Self {
+        self.lower_bound = bound;
+        self
+    }
+
+    pub fn upper_bound(mut self, bound: Bound<Vec<u8>>) -> Self {
+        self.upper_bound = bound;
+        self
+    }
+
+    pub fn keys_only(mut self, keys_only: bool) -> Self {
+        self.keys_only = keys_only;
+        self
+    }
+
+    pub fn report_tombstones(mut self, report_tombstones: bool) -> Self {
+        self.report_tombstones = report_tombstones;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    pub fn value_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.value_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// The key range the iterator is restricted to, which is the intersection of
+    /// the prefix range and the bounds. Keys out of the range are never visited.
+    ///
+    /// Returns `None` if the intersection is empty.
+    pub(crate) fn key_range(&self) -> Option<KeyRange> {
+        let (mut lower, mut upper) = (self.lower_bound.clone(), self.upper_bound.clone());
+
+        if let Some(prefix) = &self.prefix {
+            lower = max_lower(lower, Bound::Included(prefix.clone()));
+            upper = min_upper(
+                upper,
+                prefix_successor(prefix).map_or(Bound::Unbounded, Bound::Excluded),
+            );
+        }
+
+        match is_empty_range(&lower, &upper) {
+            true => None,
+            false => Some((lower, upper)),
+        }
+    }
 }
 
 impl Default for IteratorOptions {
     fn default() -> Self {
         Self {
-            filter: Box::new(|_| true),
+            filter: None,
             reverse: false,
+            prefix: None,
+            lower_bound: Bound::Unbounded,
+            upper_bound: Bound::Unbounded,
+            keys_only: false,
+            value_filter: None,
+            report_tombstones: false,
+            limit: None,
         }
     }
 }
 
-#[derive(Clone, Builder)]
+/// The tighter of two lower bounds
+pub(crate) fn max_lower<T: Ord>(a: Bound<T>, b: Bound<T>) -> Bound<T> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            match x.cmp(y) {
+                Ordering::Less => b,
+                Ordering::Greater => a,
+                Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+                Ordering::Equal => b,
+            }
+        }
+    }
+}
+
+/// The tighter of two upper bounds
+pub(crate) fn min_upper<T: Ord>(a: Bound<T>, b: Bound<T>) -> Bound<T> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            match x.cmp(y) {
+                Ordering::Less => a,
+                Ordering::Greater => b,
+                Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+                Ordering::Equal => b,
+            }
+        }
+    }
+}
+
+/// Whether no key lies between the bounds
+pub(crate) fn is_empty_range<T: Ord>(lower: &Bound<T>, upper: &Bound<T>) -> bool {
+    match (lower, upper) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+            l >= u
+        }
+    }
+}
+
+/// Returns the smallest key that is greater than every key starting with `prefix`,
+/// or `None` if there is no such key (e.g. the prefix is empty or consists of `0xff` only)
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+#[derive(Clone, Debug, PartialEq, Builder)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct WriteBatchOptions {
-    /// Size of batch
+    /// Maximum number of writes staged in a batch
     #[builder(default = "8 * 1024 * 1024")]
     pub batch_size: u32,
     /// Whether to sync when commit happens
@@ -67,3 +785,384 @@ impl Default for WriteBatchOptions {
         WriteBatchOptionsBuilder::default().build().unwrap()
     }
 }
+
+/// Where [ImportOptions] take the value of a line from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValueColumn {
+    /// The field at this index, counting from zero
+    Index(usize),
+    /// Everything after the key field, delimiters included
+    Rest,
+}
+
+/// Options of [Engine::import_delimited](crate::engine::Engine::import_delimited)
+#[derive(Clone, Builder)]
+pub struct ImportOptions {
+    /// Byte separating the fields of a line, quoting is not supported
+    #[builder(default = "b','")]
+    pub delimiter: u8,
+    #[builder(default = "0")]
+    pub key_column: usize,
+    #[builder(default = "ValueColumn::Index(1)")]
+    pub value_column: ValueColumn,
+    /// Whether the first line is a header to skip
+    #[builder(default = "false")]
+    pub skip_header: bool,
+    /// What to do with a key that already exists, including one imported from an
+    /// earlier line
+    #[builder(default = "LoadMode::Overwrite")]
+    pub mode: LoadMode,
+    /// Whether to write the records through write batches of `batch_size` records, syncing
+    /// once at the end, rather than one by one as the [SyncPolicy] says
+    #[builder(default = "true")]
+    pub batched: bool,
+    #[builder(default = "64 * 1024")]
+    pub batch_size: u32,
+    /// Most malformed lines kept in [ImportStats](crate::import::ImportStats), the others
+    /// are only counted
+    #[builder(default = "100")]
+    pub max_malformed_kept: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(builder: &OptionsBuilder) -> (Errors, InvalidField) {
+        let report = builder.build().err().unwrap();
+        let field = *report.downcast_ref::<InvalidField>().unwrap();
+        (report.current_context().clone(), field)
+    }
+
+    #[test]
+    fn build_rejects_invalid_options() {
+        assert_eq!(
+            rejected(&OptionsBuilder::default()),
+            (Errors::InvalidOptions, InvalidField("dir_path"))
+        );
+        assert_eq!(
+            rejected(OptionsBuilder::default().dir_path("".into())),
+            (Errors::InvalidDbPath, InvalidField("dir_path"))
+        );
+        assert_eq!(
+            rejected(OptionsBuilder::default().dir_path("Cargo.toml".into())),
+            (Errors::DbPathNotDir, InvalidField("dir_path"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .data_file_size(MIN_DATA_FILE_SIZE - 1)
+            ),
+            (Errors::DatafileSizeTooSmall, InvalidField("data_file_size"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .data_file_size(max_header_size() as u64 - 1)
+                    .danger_small_files(true)
+            ),
+            (Errors::DatafileSizeTooSmall, InvalidField("data_file_size"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .data_file_size(MIN_DATA_FILE_SIZE)
+                    .max_key_size(Some(1024))
+                    .max_value_size(Some(MIN_DATA_FILE_SIZE as usize - 1024))
+            ),
+            (Errors::DatafileSizeTooSmall, InvalidField("data_file_size"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .merge_ratio(1.5)
+            ),
+            (Errors::InvalidOptions, InvalidField("merge_ratio"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .merge_schedule(Some(Duration::ZERO))
+            ),
+            (Errors::InvalidOptions, InvalidField("merge_schedule"))
+        );
+        assert_eq!(
+            rejected(
+                OptionsBuilder::default()
+                    .dir_path("tmp".into())
+                    .read_only(true)
+                    .temporary(true)
+            ),
+            (Errors::InvalidOptions, InvalidField("read_only"))
+        );
+    }
+
+    #[test]
+    fn presets() {
+        let durable = Options::durable("tmp");
+        assert_eq!(durable.dir_path, PathBuf::from("tmp"));
+        assert_eq!(durable.data_file_size, 4 * 1024 * 1024);
+        assert_eq!(durable.sync_policy, SyncPolicy::Always);
+        assert_eq!(durable.merge_ratio, 0.5);
+
+        let fast = Options::fast("tmp");
+        assert_eq!(fast.data_file_size, 64 * 1024 * 1024);
+        assert_eq!(fast.sync_policy, SyncPolicy::Bytes(1024 * 1024));
+        assert_eq!(fast.merge_ratio, 0.5);
+
+        let bulk_load = Options::bulk_load("tmp");
+        assert_eq!(bulk_load.data_file_size, 256 * 1024 * 1024);
+        assert_eq!(bulk_load.sync_policy, SyncPolicy::Never);
+        assert_eq!(bulk_load.merge_ratio, 0.0);
+
+        for opts in [durable, fast, bulk_load] {
+            check_options(&opts).unwrap();
+            assert!(opts.create_if_missing && !opts.error_if_exists && !opts.temporary);
+        }
+    }
+
+    #[test]
+    fn build_accepts_missing_dir() {
+        let opts = OptionsBuilder::default()
+            .dir_path("tmp/not-created-yet".into())
+            .data_file_size(max_header_size() as u64)
+            .danger_small_files(true)
+            .build()
+            .unwrap();
+        assert!(!opts.dir_path.exists());
+    }
+
+    /// Every field of `opts` but the clock, which cannot be compared. `opts` is destructured
+    /// in full, a field added to [Options] fails to compile here until it is listed as well.
+    #[cfg(feature = "config")]
+    fn fields(opts: &Options) -> String {
+        let Options {
+            dir_path,
+            data_file_size,
+            sync_policy,
+            index_type,
+            create_if_missing,
+            error_if_exists,
+            temporary,
+            read_only,
+            merge_ratio,
+            merge_min_bytes,
+            merge_schedule,
+            verify_after_merge,
+            merge_bytes_per_sec,
+            expected_keys,
+            danger_small_files,
+            max_key_size,
+            max_value_size,
+            open_progress,
+            open_progress_interval,
+            clock: _,
+        } = opts;
+        // tuples are only `Debug` up to 12 fields
+        format!(
+            "{:?}",
+            (
+                (
+                    dir_path,
+                    data_file_size,
+                    sync_policy,
+                    index_type,
+                    create_if_missing,
+                    error_if_exists,
+                    temporary,
+                    read_only,
+                ),
+                (
+                    merge_ratio,
+                    merge_min_bytes,
+                    merge_schedule,
+                    verify_after_merge,
+                    merge_bytes_per_sec,
+                    expected_keys,
+                    danger_small_files,
+                ),
+                (
+                    max_key_size,
+                    max_value_size,
+                    open_progress.is_some(),
+                    open_progress_interval,
+                ),
+            )
+        )
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn config_defaults_match_builder() {
+        let built = OptionsBuilder::default()
+            .dir_path("tmp".into())
+            .build()
+            .unwrap();
+        for loaded in [
+            Options::from_toml_str(r#"dir_path = "tmp""#).unwrap(),
+            Options::from_json_str(r#"{"dir_path": "tmp"}"#).unwrap(),
+        ] {
+            assert_eq!(fields(&loaded), fields(&built));
+        }
+
+        let loaded: WriteBatchOptions = toml::from_str("").unwrap();
+        assert_eq!(loaded, WriteBatchOptions::default());
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn config_rejects_invalid_documents() {
+        let toml = r#"
+            dir_path = "tmp"
+            data_file_sz = 1024
+        "#;
+        let report = Options::from_toml_str(toml).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::FailToLoadConfig);
+
+        let json = r#"{"dir_path": "tmp", "sync_write": true}"#;
+        let report = Options::from_json_str(json).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::FailToLoadConfig);
+
+        // loaded options are validated like the built ones
+        let json = r#"{"dir_path": "tmp", "data_file_size": 0}"#;
+        let report = Options::from_json_str(json).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::DatafileSizeTooSmall);
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn config_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = dir.path().join("ailurus.toml");
+        std::fs::write(
+            &toml,
+            "dir_path = \"tmp\"\nsync_policy = { bytes = 4096 }\nindex_type = \"btree\"\n",
+        )
+        .unwrap();
+        let opts = Options::from_file(&toml).unwrap();
+        assert_eq!(opts.sync_policy, SyncPolicy::Bytes(4096));
+        assert!(matches!(opts.index_type, IndexType::BTree));
+
+        let json = dir.path().join("ailurus.json");
+        let config = r#"{"dir_path": "tmp", "data_file_size": 4096, "danger_small_files": true}"#;
+        std::fs::write(&json, config).unwrap();
+        assert_eq!(Options::from_file(&json).unwrap().data_file_size, 4096);
+
+        let yaml = dir.path().join("ailurus.yaml");
+        std::fs::write(&yaml, "dir_path: tmp").unwrap();
+        let report = Options::from_file(&yaml).err().unwrap();
+        assert_eq!(report.current_context(), &Errors::FailToLoadConfig);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn sync_writes_alias() {
+        let opts = OptionsBuilder::default()
+            .dir_path("tmp".into())
+            .sync_writes(true)
+            .build()
+            .unwrap();
+        assert_eq!(opts.sync_policy, SyncPolicy::Always);
+        let opts = OptionsBuilder::default()
+            .dir_path("tmp".into())
+            .sync_writes(false)
+            .build()
+            .unwrap();
+        assert_eq!(opts.sync_policy, SyncPolicy::Never);
+    }
+
+    #[test]
+    fn index_type_round_trip() {
+        for index_type in [IndexType::BTree, IndexType::SkipList, IndexType::HashMap] {
+            let parsed: IndexType = index_type.to_string().parse().unwrap();
+            assert_eq!(parsed, index_type);
+        }
+        assert_eq!(
+            "SkipList".parse::<IndexType>().unwrap(),
+            IndexType::SkipList
+        );
+        assert_eq!("BTREE".parse::<IndexType>().unwrap(), IndexType::BTree);
+    }
+
+    #[test]
+    fn index_types_supported() {
+        // an index type `indexer` cannot build is rejected here, before the engine opens
+        for name in IndexType::VARIANTS {
+            let index_type: IndexType = name.parse().unwrap();
+            assert!(index_type.is_supported(), "{}", index_type);
+            let opts = OptionsBuilder::default()
+                .dir_path("tmp".into())
+                .index_type(index_type)
+                .build();
+            assert!(opts.is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn index_type_parse_error() {
+        let err = "lsm".parse::<IndexType>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value `lsm`, expected one of: btree, skiplist, hashmap"
+        );
+    }
+
+    #[test]
+    fn chained_iterator_options() {
+        let opts = IteratorOptions::new()
+            .reverse(true)
+            .prefix(b"a")
+            .keys_only(true)
+            .filter(|key| key != b"ab");
+        let cloned = opts.clone();
+        for opts in [opts, cloned] {
+            assert!(opts.reverse && opts.keys_only);
+            assert_eq!(opts.prefix, Some(b"a".to_vec()));
+            let filter = opts.filter.unwrap();
+            assert!(filter(b"aa") && !filter(b"ab"));
+            assert!(opts.value_filter.is_none());
+        }
+        assert!(IteratorOptions::new().filter.is_none());
+    }
+
+    #[test]
+    fn successor_of_prefix() {
+        assert_eq!(prefix_successor(b"a"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
+
+    #[test]
+    fn range_intersection() {
+        let opts = IteratorOptions::new()
+            .prefix("b")
+            .lower_bound(Bound::Excluded("a".into()))
+            .upper_bound(Bound::Included("bb".into()));
+        assert_eq!(
+            opts.key_range(),
+            Some((Bound::Included("b".into()), Bound::Included("bb".into())))
+        );
+
+        let opts = IteratorOptions::new()
+            .lower_bound(Bound::Included("b".into()))
+            .upper_bound(Bound::Excluded("b".into()));
+        assert_eq!(opts.key_range(), None);
+
+        let opts = IteratorOptions::new()
+            .prefix("a")
+            .lower_bound(Bound::Included("b".into()));
+        assert_eq!(opts.key_range(), None);
+    }
+}
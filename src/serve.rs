@@ -0,0 +1,522 @@
+//! An HTTP frontend to the engine, for quick integrations and health checks.
+//!
+//! - `GET /kv/{key}` answers the value, `404` if the key is missing
+//! - `PUT /kv/{key}` sets the key to the body of the request
+//! - `DELETE /kv/{key}` deletes the key, `404` if it is missing
+//!
+//! A write to an engine opened read-only answers `409`.
+//! - `GET /kv?prefix=&limit=&after=` answers a page of entries as JSON, `{"entries": [..],
+//!   "next": ..}` where `next` is the `after` of the following page, `null` once exhausted.
+//!   The entries are encoded as [Entry] describes.
+//! - `GET /stats` answers statistics of the datafiles as JSON
+//!
+//! Keys are URL-decoded, adding `base64=true` to the query base64-decodes them afterward
+//! for binary keys, `next` is then base64 encoded as well. Each connection serves a single
+//! request.
+//!
+//! [Entry]: crate::iterator::Entry
+
+use crate::engine::Engine;
+use crate::errors::{Errors, Result};
+use crate::options::{IteratorOptions, WriteBatchOptions};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use bytes::Bytes;
+use error_stack::{Report, ResultExt};
+use log::{debug, warn};
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest body accepted in a request
+const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+/// Largest request line or header accepted
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Entries of a page without `limit`
+const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Time a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves the HTTP frontend of `engine` on `addr`, this never returns unless binding fails
+pub fn run<A: ToSocketAddrs>(engine: Arc<Engine>, addr: A) -> Result<()> {
+    HttpServer::bind(addr, engine)?.run();
+    Ok(())
+}
+
+pub struct HttpServer {
+    listener: TcpListener,
+    engine: Arc<Engine>,
+}
+
+impl HttpServer {
+    /// Binds the server to `addr`, connections are accepted once [HttpServer::run] is called
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: Arc<Engine>) -> Result<HttpServer> {
+        let listener = TcpListener::bind(addr).change_context(Errors::FailToBind)?;
+        Ok(HttpServer { listener, engine })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .change_context(Errors::FailToBind)
+    }
+
+    /// Serves the connections, each on a thread of its own. A connection failing to be
+    /// accepted is logged and skipped, so this never returns.
+    pub fn run(self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    let engine = self.engine.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve(&engine, stream) {
+                            debug!("Connection of {} closed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Fail to accept a connection: {}", e),
+            }
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Bytes,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Bytes,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: impl Into<Bytes>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn text(status: u16, message: impl Into<String>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", message.into())
+    }
+
+    fn json(value: serde_json::Value) -> Self {
+        Response::new(200, "application/json", value.to_string())
+    }
+
+    fn no_content() -> Self {
+        Response::new(204, "text/plain; charset=utf-8", Bytes::new())
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn serve(engine: &Engine, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader)? {
+        Ok(request) => handle(engine, &request),
+        Err(response) => response,
+    };
+    let mut stream = stream;
+    response.write_to(&mut stream)
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    match line.strip_suffix(b"\n") {
+        Some(line) => {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            Ok(String::from_utf8(line.to_vec()).ok())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads a request, an invalid one is answered with the returned response
+fn read_request(reader: &mut impl BufRead) -> io::Result<std::result::Result<Request, Response>> {
+    let bad_request = || Ok(Err(Response::text(400, "Invalid request")));
+    let Some(line) = read_line(reader)? else {
+        return bad_request();
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(_version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return bad_request();
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let Some(header) = read_line(reader)? else {
+            return bad_request();
+        };
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return bad_request();
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) if len <= MAX_BODY_LEN => content_length = len,
+                Ok(_) => return Ok(Err(Response::text(413, "Body too large"))),
+                Err(_) => return bad_request(),
+            },
+            "transfer-encoding" => {
+                return Ok(Err(Response::text(411, "Chunked bodies are not supported")))
+            }
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        body: body.into(),
+    }))
+}
+
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    Some(decoded)
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(
+            |byte| match byte.is_ascii_alphanumeric() || b"-._~".contains(byte) {
+                true => (*byte as char).to_string(),
+                false => format!("%{:02X}", byte),
+            },
+        )
+        .collect()
+}
+
+/// The decoded parameters of a query string
+struct Query {
+    params: Vec<(String, Vec<u8>)>,
+    base64: bool,
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Option<Query> {
+        let mut params = Vec::new();
+        for param in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty())
+        {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let name = String::from_utf8(percent_decode(name, true)?).ok()?;
+            params.push((name, percent_decode(value, true)?));
+        }
+        let base64 = params
+            .iter()
+            .any(|(name, value)| name == "base64" && value == b"true");
+        Some(Query { params, base64 })
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Decodes a key given URL-decoded
+    fn key(&self, key: &[u8]) -> Option<Bytes> {
+        match self.base64 {
+            true => STANDARD.decode(key).ok().map(Bytes::from),
+            false => Some(Bytes::copy_from_slice(key)),
+        }
+    }
+
+    fn encode_key(&self, key: &[u8]) -> String {
+        match self.base64 {
+            true => STANDARD.encode(key),
+            false => percent_encode(key),
+        }
+    }
+}
+
+fn error_response(report: Report<Errors>) -> Response {
+    let status = match report.current_context() {
+        Errors::KeyNotFound => 404,
+        Errors::EmptyKey | Errors::InvalidIteratorOptions => 400,
+        Errors::ReadOnly => 409,
+        Errors::KeyTooLarge | Errors::ValueTooLarge => 413,
+        Errors::EngineClosed => 503,
+        _ => 500,
+    };
+    Response::text(status, report.current_context().to_string())
+}
+
+fn handle(engine: &Engine, request: &Request) -> Response {
+    let Some(query) = Query::parse(request.query.as_deref()) else {
+        return Response::text(400, "Invalid query");
+    };
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        (method, path) if path.starts_with("/kv/") => {
+            let key = percent_decode(&path["/kv/".len()..], false).and_then(|key| query.key(&key));
+            let Some(key) = key else {
+                return Response::text(400, "Invalid key");
+            };
+            match method {
+                "GET" => engine.get(key).map(|value| match value {
+                    Some(value) => Response::new(200, "application/octet-stream", value),
+                    None => Response::text(404, Errors::KeyNotFound.to_string()),
+                }),
+                "PUT" => write(engine, |batch| batch.put(key, request.body.clone())),
+                "DELETE" => write(engine, |batch| batch.delete(key)),
+                _ => return Response::text(405, "Method not allowed"),
+            }
+        }
+        ("GET", "/kv") => scan(engine, &query),
+        ("GET", "/stats") => stats(engine),
+        (_, "/kv" | "/stats") => return Response::text(405, "Method not allowed"),
+        _ => return Response::text(404, "Not found"),
+    };
+    result.unwrap_or_else(error_response)
+}
+
+fn write(
+    engine: &Engine,
+    stage: impl FnOnce(&mut crate::batch::WriteBatch) -> Result<()>,
+) -> Result<Response> {
+    let mut batch = engine.write_batch(WriteBatchOptions::default());
+    stage(&mut batch)?;
+    batch.commit()?;
+    Ok(Response::no_content())
+}
+
+fn scan(engine: &Engine, query: &Query) -> Result<Response> {
+    let invalid = |param: &str| {
+        Report::new(Errors::InvalidIteratorOptions).attach_printable(format!("Invalid `{}`", param))
+    };
+    let limit = match query.get("limit") {
+        None => DEFAULT_PAGE_LIMIT,
+        Some(limit) => std::str::from_utf8(limit)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .ok_or_else(|| invalid("limit"))?,
+    };
+    let after = match query.get("after") {
+        None => None,
+        Some(after) => Some(query.key(after).ok_or_else(|| invalid("after"))?),
+    };
+    let mut opts = IteratorOptions::new();
+    if let Some(prefix) = query.get("prefix") {
+        opts = opts.prefix(query.key(prefix).ok_or_else(|| invalid("prefix"))?.to_vec());
+    }
+
+    let (entries, next) = engine.scan_page(after, limit, opts)?;
+    Ok(Response::json(json!({
+        "entries": entries,
+        "next": next.map(|key| query.encode_key(&key)),
+    })))
+}
+
+fn stats(engine: &Engine) -> Result<Response> {
+    let stat = engine.stat()?;
+    let sealed = engine.compaction_candidates();
+    let metrics = engine.metrics();
+    Ok(Response::json(json!({
+        "keys": stat.keys,
+        "datafiles": stat.datafiles,
+        "disk_bytes": stat.disk_bytes,
+        "reclaimable_bytes": stat.reclaimable_bytes,
+        "sealed_files": sealed.len(),
+        "sealed_bytes": sealed.iter().map(|file| file.size).sum::<u64>(),
+        "user_bytes_written": metrics.user_bytes_written,
+        "merge_bytes_written": metrics.merge_bytes_written,
+        "merges": metrics.merges,
+        "write_amplification": metrics.write_amplification(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::errors::Errors;
+    use crate::options::OptionsBuilder;
+    use crate::serve::HttpServer;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+
+    /// Starts a server on an ephemeral port, it lives until the end of the tests
+    fn start() -> SocketAddr {
+        start_with(Engine::open_temporary().unwrap())
+    }
+
+    fn start_with(engine: Engine) -> SocketAddr {
+        let engine = Arc::new(engine);
+        let server = HttpServer::bind("127.0.0.1:0", engine).unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+        addr
+    }
+
+    /// Sends a request, returns the status and the body of the response
+    fn request(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            method,
+            target,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[end + 4..].to_vec())
+    }
+
+    fn json(addr: SocketAddr, target: &str) -> serde_json::Value {
+        let (status, body) = request(addr, "GET", target, b"");
+        assert_eq!(status, 200);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn kv() {
+        let addr = start();
+        assert_eq!(request(addr, "GET", "/kv/greeting", b"").0, 404);
+        assert_eq!(request(addr, "PUT", "/kv/greeting", b"hello").0, 204);
+        assert_eq!(
+            request(addr, "GET", "/kv/greeting", b""),
+            (200, b"hello".to_vec())
+        );
+        assert_eq!(request(addr, "PUT", "/kv/with%20space", b"x").0, 204);
+        assert_eq!(request(addr, "GET", "/kv/with%20space", b"").1, b"x");
+        assert_eq!(request(addr, "DELETE", "/kv/greeting", b"").0, 204);
+        assert_eq!(request(addr, "DELETE", "/kv/greeting", b"").0, 404);
+        assert_eq!(request(addr, "GET", "/kv/", b"").0, 400);
+        assert_eq!(request(addr, "POST", "/kv/greeting", b"").0, 405);
+        assert_eq!(request(addr, "GET", "/nothing", b"").0, 404);
+
+        // the key `\x00\xff`
+        assert_eq!(request(addr, "PUT", "/kv/AP8=?base64=true", b"bin").0, 204);
+        assert_eq!(request(addr, "GET", "/kv/%00%FF", b"").1, b"bin");
+        assert_eq!(request(addr, "GET", "/kv/AP8?base64=true", b"").0, 400);
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = |read_only| {
+            OptionsBuilder::default()
+                .dir_path(dir.path().to_path_buf())
+                .read_only(read_only)
+                .build()
+                .unwrap()
+        };
+        let engine = Engine::new(opts(false)).unwrap();
+        engine.put("greeting".into(), "hello".into()).unwrap();
+        drop(engine);
+
+        let addr = start_with(Engine::new(opts(true)).unwrap());
+        let (status, body) = request(addr, "PUT", "/kv/greeting", b"bye");
+        assert_eq!(
+            (status, body),
+            (409, Errors::ReadOnly.to_string().into_bytes())
+        );
+        assert_eq!(request(addr, "DELETE", "/kv/greeting", b"").0, 409);
+        assert_eq!(
+            request(addr, "GET", "/kv/greeting", b""),
+            (200, b"hello".to_vec())
+        );
+    }
+
+    #[test]
+    fn scan_and_stats() {
+        let addr = start();
+        for key in ["user:1", "user:2", "user:3", "post:1"] {
+            assert_eq!(request(addr, "PUT", &format!("/kv/{}", key), b"v").0, 204);
+        }
+
+        let page = json(addr, "/kv?prefix=user%3A&limit=2");
+        let keys: Vec<_> = page["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["key"]["data"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["user:1", "user:2"]);
+        assert_eq!(page["next"], "user%3A2");
+
+        let page = json(addr, "/kv?prefix=user:&limit=2&after=user%3A2");
+        assert_eq!(page["entries"][0]["key"]["data"], "user:3");
+        assert_eq!(page["next"], serde_json::Value::Null);
+
+        let page = json(addr, "/kv?base64=true&limit=1");
+        assert_eq!(page["next"], "cG9zdDox");
+        assert_eq!(request(addr, "GET", "/kv?limit=many", b"").0, 400);
+
+        let stats = json(addr, "/stats");
+        assert_eq!(stats["keys"], 4);
+    }
+}
@@ -0,0 +1,468 @@
+//! A TCP server speaking enough of the Redis protocol (RESP2) for `redis-cli` and the
+//! usual Redis clients to use the engine as a plain key-value store.
+//!
+//! The supported commands are PING, GET, SET, MGET, MSET, DEL, EXISTS, KEYS and SCAN,
+//! the patterns of KEYS and SCAN are limited to exact keys and prefixes like `user:*`.
+
+use crate::engine::Engine;
+use crate::errors::{Errors, Result};
+use crate::options::{IteratorOptions, WriteBatchOptions};
+use bytes::Bytes;
+use error_stack::{Report, ResultExt};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+/// Keys returned by a SCAN without COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Largest bulk string accepted from a client
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most arguments accepted in a single command
+const MAX_ARGS: usize = 1024 * 1024;
+
+pub struct Server {
+    listener: TcpListener,
+    engine: Arc<Engine>,
+}
+
+impl Server {
+    /// Binds the server to `addr`, connections are accepted once [Server::run] is called
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: Arc<Engine>) -> Result<Server> {
+        let listener = TcpListener::bind(addr).change_context(Errors::FailToBind)?;
+        Ok(Server { listener, engine })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .change_context(Errors::FailToBind)
+    }
+
+    /// Serves the connections, each on a thread of its own. A connection failing to be
+    /// accepted is logged and skipped, so this never returns.
+    pub fn run(self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    let engine = self.engine.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve(&engine, stream) {
+                            debug!("Connection of {} closed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Fail to accept a connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Answers the commands of a client until it disconnects, replies to pipelined
+/// commands are flushed together
+fn serve(engine: &Engine, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let reply = match read_command(&mut reader) {
+            Ok(None) => return writer.flush(),
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => execute(engine, &args),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // the stream cannot be parsed any further
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        reply.write_to(&mut writer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a line ending with CRLF, without the CRLF
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    match line.strip_suffix(b"\r\n") {
+        Some(stripped) => Ok(Some(stripped.to_vec())),
+        None => Err(protocol_error("line not terminated by CRLF")),
+    }
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+/// Reads the arguments of the next command, `None` once the client disconnected
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Bytes>>> {
+    let line = match read_line(reader)? {
+        None => return Ok(None),
+        Some(line) => line,
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => parse_len(count, MAX_ARGS)?,
+        // an inline command, as typed in a telnet session
+        None => {
+            let args = line
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(Bytes::copy_from_slice)
+                .collect();
+            return Ok(Some(args));
+        }
+    };
+
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN)?,
+            None => return Err(protocol_error("expected a bulk string")),
+        };
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(Bytes::from(arg));
+    }
+    Ok(Some(args))
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status),
+            Reply::Error(message) => {
+                // an error is a single line
+                let message = message.replace(['\r', '\n'], " ");
+                write!(writer, "-{}\r\n", message)
+            }
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(writer))
+            }
+        }
+    }
+}
+
+/// A failed command, answered with the error message
+type CommandResult = std::result::Result<Reply, String>;
+
+fn engine_error(report: Report<Errors>) -> String {
+    format!("ERR {}", report.current_context())
+}
+
+fn execute(engine: &Engine, args: &[Bytes]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let result = match (name.as_str(), args) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        ("GET", [key]) => get(engine, key).map(Reply::Bulk),
+        ("SET", [key, value]) => set(engine, &[key.clone(), value.clone()]),
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => set(engine, pairs),
+        ("MGET", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| get(engine, key).map(Reply::Bulk))
+            .collect::<std::result::Result<_, _>>()
+            .map(Reply::Array),
+        ("DEL", keys) if !keys.is_empty() => delete(engine, keys),
+        ("EXISTS", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| get(engine, key).map(|value| value.is_some() as i64))
+            .sum::<std::result::Result<_, _>>()
+            .map(Reply::Integer),
+        ("KEYS", [pattern]) => keys(engine, pattern),
+        ("SCAN", [cursor, options @ ..]) => scan(engine, cursor, options),
+        // sent by `redis-cli` when connecting, no command is documented
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("PING" | "GET" | "SET" | "MSET" | "MGET" | "DEL" | "EXISTS" | "KEYS" | "SCAN", _) => {
+            Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            ))
+        }
+        // the name is cut to keep the reply short
+        _ => Err(format!(
+            "ERR unknown command '{}'",
+            name.chars().take(128).collect::<String>()
+        )),
+    };
+    result.unwrap_or_else(Reply::Error)
+}
+
+fn get(engine: &Engine, key: &Bytes) -> std::result::Result<Option<Bytes>, String> {
+    engine.get(key.clone()).map_err(engine_error)
+}
+
+/// Writes the key-value `pairs` at once
+fn set(engine: &Engine, pairs: &[Bytes]) -> CommandResult {
+    let mut batch = engine.write_batch(WriteBatchOptions::default());
+    for pair in pairs.chunks(2) {
+        batch
+            .put(pair[0].clone(), pair[1].clone())
+            .map_err(engine_error)?;
+    }
+    batch.commit().map_err(engine_error)?;
+    Ok(Reply::Status("OK"))
+}
+
+/// Deletes the `keys` at once, replies with the number of keys that existed
+fn delete(engine: &Engine, keys: &[Bytes]) -> CommandResult {
+    let mut batch = engine.write_batch(WriteBatchOptions::default());
+    let mut deleted = 0;
+    let unique: HashSet<&Bytes> = keys.iter().collect();
+    for key in unique {
+        match batch.delete(key.clone()) {
+            Ok(()) => deleted += 1,
+            Err(report) if report.current_context() == &Errors::KeyNotFound => {}
+            Err(report) => return Err(engine_error(report)),
+        }
+    }
+    if deleted > 0 {
+        batch.commit().map_err(engine_error)?;
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// Options of an iterator visiting the keys matched by a glob `pattern`, which may only
+/// be an exact key or a prefix followed by `*`
+fn matching(pattern: &[u8]) -> std::result::Result<IteratorOptions, String> {
+    let (body, prefix) = match pattern.strip_suffix(b"*") {
+        Some(body) => (body, true),
+        None => (pattern, false),
+    };
+    if body
+        .iter()
+        .any(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\'))
+    {
+        return Err("ERR only prefix patterns like 'user:*' are supported".to_string());
+    }
+    let opts = IteratorOptions::new().prefix(body).keys_only(true);
+    Ok(match prefix {
+        true => opts,
+        false => {
+            let exact = body.to_vec();
+            opts.filter(move |key| key == exact.as_slice())
+        }
+    })
+}
+
+fn keys(engine: &Engine, pattern: &[u8]) -> CommandResult {
+    let keys = engine
+        .iter(matching(pattern)?)
+        .map_err(engine_error)?
+        .keys()
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(keys))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count], the cursor is `0` or the hex encoded
+/// key to resume after
+fn scan(engine: &Engine, cursor: &[u8], options: &[Bytes]) -> CommandResult {
+    let after = match cursor {
+        b"0" => None,
+        cursor => Some(decode_hex(cursor).ok_or("ERR invalid cursor")?),
+    };
+    let mut opts = IteratorOptions::new().keys_only(true);
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"MATCH", Some(pattern)) => opts = matching(pattern)?,
+            (b"COUNT", Some(n)) => {
+                count = std::str::from_utf8(n)
+                    .ok()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or("ERR value is not an integer or out of range")?;
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+
+    let (entries, token) = engine.scan_page(after, count, opts).map_err(engine_error)?;
+    let cursor = token.map_or_else(|| "0".to_string(), |key| encode_hex(&key));
+    let keys = entries
+        .into_iter()
+        .map(|entry| Reply::Bulk(Some(entry.into_parts().0)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(cursor.into())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Bytes> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| Some(nibble(pair[0])? << 4 | nibble(pair[1])?))
+        .collect::<Option<Vec<u8>>>()
+        .map(Bytes::from)
+}
+
+fn nibble(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::server::{serve, Server};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        /// Serves a single connection of a fresh server, the engine is dropped with it
+        fn connect() -> Client {
+            let engine = Arc::new(Engine::open_temporary().unwrap());
+            let server = Server::bind("127.0.0.1:0", engine).unwrap();
+            let addr = server.local_addr().unwrap();
+            std::thread::spawn(move || {
+                let (stream, _) = server.listener.accept().unwrap();
+                let _ = serve(&server.engine, stream);
+            });
+            let stream = TcpStream::connect(addr).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        fn send(&mut self, args: &[&str]) {
+            let mut command = format!("*{}\r\n", args.len());
+            for arg in args {
+                command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+            self.writer.write_all(command.as_bytes()).unwrap();
+        }
+
+        fn expect(&mut self, reply: &str) {
+            let mut buf = vec![0; reply.len()];
+            self.reader.read_exact(&mut buf).unwrap();
+            assert_eq!(String::from_utf8_lossy(&buf), reply);
+        }
+
+        fn call(&mut self, args: &[&str], reply: &str) {
+            self.send(args);
+            self.expect(reply);
+        }
+    }
+
+    #[test]
+    fn strings() {
+        let mut client = Client::connect();
+        client.call(&["PING"], "+PONG\r\n");
+        client.call(&["GET", "k"], "$-1\r\n");
+        client.call(&["SET", "k", "v"], "+OK\r\n");
+        client.call(&["get", "k"], "$1\r\nv\r\n");
+        client.call(&["MSET", "a", "1", "b", "22"], "+OK\r\n");
+        client.call(
+            &["MGET", "a", "missing", "b"],
+            "*3\r\n$1\r\n1\r\n$-1\r\n$2\r\n22\r\n",
+        );
+        client.call(&["EXISTS", "a", "a", "missing"], ":2\r\n");
+        client.call(&["DEL", "a", "a", "missing"], ":1\r\n");
+        client.call(&["EXISTS", "a"], ":0\r\n");
+        client.call(
+            &["SET", "k"],
+            "-ERR wrong number of arguments for 'set' command\r\n",
+        );
+        client.call(&["FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n");
+    }
+
+    #[test]
+    fn keys_and_scan() {
+        let mut client = Client::connect();
+        client.call(
+            &[
+                "MSET", "user:1", "a", "user:2", "b", "user:3", "c", "post:1", "d",
+            ],
+            "+OK\r\n",
+        );
+        client.call(
+            &["KEYS", "user:*"],
+            "*3\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n",
+        );
+        client.call(&["KEYS", "post:1"], "*1\r\n$6\r\npost:1\r\n");
+        client.call(
+            &["KEYS", "u*r:*"],
+            "-ERR only prefix patterns like 'user:*' are supported\r\n",
+        );
+
+        // the cursor is the hex encoded key `user:2`
+        client.call(
+            &["SCAN", "0", "MATCH", "user:*", "COUNT", "2"],
+            "*2\r\n$12\r\n757365723a32\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+        );
+        client.call(
+            &["SCAN", "757365723a32", "MATCH", "user:*", "COUNT", "2"],
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:3\r\n",
+        );
+        client.call(&["SCAN", "xyz"], "-ERR invalid cursor\r\n");
+        // an even number of bytes that splits a multi-byte char into halves
+        client.call(&["SCAN", "aéb"], "-ERR invalid cursor\r\n");
+    }
+
+    #[test]
+    fn pipelined_and_inline() {
+        let mut client = Client::connect();
+        client.send(&["SET", "k", "v"]);
+        client.send(&["GET", "k"]);
+        client.writer.write_all(b"EXISTS k\r\n").unwrap();
+        client.expect("+OK\r\n$1\r\nv\r\n:1\r\n");
+
+        client.writer.write_all(b"*1\r\n+PING\r\n").unwrap();
+        let mut line = String::new();
+        client.reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("-ERR Protocol error"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Typed access to the engine, values are stored as JSON
+
+use crate::engine::Engine;
+use crate::errors::{ErrorKey, Errors, Result};
+use crate::options::IteratorOptions;
+use bytes::Bytes;
+use error_stack::ResultExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Bytes> {
+    serde_json::to_vec(value)
+        .map(Bytes::from)
+        .change_context(Errors::FailToSerialize)
+}
+
+fn decode<T: DeserializeOwned>(key: &[u8], value: &[u8]) -> Result<T> {
+    serde_json::from_slice(value)
+        .change_context(Errors::FailToDeserialize)
+        .attach_printable_lazy(|| ErrorKey::new(key))
+}
+
+impl Engine {
+    /// Stores `value` serialized as JSON under `key`
+    pub fn put_json<T: Serialize + ?Sized>(&self, key: Bytes, value: &T) -> Result<()> {
+        let value = encode(value)?;
+        self.put(key, value)
+    }
+
+    /// Gets the value of `key` stored by [Engine::put_json], a value not matching `T`
+    /// fails with [Errors::FailToDeserialize]
+    pub fn get_json<T: DeserializeOwned>(&self, key: Bytes) -> Result<T> {
+        let value = self.get_or_err(key.clone())?;
+        decode(&key, &value)
+    }
+
+    /// A typed view of the keys starting with `prefix`, the keys given to the table
+    /// are relative to the prefix.
+    ///
+    /// ```
+    /// use ailurus_kv::engine::Engine;
+    /// use ailurus_kv::typed::Table;
+    ///
+    /// let engine = Engine::open_temporary().unwrap();
+    /// let mut ages = engine.table::<&str, u32>("age:".into());
+    /// ages.put("alice", &30).unwrap();
+    /// assert_eq!(ages.get("alice").unwrap(), 30);
+    /// assert_eq!(engine.get("age:alice".into()).unwrap().unwrap(), "30");
+    /// ```
+    pub fn table<K: AsRef<[u8]>, V: Serialize + DeserializeOwned>(
+        &self,
+        prefix: Bytes,
+    ) -> Table<'_, K, V> {
+        Table {
+            engine: self,
+            prefix,
+            _types: PhantomData,
+        }
+    }
+}
+
+/// Keys of type `K` mapped to values of type `V` under a common prefix, created by
+/// [Engine::table]
+pub struct Table<'a, K, V> {
+    engine: &'a Engine,
+    prefix: Bytes,
+    _types: PhantomData<fn(K) -> V>,
+}
+
+impl<K: AsRef<[u8]>, V: Serialize + DeserializeOwned> Table<'_, K, V> {
+    fn key(&self, key: &K) -> Bytes {
+        [self.prefix.as_ref(), key.as_ref()].concat().into()
+    }
+
+    pub fn put(&mut self, key: K, value: &V) -> Result<()> {
+        let key = self.key(&key);
+        self.engine.put_json(key, value)
+    }
+
+    pub fn get(&self, key: K) -> Result<V> {
+        self.engine.get_json(self.key(&key))
+    }
+
+    pub fn delete(&mut self, key: K) -> Result<()> {
+        let key = self.key(&key);
+        self.engine.delete(key)
+    }
+
+    /// Iterates over the entries of the table in key order, the keys are yielded
+    /// without the prefix
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(Bytes, V)>> + '_> {
+        let prefix_len = self.prefix.len();
+        let iter = self
+            .engine
+            .iter(IteratorOptions::new().prefix(self.prefix.to_vec()))?;
+        Ok(iter.map(move |entry| {
+            let (key, value) = entry?.into_parts();
+            let value = decode(&key, &value)?;
+            Ok((key.slice(prefix_len..), value))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine;
+    use crate::errors::Errors;
+    use crate::typed::Table;
+    use bytes::Bytes;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Circle { radius: f64 },
+        Polygon(Vec<(i32, i32)>),
+        Empty,
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let engine = engine!();
+        let shapes = vec![
+            Shape::Circle { radius: 1.5 },
+            Shape::Polygon(vec![(0, 0), (-3, 4), (7, i32::MAX)]),
+            Shape::Empty,
+        ];
+        engine.put_json("shapes".into(), &shapes).unwrap();
+        assert_eq!(
+            engine.get_json::<Vec<Shape>>("shapes".into()).unwrap(),
+            shapes
+        );
+        assert_eq!(
+            engine
+                .get_json::<Shape>("missing".into())
+                .unwrap_err()
+                .current_context(),
+            &Errors::KeyNotFound
+        );
+    }
+
+    #[test]
+    fn deserialize_error() {
+        let engine = engine!(["raw", "not json"]);
+        engine.put_json("count".into(), &42u64).unwrap();
+        for key in ["raw", "count"] {
+            let err = engine.get_json::<Shape>(key.into()).unwrap_err();
+            assert_eq!(err.current_context(), &Errors::FailToDeserialize);
+        }
+    }
+
+    #[test]
+    fn table() {
+        let engine = engine!(["other", "value"]);
+        let mut shapes: Table<&str, Shape> = engine.table("shape:".into());
+        shapes.put("b", &Shape::Empty).unwrap();
+        shapes.put("a", &Shape::Circle { radius: 2.0 }).unwrap();
+        shapes.put("c", &Shape::Polygon(vec![(1, 2)])).unwrap();
+        assert_eq!(shapes.get("a").unwrap(), Shape::Circle { radius: 2.0 });
+
+        shapes.delete("c").unwrap();
+        assert_eq!(
+            shapes.get("c").unwrap_err().current_context(),
+            &Errors::KeyNotFound
+        );
+        assert_eq!(
+            shapes
+                .iter()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![
+                (Bytes::from("a"), Shape::Circle { radius: 2.0 }),
+                (Bytes::from("b"), Shape::Empty),
+            ]
+        );
+
+        assert_eq!(engine.get("shape:b".into()).unwrap().unwrap(), "\"Empty\"");
+        assert_eq!(engine.get("other".into()).unwrap().unwrap(), "value");
+    }
+}
@@ -0,0 +1,128 @@
+#![cfg(feature = "cli")]
+
+use ailurus_kv::engine::Engine;
+use ailurus_kv::options::OptionsBuilder;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn ailurus(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ailurus"))
+        .arg("--dir")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Runs a command expected to succeed, returns its output
+fn run(dir: &Path, args: &[&str]) -> String {
+    let output = ailurus(dir, args);
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn put_get_del() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    run(dir, &["put", "user:1", "alice"]);
+    run(dir, &["put", "user:2", "bob"]);
+    run(dir, &["put", "post:1", "hello"]);
+    assert_eq!(run(dir, &["get", "user:1"]), "alice\n");
+
+    assert_eq!(
+        run(dir, &["scan", "--prefix", "user:"]),
+        "user:1\talice\nuser:2\tbob\n"
+    );
+    assert_eq!(run(dir, &["scan", "--limit", "1"]), "post:1\thello\n");
+
+    run(dir, &["del", "user:1"]);
+    let output = ailurus(dir, &["get", "user:1"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Key not found"));
+    assert_eq!(run(dir, &["stat"]).lines().next(), Some("keys: 2"));
+}
+
+#[test]
+fn hex_and_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    run(dir, &["--hex", "put", "00ff", "6869"]);
+    assert_eq!(run(dir, &["--hex", "get", "00ff"]), "6869\n");
+    assert_eq!(
+        run(dir, &["--json", "--hex", "scan"]),
+        concat!(
+            r#"{"key":{"encoding":"base64","data":"AP8="},"#,
+            r#""value":{"encoding":"utf8","data":"hi"}}"#,
+            "\n"
+        )
+    );
+    let stat: serde_json::Value = serde_json::from_str(&run(dir, &["--json", "stat"])).unwrap();
+    assert_eq!(stat["keys"], 1);
+    assert!(!ailurus(dir, &["--hex", "get", "0"]).status.success());
+}
+
+#[test]
+fn inspection_never_creates() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let output = ailurus(&missing, &["get", "key"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Database does not exist"));
+    assert!(!missing.join("000000000.data").exists());
+}
+
+#[test]
+fn inspection_is_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    run(dir, &["put", "key", "value"]);
+    // a leftover a writing engine would remove
+    let leftover = dir.join("000000009.data.tmp");
+    std::fs::write(&leftover, "torn").unwrap();
+
+    let opts = OptionsBuilder::default()
+        .dir_path(dir.to_path_buf())
+        .read_only(true)
+        .build()
+        .unwrap();
+    let engine = Engine::new(opts).unwrap();
+    assert_eq!(run(dir, &["get", "key"]), "value\n");
+    assert_eq!(run(dir, &["scan"]), "key\tvalue\n");
+    assert_eq!(run(dir, &["stat"]).lines().next(), Some("keys: 1"));
+    assert_eq!(run(dir, &["verify"]), "");
+    assert!(leftover.exists());
+
+    let output = ailurus(dir, &["put", "key", "other"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("used by another engine"));
+    drop(engine);
+    run(dir, &["put", "key", "other"]);
+    assert!(!leftover.exists());
+}
+
+#[test]
+fn verify_and_merge() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    run(dir, &["put", "key", "v1"]);
+    run(dir, &["put", "key", "v2"]);
+    assert_eq!(run(dir, &["verify"]), "");
+    let merged: serde_json::Value = serde_json::from_str(&run(dir, &["--json", "merge"])).unwrap();
+    assert_eq!(merged["records_copied"], 1);
+    assert_eq!(merged["records_dropped"], 1);
+    assert_eq!(run(dir, &["get", "key"]), "v2\n");
+}
+
+#[test]
+fn usage() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = ailurus(dir.path(), &["frobnicate"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Usage: ailurus"));
+}
@@ -0,0 +1,120 @@
+//! Compatibility of the on-disk format: the fixtures in `tests/compat/v<N>`, written by
+//! earlier versions of the crate, are still read, and the current version writes the bytes
+//! of the fixture of the current format.
+
+#[path = "compat/workload.rs"]
+mod workload;
+
+use ailurus_kv::engine::{Engine, LOCK_FILE};
+use ailurus_kv::options::{IteratorOptions, OptionsBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Fixture of the format written by this version of the crate, a change of the format adds
+/// a fixture with `examples/compat_fixture.rs` and bumps this one
+const CURRENT_FORMAT: &str = "v2";
+
+fn fixtures() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat");
+    let mut fixtures: Vec<PathBuf> = fs::read_dir(root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+/// Copies the database of the fixture into a fresh directory, opening it may write to it
+fn copy_db(fixture: &Path) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for entry in fs::read_dir(fixture.join("db")).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), dir.path().join(entry.file_name())).unwrap();
+    }
+    dir
+}
+
+fn open(dir: &Path) -> Engine {
+    let opts = OptionsBuilder::default()
+        .dir_path(dir.to_path_buf())
+        .create_if_missing(false)
+        .build()
+        .unwrap();
+    Engine::new(opts).unwrap()
+}
+
+fn render(engine: &Engine) -> String {
+    let entries: Vec<_> = engine
+        .iter(IteratorOptions::default())
+        .unwrap()
+        .map(|entry| entry.unwrap().into_parts())
+        .collect();
+    workload::render(
+        entries
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_ref())),
+    )
+}
+
+#[test]
+fn read_fixtures() {
+    let fixtures = fixtures();
+    assert!(fixtures
+        .iter()
+        .any(|fixture| fixture.ends_with(CURRENT_FORMAT)));
+    for fixture in fixtures {
+        let expected = fs::read_to_string(fixture.join("expected.txt")).unwrap();
+        let dir = copy_db(&fixture);
+        let engine = open(dir.path());
+        assert_eq!(render(&engine), expected, "{:?}", fixture);
+        assert_eq!(engine.verify().unwrap(), vec![], "{:?}", fixture);
+
+        // written to by the current version, and read back
+        engine.put("zz-new".into(), "written".into()).unwrap();
+        drop(engine);
+        let engine = open(dir.path());
+        let expected = expected + "zz-new\twritten\n";
+        assert_eq!(render(&engine), expected, "{:?}", fixture);
+    }
+}
+
+#[test]
+fn write_current_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("db");
+    let expected = workload::write(&db);
+
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/compat")
+        .join(CURRENT_FORMAT);
+    let rendered = workload::render(
+        expected
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice())),
+    );
+    assert_eq!(
+        rendered,
+        fs::read_to_string(fixture.join("expected.txt")).unwrap()
+    );
+
+    // the lock file holds nothing
+    let names = |dir: &Path| -> Vec<_> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != LOCK_FILE)
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&db), names(&fixture.join("db")));
+    for name in names(&db) {
+        assert!(
+            fs::read(db.join(&name)).unwrap() == fs::read(fixture.join("db").join(&name)).unwrap(),
+            "{:?} differs from the one of the {} fixture, the on-disk format changed",
+            name,
+            CURRENT_FORMAT
+        );
+    }
+}
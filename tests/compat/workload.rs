@@ -0,0 +1,75 @@
+//! The canonical workload of the compatibility fixtures, shared by the generator
+//! `examples/compat_fixture.rs` and `tests/compat.rs`
+
+use ailurus_kv::engine::Engine;
+use ailurus_kv::options::{OptionsBuilder, WriteBatchOptions};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type Expected = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Writes the workload into a fresh database at `dir`, returns the entries it leaves.
+/// Its datafiles hold 256 bytes, it thus spans several of them.
+pub fn write(dir: &Path) -> Expected {
+    let opts = OptionsBuilder::default()
+        .dir_path(dir.to_path_buf())
+        .data_file_size(256)
+        .danger_small_files(true)
+        .error_if_exists(true)
+        .build()
+        .unwrap();
+    let engine = Engine::new(opts).unwrap();
+    let mut expected = Expected::new();
+
+    for i in 0..20 {
+        let (key, value) = (format!("key-{:02}", i), format!("value-{}", i));
+        put(&engine, &mut expected, key.as_bytes(), value.as_bytes());
+    }
+    // overwritten in a later datafile
+    put(&engine, &mut expected, b"key-03", b"overwritten");
+    put(&engine, &mut expected, b"empty", b"");
+    put(
+        &engine,
+        &mut expected,
+        b"\x00\xffbinary",
+        b"\x00\x01\xfe\xff",
+    );
+    // sizes taking two bytes
+    put(&engine, &mut expected, &[b'k'; 200], &[b'v'; 130]);
+    put(&engine, &mut expected, b"resurrected", b"first");
+
+    for key in ["key-07", "key-11", "resurrected"] {
+        engine.delete(key.into()).unwrap();
+        expected.remove(key.as_bytes());
+    }
+    put(&engine, &mut expected, b"resurrected", b"second");
+
+    let mut batch = engine.write_batch(WriteBatchOptions::default());
+    for i in 0..4 {
+        let (key, value) = (format!("batch-{}", i), format!("batched-{}", i));
+        batch.put(key.clone().into(), value.clone().into()).unwrap();
+        expected.insert(key.into_bytes(), value.into_bytes());
+    }
+    batch.delete("key-15".into()).unwrap();
+    expected.remove(b"key-15".as_slice());
+    batch.commit().unwrap();
+
+    engine.sync().unwrap();
+    expected
+}
+
+fn put(engine: &Engine, expected: &mut Expected, key: &[u8], value: &[u8]) {
+    engine
+        .put(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value))
+        .unwrap();
+    expected.insert(key.to_vec(), value.to_vec());
+}
+
+/// The entries one per line, the key and value escaped and separated by a tab
+pub fn render<'a>(entries: impl IntoIterator<Item = (&'a [u8], &'a [u8])>) -> String {
+    entries
+        .into_iter()
+        .map(|(key, value)| format!("{}\t{}\n", key.escape_ascii(), value.escape_ascii()))
+        .collect()
+}